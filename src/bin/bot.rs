@@ -1,9 +1,10 @@
 use anyhow::{Context as _, Result};
+use clap::ValueEnum;
 use rusty_ponyo::{
     bot::{
         alias::MessageAliasBot,
         auth::GenkaiAuthBot,
-        genkai_point::{GenkaiPointBot, plot},
+        genkai_point::{ExclusionRules, GenkaiPointBot, digest::DigestPeriod, plot},
         gh::GitHubCodePreviewBot,
         meigen::MeigenBot,
         vc_diff::VcDiffBot,
@@ -51,18 +52,59 @@ async fn async_main() -> Result<()> {
     #[cfg(feature = "plot_charming")]
     let plotter = plot::charming::Charming::new();
 
+    let plotter = plot::pool::RenderPool::new(plotter, render_pool_options()?);
+
     let pgp_whitelist = env_var("PGP_SOURCE_DOMAIN_WHITELIST")?
         .split(',')
         .map(|x| x.to_string())
         .collect();
+    let pgp_keyserver = env_var("PGP_KEYSERVER").ok();
+
+    let gitlab_hosts = env_list("GH_PREVIEW_GITLAB_HOSTS");
+    let gitea_hosts = env_list("GH_PREVIEW_GITEA_HOSTS");
+    let github_token = env_var("GITHUB_TOKEN").ok();
+
+    let exclusion_rules = ExclusionRules {
+        afk_channel_id: env_var("GENKAI_POINT_AFK_CHANNEL_ID")
+            .ok()
+            .map(|x| x.parse())
+            .transpose()
+            .context("failed to parse GENKAI_POINT_AFK_CHANNEL_ID")?,
+        deafen_threshold: env_var("GENKAI_POINT_DEAFEN_THRESHOLD_MINUTES")
+            .ok()
+            .map(|x| x.parse().map(chrono::Duration::minutes))
+            .transpose()
+            .context("failed to parse GENKAI_POINT_DEAFEN_THRESHOLD_MINUTES")?,
+    };
+
+    let digests = env_list("GENKAI_POINT_DIGESTS")
+        .iter()
+        .map(|x| DigestPeriod::from_str(x, true).map_err(anyhow::Error::msg))
+        .collect::<Result<_>>()
+        .context("failed to parse GENKAI_POINT_DIGESTS")?;
+
+    let mut genkai_point = GenkaiPointBot::new(local_db.clone(), plotter)
+        .with_exclusion_rules(exclusion_rules)
+        .with_digests(digests);
+    if env_var("GENKAI_POINT_RECORD_SUB_SESSIONS").is_ok() {
+        genkai_point = genkai_point.with_sub_sessions();
+    }
 
     let client = client
         .add_service(MessageAliasBot::new(local_db.clone()))
-        .add_service(GenkaiPointBot::new(local_db.clone(), plotter))
-        .add_service(GitHubCodePreviewBot)
-        .add_service(GenkaiAuthBot::new(remote_db.clone(), pgp_whitelist))
+        .add_service(genkai_point)
+        .add_service(GitHubCodePreviewBot::new(
+            &gitlab_hosts,
+            &gitea_hosts,
+            github_token,
+        ))
+        .add_service(GenkaiAuthBot::new(
+            remote_db.clone(),
+            pgp_whitelist,
+            pgp_keyserver,
+        ))
         .add_service(MeigenBot::new(remote_db))
-        .add_service(VcDiffBot::new());
+        .add_service(VcDiffBot::new(local_db));
 
     #[cfg(feature = "console_client")]
     {
@@ -77,10 +119,41 @@ async fn async_main() -> Result<()> {
     Ok(())
 }
 
+fn render_pool_options() -> Result<plot::pool::RenderPoolOptions> {
+    let mut options = plot::pool::RenderPoolOptions::default();
+
+    if let Ok(x) = env_var("GENKAI_POINT_RENDER_WORKERS") {
+        options.workers = x
+            .parse()
+            .context("failed to parse GENKAI_POINT_RENDER_WORKERS")?;
+    }
+    if let Ok(x) = env_var("GENKAI_POINT_RENDER_TIMEOUT_SECONDS") {
+        options.timeout = std::time::Duration::from_secs(
+            x.parse()
+                .context("failed to parse GENKAI_POINT_RENDER_TIMEOUT_SECONDS")?,
+        );
+    }
+
+    Ok(options)
+}
+
 fn env_var(name: &str) -> Result<String> {
     std::env::var(name).with_context(|| format!("failed to get {name} environment variable"))
 }
 
+// カンマ区切りの任意の環境変数
+fn env_list(name: &str) -> Vec<String> {
+    std::env::var(name)
+        .map(|x| {
+            x.split(',')
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+                .map(|x| x.to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 macro_rules! assert_one_feature {
     ($a:literal, $b: literal) => {
         #[cfg(all(feature = $a, feature = $b))]
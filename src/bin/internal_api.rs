@@ -2,21 +2,39 @@ use {
     anyhow::Context as _,
     axum::{
         Json, Router,
-        extract::{Path, Query, State},
-        http::StatusCode,
+        extract::{FromRef, Path, Query, State},
+        http::{HeaderMap, StatusCode, header},
         response::{IntoResponse, Response},
-        routing::get,
+        routing::{get, post},
     },
+    chrono::{DateTime, NaiveDate, Utc},
     rusty_ponyo::{
-        bot::meigen::{
-            FindOptions, MeigenDatabase, SortDirection, SortKey,
-            model::{Meigen, MeigenId},
+        bot::{
+            Context, SendMessage,
+            auth::{
+                AuthError, GenkaiAuthDatabase, authenticate,
+                device::{self, DeviceCode, DeviceFlowError, IssuedToken},
+                model::{Token, TokenScope},
+                pgp_key_fingerprints,
+            },
+            genkai_point::{
+                Plotter,
+                plot::{
+                    self, GraphOptions,
+                    chart::OutputFormat,
+                    pool::{RenderError, RenderPool, RenderPoolOptions},
+                },
+            },
+            meigen::{
+                FindOptions, MeigenDatabase, SortDirection, SortKey,
+                model::{Meigen, MeigenId},
+            },
         },
         db,
     },
     serde::Deserialize,
     serde_json::json,
-    std::net::SocketAddr,
+    std::{net::SocketAddr, sync::Arc},
     tokio::net::TcpListener,
     tower_http::trace::TraceLayer,
     tracing::error,
@@ -30,6 +48,29 @@ type Db = db::mongodb::MongoDb;
 #[cfg(feature = "memory_db")]
 type Db = db::mem::MemoryDB;
 
+assert_one_feature!("plot_plotters", "plot_matplotlib", "plot_charming");
+
+#[cfg(feature = "plot_plotters")]
+type GraphPlotter = plot::plotters::Plotters;
+
+#[cfg(feature = "plot_matplotlib")]
+type GraphPlotter = plot::matplotlib::Matplotlib;
+
+#[cfg(feature = "plot_charming")]
+type GraphPlotter = plot::charming::Charming;
+
+#[derive(Clone)]
+struct AppState {
+    db: Db,
+    plotter: Arc<RenderPool<GraphPlotter>>,
+}
+
+impl FromRef<AppState> for Db {
+    fn from_ref(state: &AppState) -> Self {
+        state.db.clone()
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv::dotenv().ok();
@@ -40,13 +81,21 @@ async fn main() -> anyhow::Result<()> {
         .context("failed to parse INTERNAL_API_ADDR")?;
 
     let db = build_db().await?;
+    let plotter = Arc::new(RenderPool::new(
+        GraphPlotter::new(),
+        RenderPoolOptions::default(),
+    ));
 
     let app = Router::new()
-        .route("/meigen/:id", get(get_meigen_by_id))
+        .route("/meigen/{id}", get(get_meigen_by_id))
         .route("/meigen", get(search_meigen))
         .route("/meigen/count", get(count_meigen))
+        .route("/auth/pgp/{user_id}", get(get_pgp_key))
+        .route("/auth/device", post(start_device_flow))
+        .route("/auth/device/token", post(poll_device_flow))
+        .route("/genkai_point/graph", get(genkai_point_graph))
         .layer(TraceLayer::new_for_http())
-        .with_state(db);
+        .with_state(AppState { db, plotter });
 
     tracing_subscriber::fmt()
         .with_ansi(std::env::var("NO_COLOR").is_err())
@@ -84,10 +133,26 @@ struct SearchQuery {
     random: bool,
 }
 
+// checks the token in `Authorization: Bearer <token>` header
+async fn authorize(db: &Db, headers: &HeaderMap, scope: TokenScope) -> Result<Token, ApiError> {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .ok_or_else(|| ApiError::Unauthorized("bearer token is required".into()))?;
+
+    authenticate(db, token.trim(), scope, Utc::now())
+        .await
+        .map_err(Into::into)
+}
+
 async fn get_meigen_by_id(
     State(db): State<Db>,
+    headers: HeaderMap,
     Path(id): Path<MeigenId>,
 ) -> Result<Json<Meigen>, ApiError> {
+    authorize(&db, &headers, TokenScope::MeigenRead).await?;
+
     match db.load(id).await {
         Ok(Some(meigen)) => Ok(Json(meigen)),
         Ok(None) => Err(ApiError::NotFound(format!("meigen No.{id} not found"))),
@@ -97,8 +162,11 @@ async fn get_meigen_by_id(
 
 async fn search_meigen(
     State(db): State<Db>,
+    headers: HeaderMap,
     Query(q): Query<SearchQuery>,
 ) -> Result<Json<Vec<Meigen>>, ApiError> {
+    authorize(&db, &headers, TokenScope::MeigenRead).await?;
+
     let sort = q.sort.unwrap_or_default();
     let dir = q.dir.unwrap_or_default();
     let offset = q.offset.unwrap_or(0);
@@ -123,7 +191,12 @@ async fn search_meigen(
     db.search(options).await.map(Json).map_err(Into::into)
 }
 
-async fn count_meigen(State(db): State<Db>) -> Result<Json<CountResponse>, ApiError> {
+async fn count_meigen(
+    State(db): State<Db>,
+    headers: HeaderMap,
+) -> Result<Json<CountResponse>, ApiError> {
+    authorize(&db, &headers, TokenScope::MeigenRead).await?;
+
     db.count()
         .await
         .map(|count| Json(CountResponse { count }))
@@ -135,16 +208,183 @@ struct CountResponse {
     count: u32,
 }
 
+async fn get_pgp_key(
+    State(db): State<Db>,
+    Path(user_id): Path<u64>,
+) -> Result<Json<PgpKeyResponse>, ApiError> {
+    let Some(key) = db.get_pgp_key(user_id).await? else {
+        return Err(ApiError::NotFound(format!("user {user_id} has no pgp key")));
+    };
+
+    let verified_at = db.get_pgp_key_verified_at(user_id).await?;
+    let fingerprints = pgp_key_fingerprints(&key)?;
+
+    Ok(Json(PgpKeyResponse {
+        user_id,
+        fingerprints,
+        key,
+        verified: verified_at.is_some(),
+        verified_at,
+    }))
+}
+
+#[derive(serde::Serialize)]
+struct PgpKeyResponse {
+    user_id: u64,
+    fingerprints: Vec<String>,
+    key: String,
+    // whether the user proved the ownership of the key with `g!auth verify`
+    verified: bool,
+    verified_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct DeviceFlowRequest {
+    name: String,
+    scopes: Vec<TokenScope>,
+}
+
+async fn start_device_flow(
+    State(db): State<Db>,
+    Json(req): Json<DeviceFlowRequest>,
+) -> Result<Json<DeviceCode>, ApiError> {
+    device::start(&db, req.name, req.scopes, Utc::now())
+        .await
+        .map(Json)
+        .map_err(Into::into)
+}
+
+#[derive(Deserialize)]
+struct DeviceTokenRequest {
+    device_code: String,
+}
+
+async fn poll_device_flow(
+    State(db): State<Db>,
+    Json(req): Json<DeviceTokenRequest>,
+) -> Result<Json<IssuedToken>, ApiError> {
+    device::poll(&db, &req.device_code, Utc::now())
+        .await
+        .map(Json)
+        .map_err(Into::into)
+}
+
+#[derive(Deserialize)]
+struct GraphQuery {
+    top: Option<u8>,
+    // comma separated user ids
+    users: Option<String>,
+    since: Option<NaiveDate>,
+    until: Option<NaiveDate>,
+    rolling: Option<u16>,
+    #[serde(default)]
+    format: OutputFormat,
+}
+
+async fn genkai_point_graph(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(q): Query<GraphQuery>,
+) -> Result<Response, ApiError> {
+    authorize(&state.db, &headers, TokenScope::PointRead).await?;
+
+    if !state.plotter.supports(q.format) {
+        return Err(ApiError::BadRequest(format!(
+            "format {} is not supported by this server",
+            q.format.extension()
+        )));
+    }
+
+    let users = match q.users.as_deref() {
+        Some(users) => users
+            .split(',')
+            .map(|x| x.trim().parse())
+            .collect::<Result<Vec<u64>, _>>()
+            .map_err(|_| ApiError::BadRequest("users must be comma separated ids".into()))?,
+        None => vec![],
+    };
+
+    let options = GraphOptions {
+        users,
+        top: q.top.unwrap_or(5).clamp(1, 11) as _,
+        since: q.since,
+        until: q.until,
+        rolling: q.rolling.filter(|x| *x >= 1).map(|x| x as _),
+        format: q.format,
+    };
+
+    let Some(image) = plot::plot(&state.db, &UserIdNames, &*state.plotter, &options).await? else {
+        return Err(ApiError::NotFound("not enough data to plot".into()));
+    };
+
+    Ok(([(header::CONTENT_TYPE, q.format.mime_type())], image).into_response())
+}
+
+// the api has no access to discord, so users are labeled with their ids
+struct UserIdNames;
+
+impl Context for UserIdNames {
+    async fn send_message(&self, _msg: SendMessage<'_>) -> anyhow::Result<()> {
+        anyhow::bail!("internal api can't send messages")
+    }
+
+    async fn send_message_to(&self, _channel_id: u64, _msg: SendMessage<'_>) -> anyhow::Result<()> {
+        anyhow::bail!("internal api can't send messages")
+    }
+
+    async fn get_user_name(&self, user_id: u64) -> anyhow::Result<String> {
+        Ok(user_id.to_string())
+    }
+
+    async fn get_channel_name(&self, channel_id: u64) -> anyhow::Result<String> {
+        Ok(channel_id.to_string())
+    }
+
+    async fn is_bot(&self, _user_id: u64) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+}
+
 #[derive(Debug)]
 enum ApiError {
     BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
     NotFound(String),
+    Unavailable(String),
     Internal(anyhow::Error),
 }
 
 impl From<anyhow::Error> for ApiError {
     fn from(value: anyhow::Error) -> Self {
-        Self::Internal(value)
+        match value.downcast_ref::<RenderError>() {
+            Some(RenderError::Busy) => Self::Unavailable("renderer is busy, try later".into()),
+            Some(RenderError::Timeout) => Self::Unavailable("rendering timed out".into()),
+            None => Self::Internal(value),
+        }
+    }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(value: AuthError) -> Self {
+        match value {
+            AuthError::Internal(err) => Self::Internal(err),
+            err @ AuthError::MissingScope(_) => Self::Forbidden(err.to_string()),
+            err => Self::Unauthorized(err.to_string()),
+        }
+    }
+}
+
+impl From<DeviceFlowError> for ApiError {
+    fn from(value: DeviceFlowError) -> Self {
+        match value {
+            DeviceFlowError::InvalidRequest(msg) => Self::BadRequest(msg.to_owned()),
+            err @ DeviceFlowError::TooManyPending => Self::Unavailable(err.to_string()),
+            DeviceFlowError::Internal(err) => Self::Internal(err),
+            // tools are expected to check this code and keep polling while it's "authorization_pending",
+            // adding 5 seconds to the interval on "slow_down"
+            err => Self::BadRequest(err.to_string()),
+        }
     }
 }
 
@@ -154,9 +394,20 @@ impl IntoResponse for ApiError {
             ApiError::BadRequest(msg) => {
                 (StatusCode::BAD_REQUEST, Json(json!({ "error": msg }))).into_response()
             }
+            ApiError::Unauthorized(msg) => {
+                (StatusCode::UNAUTHORIZED, Json(json!({ "error": msg }))).into_response()
+            }
+            ApiError::Forbidden(msg) => {
+                (StatusCode::FORBIDDEN, Json(json!({ "error": msg }))).into_response()
+            }
             ApiError::NotFound(msg) => {
                 (StatusCode::NOT_FOUND, Json(json!({ "error": msg }))).into_response()
             }
+            ApiError::Unavailable(msg) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({ "error": msg })),
+            )
+                .into_response(),
             ApiError::Internal(err) => {
                 error!(?err, "internal error while handling request");
                 (
@@ -193,6 +444,56 @@ macro_rules! assert_one_feature {
             " feature."
         ));
     };
+    ($a:literal, $b:literal, $c:literal) => {
+        #[cfg(all(feature = $a, feature = $b, feature = $c))]
+        compile_error!(concat!(
+            "You can't enable both of ",
+            $a,
+            " and ",
+            $b,
+            " and ",
+            $c,
+            " feature at the same time."
+        ));
+
+        #[cfg(all(feature = $a, feature = $b))]
+        compile_error!(concat!(
+            "You can't enable both of ",
+            $a,
+            " and ",
+            $b,
+            " feature at the same time."
+        ));
+
+        #[cfg(all(feature = $b, feature = $c))]
+        compile_error!(concat!(
+            "You can't enable both of ",
+            $b,
+            " and ",
+            $c,
+            " feature at the same time."
+        ));
+
+        #[cfg(all(feature = $c, feature = $a))]
+        compile_error!(concat!(
+            "You can't enable both of ",
+            $c,
+            " and ",
+            $a,
+            " feature at the same time."
+        ));
+
+        #[cfg(not(any(feature = $a, feature = $b, feature = $c)))]
+        compile_error!(concat!(
+            "You must enable either ",
+            $a,
+            " or ",
+            $b,
+            " or ",
+            $c,
+            " feature."
+        ));
+    };
 }
 
 use assert_one_feature;
@@ -0,0 +1,556 @@
+//! RFC 8628 (OAuth 2.0 Device Authorization Grant) に似た、PGP 鍵なしでツールにトークンを渡すためのフロー
+//!
+//! 1. ツールが internal API で [`start`] を呼び、device code と user code を受け取る
+//! 2. ユーザーが Discord で `g!auth approve <user code>` を実行する
+//! 3. ツールが device code で [`poll`] し、承認されていればトークンを受け取る
+
+use {
+    super::{
+        GenkaiAuthDatabase, MAX_TOKENS_PER_USER, gen_token, hash_token,
+        model::{DeviceAuthorization, Token, TokenScope},
+    },
+    anyhow::{Context as _, anyhow},
+    chrono::{DateTime, Duration, Utc},
+    rand::{RngExt, SeedableRng, prelude::StdRng},
+    serde::Serialize,
+};
+
+// user code を入力するまでの猶予
+const DEVICE_CODE_TTL: Duration = Duration::minutes(10);
+// 発行されるトークンの有効期間
+const TOKEN_LIFETIME: Duration = Duration::days(90);
+// ツールがポーリングする間隔 (秒)。これより短い間隔でポーリングされたら slow_down を返す
+const POLL_INTERVAL: u64 = 5;
+// 誰でも始められるので、承認待ちが溜まりすぎないようにする
+const MAX_PENDING_AUTHORIZATIONS: usize = 100;
+// user code が承認待ちのものと衝突したときに作り直す回数
+const MAX_USER_CODE_ATTEMPTS: usize = 5;
+
+// 読み間違えにくい文字だけを使う (RFC 8628 6.1)
+const USER_CODE_CHARS: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+const USER_CODE_LEN: usize = 8;
+const MAX_NAME_LEN: usize = 32;
+
+#[derive(Debug, Serialize)]
+pub struct DeviceCode {
+    pub device_code: String,
+    /// `XXXX-XXXX` 形式
+    pub user_code: String,
+    /// 秒
+    pub expires_in: i64,
+    /// 秒
+    pub interval: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IssuedToken {
+    pub token: String,
+    pub name: String,
+    pub scopes: Vec<TokenScope>,
+    pub expires_at: DateTime<Utc>,
+}
+
+// the messages are the error codes defined in RFC 8628 3.5
+#[derive(Debug, thiserror::Error)]
+pub enum DeviceFlowError {
+    #[error("invalid_request")]
+    InvalidRequest(&'static str),
+    #[error("authorization_pending")]
+    Pending,
+    #[error("expired_token")]
+    Expired,
+    #[error("invalid_grant")]
+    InvalidGrant,
+    #[error("slow_down")]
+    SlowDown,
+    #[error("too many pending authorizations, try later")]
+    TooManyPending,
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+/// ツールからのログイン要求を受け付けます
+pub async fn start(
+    db: &impl GenkaiAuthDatabase,
+    name: String,
+    mut scopes: Vec<TokenScope>,
+    now: DateTime<Utc>,
+) -> Result<DeviceCode, DeviceFlowError> {
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(DeviceFlowError::InvalidRequest(
+            "name must be 1 to 32 characters",
+        ));
+    }
+
+    if scopes.is_empty() {
+        return Err(DeviceFlowError::InvalidRequest(
+            "at least one scope is required",
+        ));
+    }
+
+    scopes.sort_by_key(|x| x.as_str());
+    scopes.dedup();
+
+    let pending = db
+        .count_device_authorizations(now)
+        .await
+        .context("failed to count device authorizations")?;
+    if pending >= MAX_PENDING_AUTHORIZATIONS {
+        return Err(DeviceFlowError::TooManyPending);
+    }
+
+    let mut codes = None;
+    for _ in 0..MAX_USER_CODE_ATTEMPTS {
+        let (device_code, user_code) = gen_codes();
+
+        let created = db
+            .create_device_authorization(DeviceAuthorization {
+                hashed_device_code: hash_token(&device_code),
+                user_code: user_code.clone(),
+                name: name.clone(),
+                scopes: scopes.clone(),
+                created_at: now,
+                expires_at: now + DEVICE_CODE_TTL,
+                approved_by: None,
+                last_polled_at: None,
+            })
+            .await
+            .context("failed to create device authorization")?;
+
+        if created {
+            codes = Some((device_code, user_code));
+            break;
+        }
+    }
+
+    let (device_code, user_code) =
+        codes.ok_or_else(|| anyhow!("failed to generate an unused user code"))?;
+
+    Ok(DeviceCode {
+        device_code,
+        user_code: format!(
+            "{}-{}",
+            &user_code[..USER_CODE_LEN / 2],
+            &user_code[USER_CODE_LEN / 2..]
+        ),
+        expires_in: DEVICE_CODE_TTL.num_seconds(),
+        interval: POLL_INTERVAL,
+    })
+}
+
+// returns (device code, user code)
+fn gen_codes() -> (String, String) {
+    let mut seed_rng = rand::rng();
+    let mut rng = StdRng::from_rng(&mut seed_rng);
+
+    let device_code = hex::encode(rng.random::<[u8; 32]>());
+    let user_code = (0..USER_CODE_LEN)
+        .map(|_| USER_CODE_CHARS[rng.random_range(0..USER_CODE_CHARS.len())] as char)
+        .collect::<String>();
+
+    (device_code, user_code)
+}
+
+/// 承認されていればトークンを発行します。トークンを受け取れるのは1回だけです。
+pub async fn poll(
+    db: &impl GenkaiAuthDatabase,
+    device_code: &str,
+    now: DateTime<Utc>,
+) -> Result<IssuedToken, DeviceFlowError> {
+    let hashed = hash_token(device_code);
+
+    let auth = db
+        .get_device_authorization(&hashed)
+        .await
+        .context("failed to fetch device authorization")?
+        .ok_or(DeviceFlowError::InvalidGrant)?;
+
+    if auth.is_expired(now) {
+        db.delete_device_authorization(&hashed)
+            .await
+            .context("failed to delete expired device authorization")?;
+        return Err(DeviceFlowError::Expired);
+    }
+
+    let polled = db
+        .record_device_poll(&hashed, now, now - Duration::seconds(POLL_INTERVAL as i64))
+        .await
+        .context("failed to record device poll")?;
+    if !polled {
+        return Err(DeviceFlowError::SlowDown);
+    }
+
+    let Some(user_id) = auth.approved_by else {
+        return Err(DeviceFlowError::Pending);
+    };
+
+    // the user may have approved other codes with the same name since then
+    if check_new_token(db, user_id, &auth.name).await?.is_some() {
+        db.delete_device_authorization(&hashed)
+            .await
+            .context("failed to delete device authorization")?;
+        return Err(DeviceFlowError::InvalidGrant);
+    }
+
+    // whoever deletes it first gets the token
+    let claimed = db
+        .delete_device_authorization(&hashed)
+        .await
+        .context("failed to delete device authorization")?;
+
+    if !claimed {
+        return Err(DeviceFlowError::InvalidGrant);
+    }
+
+    let (id, token) = gen_token();
+    let expires_at = now + TOKEN_LIFETIME;
+
+    db.register_token(Token {
+        id,
+        user_id,
+        name: auth.name.clone(),
+        hashed_token: hash_token(&token),
+        scopes: auth.scopes.clone(),
+        created_at: now,
+        expires_at: Some(expires_at),
+        last_used_at: None,
+    })
+    .await
+    .context("failed to register new token")?;
+
+    Ok(IssuedToken {
+        token,
+        name: auth.name,
+        scopes: auth.scopes,
+        expires_at,
+    })
+}
+
+// returns why the user can't have a new token with the name, if so
+async fn check_new_token(
+    db: &impl GenkaiAuthDatabase,
+    user_id: u64,
+    name: &str,
+) -> anyhow::Result<Option<&'static str>> {
+    let tokens = db
+        .get_tokens(user_id)
+        .await
+        .context("failed to fetch user's tokens")?;
+
+    if tokens.iter().any(|x| x.name == name) {
+        return Ok(Some(
+            "同じ名前のトークンがすでに登録されています。先に revoke してください。",
+        ));
+    }
+
+    if tokens.len() >= MAX_TOKENS_PER_USER {
+        return Ok(Some(
+            "トークンの数が上限に達しています。不要なトークンを revoke してください。",
+        ));
+    }
+
+    Ok(None)
+}
+
+fn normalize_user_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+// returns a message for the user if it can't be approved
+pub(super) async fn approve(
+    db: &impl GenkaiAuthDatabase,
+    user_code: &str,
+    user_id: u64,
+    now: DateTime<Utc>,
+) -> anyhow::Result<Result<DeviceAuthorization, &'static str>> {
+    const NOT_FOUND: &str = "コードが見つからないか、有効期限が切れています。";
+
+    let user_code = normalize_user_code(user_code);
+
+    let auth = db
+        .get_device_authorization_by_user_code(&user_code)
+        .await
+        .context("failed to fetch device authorization")?
+        .filter(|x| !x.is_expired(now) && x.approved_by.is_none());
+
+    let Some(auth) = auth else {
+        return Ok(Err(NOT_FOUND));
+    };
+
+    if let Some(reason) = check_new_token(db, user_id, &auth.name).await? {
+        return Ok(Err(reason));
+    }
+
+    // approve exactly the one checked above
+    let approved = db
+        .approve_device_authorization(&auth.hashed_device_code, user_id)
+        .await
+        .context("failed to approve device authorization")?;
+
+    if !approved {
+        return Ok(Err(NOT_FOUND));
+    }
+
+    Ok(Ok(auth))
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::{bot::auth::authenticate, db::mem::MemoryDB},
+        pretty_assertions::assert_eq,
+    };
+
+    #[tokio::test]
+    async fn device_flow() {
+        let db = MemoryDB::new();
+        let now = Utc::now();
+
+        assert!(matches!(
+            start(&db, "tool".to_owned(), vec![], now).await,
+            Err(DeviceFlowError::InvalidRequest(_))
+        ));
+
+        let code = start(
+            &db,
+            "tool".to_owned(),
+            vec![TokenScope::PointRead, TokenScope::MeigenRead],
+            now,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(code.user_code.len(), USER_CODE_LEN + 1);
+        assert!(matches!(
+            poll(&db, &code.device_code, now).await,
+            Err(DeviceFlowError::Pending)
+        ));
+
+        assert!(approve(&db, "BBBB-BBBB", 1, now).await.unwrap().is_err());
+
+        let approved = approve(&db, &code.user_code.to_lowercase(), 1, now)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(approved.name, "tool");
+        // can't be approved twice
+        assert!(
+            approve(&db, &code.user_code, 2, now)
+                .await
+                .unwrap()
+                .is_err()
+        );
+
+        assert!(matches!(
+            poll(&db, "wrong", now).await,
+            Err(DeviceFlowError::InvalidGrant)
+        ));
+
+        let later = now + Duration::seconds(POLL_INTERVAL as i64);
+        let issued = poll(&db, &code.device_code, later).await.unwrap();
+        assert_eq!(
+            issued.scopes,
+            vec![TokenScope::MeigenRead, TokenScope::PointRead]
+        );
+
+        let token = authenticate(&db, &issued.token, TokenScope::PointRead, now)
+            .await
+            .unwrap();
+        assert_eq!(token.user_id, 1);
+        assert_eq!(token.name, "tool");
+
+        // the token is handed out only once
+        assert!(matches!(
+            poll(&db, &code.device_code, later).await,
+            Err(DeviceFlowError::InvalidGrant)
+        ));
+    }
+
+    #[tokio::test]
+    async fn approved_twice_with_same_name() {
+        let db = MemoryDB::new();
+        let now = Utc::now();
+
+        let first = start(&db, "tool".to_owned(), vec![TokenScope::MeigenRead], now)
+            .await
+            .unwrap();
+        let second = start(&db, "tool".to_owned(), vec![TokenScope::MeigenRead], now)
+            .await
+            .unwrap();
+
+        // neither has been polled yet, so both can be approved
+        approve(&db, &first.user_code, 1, now)
+            .await
+            .unwrap()
+            .unwrap();
+        approve(&db, &second.user_code, 1, now)
+            .await
+            .unwrap()
+            .unwrap();
+
+        poll(&db, &second.device_code, now).await.unwrap();
+        assert!(matches!(
+            poll(&db, &first.device_code, now).await,
+            Err(DeviceFlowError::InvalidGrant)
+        ));
+        // and it's gone
+        assert!(matches!(
+            poll(&db, &first.device_code, now).await,
+            Err(DeviceFlowError::InvalidGrant)
+        ));
+
+        assert_eq!(db.get_tokens(1).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn token_limit_is_checked_on_poll() {
+        let db = MemoryDB::new();
+        let now = Utc::now();
+
+        let mut codes = vec![];
+        for i in 0..=MAX_TOKENS_PER_USER {
+            let code = start(&db, format!("tool{i}"), vec![TokenScope::MeigenRead], now)
+                .await
+                .unwrap();
+            approve(&db, &code.user_code, 1, now)
+                .await
+                .unwrap()
+                .unwrap();
+            codes.push(code);
+        }
+
+        let (last, rest) = codes.split_last().unwrap();
+        for code in rest {
+            poll(&db, &code.device_code, now).await.unwrap();
+        }
+        assert!(matches!(
+            poll(&db, &last.device_code, now).await,
+            Err(DeviceFlowError::InvalidGrant)
+        ));
+
+        assert_eq!(db.get_tokens(1).await.unwrap().len(), MAX_TOKENS_PER_USER);
+    }
+
+    #[tokio::test]
+    async fn slow_down() {
+        let db = MemoryDB::new();
+        let now = Utc::now();
+
+        let code = start(&db, "tool".to_owned(), vec![TokenScope::MeigenRead], now)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            poll(&db, &code.device_code, now).await,
+            Err(DeviceFlowError::Pending)
+        ));
+        assert!(matches!(
+            poll(&db, &code.device_code, now + Duration::seconds(1)).await,
+            Err(DeviceFlowError::SlowDown)
+        ));
+
+        // the interval counts from the last accepted poll
+        let later = now + Duration::seconds(POLL_INTERVAL as i64);
+        assert!(matches!(
+            poll(&db, &code.device_code, later).await,
+            Err(DeviceFlowError::Pending)
+        ));
+    }
+
+    #[tokio::test]
+    async fn pending_authorizations_are_limited() {
+        let db = MemoryDB::new();
+        let now = Utc::now();
+
+        for _ in 0..MAX_PENDING_AUTHORIZATIONS {
+            start(&db, "tool".to_owned(), vec![TokenScope::MeigenRead], now)
+                .await
+                .unwrap();
+        }
+
+        assert!(matches!(
+            start(&db, "tool".to_owned(), vec![TokenScope::MeigenRead], now).await,
+            Err(DeviceFlowError::TooManyPending)
+        ));
+
+        // expired ones don't count
+        let later = now + DEVICE_CODE_TTL;
+        start(&db, "tool".to_owned(), vec![TokenScope::MeigenRead], later)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn user_code_is_unique() {
+        let db = MemoryDB::new();
+        let now = Utc::now();
+
+        let auth = |device_code: &str| DeviceAuthorization {
+            hashed_device_code: hash_token(device_code),
+            user_code: "BCDFGHJK".to_owned(),
+            name: device_code.to_owned(),
+            scopes: vec![TokenScope::MeigenRead],
+            created_at: now,
+            expires_at: now + DEVICE_CODE_TTL,
+            approved_by: None,
+            last_polled_at: None,
+        };
+
+        assert!(db.create_device_authorization(auth("first")).await.unwrap());
+        assert!(
+            !db.create_device_authorization(auth("second"))
+                .await
+                .unwrap()
+        );
+
+        let approved = approve(&db, "BCDF-GHJK", 1, now).await.unwrap().unwrap();
+        assert_eq!(approved.name, "first");
+    }
+
+    #[tokio::test]
+    async fn expired_device_code() {
+        let db = MemoryDB::new();
+        let now = Utc::now();
+
+        let code = start(&db, "tool".to_owned(), vec![TokenScope::MeigenRead], now)
+            .await
+            .unwrap();
+
+        let later = now + DEVICE_CODE_TTL;
+        assert!(
+            approve(&db, &code.user_code, 1, later)
+                .await
+                .unwrap()
+                .is_err()
+        );
+        assert!(matches!(
+            poll(&db, &code.device_code, later).await,
+            Err(DeviceFlowError::Expired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn abandoned_device_code_is_removed() {
+        let db = MemoryDB::new();
+        let now = Utc::now();
+
+        let abandoned = start(&db, "tool".to_owned(), vec![TokenScope::MeigenRead], now)
+            .await
+            .unwrap();
+
+        // the next request sweeps it
+        let later = now + DEVICE_CODE_TTL;
+        start(&db, "tool".to_owned(), vec![TokenScope::MeigenRead], later)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            poll(&db, &abandoned.device_code, later).await,
+            Err(DeviceFlowError::InvalidGrant)
+        ));
+    }
+}
@@ -0,0 +1,160 @@
+use {
+    sequoia_net::wkd,
+    url::{Host, Url},
+};
+
+/// 公開鍵の取得元
+#[derive(Debug)]
+pub(super) enum KeySource {
+    /// ホワイトリストにあるドメインの URL
+    Url(String),
+    /// Web Key Directory で探すメールアドレス
+    Wkd(String),
+    /// キーサーバーで探す指紋
+    Keyserver(String),
+    /// メッセージに添付された鍵 (armored or binary)
+    Attachment(Vec<u8>),
+}
+
+/// Web Key Directory で公開鍵を探す場所
+pub(super) struct Wkd {
+    // テストではメールアドレスのドメインの代わりにここへ問い合わせる
+    base: Option<Url>,
+}
+
+impl Wkd {
+    pub(super) fn new() -> Self {
+        Self { base: None }
+    }
+
+    #[cfg(test)]
+    pub(super) fn with_base(base: &str) -> Self {
+        Self {
+            base: Some(Url::parse(base).unwrap()),
+        }
+    }
+
+    /// `email` の公開鍵がありうる URL を、問い合わせる順 (advanced, direct) に返します
+    pub(super) fn urls(&self, email: &str) -> Option<[Url; 2]> {
+        let domain = email_domain(email)?;
+        let (local, _) = email.split_once('@')?;
+
+        let wkd = wkd::Url::from(format!("{local}@{domain}")).ok()?;
+        let mut urls = [
+            wkd.to_url(wkd::Variant::Advanced).ok()?,
+            wkd.to_url(wkd::Variant::Direct).ok()?,
+        ];
+
+        if let Some(base) = &self.base {
+            for url in &mut urls {
+                url.set_scheme(base.scheme()).ok()?;
+                url.set_host(base.host_str()).ok()?;
+                url.set_port(base.port()).ok()?;
+            }
+        }
+
+        Some(urls)
+    }
+}
+
+/// メールアドレスのドメインを正規化して返します。
+/// 問い合わせ先のホストになるので、IP アドレスやポート、パスなどを含むものは受け付けません
+pub(super) fn email_domain(email: &str) -> Option<String> {
+    let (local, domain) = email.split_once('@')?;
+    if local.is_empty()
+        || domain
+            .chars()
+            .any(|c| ":/?#@".contains(c) || c.is_whitespace())
+    {
+        return None;
+    }
+
+    match Host::parse(domain).ok()? {
+        Host::Domain(domain) => Some(domain),
+        Host::Ipv4(_) | Host::Ipv6(_) => None,
+    }
+}
+
+/// Verifying Keyserver (e.g. keys.openpgp.org)
+pub(super) struct Vks {
+    base: String,
+}
+
+impl Vks {
+    pub(super) fn new(base: &str) -> Self {
+        Self {
+            base: base.trim_end_matches('/').to_owned(),
+        }
+    }
+
+    pub(super) fn url(&self, fingerprint: &str) -> String {
+        format!("{}/vks/v1/by-fingerprint/{fingerprint}", self.base)
+    }
+}
+
+/// `0x` やスペースを取り除いて大文字の16進数にします。v4 (40桁) と v6 (64桁) の指紋を受け付けます。
+pub(super) fn normalize_fingerprint(fingerprint: &str) -> Option<String> {
+    let fingerprint = fingerprint.strip_prefix("0x").unwrap_or(fingerprint);
+    let fingerprint = fingerprint
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_ascii_uppercase();
+
+    (matches!(fingerprint.len(), 40 | 64) && fingerprint.chars().all(|c| c.is_ascii_hexdigit()))
+        .then_some(fingerprint)
+}
+
+#[cfg(test)]
+mod test {
+    use {super::*, pretty_assertions::assert_eq};
+
+    #[test]
+    fn wkd_urls() {
+        // test vector from draft-koch-openpgp-webkey-service
+        let [advanced, direct] = Wkd::new().urls("Joe.Doe@Example.ORG").unwrap();
+
+        assert_eq!(
+            advanced.as_str(),
+            "https://openpgpkey.example.org/.well-known/openpgpkey/example.org/hu/iy9q119eutrkn8s1mk4r39qejnbu3n5q?l=Joe.Doe"
+        );
+        assert_eq!(
+            direct.as_str(),
+            "https://example.org/.well-known/openpgpkey/hu/iy9q119eutrkn8s1mk4r39qejnbu3n5q?l=Joe.Doe"
+        );
+
+        assert!(Wkd::new().urls("example.org").is_none());
+        assert!(Wkd::new().urls("@example.org").is_none());
+    }
+
+    #[test]
+    fn email_domains() {
+        assert_eq!(
+            email_domain("x@Example.ORG").as_deref(),
+            Some("example.org")
+        );
+
+        // these would point the bot to arbitrary hosts, ports and paths
+        assert_eq!(email_domain("x@127.0.0.1"), None);
+        assert_eq!(email_domain("x@[::1]"), None);
+        assert_eq!(email_domain("x@127.0.0.1:8080/admin?"), None);
+        assert_eq!(email_domain("x@internal-host:8443/p#"), None);
+        assert_eq!(email_domain("x@evil.example/@example.org"), None);
+        assert_eq!(email_domain("x@exa mple.org"), None);
+        assert_eq!(email_domain("x@"), None);
+    }
+
+    #[test]
+    fn fingerprints() {
+        assert_eq!(
+            normalize_fingerprint("0x0123 4567 89ab cdef 0123  4567 89ab cdef 0123 4567")
+                .as_deref(),
+            Some("0123456789ABCDEF0123456789ABCDEF01234567")
+        );
+        assert_eq!(normalize_fingerprint("0123456789ABCDEF"), None);
+        assert_eq!(
+            normalize_fingerprint("0123456789ABCDEF0123456789ABCDEF0123456Z"),
+            None
+        );
+    }
+}
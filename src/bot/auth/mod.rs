@@ -1,21 +1,49 @@
+pub mod device;
+mod keysource;
+pub mod model;
+mod verify;
+
 use {
-    crate::bot::{BotService, Context, Message, Runtime, User, parse_command, ui},
-    anyhow::{Context as _, Result},
+    crate::bot::{
+        Attachment, BotService, Context, IsUpdated, Message, Runtime, User,
+        auth::{
+            keysource::{KeySource, Vks, Wkd, email_domain, normalize_fingerprint},
+            model::{DeviceAuthorization, Token, TokenScope},
+            verify::{Answer, Challenge, Challenges},
+        },
+        genkai_point::parse_duration,
+        parse_command, ui,
+    },
+    anyhow::{Context as _, Result, bail},
+    chrono::{DateTime, Duration as ChronoDuration, Utc},
+    chrono_tz::Asia::Tokyo,
+    clap::ArgGroup,
     rand::{RngExt, SeedableRng, prelude::StdRng},
     sequoia_openpgp::{
         Cert,
+        armor::{Kind, Writer as ArmorWriter},
         cert::CertParser,
         parse::{PacketParser, Parse},
         policy::StandardPolicy,
-        serialize::stream::{Armorer, Encryptor, LiteralWriter, Message as OpenGPGMessage},
+        serialize::{
+            Serialize,
+            stream::{Armorer, Encryptor, LiteralWriter, Message as OpenGPGMessage},
+        },
     },
     sha2::Digest,
-    std::{future::Future, io::Write, time::Duration},
+    std::{
+        fmt::Write as _,
+        future::Future,
+        io::Write,
+        time::{Duration, Instant},
+    },
     url::{Host, Origin, Url},
 };
 
 const NAME: &str = "rusty_ponyo::bot::auth";
 const PREFIX: &str = "g!auth";
+const DEFAULT_KEYSERVER: &str = "https://keys.openpgp.org";
+const KEY_SIZE_LIMIT: u64 = 1024 * 64; // 64KIB
 
 ui! {
     /// 限界認証情報の設定管理を行います
@@ -34,18 +62,64 @@ enum Command {
     },
 
     /// あなたのトークンを作成してDMに送信します
-    Token,
+    Token {
+        /// トークンの名前。使うツールごとに分けてください
+        name: String,
+
+        /// トークンに許可する操作
+        #[clap(long, short, value_enum, required = true, num_args = 1..)]
+        scope: Vec<TokenScope>,
+
+        /// トークンの有効期間
+        /// フォーマットは humantime クレートに則ります
+        #[clap(long, value_parser = parse_duration, default_value = "90days")]
+        expires_in: ChronoDuration,
+    },
+
+    /// あなたのトークンの一覧を表示します
+    List,
+
+    /// 登録されている PGP 公開鍵の情報を表示します
+    Show,
+
+    /// PGP 公開鍵の持ち主であることを確認します
+    /// 引数なしで実行すると、確認用の文字列を鍵で暗号化してDMに送信します
+    /// 復号した文字列を指定するか、署名用の文字列への detached signature を添付して答えてください
+    Verify {
+        /// 復号した文字列
+        nonce: Option<String>,
+    },
 
-    /// あなたのトークンを無効化します
-    Revoke,
+    /// ツールからのログイン要求を承認し、トークンを発行します
+    Approve {
+        /// ツールに表示されたコード
+        code: String,
+    },
+
+    /// 指定した名前のトークンを無効化します
+    Revoke {
+        /// トークンの名前
+        name: String,
+    },
 }
 
 #[derive(Debug, clap::Subcommand)]
 enum SetCommand {
     /// PGP公開鍵を設定します
+    /// URL などを指定しなかった場合は、メッセージに添付された鍵を使います
+    #[clap(group(ArgGroup::new("source").args(["src_url", "wkd", "keyserver"])))]
     Pgp {
         /// 公開鍵のURL
-        src_url: String,
+        src_url: Option<String>,
+
+        /// Web Key Directory からこのメールアドレスの公開鍵を探します
+        /// ドメインはホワイトリストにあるものに限ります
+        #[clap(long)]
+        wkd: Option<String>,
+
+        /// キーサーバーからこの指紋の公開鍵を取得します
+        #[clap(long)]
+        keyserver: Option<String>,
     },
 }
 
@@ -53,19 +127,77 @@ pub trait GenkaiAuthDatabase: Send + Sync {
     fn register_pgp_key(&self, user_id: u64, cert: &str)
     -> impl Future<Output = Result<()>> + Send;
     fn get_pgp_key(&self, user_id: u64) -> impl Future<Output = Result<Option<String>>> + Send;
+    /// `cert` が現在登録されている鍵と同じ場合のみ、本人確認済みとして記録します
+    fn mark_pgp_key_verified(
+        &self,
+        user_id: u64,
+        cert: &str,
+        verified_at: DateTime<Utc>,
+    ) -> impl Future<Output = Result<IsUpdated>> + Send;
+    /// 鍵を登録し直すと None に戻ります
+    fn get_pgp_key_verified_at(
+        &self,
+        user_id: u64,
+    ) -> impl Future<Output = Result<Option<DateTime<Utc>>>> + Send;
 
-    fn register_token(
+    fn register_token(&self, token: Token) -> impl Future<Output = Result<()>> + Send;
+    fn revoke_token(
         &self,
         user_id: u64,
-        hashed_token: &str,
+        name: &str,
+    ) -> impl Future<Output = Result<IsUpdated>> + Send;
+    fn get_tokens(&self, user_id: u64) -> impl Future<Output = Result<Vec<Token>>> + Send;
+    /// トークンの識別子から検索します
+    fn get_token(&self, id: &str) -> impl Future<Output = Result<Option<Token>>> + Send;
+    fn touch_token(
+        &self,
+        id: &str,
+        used_at: DateTime<Utc>,
     ) -> impl Future<Output = Result<()>> + Send;
-    fn revoke_token(&self, user_id: u64) -> impl Future<Output = Result<()>> + Send;
-    fn get_token(&self, user_id: u64) -> impl Future<Output = Result<Option<String>>> + Send;
+
+    /// user code がすでに使われていれば作成せずに false を返します
+    fn create_device_authorization(
+        &self,
+        auth: DeviceAuthorization,
+    ) -> impl Future<Output = Result<IsUpdated>> + Send;
+    /// `now` の時点で期限切れでないものの数
+    fn count_device_authorizations(
+        &self,
+        now: DateTime<Utc>,
+    ) -> impl Future<Output = Result<usize>> + Send;
+    fn get_device_authorization(
+        &self,
+        hashed_device_code: &str,
+    ) -> impl Future<Output = Result<Option<DeviceAuthorization>>> + Send;
+    fn get_device_authorization_by_user_code(
+        &self,
+        user_code: &str,
+    ) -> impl Future<Output = Result<Option<DeviceAuthorization>>> + Send;
+    /// まだ承認されていない場合のみ承認します
+    fn approve_device_authorization(
+        &self,
+        hashed_device_code: &str,
+        user_id: u64,
+    ) -> impl Future<Output = Result<IsUpdated>> + Send;
+    /// 前回のポーリングが `last_polled_before` 以前 (または初回) の場合のみ、ポーリングした時刻を記録します
+    fn record_device_poll(
+        &self,
+        hashed_device_code: &str,
+        polled_at: DateTime<Utc>,
+        last_polled_before: DateTime<Utc>,
+    ) -> impl Future<Output = Result<IsUpdated>> + Send;
+    fn delete_device_authorization(
+        &self,
+        hashed_device_code: &str,
+    ) -> impl Future<Output = Result<IsUpdated>> + Send;
 }
 
 pub struct GenkaiAuthBot<D> {
     db: D,
     pgp_pubkey_source_domain_whitelist: Vec<String>,
+    wkd: Wkd,
+    keyserver: Vks,
+    challenges: Challenges,
 }
 
 impl<R: Runtime, D: GenkaiAuthDatabase> BotService<R> for GenkaiAuthBot<D> {
@@ -84,10 +216,31 @@ impl<R: Runtime, D: GenkaiAuthDatabase> BotService<R> for GenkaiAuthBot<D> {
 
         match parsed.command {
             Command::Set {
-                what: SetCommand::Pgp { src_url },
-            } => self.set_pgp(msg, ctx, &src_url).await?,
-            Command::Token => self.token(msg, ctx).await?,
-            Command::Revoke => self.revoke(msg, ctx).await?,
+                what:
+                    SetCommand::Pgp {
+                        src_url,
+                        wkd,
+                        keyserver,
+                    },
+            } => {
+                let source = match (src_url, wkd, keyserver) {
+                    (Some(url), _, _) => Some(KeySource::Url(url)),
+                    (_, Some(email), _) => Some(KeySource::Wkd(email)),
+                    (_, _, Some(fingerprint)) => Some(KeySource::Keyserver(fingerprint)),
+                    (None, None, None) => None,
+                };
+                self.set_pgp(msg, ctx, source).await?
+            }
+            Command::Token {
+                name,
+                scope,
+                expires_in,
+            } => self.token(msg, ctx, name, scope, expires_in).await?,
+            Command::List => self.list(msg, ctx).await?,
+            Command::Show => self.show(msg, ctx).await?,
+            Command::Verify { nonce } => self.verify(msg, ctx, nonce).await?,
+            Command::Approve { code } => self.approve(msg, ctx, &code).await?,
+            Command::Revoke { name } => self.revoke(msg, ctx, &name).await?,
         }
 
         Ok(())
@@ -95,24 +248,55 @@ impl<R: Runtime, D: GenkaiAuthDatabase> BotService<R> for GenkaiAuthBot<D> {
 }
 
 impl<D: GenkaiAuthDatabase> GenkaiAuthBot<D> {
-    pub fn new(db: D, pubkey_whitelist: Vec<String>) -> Self {
+    /// `keyserver` は VKS (Verifying Keyserver) の URL です。None なら keys.openpgp.org を使います。
+    pub fn new(db: D, pubkey_whitelist: Vec<String>, keyserver: Option<String>) -> Self {
         Self {
             db,
             pgp_pubkey_source_domain_whitelist: pubkey_whitelist,
+            wkd: Wkd::new(),
+            keyserver: Vks::new(keyserver.as_deref().unwrap_or(DEFAULT_KEYSERVER)),
+            challenges: Challenges::new(),
         }
     }
 
-    async fn set_pgp(&self, msg: &impl Message, ctx: &impl Context, url: &str) -> Result<()> {
-        let verify_result = match self.verify_url(url) {
-            Ok(_) => download_gpg_key(url).await,
-            Err(e) => Err(e),
+    async fn set_pgp(
+        &self,
+        msg: &impl Message,
+        ctx: &impl Context,
+        source: Option<KeySource>,
+    ) -> Result<()> {
+        let source = match source {
+            Some(source) => source,
+            None => match msg.attachments().first() {
+                Some(attachment) if attachment.size() as u64 > KEY_SIZE_LIMIT => {
+                    ctx.send_text_message("添付された鍵が大きすぎます。64KiB までにしてください。")
+                        .await?;
+                    return Ok(());
+                }
+                Some(attachment) => KeySource::Attachment(
+                    attachment
+                        .download()
+                        .await
+                        .context("failed to download attachment")?,
+                ),
+                None => {
+                    ctx.send_text_message("公開鍵のURL、`--wkd`、`--keyserver` のいずれかを指定するか、公開鍵を添付してください。").await?;
+                    return Ok(());
+                }
+            },
         };
 
-        let cert = match verify_result {
-            Ok(v) => v,
+        let cert = match self.fetch_key(&source).await {
+            Ok(certs) => armor(&certs)?,
             Err(e) => {
+                let hint = match source {
+                    KeySource::Url(_) => "URL",
+                    KeySource::Wkd(_) => "メールアドレス",
+                    KeySource::Keyserver(_) => "指紋",
+                    KeySource::Attachment(_) => "添付ファイル",
+                };
                 ctx.send_text_message(&format!(
-                    "公開鍵の処理に失敗しました。URLを確認して下さい。: {e}",
+                    "公開鍵の処理に失敗しました。{hint}を確認して下さい。: {e}",
                 ))
                 .await?;
                 return Ok(());
@@ -129,11 +313,32 @@ impl<D: GenkaiAuthDatabase> GenkaiAuthBot<D> {
         Ok(())
     }
 
-    async fn token(&self, msg: &impl Message, ctx: &impl Context) -> Result<()> {
+    async fn token(
+        &self,
+        msg: &impl Message,
+        ctx: &impl Context,
+        name: String,
+        mut scopes: Vec<TokenScope>,
+        expires_in: ChronoDuration,
+    ) -> Result<()> {
         let author = msg.author();
 
-        if self.db.get_token(author.id()).await?.is_some() {
-            ctx.send_text_message("すでにトークンが登録されています。新しいトークンを作成したい場合は先に revoke してください。現在登録されているトークンの開示は出来ません。").await?;
+        let tokens = self
+            .db
+            .get_tokens(author.id())
+            .await
+            .context("failed to fetch user's tokens")?;
+
+        if tokens.iter().any(|x| x.name == name) {
+            ctx.send_text_message("同じ名前のトークンがすでに登録されています。作り直したい場合は先に revoke してください。現在登録されているトークンの開示は出来ません。").await?;
+            return Ok(());
+        }
+
+        if tokens.len() >= MAX_TOKENS_PER_USER {
+            ctx.send_text_message(&format!(
+                "トークンは1人{MAX_TOKENS_PER_USER}個までです。不要なトークンを revoke してください。"
+            ))
+            .await?;
             return Ok(());
         }
 
@@ -143,29 +348,53 @@ impl<D: GenkaiAuthDatabase> GenkaiAuthBot<D> {
             .await
             .context("failed to fetch user's gpg key")?;
 
-        if gpg_key.is_none() {
+        let Some(gpg_key) = gpg_key else {
             ctx.send_text_message("GPG鍵が登録されていません。トークンを送信するために必要です。登録方法はhelpを参照してください。").await?;
             return Ok(());
-        }
+        };
+
+        let now = Utc::now();
+        let Some(expires_at) = now.checked_add_signed(expires_in) else {
+            ctx.send_text_message("有効期間が長すぎます").await?;
+            return Ok(());
+        };
 
-        let mut token = gen_token();
+        scopes.sort_by_key(|x| x.as_str());
+        scopes.dedup();
 
-        let mut hasher = sha2::Sha512::new();
-        hasher.update(token.as_bytes());
-        let hashed = hasher.finalize();
-        let hashed = hex::encode(hashed);
+        let (id, mut token) = gen_token();
+        let hashed_token = hash_token(&token);
+
+        token.push('\n');
+        let encrypted_token = match encrypt(&gpg_key, &token) {
+            Ok(x) => x,
+            Err(e) => {
+                ctx.send_text_message(&format!(
+                    "登録されている公開鍵でトークンを暗号化できませんでした。`g!auth show` で鍵を確認してください。: {e:#}"
+                ))
+                .await?;
+                return Ok(());
+            }
+        };
 
         self.db
-            .register_token(msg.author().id(), &hashed)
+            .register_token(Token {
+                id,
+                user_id: author.id(),
+                name: name.clone(),
+                hashed_token,
+                scopes,
+                created_at: now,
+                expires_at: Some(expires_at),
+                last_used_at: None,
+            })
             .await
             .context("failed to register new token")?;
 
-        token.push('\n');
-        let encrypted_token = encrypt(&gpg_key.unwrap(), &token)?;
-
         author
             .dm_text(&format!(
                 include_str!("messages/token_text.txt"),
+                NAME = name,
                 TOKEN = encrypted_token
             ))
             .await?;
@@ -173,32 +402,318 @@ impl<D: GenkaiAuthDatabase> GenkaiAuthBot<D> {
         Ok(())
     }
 
-    async fn revoke(&self, msg: &impl Message, ctx: &impl Context) -> Result<()> {
-        self.db
-            .revoke_token(msg.author().id())
+    async fn list(&self, msg: &impl Message, ctx: &impl Context) -> Result<()> {
+        let tokens = self
+            .db
+            .get_tokens(msg.author().id())
+            .await
+            .context("failed to fetch user's tokens")?;
+
+        if tokens.is_empty() {
+            ctx.send_text_message("トークンは登録されていません")
+                .await?;
+            return Ok(());
+        }
+
+        let now = Utc::now();
+        let list = tokens
+            .iter()
+            .map(|x| {
+                let scopes = x.scopes.iter().map(|x| x.as_str()).collect::<Vec<_>>().join(", ");
+                let expires_at = match x.expires_at {
+                    _ if x.is_expired(now) => "期限切れ".to_owned(),
+                    Some(e) => format_time(e),
+                    None => "なし".to_owned(),
+                };
+                let last_used_at = x.last_used_at.map(format_time).unwrap_or_else(|| "未使用".to_owned());
+
+                format!(
+                    "{}\n  スコープ: {scopes}\n  作成: {} / 有効期限: {expires_at} / 最終使用: {last_used_at}",
+                    x.name,
+                    format_time(x.created_at),
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        ctx.send_text_message(&format!("```\n{list}\n```")).await?;
+
+        Ok(())
+    }
+
+    async fn show(&self, msg: &impl Message, ctx: &impl Context) -> Result<()> {
+        let key = self
+            .db
+            .get_pgp_key(msg.author().id())
+            .await
+            .context("failed to fetch user's gpg key")?;
+
+        let Some(key) = key else {
+            ctx.send_text_message("GPG鍵が登録されていません").await?;
+            return Ok(());
+        };
+
+        let verified_at = self
+            .db
+            .get_pgp_key_verified_at(msg.author().id())
+            .await
+            .context("failed to fetch verification status")?;
+
+        let verified = match verified_at {
+            Some(t) => format!("済み ({})", format_time(t)),
+            None => "未確認 (`g!auth verify` で確認できます)".to_owned(),
+        };
+
+        ctx.send_text_message(&format!(
+            "```\n{}\n```\n本人確認: {verified}",
+            describe_key(&key)?
+        ))
+        .await?;
+
+        Ok(())
+    }
+
+    async fn verify(
+        &self,
+        msg: &impl Message,
+        ctx: &impl Context,
+        nonce: Option<String>,
+    ) -> Result<()> {
+        let answer = match (nonce, msg.attachments().first()) {
+            (Some(nonce), _) => Answer::Nonce(nonce),
+            (None, Some(attachment)) if attachment.size() as u64 > KEY_SIZE_LIMIT => {
+                ctx.send_text_message("添付された署名が大きすぎます")
+                    .await?;
+                return Ok(());
+            }
+            (None, Some(attachment)) => Answer::Signature(
+                attachment
+                    .download()
+                    .await
+                    .context("failed to download attachment")?,
+            ),
+            (None, None) => return self.issue_challenge(msg, ctx).await,
+        };
+
+        let result = match self.answer_challenge(msg.author().id(), &answer).await? {
+            Ok(()) => "本人確認が完了しました",
+            Err(e) => e,
+        };
+
+        ctx.send_text_message(result).await?;
+
+        Ok(())
+    }
+
+    async fn issue_challenge(&self, msg: &impl Message, ctx: &impl Context) -> Result<()> {
+        let author = msg.author();
+
+        let key = self
+            .db
+            .get_pgp_key(author.id())
+            .await
+            .context("failed to fetch user's gpg key")?;
+
+        let Some(key) = key else {
+            ctx.send_text_message("GPG鍵が登録されていません").await?;
+            return Ok(());
+        };
+
+        let challenge = Challenge::new(author.id(), key, Instant::now());
+        let encrypted_nonce = encrypt(&challenge.key, &format!("{}\n", challenge.nonce))?;
+
+        author
+            .dm_text(&format!(
+                include_str!("messages/verify_text.txt"),
+                NONCE = encrypted_nonce,
+                STATEMENT = challenge.statement,
+            ))
+            .await?;
+
+        self.challenges.insert(author.id(), challenge).await;
+
+        ctx.send_text_message("DMに確認用のメッセージを送信しました")
+            .await?;
+
+        Ok(())
+    }
+
+    // returns a message for the user if the answer is not accepted
+    async fn answer_challenge(
+        &self,
+        user_id: u64,
+        answer: &Answer,
+    ) -> Result<std::result::Result<(), &'static str>> {
+        let Some(challenge) = self.challenges.take(user_id, Instant::now()).await else {
+            return Ok(Err(
+                "確認中のチャレンジがありません。`g!auth verify` からやり直してください。",
+            ));
+        };
+
+        if !challenge.check(answer) {
+            return Ok(Err(
+                "確認に失敗しました。`g!auth verify` からやり直してください。",
+            ));
+        }
+
+        let marked = self
+            .db
+            .mark_pgp_key_verified(user_id, &challenge.key, Utc::now())
+            .await
+            .context("failed to mark the key as verified")?;
+
+        if !marked {
+            return Ok(Err(
+                "確認中に鍵が変更されました。`g!auth verify` からやり直してください。",
+            ));
+        }
+
+        Ok(Ok(()))
+    }
+
+    async fn approve(&self, msg: &impl Message, ctx: &impl Context, code: &str) -> Result<()> {
+        let result = match device::approve(&self.db, code, msg.author().id(), Utc::now()).await? {
+            Ok(auth) => {
+                let scopes = auth
+                    .scopes
+                    .iter()
+                    .map(|x| x.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!(
+                    "「{}」に {scopes} を許可しました。ツールに戻ってください。",
+                    auth.name
+                )
+            }
+            Err(e) => e.to_owned(),
+        };
+
+        ctx.send_text_message(&result).await?;
+
+        Ok(())
+    }
+
+    async fn revoke(&self, msg: &impl Message, ctx: &impl Context, name: &str) -> Result<()> {
+        let revoked = self
+            .db
+            .revoke_token(msg.author().id(), name)
             .await
             .context("failed to revoke token")?;
 
-        ctx.send_text_message("トークンを無効化しました。").await?;
+        if revoked {
+            ctx.send_text_message("トークンを無効化しました。").await?;
+        } else {
+            ctx.send_text_message("その名前のトークンは見つかりませんでした。")
+                .await?;
+        }
 
         Ok(())
     }
 
-    fn verify_url(&self, url: &str) -> std::result::Result<(), &'static str> {
+    async fn fetch_key(&self, source: &KeySource) -> std::result::Result<Vec<Cert>, &'static str> {
+        let certs = self.fetch_key_unchecked(source).await?;
+
+        // tokens are sent encrypted, so keys that can't be used for that are useless here
+        if !certs.iter().any(has_encryption_key) {
+            return Err("the key doesn't have a usable encryption subkey");
+        }
+
+        Ok(certs)
+    }
+
+    async fn fetch_key_unchecked(
+        &self,
+        source: &KeySource,
+    ) -> std::result::Result<Vec<Cert>, &'static str> {
+        match source {
+            KeySource::Attachment(data) => parse_key_data(data),
+
+            KeySource::Url(url) => {
+                let url = self.verify_url(url)?;
+                download_gpg_key(&url, &self.pgp_pubkey_source_domain_whitelist).await
+            }
+
+            KeySource::Wkd(email) => {
+                let domain = email_domain(email).ok_or("invalid email address")?;
+                if !self.pgp_pubkey_source_domain_whitelist.contains(&domain) {
+                    return Err("the domain of the email address is not on whitelist.");
+                }
+
+                let [advanced, direct] = self.wkd.urls(email).ok_or("invalid email address")?;
+
+                // the advanced method is served from the openpgpkey subdomain of the whitelisted domain
+                let mut allowed_hosts = self.pgp_pubkey_source_domain_whitelist.clone();
+                allowed_hosts.extend(
+                    [&advanced, &direct]
+                        .iter()
+                        .filter_map(|x| x.host_str())
+                        .map(str::to_owned),
+                );
+
+                // try the advanced method first, then the direct method
+                let certs = match download_gpg_key(&advanced, &allowed_hosts).await {
+                    Ok(certs) => certs,
+                    Err(_) => download_gpg_key(&direct, &allowed_hosts).await?,
+                };
+
+                let certs = certs
+                    .into_iter()
+                    .filter(|x| has_email(x, email))
+                    .collect::<Vec<_>>();
+
+                if certs.is_empty() {
+                    return Err("the key doesn't have a user id with the email address");
+                }
+
+                Ok(certs)
+            }
+
+            KeySource::Keyserver(fingerprint) => {
+                let fingerprint =
+                    normalize_fingerprint(fingerprint).ok_or("invalid fingerprint")?;
+
+                let url = Url::parse(&self.keyserver.url(&fingerprint))
+                    .map_err(|_| "failed to parse keyserver url")?;
+                let keyserver_host = url
+                    .host_str()
+                    .map(str::to_owned)
+                    .into_iter()
+                    .collect::<Vec<_>>();
+
+                let certs = download_gpg_key(&url, &keyserver_host)
+                    .await?
+                    .into_iter()
+                    .filter(|x| x.fingerprint().to_hex() == fingerprint)
+                    .collect::<Vec<_>>();
+
+                if certs.is_empty() {
+                    return Err("the keyserver returned a different key");
+                }
+
+                Ok(certs)
+            }
+        }
+    }
+
+    fn verify_url(&self, url: &str) -> std::result::Result<Url, &'static str> {
         let url = Url::parse(url).map_err(|_| "failed to parse url")?;
 
-        if !matches!(url.origin(), Origin::Tuple(_, Host::Domain(d), _)
-                if self.pgp_pubkey_source_domain_whitelist.contains(&d))
-        {
+        if !is_allowed_url(&url, &self.pgp_pubkey_source_domain_whitelist) {
             return Err("provided url doesn't contain domain or its domain is not on whitelist.");
         }
 
-        Ok(())
+        Ok(url)
     }
 }
 
-fn gen_token() -> String {
-    const PREFIX: &str = "gauth";
+const TOKEN_PREFIX: &str = "gauth";
+const TOKEN_ID_LEN: usize = 16;
+const MAX_TOKENS_PER_USER: usize = 10;
+// last used time is recorded at this granularity, so that every request doesn't write to db
+const TOUCH_INTERVAL: ChronoDuration = ChronoDuration::minutes(1);
+
+// returns (id, token)
+fn gen_token() -> (String, String) {
     const LEN: usize = 80;
     const BLUR: usize = 10;
 
@@ -206,9 +721,12 @@ fn gen_token() -> String {
     let mut rng = StdRng::from_rng(&mut seed_rng);
     let rune = |rng: &mut StdRng| rng.random_range(33u8..=117) as char;
 
-    let mut token = String::with_capacity(PREFIX.len() + LEN + BLUR + 1);
+    let id = hex::encode(rng.random::<[u8; TOKEN_ID_LEN / 2]>());
+
+    let mut token = String::with_capacity(TOKEN_PREFIX.len() + TOKEN_ID_LEN + LEN + BLUR + 1);
 
-    token.push_str(PREFIX);
+    token.push_str(TOKEN_PREFIX);
+    token.push_str(&id);
 
     for _ in 0..LEN {
         token.push(rune(&mut rng));
@@ -218,62 +736,270 @@ fn gen_token() -> String {
         token.push(rune(&mut rng));
     }
 
-    token
+    (id, token)
+}
+
+/// トークン文字列から識別子を取り出します
+fn parse_token_id(token: &str) -> Option<&str> {
+    let id = token.strip_prefix(TOKEN_PREFIX)?.get(..TOKEN_ID_LEN)?;
+
+    id.chars()
+        .all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+        .then_some(id)
 }
 
-fn parse_gpg_key(armored: &str) -> Result<Vec<Cert>> {
-    let parser =
-        PacketParser::from_bytes(armored.as_bytes()).context("failed to parse key(packet)")?;
+/// 識別子を持たない古い形式のトークンに、移行時に割り当てる識別子
+pub fn legacy_token_id(hashed_token: &str) -> String {
+    format!("legacy-{}", &hashed_token[..TOKEN_ID_LEN])
+}
+
+fn hash_token(token: &str) -> String {
+    let mut hasher = sha2::Sha512::new();
+    hasher.update(token.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("invalid token")]
+    Invalid,
+    #[error("token has expired")]
+    Expired,
+    #[error("token doesn't have the scope \"{0}\"")]
+    MissingScope(TokenScope),
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+/// トークンを検証し、`scope` の操作が許可されていればそのトークンの情報を返します。
+/// 検証に成功した場合は、前回の記録から一定時間経っていれば最終使用日時を更新します。
+pub async fn authenticate(
+    db: &impl GenkaiAuthDatabase,
+    token: &str,
+    scope: TokenScope,
+    now: DateTime<Utc>,
+) -> std::result::Result<Token, AuthError> {
+    let hashed = hash_token(token);
+
+    let mut found = match parse_token_id(token) {
+        Some(id) => db.get_token(id).await.context("failed to fetch token")?,
+        None => None,
+    };
+
+    // tokens issued before they carried an id are migrated with the id derived from the hash.
+    // they start with the same prefix, so the parsed id may be just a part of the secret.
+    if found.as_ref().is_none_or(|x| x.hashed_token != hashed) {
+        found = db
+            .get_token(&legacy_token_id(&hashed))
+            .await
+            .context("failed to fetch token")?;
+    }
+
+    let found = found
+        .filter(|x| x.hashed_token == hashed)
+        .ok_or(AuthError::Invalid)?;
+
+    if found.is_expired(now) {
+        return Err(AuthError::Expired);
+    }
+
+    if !found.has_scope(scope) {
+        return Err(AuthError::MissingScope(scope));
+    }
+
+    if found
+        .last_used_at
+        .is_none_or(|last| now - last >= TOUCH_INTERVAL)
+    {
+        db.touch_token(&found.id, now)
+            .await
+            .context("failed to update token's last used time")?;
+    }
+
+    Ok(found)
+}
+
+fn parse_gpg_key(data: &[u8]) -> Result<Vec<Cert>> {
+    let parser = PacketParser::from_bytes(data).context("failed to parse key(packet)")?;
 
     CertParser::from(parser)
         .collect::<Result<Vec<Cert>, _>>()
         .context("failed to parse key(cert)")
 }
 
-// verify that certs are parsable, and return downloaded armor certs.
-async fn download_gpg_key(url: &str) -> std::result::Result<String, &'static str> {
+// WKD serves binary keys, so keys are always stored as re-armored ones.
+fn armor(certs: &[Cert]) -> Result<String> {
+    let mut output = vec![];
+    let mut writer =
+        ArmorWriter::new(&mut output, Kind::PublicKey).context("failed to create armor writer")?;
+
+    for cert in certs {
+        cert.serialize(&mut writer)
+            .context("failed to serialize key")?;
+    }
+
+    writer.finalize().context("failed to armor key")?;
+
+    String::from_utf8(output).context("armored key is not utf-8")
+}
+
+fn has_encryption_key(cert: &Cert) -> bool {
+    let policy = StandardPolicy::new();
+
+    cert.keys()
+        .with_policy(&policy, None)
+        .supported()
+        .alive()
+        .revoked(false)
+        .for_transport_encryption()
+        .next()
+        .is_some()
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.with_timezone(&Tokyo)
+        .format("%Y/%m/%d %H:%M")
+        .to_string()
+}
+
+/// 鍵の指紋 (大文字の16進数) を返します
+pub fn pgp_key_fingerprints(armored: &str) -> Result<Vec<String>> {
+    Ok(parse_gpg_key(armored.as_bytes())?
+        .iter()
+        .map(|x| x.fingerprint().to_hex())
+        .collect())
+}
+
+/// 登録されている鍵の指紋、ユーザーID、有効期限、暗号化に使えるかどうかを表示用にまとめます
+fn describe_key(armored: &str) -> Result<String> {
+    let certs = parse_gpg_key(armored.as_bytes())?;
+    let policy = StandardPolicy::new();
+
+    let mut buf = String::new();
+
+    for cert in &certs {
+        if !buf.is_empty() {
+            buf.push_str("\n\n");
+        }
+
+        let _ = writeln!(buf, "指紋: {}", cert.fingerprint().to_hex());
+
+        for userid in cert.userids() {
+            let _ = writeln!(buf, "ユーザーID: {}", userid.userid());
+        }
+
+        let expiry = match cert.with_policy(&policy, None) {
+            Ok(valid) => match valid.primary_key().key_expiration_time() {
+                Some(t) => format_time(t.into()),
+                None => "なし".to_owned(),
+            },
+            Err(e) => format!("鍵が無効です ({e})"),
+        };
+        let _ = writeln!(buf, "有効期限: {expiry}");
+
+        let encryption = if has_encryption_key(cert) {
+            "あり"
+        } else {
+            "なし (トークンを受け取れません)"
+        };
+        let _ = write!(buf, "暗号化用の鍵: {encryption}");
+    }
+
+    Ok(buf)
+}
+
+fn has_email(cert: &Cert, email: &str) -> bool {
+    let email = email.to_lowercase();
+    let bracketed = format!("<{email}>");
+
+    cert.userids().any(|x| {
+        let userid = x.userid().to_string().to_lowercase();
+        userid == email || userid.contains(&bracketed)
+    })
+}
+
+fn is_allowed_url(url: &Url, allowed_hosts: &[String]) -> bool {
+    matches!(url.origin(), Origin::Tuple(_, Host::Domain(d), _) if allowed_hosts.contains(&d))
+}
+
+// verify that certs are parsable, and return downloaded certs.
+// redirects are followed only while they stay on `allowed_hosts`.
+async fn download_gpg_key(
+    url: &Url,
+    allowed_hosts: &[String],
+) -> std::result::Result<Vec<Cert>, &'static str> {
     const TIMEOUT: Duration = Duration::from_secs(5);
-    const DL_SIZE_LIMIT: u64 = 1024 * 64; // 64KIB
+    const MAX_REDIRECTS: usize = 5;
+
+    let allowed_hosts = allowed_hosts.to_vec();
+    let redirect = reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if is_allowed_url(attempt.url(), &allowed_hosts) {
+            attempt.follow()
+        } else {
+            attempt.error("redirected to a host which is not on whitelist")
+        }
+    });
 
     let client = reqwest::Client::builder()
         .connect_timeout(TIMEOUT)
         .timeout(TIMEOUT)
+        .redirect(redirect)
         .build()
         .expect("failed to build reqwest client");
 
     let con = client
-        .get(url)
+        .get(url.clone())
         .send()
         .await
-        .map_err(|_| "failed to connect to host")?;
+        .map_err(|_| "failed to connect to host")?
+        .error_for_status()
+        .map_err(|_| "key was not found on the host")?;
 
     match con.content_length() {
-        Some(size) if size <= DL_SIZE_LIMIT => {}
+        Some(size) if size <= KEY_SIZE_LIMIT => {}
         _ => return Err("download size is too big or not known"),
     }
 
     let body = con
-        .text()
+        .bytes()
         .await
         .map_err(|_| "failed to download key(body)")?;
 
-    let _ = parse_gpg_key(&body).map_err(|_| "failed to parse key")?;
+    parse_key_data(&body)
+}
+
+// verify that the data is a parsable key.
+fn parse_key_data(data: &[u8]) -> std::result::Result<Vec<Cert>, &'static str> {
+    let certs = parse_gpg_key(data).map_err(|_| "failed to parse key")?;
 
-    Ok(body)
+    if certs.is_empty() {
+        return Err("no key was found in the data");
+    }
+
+    Ok(certs)
 }
 
 fn encrypt(cert: &str, text: &str) -> Result<String> {
-    let certs = parse_gpg_key(cert)?;
+    let certs = parse_gpg_key(cert.as_bytes())?;
     let policy = StandardPolicy::new();
 
-    let recipients = certs.iter().flat_map(|x| {
-        x.keys()
-            .with_policy(&policy, None)
-            .supported()
-            .alive()
-            .revoked(false)
-            .for_transport_encryption()
-    });
+    let recipients = certs
+        .iter()
+        .flat_map(|x| {
+            x.keys()
+                .with_policy(&policy, None)
+                .supported()
+                .alive()
+                .revoked(false)
+                .for_transport_encryption()
+        })
+        .collect::<Vec<_>>();
+
+    if recipients.is_empty() {
+        bail!("the key doesn't have a usable encryption subkey");
+    }
 
     let mut output = vec![];
     let message = OpenGPGMessage::new(&mut output);
@@ -287,3 +1013,343 @@ fn encrypt(cert: &str, text: &str) -> Result<String> {
 
     Ok(String::from_utf8_lossy(&output).into_owned())
 }
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::db::mem::MemoryDB,
+        axum::{Router, response::Redirect, routing::get},
+        pretty_assertions::assert_eq,
+    };
+
+    const TEST_KEY: &str = include_str!("test_key.asc");
+    const TEST_KEY_BINARY: &[u8] = include_bytes!("test_key.gpg");
+    const TEST_KEY_FINGERPRINT: &str = "CED198DC0330F028749BF7B5A1EAB3B84381247E";
+
+    #[test]
+    fn token_carries_id() {
+        let (id, token) = gen_token();
+
+        assert_eq!(id.len(), TOKEN_ID_LEN);
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_eq!(parse_token_id(&token), Some(id.as_str()));
+
+        assert_eq!(parse_token_id("gauth0123"), None);
+        assert_eq!(parse_token_id("gauth0123456789ABCDEF!!!!"), None);
+        assert_eq!(parse_token_id("token0123456789abcdef!!!!"), None);
+    }
+
+    #[tokio::test]
+    async fn authenticate_checks_expiry_and_scope() {
+        let db = MemoryDB::new();
+        let now = Utc::now();
+
+        let (id, token) = gen_token();
+        db.register_token(Token {
+            id: id.clone(),
+            user_id: 1,
+            name: "tool".to_owned(),
+            hashed_token: hash_token(&token),
+            scopes: vec![TokenScope::MeigenRead],
+            created_at: now,
+            expires_at: Some(now + ChronoDuration::days(1)),
+            last_used_at: None,
+        })
+        .await
+        .unwrap();
+
+        let found = authenticate(&db, &token, TokenScope::MeigenRead, now)
+            .await
+            .unwrap();
+        assert_eq!(found.name, "tool");
+        assert_eq!(
+            db.get_token(&id).await.unwrap().unwrap().last_used_at,
+            Some(now)
+        );
+
+        // not recorded again within the interval
+        let soon = now + ChronoDuration::seconds(10);
+        authenticate(&db, &token, TokenScope::MeigenRead, soon)
+            .await
+            .unwrap();
+        assert_eq!(
+            db.get_token(&id).await.unwrap().unwrap().last_used_at,
+            Some(now)
+        );
+
+        let after = now + TOUCH_INTERVAL;
+        authenticate(&db, &token, TokenScope::MeigenRead, after)
+            .await
+            .unwrap();
+        assert_eq!(
+            db.get_token(&id).await.unwrap().unwrap().last_used_at,
+            Some(after)
+        );
+
+        assert!(matches!(
+            authenticate(&db, &token, TokenScope::MeigenWrite, now).await,
+            Err(AuthError::MissingScope(TokenScope::MeigenWrite))
+        ));
+
+        // same id, different secret
+        let forged = format!("{TOKEN_PREFIX}{id}forged");
+        assert!(matches!(
+            authenticate(&db, &forged, TokenScope::MeigenRead, now).await,
+            Err(AuthError::Invalid)
+        ));
+
+        let later = now + ChronoDuration::days(2);
+        assert!(matches!(
+            authenticate(&db, &token, TokenScope::MeigenRead, later).await,
+            Err(AuthError::Expired)
+        ));
+
+        assert!(db.revoke_token(1, "tool").await.unwrap());
+        assert!(!db.revoke_token(1, "tool").await.unwrap());
+        assert!(matches!(
+            authenticate(&db, &token, TokenScope::MeigenRead, now).await,
+            Err(AuthError::Invalid)
+        ));
+    }
+
+    #[tokio::test]
+    async fn authenticate_legacy_token() {
+        let db = MemoryDB::new();
+        let now = Utc::now();
+
+        // the format before tokens carried an id
+        let token = "gauth!legacy-secret!";
+        let hashed_token = hash_token(token);
+        db.register_token(Token {
+            id: legacy_token_id(&hashed_token),
+            user_id: 1,
+            name: "legacy".to_owned(),
+            hashed_token,
+            scopes: vec![TokenScope::MeigenRead],
+            created_at: now,
+            expires_at: None,
+            last_used_at: None,
+        })
+        .await
+        .unwrap();
+
+        let found = authenticate(&db, token, TokenScope::MeigenRead, now)
+            .await
+            .unwrap();
+        assert_eq!(found.name, "legacy");
+        assert_eq!(found.last_used_at, None);
+        assert_eq!(db.get_tokens(1).await.unwrap()[0].last_used_at, Some(now));
+    }
+
+    // 鍵を配布しているサーバーの代わりにローカルで応答する HTTP サーバーを立て、そのベース URL を返す
+    async fn stand_in() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let base = format!("http://localhost:{port}");
+
+        let wkd = Wkd::with_base(&base);
+        let [advanced, _] = wkd.urls("test@example.com").unwrap();
+        // only published by the direct method, and the key doesn't have this user id
+        let [_, direct] = wkd.urls("alias@example.com").unwrap();
+
+        let app = Router::new()
+            .route("/key.asc", get(|| async { TEST_KEY }))
+            // leaves the whitelisted domain
+            .route(
+                "/redirect.asc",
+                get(move || async move {
+                    Redirect::temporary(&format!("http://127.0.0.1:{port}/key.asc"))
+                }),
+            )
+            .route(advanced.path(), get(|| async { TEST_KEY_BINARY }))
+            .route(direct.path(), get(|| async { TEST_KEY_BINARY }))
+            .route(
+                &format!("/vks/v1/by-fingerprint/{TEST_KEY_FINGERPRINT}"),
+                get(|| async { TEST_KEY }),
+            );
+
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        base
+    }
+
+    async fn fetch(
+        bot: &GenkaiAuthBot<MemoryDB>,
+        source: KeySource,
+    ) -> std::result::Result<Vec<String>, &'static str> {
+        let certs = bot.fetch_key(&source).await?;
+
+        // stored keys must be readable again
+        let armored = armor(&certs).unwrap();
+        let reparsed = parse_gpg_key(armored.as_bytes()).unwrap();
+
+        Ok(reparsed.iter().map(|x| x.fingerprint().to_hex()).collect())
+    }
+
+    #[tokio::test]
+    async fn fetch_key_from_sources() {
+        let base = stand_in().await;
+        let bot = GenkaiAuthBot {
+            db: MemoryDB::new(),
+            pgp_pubkey_source_domain_whitelist: vec![
+                "localhost".to_owned(),
+                "example.com".to_owned(),
+            ],
+            wkd: Wkd::with_base(&base),
+            keyserver: Vks::new(&base),
+            challenges: Challenges::new(),
+        };
+
+        let expected = Ok(vec![TEST_KEY_FINGERPRINT.to_owned()]);
+
+        assert_eq!(
+            fetch(&bot, KeySource::Url(format!("{base}/key.asc"))).await,
+            expected
+        );
+        assert!(
+            fetch(
+                &bot,
+                KeySource::Url(base.replace("localhost", "127.0.0.1") + "/key.asc")
+            )
+            .await
+            .is_err()
+        );
+        assert!(
+            fetch(&bot, KeySource::Url(format!("{base}/redirect.asc")))
+                .await
+                .is_err()
+        );
+
+        assert_eq!(
+            fetch(&bot, KeySource::Wkd("test@example.com".to_owned())).await,
+            expected
+        );
+        assert_eq!(
+            fetch(&bot, KeySource::Wkd("Test@Example.com".to_owned())).await,
+            expected
+        );
+        assert_eq!(
+            fetch(&bot, KeySource::Wkd("alias@example.com".to_owned())).await,
+            Err("the key doesn't have a user id with the email address")
+        );
+        assert!(
+            fetch(&bot, KeySource::Wkd("nobody@example.com".to_owned()))
+                .await
+                .is_err()
+        );
+        assert_eq!(
+            fetch(&bot, KeySource::Wkd("test@example.org".to_owned())).await,
+            Err("the domain of the email address is not on whitelist.")
+        );
+        assert_eq!(
+            fetch(
+                &bot,
+                KeySource::Wkd(base.replace("http://localhost", "test@127.0.0.1") + "/key.asc?")
+            )
+            .await,
+            Err("invalid email address")
+        );
+
+        assert_eq!(
+            fetch(&bot, KeySource::Attachment(TEST_KEY_BINARY.to_vec())).await,
+            expected
+        );
+        assert_eq!(
+            fetch(&bot, KeySource::Attachment(TEST_KEY.as_bytes().to_vec())).await,
+            expected
+        );
+        assert!(
+            fetch(&bot, KeySource::Attachment(b"not a key".to_vec()))
+                .await
+                .is_err()
+        );
+
+        assert_eq!(
+            fetch(
+                &bot,
+                KeySource::Keyserver(TEST_KEY_FINGERPRINT.to_lowercase())
+            )
+            .await,
+            expected
+        );
+        assert!(
+            fetch(&bot, KeySource::Keyserver("0".repeat(40)))
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn describe_registered_key() {
+        assert_eq!(
+            describe_key(TEST_KEY).unwrap(),
+            format!(
+                "指紋: {TEST_KEY_FINGERPRINT}
+ユーザーID: Rusty Ponyo Test <test@example.com>
+有効期限: なし
+暗号化用の鍵: あり"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn verification_is_reset_when_key_changes() {
+        let bot = GenkaiAuthBot::new(MemoryDB::new(), vec![], None);
+
+        bot.db.register_pgp_key(1, TEST_KEY).await.unwrap();
+
+        // nothing issued yet
+        assert!(
+            bot.answer_challenge(1, &Answer::Nonce(String::new()))
+                .await
+                .unwrap()
+                .is_err()
+        );
+
+        let challenge = Challenge::new(1, TEST_KEY.to_owned(), Instant::now());
+        let nonce = challenge.nonce.clone();
+
+        bot.challenges.insert(1, challenge).await;
+        assert!(
+            bot.answer_challenge(1, &Answer::Nonce("wrong".to_owned()))
+                .await
+                .unwrap()
+                .is_err()
+        );
+        // the challenge is consumed by the wrong answer
+        assert!(
+            bot.answer_challenge(1, &Answer::Nonce(nonce.clone()))
+                .await
+                .unwrap()
+                .is_err()
+        );
+        assert_eq!(bot.db.get_pgp_key_verified_at(1).await.unwrap(), None);
+
+        let challenge = Challenge::new(1, TEST_KEY.to_owned(), Instant::now());
+        let nonce = challenge.nonce.clone();
+        bot.challenges.insert(1, challenge).await;
+        assert_eq!(
+            bot.answer_challenge(1, &Answer::Nonce(nonce))
+                .await
+                .unwrap(),
+            Ok(())
+        );
+        assert!(bot.db.get_pgp_key_verified_at(1).await.unwrap().is_some());
+
+        bot.db.register_pgp_key(1, TEST_KEY).await.unwrap();
+        assert_eq!(bot.db.get_pgp_key_verified_at(1).await.unwrap(), None);
+
+        // the key was replaced after the challenge was issued
+        let challenge = Challenge::new(1, "old key".to_owned(), Instant::now());
+        let nonce = challenge.nonce.clone();
+        bot.challenges.insert(1, challenge).await;
+        assert!(
+            bot.answer_challenge(1, &Answer::Nonce(nonce))
+                .await
+                .unwrap()
+                .is_err()
+        );
+        assert_eq!(bot.db.get_pgp_key_verified_at(1).await.unwrap(), None);
+    }
+}
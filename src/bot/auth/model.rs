@@ -0,0 +1,83 @@
+use {
+    chrono::{DateTime, Utc},
+    clap::ValueEnum,
+    serde::{Deserialize, Serialize},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum TokenScope {
+    #[serde(rename = "meigen:read")]
+    #[value(name = "meigen:read")]
+    MeigenRead,
+
+    #[serde(rename = "meigen:write")]
+    #[value(name = "meigen:write")]
+    MeigenWrite,
+
+    #[serde(rename = "point:read")]
+    #[value(name = "point:read")]
+    PointRead,
+}
+
+impl TokenScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TokenScope::MeigenRead => "meigen:read",
+            TokenScope::MeigenWrite => "meigen:write",
+            TokenScope::PointRead => "point:read",
+        }
+    }
+}
+
+impl std::fmt::Display for TokenScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Token {
+    /// トークン文字列に埋め込まれている識別子
+    pub id: String,
+    pub user_id: u64,
+    pub name: String,
+    /// トークン文字列全体の SHA-512 (hex)
+    pub hashed_token: String,
+    pub scopes: Vec<TokenScope>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl Token {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|x| x <= now)
+    }
+
+    pub fn has_scope(&self, scope: TokenScope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
+/// internal API から始まるデバイス認可フローの途中経過
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceAuthorization {
+    /// ツールが持っている device code の SHA-512 (hex)
+    pub hashed_device_code: String,
+    /// ユーザーが `g!auth approve` に入力するコード (ハイフンなし)
+    pub user_code: String,
+    /// 発行されるトークンの名前
+    pub name: String,
+    pub scopes: Vec<TokenScope>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub approved_by: Option<u64>,
+    /// ツールが最後にポーリングした時刻
+    pub last_polled_at: Option<DateTime<Utc>>,
+}
+
+impl DeviceAuthorization {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at <= now
+    }
+}
@@ -0,0 +1,178 @@
+use {
+    super::parse_gpg_key,
+    anyhow::{Result, anyhow},
+    rand::{RngExt, SeedableRng, prelude::StdRng},
+    sequoia_openpgp::{
+        Cert, KeyHandle,
+        parse::{
+            Parse,
+            stream::{DetachedVerifierBuilder, MessageLayer, MessageStructure, VerificationHelper},
+        },
+        policy::StandardPolicy,
+    },
+    std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    },
+    tokio::sync::Mutex,
+};
+
+// チャレンジに答えられる期間
+const CHALLENGE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// 鍵の所有を確認するためのチャレンジ
+pub(super) struct Challenge {
+    /// チャレンジを発行したときに登録されていた鍵
+    pub(super) key: String,
+    /// 鍵で暗号化して送る秘密の文字列
+    pub(super) nonce: String,
+    /// 署名で答える場合に署名してもらう文字列
+    pub(super) statement: String,
+    issued_at: Instant,
+}
+
+pub(super) enum Answer {
+    Nonce(String),
+    /// `statement` への detached signature (armored or binary)
+    Signature(Vec<u8>),
+}
+
+impl Challenge {
+    pub(super) fn new(user_id: u64, key: String, now: Instant) -> Self {
+        let mut seed_rng = rand::rng();
+        let mut rng = StdRng::from_rng(&mut seed_rng);
+
+        let nonce = hex::encode(rng.random::<[u8; 16]>());
+        let challenge_id = hex::encode(rng.random::<[u8; 8]>());
+
+        Self {
+            key,
+            nonce,
+            statement: format!("rusty-ponyo-verify:{user_id}:{challenge_id}"),
+            issued_at: now,
+        }
+    }
+
+    pub(super) fn check(&self, answer: &Answer) -> bool {
+        match answer {
+            Answer::Nonce(nonce) => nonce.trim() == self.nonce,
+
+            // `echo` adds a trailing newline, so accept both.
+            Answer::Signature(signature) => {
+                [self.statement.clone(), format!("{}\n", self.statement)]
+                    .iter()
+                    .any(|x| verify_signature(&self.key, x.as_bytes(), signature).is_ok())
+            }
+        }
+    }
+}
+
+/// ユーザーごとに発行中のチャレンジ
+pub(super) struct Challenges {
+    inner: Mutex<HashMap<u64, Challenge>>,
+}
+
+impl Challenges {
+    pub(super) fn new() -> Self {
+        Self {
+            inner: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 新しいチャレンジを登録します。発行中のものがあれば置き換えます。
+    pub(super) async fn insert(&self, user_id: u64, challenge: Challenge) {
+        let mut inner = self.inner.lock().await;
+        inner.retain(|_, x| x.issued_at.elapsed() < CHALLENGE_TTL);
+        inner.insert(user_id, challenge);
+    }
+
+    /// 発行中のチャレンジを取り出します。答えられるのは1回だけです。
+    pub(super) async fn take(&self, user_id: u64, now: Instant) -> Option<Challenge> {
+        self.inner
+            .lock()
+            .await
+            .remove(&user_id)
+            .filter(|x| now.duration_since(x.issued_at) < CHALLENGE_TTL)
+    }
+}
+
+struct Helper {
+    certs: Vec<Cert>,
+}
+
+impl VerificationHelper for Helper {
+    fn get_certs(&mut self, _ids: &[KeyHandle]) -> sequoia_openpgp::Result<Vec<Cert>> {
+        Ok(self.certs.clone())
+    }
+
+    fn check(&mut self, structure: MessageStructure) -> sequoia_openpgp::Result<()> {
+        for layer in structure {
+            if let MessageLayer::SignatureGroup { results } = layer
+                && results.iter().any(|x| x.is_ok())
+            {
+                return Ok(());
+            }
+        }
+
+        Err(anyhow!("no valid signature was found"))
+    }
+}
+
+fn verify_signature(armored_cert: &str, data: &[u8], signature: &[u8]) -> Result<()> {
+    let certs = parse_gpg_key(armored_cert.as_bytes())?;
+    let policy = StandardPolicy::new();
+
+    let mut verifier = DetachedVerifierBuilder::from_bytes(signature)?.with_policy(
+        &policy,
+        None,
+        Helper { certs },
+    )?;
+
+    verifier.verify_bytes(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEST_KEY: &str = include_str!("test_key.asc");
+    // `printf 'rusty-ponyo-verify:1:0123456789abcdef' | gpg --detach-sign --armor`
+    const TEST_SIGNATURE: &str = include_str!("test_signature.asc");
+
+    fn challenge() -> Challenge {
+        Challenge {
+            key: TEST_KEY.to_owned(),
+            nonce: "0123456789abcdef0123456789abcdef".to_owned(),
+            statement: "rusty-ponyo-verify:1:0123456789abcdef".to_owned(),
+            issued_at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn check_answers() {
+        let challenge = challenge();
+
+        assert!(challenge.check(&Answer::Nonce(
+            " 0123456789abcdef0123456789abcdef\n".to_owned()
+        )));
+        assert!(!challenge.check(&Answer::Nonce("0123".to_owned())));
+
+        assert!(challenge.check(&Answer::Signature(TEST_SIGNATURE.as_bytes().to_vec())));
+        assert!(!challenge.check(&Answer::Signature(b"not a signature".to_vec())));
+    }
+
+    #[tokio::test]
+    async fn challenge_can_be_answered_once() {
+        let challenges = Challenges::new();
+        let now = Instant::now();
+
+        challenges.insert(1, challenge()).await;
+        assert!(challenges.take(1, now).await.is_some());
+        assert!(challenges.take(1, now).await.is_none());
+
+        let expired = challenge();
+        let later = expired.issued_at + CHALLENGE_TTL;
+        challenges.insert(1, expired).await;
+        assert!(challenges.take(1, later).await.is_none());
+    }
+}
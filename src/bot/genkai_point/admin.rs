@@ -0,0 +1,340 @@
+use {
+    crate::bot::genkai_point::model::{Session, SessionId},
+    chrono::{DateTime, NaiveDateTime, TimeZone, Utc},
+    chrono_tz::Asia::Tokyo,
+    serde::{Deserialize, Serialize},
+};
+
+/// 管理者によるセッションの修正
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "action")]
+pub enum Correction {
+    /// `at` で2つのセッションに分けます
+    Split { at: DateTime<Utc> },
+    /// 閉じられていないセッションを `at` で閉じます
+    Close { at: DateTime<Utc> },
+    /// セッションを削除します
+    Delete,
+    /// 入室・退出時刻を変更します。None の方はそのままです
+    Adjust {
+        joined_at: Option<DateTime<Utc>>,
+        left_at: Option<DateTime<Utc>>,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum CorrectionError {
+    #[error("分割する時刻はセッションの途中を指定してください")]
+    OutOfSession,
+    #[error("このセッションはすでに閉じられています。退出時刻を変えるには adjust を使ってください")]
+    AlreadyClosed,
+    #[error("退出時刻は入室時刻より後にしてください")]
+    Reversed,
+    #[error("変更する時刻を指定してください")]
+    Nothing,
+}
+
+/// セッションの修正の記録
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditLogEntry {
+    pub operator_id: u64,
+    pub at: DateTime<Utc>,
+    pub session_id: SessionId,
+    pub correction: Correction,
+    pub before: Session,
+    /// 削除なら空で、分割なら2つになります
+    pub after: Vec<Session>,
+}
+
+impl Correction {
+    /// 修正後のセッションを返します
+    pub fn apply(self, session: &Session) -> Result<Vec<Session>, CorrectionError> {
+        match self {
+            Correction::Split { at } => {
+                if at <= session.joined_at || session.left_at.is_some_and(|x| x <= at) {
+                    return Err(CorrectionError::OutOfSession);
+                }
+
+                Ok(vec![
+                    session.resize(session.joined_at, Some(at)),
+                    session.resize(at, session.left_at),
+                ])
+            }
+
+            Correction::Close { at } => {
+                if session.left_at.is_some() {
+                    return Err(CorrectionError::AlreadyClosed);
+                }
+                if at <= session.joined_at {
+                    return Err(CorrectionError::Reversed);
+                }
+
+                Ok(vec![session.resize(session.joined_at, Some(at))])
+            }
+
+            Correction::Delete => Ok(vec![]),
+
+            Correction::Adjust { joined_at, left_at } => {
+                if joined_at.is_none() && left_at.is_none() {
+                    return Err(CorrectionError::Nothing);
+                }
+
+                let joined_at = joined_at.unwrap_or(session.joined_at);
+                let left_at = left_at.or(session.left_at);
+
+                if left_at.is_some_and(|x| x <= joined_at) {
+                    return Err(CorrectionError::Reversed);
+                }
+
+                Ok(vec![session.resize(joined_at, left_at)])
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Correction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let time = |x: Option<DateTime<Utc>>| x.map(format_time).unwrap_or_else(|| "-".into());
+
+        match *self {
+            Correction::Split { at } => write!(f, "split {}", format_time(at)),
+            Correction::Close { at } => write!(f, "close {}", format_time(at)),
+            Correction::Delete => write!(f, "delete"),
+            Correction::Adjust { joined_at, left_at } => {
+                write!(f, "adjust {} 〜 {}", time(joined_at), time(left_at))
+            }
+        }
+    }
+}
+
+/// `sessions` のうち、`id` 以外で `after` のどれかと重なるセッションを返します
+pub fn find_overlap<'a>(
+    sessions: &'a [(SessionId, Session)],
+    id: &SessionId,
+    after: &[Session],
+) -> Option<&'a SessionId> {
+    sessions
+        .iter()
+        .filter(|(x, _)| x != id)
+        .find(|(_, s)| {
+            after
+                .iter()
+                .any(|a| a.joined_at < s.left_at() && s.joined_at < a.left_at())
+        })
+        .map(|(x, _)| x)
+}
+
+/// `2024-01-01 03:00` のような日本時間の日時をパースします。秒と、日付と時刻の間の `T` は省略できます
+pub fn parse_datetime(s: &str) -> Result<DateTime<Utc>, chrono::ParseError> {
+    const FORMATS: [&str; 4] = [
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%dT%H:%M:%S",
+    ];
+
+    // the first success, or the last error
+    let parsed = FORMATS
+        .iter()
+        .map(|f| NaiveDateTime::parse_from_str(s.trim(), f))
+        .reduce(|acc, x| acc.or(x))
+        .unwrap()?;
+
+    Ok(Tokyo
+        .from_local_datetime(&parsed)
+        .unwrap()
+        .with_timezone(&Utc))
+}
+
+pub fn format_time(time: DateTime<Utc>) -> String {
+    time.with_timezone(&Tokyo)
+        .format("%Y/%m/%d %H:%M:%S")
+        .to_string()
+}
+
+/// `admin sessions` などで表示する1行
+pub fn format_session(id: &SessionId, session: &Session) -> String {
+    let left_at = match session.left_at {
+        Some(left_at) => format_time(left_at),
+        None => "(継続中)".to_owned(),
+    };
+
+    let mut line = format!(
+        "{id} {} 〜 {left_at} {:>6.2}h",
+        format_time(session.joined_at),
+        session.duration().num_minutes() as f64 / 60.0
+    );
+
+    if !session.excluded.is_empty() {
+        line += &format!(" 除外{}件", session.excluded.len());
+    }
+
+    line
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::bot::genkai_point::{
+            datetime,
+            model::{ExcludedPeriod, ExclusionReason},
+        },
+        pretty_assertions::assert_eq,
+    };
+
+    fn session(joined_at: DateTime<Utc>, left_at: Option<DateTime<Utc>>) -> Session {
+        Session {
+            user_id: 0,
+            joined_at,
+            left_at,
+            excluded: vec![],
+            uncertain: vec![],
+        }
+    }
+
+    fn times(sessions: &[Session]) -> Vec<(DateTime<Utc>, Option<DateTime<Utc>>)> {
+        sessions.iter().map(|s| (s.joined_at, s.left_at)).collect()
+    }
+
+    #[test]
+    fn split() {
+        let mut s = session(datetime!(2021/3/1 22:00:00), None);
+        s.excluded.push(ExcludedPeriod {
+            reason: ExclusionReason::Afk,
+            from: datetime!(2021/3/1 23:00:00),
+            until: None,
+        });
+
+        let after = Correction::Split {
+            at: datetime!(2021/3/2 00:00:00),
+        }
+        .apply(&s)
+        .unwrap();
+
+        assert_eq!(
+            times(&after),
+            vec![
+                (
+                    datetime!(2021/3/1 22:00:00),
+                    Some(datetime!(2021/3/2 00:00:00))
+                ),
+                (datetime!(2021/3/2 00:00:00), None),
+            ]
+        );
+
+        // the ongoing afk period is split too, and stays open in the latter one
+        assert_eq!(after[0].excluded[0].from, datetime!(2021/3/1 23:00:00));
+        assert_eq!(
+            after[0].excluded[0].until,
+            Some(datetime!(2021/3/2 00:00:00))
+        );
+        assert_eq!(after[1].excluded[0].from, datetime!(2021/3/2 00:00:00));
+        assert_eq!(after[1].excluded[0].until, None);
+
+        let closed = session(
+            datetime!(2021/3/1 22:00:00),
+            Some(datetime!(2021/3/1 23:00:00)),
+        );
+        for at in [datetime!(2021/3/1 22:00:00), datetime!(2021/3/1 23:30:00)] {
+            assert_eq!(
+                Correction::Split { at }.apply(&closed).unwrap_err(),
+                CorrectionError::OutOfSession
+            );
+        }
+    }
+
+    #[test]
+    fn close_and_adjust() {
+        let open = session(datetime!(2021/3/1 22:00:00), None);
+        let closed = session(
+            datetime!(2021/3/1 22:00:00),
+            Some(datetime!(2021/3/1 23:00:00)),
+        );
+
+        let at = datetime!(2021/3/1 23:30:00);
+        assert_eq!(
+            times(&Correction::Close { at }.apply(&open).unwrap()),
+            vec![(datetime!(2021/3/1 22:00:00), Some(at))]
+        );
+        assert_eq!(
+            Correction::Close { at }.apply(&closed).unwrap_err(),
+            CorrectionError::AlreadyClosed
+        );
+
+        let adjust = Correction::Adjust {
+            joined_at: Some(datetime!(2021/3/1 22:30:00)),
+            left_at: None,
+        };
+        assert_eq!(
+            times(&adjust.apply(&closed).unwrap()),
+            vec![(
+                datetime!(2021/3/1 22:30:00),
+                Some(datetime!(2021/3/1 23:00:00))
+            )]
+        );
+
+        let reversed = Correction::Adjust {
+            joined_at: None,
+            left_at: Some(datetime!(2021/3/1 21:00:00)),
+        };
+        assert_eq!(
+            reversed.apply(&closed).unwrap_err(),
+            CorrectionError::Reversed
+        );
+
+        let nothing = Correction::Adjust {
+            joined_at: None,
+            left_at: None,
+        };
+        assert_eq!(
+            nothing.apply(&closed).unwrap_err(),
+            CorrectionError::Nothing
+        );
+
+        assert!(Correction::Delete.apply(&closed).unwrap().is_empty());
+    }
+
+    #[test]
+    fn overlap() {
+        let id = |x: &str| SessionId(x.into());
+        let sessions = vec![
+            (
+                id("a"),
+                session(
+                    datetime!(2021/3/1 20:00:00),
+                    Some(datetime!(2021/3/1 21:00:00)),
+                ),
+            ),
+            (
+                id("b"),
+                session(
+                    datetime!(2021/3/1 22:00:00),
+                    Some(datetime!(2021/3/1 23:00:00)),
+                ),
+            ),
+        ];
+
+        // touching is fine
+        let after = [session(
+            datetime!(2021/3/1 21:00:00),
+            Some(datetime!(2021/3/1 23:00:00)),
+        )];
+        assert_eq!(find_overlap(&sessions, &id("b"), &after), None);
+
+        let after = [session(
+            datetime!(2021/3/1 20:30:00),
+            Some(datetime!(2021/3/1 23:00:00)),
+        )];
+        assert_eq!(find_overlap(&sessions, &id("b"), &after), Some(&id("a")));
+    }
+
+    #[test]
+    fn parse() {
+        let expected = datetime!(2021/3/1 23:30:00);
+
+        assert_eq!(parse_datetime("2021-03-01 23:30").unwrap(), expected);
+        assert_eq!(parse_datetime("2021-03-01T23:30:00").unwrap(), expected);
+        assert!(parse_datetime("2021/03/01 23:30").is_err());
+    }
+}
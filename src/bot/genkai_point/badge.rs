@@ -0,0 +1,322 @@
+use {
+    crate::bot::genkai_point::model::Session,
+    chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Utc},
+    chrono_tz::Asia::Tokyo,
+    std::collections::BTreeSet,
+};
+
+/// この時刻 (JST) までの深夜に VC にいた日を「限界な夜」とします
+const NIGHT_END_HOUR: u32 = 5;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Badge {
+    pub id: &'static str,
+    pub name: &'static str,
+    pub description: &'static str,
+    condition: Condition,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Condition {
+    /// N 夜連続で深夜に VC にいた
+    NightStreak(usize),
+    /// 合計 VC 時間が N 時間以上
+    TotalHours(i64),
+    /// VC にいるまま N 時 (JST) を迎えた
+    StayedAt(u32),
+    /// 1 回のセッションが N 時間以上
+    SessionHours(i64),
+}
+
+pub const BADGES: &[Badge] = &[
+    Badge {
+        id: "night_streak_3",
+        name: "三日坊主",
+        description: "3夜連続で深夜に VC にいた",
+        condition: Condition::NightStreak(3),
+    },
+    Badge {
+        id: "night_streak_7",
+        name: "限界週間",
+        description: "7夜連続で深夜に VC にいた",
+        condition: Condition::NightStreak(7),
+    },
+    Badge {
+        id: "night_streak_30",
+        name: "昼夜逆転",
+        description: "30夜連続で深夜に VC にいた",
+        condition: Condition::NightStreak(30),
+    },
+    Badge {
+        id: "total_100h",
+        name: "初めての100時間",
+        description: "合計 VC 時間が100時間に達した",
+        condition: Condition::TotalHours(100),
+    },
+    Badge {
+        id: "total_1000h",
+        name: "VC の住人",
+        description: "合計 VC 時間が1000時間に達した",
+        condition: Condition::TotalHours(1000),
+    },
+    Badge {
+        id: "survived_5am",
+        name: "朝5時の生存者",
+        description: "VC にいるまま朝5時を迎えた",
+        condition: Condition::StayedAt(5),
+    },
+    Badge {
+        id: "session_12h",
+        name: "長期滞在",
+        description: "1回で12時間以上 VC にいた",
+        condition: Condition::SessionHours(12),
+    },
+    Badge {
+        id: "session_24h",
+        name: "24時間戦えますか",
+        description: "1回で24時間以上 VC にいた",
+        condition: Condition::SessionHours(24),
+    },
+];
+
+/// バッジの判定に使う記録
+#[derive(Debug, PartialEq, Eq)]
+pub struct Records {
+    pub longest_night_streak: usize,
+    pub total_vc_duration: Duration,
+    pub longest_session: Duration,
+}
+
+impl Records {
+    pub fn from_sessions(sessions: &[Session]) -> Self {
+        Records {
+            longest_night_streak: longest_streak(&nights(sessions)),
+            total_vc_duration: sessions
+                .iter()
+                .fold(Duration::zero(), |acc, s| acc + s.duration()),
+            longest_session: sessions
+                .iter()
+                .map(|s| s.duration())
+                .max()
+                .unwrap_or_else(Duration::zero),
+        }
+    }
+}
+
+impl Badge {
+    fn is_unlocked(&self, records: &Records, sessions: &[Session]) -> bool {
+        match self.condition {
+            Condition::NightStreak(n) => records.longest_night_streak >= n,
+            Condition::TotalHours(h) => records.total_vc_duration >= Duration::hours(h),
+            Condition::StayedAt(hour) => sessions.iter().any(|s| stayed_at(s, hour)),
+            Condition::SessionHours(h) => records.longest_session >= Duration::hours(h),
+        }
+    }
+}
+
+/// 獲得済みのバッジを `BADGES` の順で返します
+pub fn unlocked_badges(sessions: &[Session]) -> Vec<&'static Badge> {
+    let records = Records::from_sessions(sessions);
+
+    BADGES
+        .iter()
+        .filter(|b| b.is_unlocked(&records, sessions))
+        .collect()
+}
+
+/// `before` の時点では獲得していなかったが、`after` の時点で獲得しているバッジを返します
+pub fn newly_unlocked_badges(before: &[Session], after: &[Session]) -> Vec<&'static Badge> {
+    let unlocked = unlocked_badges(before);
+
+    unlocked_badges(after)
+        .into_iter()
+        .filter(|b| !unlocked.contains(b))
+        .collect()
+}
+
+/// 最後のセッション (`sessions` の末尾) を閉じたことで獲得したバッジを返します。
+/// 最後のセッションが `resumed_from` に一度閉じてから再開したものなら、その時点までに獲得していたバッジは含めません
+pub fn badges_unlocked_by_last_session(
+    sessions: &[Session],
+    resumed_from: Option<DateTime<Utc>>,
+) -> Vec<&'static Badge> {
+    let Some((_, rest)) = sessions.split_last() else {
+        return vec![];
+    };
+
+    match resumed_from {
+        Some(left_at) => {
+            let mut before = sessions.to_vec();
+            before.last_mut().unwrap().left_at = Some(left_at);
+            newly_unlocked_badges(&before, sessions)
+        }
+        None => newly_unlocked_badges(rest, sessions),
+    }
+}
+
+fn local_range(session: &Session) -> (NaiveDateTime, NaiveDateTime) {
+    (
+        session.joined_at.with_timezone(&Tokyo).naive_local(),
+        session.left_at().with_timezone(&Tokyo).naive_local(),
+    )
+}
+
+fn dates(from: NaiveDate, until: NaiveDate) -> impl Iterator<Item = NaiveDate> {
+    from.iter_days().take_while(move |d| *d <= until)
+}
+
+// dates whose 0:00 - NIGHT_END_HOUR:00 overlaps with any session
+fn nights(sessions: &[Session]) -> BTreeSet<NaiveDate> {
+    let mut nights = BTreeSet::new();
+
+    for session in sessions {
+        let (joined_at, left_at) = local_range(session);
+
+        for date in dates(joined_at.date(), left_at.date()) {
+            let night_start = date.and_hms_opt(0, 0, 0).unwrap();
+            let night_end = date.and_hms_opt(NIGHT_END_HOUR, 0, 0).unwrap();
+
+            if joined_at < night_end && night_start < left_at {
+                nights.insert(date);
+            }
+        }
+    }
+
+    nights
+}
+
+fn longest_streak(dates: &BTreeSet<NaiveDate>) -> usize {
+    let mut longest = 0;
+    let mut current = 0;
+    let mut prev: Option<NaiveDate> = None;
+
+    for &date in dates {
+        current = match prev {
+            Some(p) if p.succ_opt() == Some(date) => current + 1,
+            _ => 1,
+        };
+        longest = longest.max(current);
+        prev = Some(date);
+    }
+
+    longest
+}
+
+fn stayed_at(session: &Session, hour: u32) -> bool {
+    let (joined_at, left_at) = local_range(session);
+
+    dates(joined_at.date(), left_at.date()).any(|date| {
+        let t = date.and_hms_opt(hour, 0, 0).unwrap();
+        joined_at <= t && t < left_at
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use {super::*, crate::bot::genkai_point::datetime, pretty_assertions::assert_eq};
+
+    fn session(joined_at: chrono::DateTime<chrono::Utc>, hours: i64) -> Session {
+        Session {
+            user_id: 0,
+            joined_at,
+            left_at: Some(joined_at + Duration::hours(hours)),
+            excluded: vec![],
+            uncertain: vec![],
+        }
+    }
+
+    fn ids(badges: Vec<&'static Badge>) -> Vec<&'static str> {
+        badges.into_iter().map(|b| b.id).collect()
+    }
+
+    #[test]
+    fn night_streak() {
+        let sessions = [
+            // crosses midnight, so counts as the night of 3/2
+            session(datetime!(2021/3/1 23:00:00), 2),
+            session(datetime!(2021/3/3 1:00:00), 1),
+            session(datetime!(2021/3/4 3:00:00), 1),
+            // daytime only
+            session(datetime!(2021/3/5 12:00:00), 3),
+            session(datetime!(2021/3/6 2:00:00), 1),
+        ];
+
+        let records = Records::from_sessions(&sessions);
+        assert_eq!(records.longest_night_streak, 3);
+        assert_eq!(records.longest_session, Duration::hours(3));
+        assert_eq!(records.total_vc_duration, Duration::hours(8));
+
+        assert_eq!(ids(unlocked_badges(&sessions)), vec!["night_streak_3"]);
+    }
+
+    #[test]
+    fn long_sessions() {
+        let sessions = [
+            session(datetime!(2021/3/1 20:00:00), 25),
+            session(datetime!(2021/3/10 0:00:00), 80),
+        ];
+
+        assert_eq!(
+            ids(unlocked_badges(&sessions)),
+            vec![
+                "night_streak_3",
+                "total_100h",
+                "survived_5am",
+                "session_12h",
+                "session_24h",
+            ]
+        );
+    }
+
+    #[test]
+    fn survived_5am() {
+        // left just before 5am
+        let before = [session(datetime!(2021/3/1 22:00:00), 7)];
+        assert!(ids(unlocked_badges(&before)).is_empty());
+
+        let after = [
+            session(datetime!(2021/3/1 22:00:00), 7),
+            session(datetime!(2021/3/2 4:00:00), 2),
+        ];
+
+        assert_eq!(
+            ids(newly_unlocked_badges(&before, &after)),
+            vec!["survived_5am"]
+        );
+    }
+
+    #[test]
+    fn nothing_new() {
+        let sessions = [session(datetime!(2021/3/1 20:00:00), 13)];
+
+        assert_eq!(
+            ids(unlocked_badges(&sessions)),
+            vec!["survived_5am", "session_12h"]
+        );
+        assert!(newly_unlocked_badges(&sessions, &sessions).is_empty());
+    }
+
+    #[test]
+    fn resumed_session() {
+        // left at 2:00 after 13 hours, then came back within 5 minutes and stayed until 6:00
+        let mut sessions = [session(datetime!(2021/3/1 13:00:00), 17)];
+        let resumed_from = datetime!(2021/3/2 2:00:00);
+
+        assert_eq!(
+            ids(badges_unlocked_by_last_session(&sessions, None)),
+            vec!["survived_5am", "session_12h"]
+        );
+
+        // session_12h was already announced when the user left at 2:00
+        assert_eq!(
+            ids(badges_unlocked_by_last_session(
+                &sessions,
+                Some(resumed_from)
+            )),
+            vec!["survived_5am"]
+        );
+
+        sessions[0].left_at = Some(datetime!(2021/3/2 4:00:00));
+        assert!(badges_unlocked_by_last_session(&sessions, Some(resumed_from)).is_empty());
+    }
+}
@@ -0,0 +1,112 @@
+use {
+    crate::bot::genkai_point::model::UserStat,
+    std::{cmp::Reverse, collections::HashMap},
+};
+
+/// 2つのフォーミュラで計算したときの、あるユーザーの順位とポイントの違い
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    pub user_id: u64,
+    pub rank_before: usize,
+    pub rank_after: usize,
+    pub point_before: u64,
+    pub point_after: u64,
+    pub efficiency_before: f64,
+    pub efficiency_after: f64,
+}
+
+impl Comparison {
+    /// 順位が上がったら正になります
+    pub fn rank_delta(&self) -> i64 {
+        self.rank_before as i64 - self.rank_after as i64
+    }
+
+    pub fn point_delta(&self) -> i64 {
+        self.point_after as i64 - self.point_before as i64
+    }
+
+    pub fn efficiency_delta(&self) -> f64 {
+        self.efficiency_after - self.efficiency_before
+    }
+}
+
+// ranking by genkai point, ties are broken by user id
+fn ranks(stats: &[UserStat]) -> HashMap<u64, (usize, &UserStat)> {
+    let mut sorted = stats.iter().collect::<Vec<_>>();
+    sorted.sort_unstable_by_key(|x| (Reverse(x.genkai_point), x.user_id));
+
+    sorted
+        .into_iter()
+        .enumerate()
+        .map(|(index, stat)| (stat.user_id, (index + 1, stat)))
+        .collect()
+}
+
+/// `before` のフォーミュラから `after` のフォーミュラに変えたときの違いを、
+/// `after` での順位順に返します。両方に含まれるユーザーだけが対象です
+pub fn compare(before: &[UserStat], after: &[UserStat]) -> Vec<Comparison> {
+    let before = ranks(before);
+
+    let mut result = ranks(after)
+        .into_iter()
+        .filter_map(|(user_id, (rank_after, after))| {
+            let &(rank_before, before) = before.get(&user_id)?;
+
+            Some(Comparison {
+                user_id,
+                rank_before,
+                rank_after,
+                point_before: before.genkai_point,
+                point_after: after.genkai_point,
+                efficiency_before: *before.efficiency,
+                efficiency_after: *after.efficiency,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    result.sort_unstable_by_key(|x| x.rank_after);
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        chrono::{Duration, Utc},
+        ordered_float::NotNan,
+        pretty_assertions::assert_eq,
+    };
+
+    fn stat(user_id: u64, genkai_point: u64, efficiency: f64) -> UserStat {
+        UserStat {
+            user_id,
+            genkai_point,
+            total_vc_duration: Duration::hours(1),
+            efficiency: NotNan::new(efficiency).unwrap(),
+            last_activity_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn rank_changes() {
+        let before = [stat(1, 100, 0.5), stat(2, 80, 0.25), stat(3, 80, 0.75)];
+        let after = [stat(1, 90, 0.5), stat(2, 120, 0.5), stat(3, 10, 0.25)];
+
+        let result = compare(&before, &after);
+
+        assert_eq!(
+            result.iter().map(|x| x.user_id).collect::<Vec<_>>(),
+            vec![2, 1, 3]
+        );
+
+        // tie at 80pt is broken by user id
+        assert_eq!(result[0].rank_before, 2);
+        assert_eq!(result[0].rank_delta(), 1);
+        assert_eq!(result[0].point_delta(), 40);
+        assert_eq!(result[0].efficiency_delta(), 0.25);
+
+        assert_eq!(result[1].rank_delta(), -1);
+        assert_eq!(result[2].rank_delta(), 0);
+        assert_eq!(result[2].point_delta(), -70);
+    }
+}
@@ -0,0 +1,335 @@
+use {
+    crate::bot::{
+        Context, SendAttachment, SendMessage,
+        genkai_point::{
+            GenkaiPointDatabase, Plotter, formula::default_formula, model::Session, plot,
+            users_stats,
+        },
+    },
+    anyhow::{Context as _, Result},
+    chrono::{DateTime, Datelike, Days, Duration, Months, NaiveDate, TimeZone, Utc},
+    chrono_tz::Asia::Tokyo,
+    clap::ValueEnum,
+    std::{
+        cmp::Reverse,
+        collections::{HashMap, HashSet},
+        sync::Arc,
+    },
+};
+
+/// ダイジェストに載せる人数
+const TOP_MOVERS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DigestPeriod {
+    /// 毎週月曜日の 0 時 (JST) に先週の分を投稿します
+    Weekly,
+    /// 毎月 1 日の 0 時 (JST) に先月の分を投稿します
+    Monthly,
+}
+
+impl DigestPeriod {
+    fn name(self) -> &'static str {
+        match self {
+            DigestPeriod::Weekly => "週間",
+            DigestPeriod::Monthly => "月間",
+        }
+    }
+
+    /// `after` より後で最初の集計の区切り
+    fn next_boundary(self, after: DateTime<Utc>) -> DateTime<Utc> {
+        let date = after.with_timezone(&Tokyo).date_naive();
+
+        let next = match self {
+            DigestPeriod::Weekly => {
+                date + Days::new(7 - date.weekday().num_days_from_monday() as u64)
+            }
+            DigestPeriod::Monthly => date.with_day(1).unwrap() + Months::new(1),
+        };
+
+        midnight(next)
+    }
+
+    /// `until` で終わる集計期間
+    fn window(self, until: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+        let date = until.with_timezone(&Tokyo).date_naive();
+
+        let from = match self {
+            DigestPeriod::Weekly => date - Days::new(7),
+            DigestPeriod::Monthly => date - Months::new(1),
+        };
+
+        (midnight(from), until)
+    }
+}
+
+fn midnight(date: NaiveDate) -> DateTime<Utc> {
+    Tokyo
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0).unwrap())
+        .unwrap()
+        .with_timezone(&Utc)
+}
+
+/// 区切りを迎えるたびにダイジェストを投稿し続けます。
+/// 区切りの時点で止まっていた場合、その期間のダイジェストは投稿されません。
+pub(super) async fn run<D, P, C>(db: Arc<D>, plotter: Arc<P>, ctx: C, period: DigestPeriod)
+where
+    D: GenkaiPointDatabase,
+    P: Plotter,
+    C: Context,
+{
+    let mut last = Utc::now();
+
+    loop {
+        let next = period.next_boundary(last);
+
+        if let Ok(wait) = (next - Utc::now()).to_std() {
+            tokio::time::sleep(wait).await;
+        }
+
+        if let Err(e) = post(&*db, &ctx, &*plotter, period, next).await {
+            tracing::error!("failed to post {period:?} digest: {e:?}");
+        }
+
+        last = next;
+    }
+}
+
+async fn post(
+    db: &impl GenkaiPointDatabase,
+    ctx: &impl Context,
+    plotter: &impl Plotter,
+    period: DigestPeriod,
+    until: DateTime<Utc>,
+) -> Result<()> {
+    let (from, until) = period.window(until);
+
+    let mut sessions = db
+        .get_all_sessions()
+        .await
+        .context("failed to get sessions")?;
+
+    let mut bots = HashSet::new();
+    for user_id in sessions.iter().map(|s| s.user_id).collect::<HashSet<_>>() {
+        if ctx.is_bot(user_id).await? {
+            bots.insert(user_id);
+        }
+    }
+    sessions.retain(|s| !bots.contains(&s.user_id));
+
+    let format_date = |d: DateTime<Utc>| d.with_timezone(&Tokyo).format("%Y/%m/%d");
+
+    let mut lines = vec![format!(
+        "限界ポイント{}ダイジェスト ({} - {})",
+        period.name(),
+        format_date(from),
+        format_date(until - Duration::days(1)),
+    )];
+
+    lines.push("トップムーバー:".to_owned());
+
+    let movers = movers(&sessions, from, until)?;
+    if movers.is_empty() {
+        lines.push("  なし".to_owned());
+    }
+
+    for (index, mover) in movers.iter().enumerate() {
+        let rank = match mover.rank_before {
+            Some(before) => format!("{before}位 → {}位", mover.rank_after),
+            None => format!("初登場 {}位", mover.rank_after),
+        };
+
+        lines.push(format!(
+            "  #{} +{}pt (+{:.2}h) {} {}",
+            index + 1,
+            mover.point,
+            mover.vc_duration.num_minutes() as f64 / 60.0,
+            rank,
+            ctx.get_user_name(mover.user_id).await?,
+        ));
+    }
+
+    lines.push("新規参加:".to_owned());
+
+    let mut entrants = vec![];
+    for user_id in new_entrants(&sessions, from, until) {
+        entrants.push(ctx.get_user_name(user_id).await?);
+    }
+
+    if entrants.is_empty() {
+        lines.push("  なし".to_owned());
+    } else {
+        lines.push(format!("  {}", entrants.join(", ")));
+    }
+
+    let content = format!("```\n{}\n```", lines.join("\n"));
+
+    let window_sessions = sessions
+        .iter()
+        .filter_map(|s| s.clip(from, until))
+        .collect();
+
+    let options = plot::GraphOptions {
+        top: TOP_MOVERS,
+        ..Default::default()
+    };
+    let graph = plot::plot_sessions(window_sessions, ctx, plotter, &options).await?;
+    let attachments = graph.as_deref().map(|data| SendAttachment {
+        name: "digest.png",
+        data,
+    });
+
+    ctx.send_message(SendMessage {
+        content: &content,
+        attachments: attachments.as_slice(),
+    })
+    .await
+    .context("failed to send message")
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Mover {
+    user_id: u64,
+    point: u64,
+    vc_duration: Duration,
+    rank_before: Option<usize>,
+    rank_after: usize,
+}
+
+// lifetime ranking by genkai point as of `until`
+fn ranks(sessions: &[Session], until: DateTime<Utc>) -> Result<HashMap<u64, usize>> {
+    let sessions = sessions
+        .iter()
+        .filter_map(|s| s.clip(DateTime::<Utc>::MIN_UTC, until))
+        .collect();
+
+    let mut stats = users_stats(sessions, &default_formula())?;
+    stats.sort_unstable_by_key(|x| (Reverse(x.genkai_point), x.user_id));
+
+    Ok(stats
+        .iter()
+        .enumerate()
+        .map(|(index, stat)| (stat.user_id, index + 1))
+        .collect())
+}
+
+// users who earned the most points in `[from, until)`
+fn movers(sessions: &[Session], from: DateTime<Utc>, until: DateTime<Utc>) -> Result<Vec<Mover>> {
+    let before = ranks(sessions, from)?;
+    let after = ranks(sessions, until)?;
+
+    let window_sessions = sessions
+        .iter()
+        .filter_map(|s| s.clip(from, until))
+        .collect();
+
+    let mut stats = users_stats(window_sessions, &default_formula())?;
+    stats.sort_unstable_by_key(|x| {
+        (
+            Reverse(x.genkai_point),
+            Reverse(x.total_vc_duration),
+            x.user_id,
+        )
+    });
+
+    Ok(stats
+        .into_iter()
+        .take(TOP_MOVERS)
+        .map(|stat| Mover {
+            user_id: stat.user_id,
+            point: stat.genkai_point,
+            vc_duration: stat.total_vc_duration,
+            rank_before: before.get(&stat.user_id).copied(),
+            rank_after: after[&stat.user_id],
+        })
+        .collect())
+}
+
+// users whose first session started in `[from, until)`, in the order of joining
+fn new_entrants(sessions: &[Session], from: DateTime<Utc>, until: DateTime<Utc>) -> Vec<u64> {
+    let mut first_joined = HashMap::new();
+
+    for session in sessions {
+        first_joined
+            .entry(session.user_id)
+            .and_modify(|x: &mut DateTime<Utc>| *x = (*x).min(session.joined_at))
+            .or_insert(session.joined_at);
+    }
+
+    let mut entrants = first_joined
+        .into_iter()
+        .filter(|(_, joined_at)| from <= *joined_at && *joined_at < until)
+        .collect::<Vec<_>>();
+
+    entrants.sort_unstable_by_key(|&(user_id, joined_at)| (joined_at, user_id));
+    entrants.into_iter().map(|(user_id, _)| user_id).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use {super::*, crate::bot::genkai_point::datetime, pretty_assertions::assert_eq};
+
+    fn session(user_id: u64, joined_at: DateTime<Utc>, hours: i64) -> Session {
+        Session {
+            user_id,
+            joined_at,
+            left_at: Some(joined_at + Duration::hours(hours)),
+            excluded: vec![],
+            uncertain: vec![],
+        }
+    }
+
+    #[test]
+    fn boundaries() {
+        use DigestPeriod::*;
+
+        // wednesday
+        let next = Weekly.next_boundary(datetime!(2024/1/3 12:00:00));
+        assert_eq!(next, datetime!(2024/1/8 00:00:00));
+        assert_eq!(Weekly.window(next), (datetime!(2024/1/1 00:00:00), next));
+        assert_eq!(Weekly.next_boundary(next), datetime!(2024/1/15 00:00:00));
+
+        let next = Monthly.next_boundary(datetime!(2024/1/31 23:00:00));
+        assert_eq!(next, datetime!(2024/2/1 00:00:00));
+        assert_eq!(Monthly.window(next), (datetime!(2024/1/1 00:00:00), next));
+        assert_eq!(Monthly.next_boundary(next), datetime!(2024/3/1 00:00:00));
+    }
+
+    #[test]
+    fn digest_entries() {
+        let sessions = [
+            session(1, datetime!(2024/1/2 0:00:00), 4),
+            session(1, datetime!(2024/1/10 1:00:00), 1),
+            session(2, datetime!(2024/1/2 22:00:00), 1),
+            session(2, datetime!(2024/1/9 0:00:00), 5),
+            session(3, datetime!(2024/1/11 0:00:00), 1),
+            // crosses the end of the window
+            session(4, datetime!(2024/1/14 23:00:00), 3),
+        ];
+
+        let from = datetime!(2024/1/8 00:00:00);
+        let until = datetime!(2024/1/15 00:00:00);
+
+        let movers = movers(&sessions, from, until).unwrap();
+
+        assert_eq!(
+            movers.iter().map(|x| x.user_id).collect::<Vec<_>>(),
+            vec![2, 1, 3, 4]
+        );
+
+        assert_eq!(
+            movers[0],
+            Mover {
+                user_id: 2,
+                point: movers[0].point,
+                vc_duration: Duration::hours(5),
+                rank_before: Some(2),
+                rank_after: 1,
+            }
+        );
+        assert_eq!(movers[2].rank_before, None);
+        assert_eq!(movers[3].vc_duration, Duration::hours(1));
+
+        assert_eq!(new_entrants(&sessions, from, until), vec![3, 4]);
+    }
+}
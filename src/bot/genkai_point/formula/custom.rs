@@ -0,0 +1,294 @@
+use {
+    super::{GenkaiPointFormula, GenkaiPointFormulaOutput},
+    crate::bot::genkai_point::model::Session,
+    chrono::Timelike,
+    chrono_tz::Asia::Tokyo,
+    serde::{Deserialize, Serialize},
+};
+
+const MAX_NAME_LEN: usize = 32;
+const MAX_POINTS: usize = 48;
+const MAX_WEIGHT: f64 = 100.0;
+/// 数値積分の刻み幅 (時)
+const STEP_HOURS: f64 = 1.0 / 60.0;
+
+/// 時刻 (JST) ごとの重み w(h) を区分線形な表で定義するフォーミュラです。
+/// VC にいた間の w を積分したものがポイントになるので、重みは1時間あたりのポイントです
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CustomFormula {
+    pub name: String,
+    pub created_by: u64,
+    /// 時刻順に並んだ制御点。間は線形補間し、最後の点から翌日の最初の点へ循環します
+    pub points: Vec<WeightPoint>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct WeightPoint {
+    pub hour: f64,
+    pub weight: f64,
+}
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum CustomFormulaError {
+    #[error("名前は{MAX_NAME_LEN}文字以内の英数字と `_`, `-` で指定してください")]
+    Name,
+    #[error("`時刻:重み` の形式で指定してください: {0}")]
+    Format(String),
+    #[error("時刻は0以上24未満で指定してください: {0}")]
+    Hour(String),
+    #[error("重みは0以上{MAX_WEIGHT}以下で指定してください: {0}")]
+    Weight(String),
+    #[error("同じ時刻が複数回指定されています: {0}")]
+    Duplicated(String),
+    #[error("制御点は1個以上{MAX_POINTS}個以下で指定してください")]
+    Count,
+}
+
+pub fn validate_name(name: &str) -> Result<(), CustomFormulaError> {
+    let valid = (1..=MAX_NAME_LEN).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(CustomFormulaError::Name)
+    }
+}
+
+/// `0:10 3:7 6:0 22:5` のような表をパースします。カンマ区切りでも構いません
+pub fn parse_table(s: &str) -> Result<Vec<WeightPoint>, CustomFormulaError> {
+    let mut points = vec![];
+
+    for token in s.split([' ', ',']).filter(|x| !x.is_empty()) {
+        let (hour, weight) = token
+            .split_once(':')
+            .ok_or_else(|| CustomFormulaError::Format(token.to_owned()))?;
+
+        let hour = hour
+            .parse::<f64>()
+            .ok()
+            .filter(|x| (0.0..24.0).contains(x))
+            .ok_or_else(|| CustomFormulaError::Hour(token.to_owned()))?;
+        let weight = weight
+            .parse::<f64>()
+            .ok()
+            .filter(|x| (0.0..=MAX_WEIGHT).contains(x))
+            .ok_or_else(|| CustomFormulaError::Weight(token.to_owned()))?;
+
+        if points.iter().any(|x: &WeightPoint| x.hour == hour) {
+            return Err(CustomFormulaError::Duplicated(token.to_owned()));
+        }
+
+        points.push(WeightPoint { hour, weight });
+    }
+
+    if !(1..=MAX_POINTS).contains(&points.len()) {
+        return Err(CustomFormulaError::Count);
+    }
+
+    points.sort_unstable_by(|a, b| a.hour.total_cmp(&b.hour));
+
+    Ok(points)
+}
+
+pub fn format_table(points: &[WeightPoint]) -> String {
+    points
+        .iter()
+        .map(|p| format!("{}:{}", p.hour, p.weight))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl CustomFormula {
+    fn max_weight(&self) -> f64 {
+        self.points.iter().map(|p| p.weight).fold(0.0, f64::max)
+    }
+
+    // integrates w over `hours` hours starting at `start` o'clock, with the midpoint rule
+    fn integrate(&self, start: f64, hours: f64) -> f64 {
+        if hours <= 0.0 {
+            return 0.0;
+        }
+
+        let steps = (hours / STEP_HOURS).ceil();
+        let dt = hours / steps;
+
+        (0..steps as u64)
+            .map(|i| self.weight_at((start + (i as f64 + 0.5) * dt).rem_euclid(24.0)) * dt)
+            .sum()
+    }
+}
+
+fn interpolate(from: WeightPoint, until: WeightPoint, hour: f64) -> f64 {
+    if until.hour == from.hour {
+        return from.weight;
+    }
+
+    let ratio = (hour - from.hour) / (until.hour - from.hour);
+    from.weight + (until.weight - from.weight) * ratio
+}
+
+impl GenkaiPointFormula for CustomFormula {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn calc(&self, sessions: &[Session]) -> GenkaiPointFormulaOutput {
+        let mut now_point = 0.0;
+        let mut total_hours = 0.0;
+
+        // excluded periods don't count, same as v4
+        for (joined_at, left_at) in sessions.iter().flat_map(|s| s.active_periods()) {
+            let start = joined_at.with_timezone(&Tokyo);
+            let start = start.num_seconds_from_midnight() as f64 / 3600.0
+                + start.nanosecond() as f64 / 3600e9;
+            let hours = (left_at - joined_at).num_milliseconds() as f64 / 3600e3;
+
+            now_point += self.integrate(start, hours);
+            total_hours += hours;
+        }
+
+        let max_point = self.max_weight() * total_hours;
+
+        let point = now_point.round() as u64;
+        let efficiency = if max_point > 0.0 {
+            now_point / max_point
+        } else {
+            0.0
+        };
+
+        GenkaiPointFormulaOutput { point, efficiency }
+    }
+
+    fn weight(&self, hour: f64) -> f64 {
+        self.integrate(hour, 1.0)
+    }
+}
+
+impl CustomFormula {
+    /// 時刻 `hour` (0以上24未満) における重み
+    pub fn weight_at(&self, hour: f64) -> f64 {
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+
+        // the segment which wraps around midnight
+        let (from, until) = match self.points.iter().position(|p| hour < p.hour) {
+            Some(0) | None => {
+                let hour = if hour < first.hour { hour + 24.0 } else { hour };
+                let until = WeightPoint {
+                    hour: first.hour + 24.0,
+                    ..first
+                };
+                return interpolate(last, until, hour);
+            }
+            Some(i) => (self.points[i - 1], self.points[i]),
+        };
+
+        interpolate(from, until, hour)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::bot::genkai_point::{
+            datetime,
+            model::{ExcludedPeriod, ExclusionReason},
+        },
+        pretty_assertions::assert_eq,
+    };
+
+    fn formula(table: &str) -> CustomFormula {
+        CustomFormula {
+            name: "test".into(),
+            created_by: 0,
+            points: parse_table(table).unwrap(),
+        }
+    }
+
+    fn session(joined_at: chrono::DateTime<chrono::Utc>, hours: i64) -> Session {
+        Session {
+            user_id: 0,
+            joined_at,
+            left_at: Some(joined_at + chrono::Duration::hours(hours)),
+            excluded: vec![],
+            uncertain: vec![],
+        }
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            parse_table("6:0, 0:10 3:7.5").unwrap(),
+            vec![
+                WeightPoint {
+                    hour: 0.0,
+                    weight: 10.0
+                },
+                WeightPoint {
+                    hour: 3.0,
+                    weight: 7.5
+                },
+                WeightPoint {
+                    hour: 6.0,
+                    weight: 0.0
+                },
+            ]
+        );
+
+        use CustomFormulaError::*;
+        assert_eq!(parse_table("0=1"), Err(Format("0=1".into())));
+        assert_eq!(parse_table("24:1"), Err(Hour("24:1".into())));
+        assert_eq!(parse_table("0:-1"), Err(Weight("0:-1".into())));
+        assert_eq!(parse_table("0:NaN"), Err(Weight("0:NaN".into())));
+        assert_eq!(parse_table("1:1 1:2"), Err(Duplicated("1:2".into())));
+        assert_eq!(parse_table(" "), Err(Count));
+
+        assert!(validate_name("night_owl-2").is_ok());
+        assert_eq!(validate_name("夜"), Err(Name));
+        assert_eq!(validate_name(""), Err(Name));
+    }
+
+    #[test]
+    fn weight() {
+        let f = formula("2:10 4:0 22:0");
+
+        assert_eq!(f.weight_at(2.0), 10.0);
+        assert_eq!(f.weight_at(3.0), 5.0);
+        assert_eq!(f.weight_at(12.0), 0.0);
+        // wraps around midnight: 22:00 (0) -> 2:00 (10)
+        assert_eq!(f.weight_at(23.0), 2.5);
+        assert_eq!(f.weight_at(0.0), 5.0);
+
+        let constant = formula("12:3");
+        assert_eq!(constant.weight_at(0.0), 3.0);
+        assert_eq!(constant.weight_at(12.0), 3.0);
+        assert_eq!(constant.weight_at(23.5), 3.0);
+    }
+
+    #[test]
+    fn calc() {
+        let constant = formula("0:10");
+        let output = constant.calc(&[session(datetime!(2021/3/1 23:00:00), 2)]);
+        assert_eq!(output.point, 20);
+        assert!((output.efficiency - 1.0).abs() < 1e-9);
+
+        // triangle over 22:00 - 2:00 peaking at midnight, crossing the day boundary
+        let night = formula("0:10 2:0 22:0");
+        let output = night.calc(&[session(datetime!(2021/3/1 22:00:00), 4)]);
+        assert_eq!(output.point, 20);
+        assert!((output.efficiency - 0.5).abs() < 1e-3);
+
+        // the afk part is not counted
+        let mut afk = session(datetime!(2021/3/1 22:00:00), 4);
+        afk.excluded.push(ExcludedPeriod {
+            reason: ExclusionReason::Afk,
+            from: datetime!(2021/3/2 00:00:00),
+            until: None,
+        });
+        assert_eq!(night.calc(&[afk]).point, 10);
+    }
+}
@@ -1,12 +1,17 @@
 use {crate::bot::genkai_point::model::Session, v3::FormulaV3};
 
+pub mod custom;
 pub mod v1;
 pub mod v2;
 pub mod v3;
+pub mod v4;
 
 pub trait GenkaiPointFormula: Send + Sync + 'static {
-    fn name(&self) -> &'static str;
+    fn name(&self) -> &str;
     fn calc(&self, sessions: &[Session]) -> GenkaiPointFormulaOutput;
+    /// 時刻 `hour` (JST, 0以上24未満) から1時間 VC にいたときに得られるポイント。
+    /// フォーミュラ同士を比べるためのもので、丸めは行いません
+    fn weight(&self, hour: f64) -> f64;
 }
 
 pub struct GenkaiPointFormulaOutput {
@@ -21,11 +26,47 @@ pub fn default_formula() -> impl GenkaiPointFormula {
 pub struct DynGenkaiPointFormula(pub Box<dyn GenkaiPointFormula>);
 
 impl GenkaiPointFormula for DynGenkaiPointFormula {
-    fn name(&self) -> &'static str {
+    fn name(&self) -> &str {
         self.0.name()
     }
 
     fn calc(&self, sessions: &[Session]) -> GenkaiPointFormulaOutput {
         self.0.calc(sessions)
     }
+
+    fn weight(&self, hour: f64) -> f64 {
+        self.0.weight(hour)
+    }
+}
+
+#[test]
+fn weight_test() {
+    use {crate::bot::genkai_point::datetime, chrono::Duration};
+
+    let formulas: [&dyn GenkaiPointFormula; 4] =
+        [&v1::FormulaV1, &v2::FormulaV2, &FormulaV3, &v4::FormulaV4];
+    let midnight = datetime!(2021/3/1 00:00:00);
+
+    // staying an hour from each hour gives the weight
+    for formula in formulas {
+        for hour in 0..24 {
+            let joined_at = midnight + Duration::hours(hour);
+            let session = Session {
+                user_id: 0,
+                joined_at,
+                left_at: Some(joined_at + Duration::hours(1)),
+                excluded: vec![],
+                uncertain: vec![],
+            };
+
+            let point = formula.calc(&[session]).point as f64;
+
+            // calc rounds the points
+            assert!(
+                (formula.weight(hour as f64) - point).abs() <= 0.5 + 1e-9,
+                "{} at {hour}",
+                formula.name()
+            );
+        }
+    }
 }
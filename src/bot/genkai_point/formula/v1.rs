@@ -10,7 +10,7 @@ use {
 pub struct FormulaV1;
 
 impl GenkaiPointFormula for FormulaV1 {
-    fn name(&self) -> &'static str {
+    fn name(&self) -> &str {
         "v1"
     }
 
@@ -41,6 +41,11 @@ impl GenkaiPointFormula for FormulaV1 {
 
         GenkaiPointFormulaOutput { point, efficiency }
     }
+
+    fn weight(&self, hour: f64) -> f64 {
+        // points are given at the end of each hour
+        hour_to_point((hour as u32 + 1) % 24) as f64
+    }
 }
 
 fn hour_to_point(hour: u32) -> u64 {
@@ -78,6 +83,8 @@ fn session_test() {
                 user_id: 0,
                 joined_at: $d1,
                 left_at: Some($d2),
+                excluded: vec![],
+                uncertain: vec![],
             };
             assert_eq!(FormulaV1.calc(&[session]).point, $point);
         }};
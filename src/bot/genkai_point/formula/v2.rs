@@ -10,7 +10,7 @@ use {
 pub struct FormulaV2;
 
 impl GenkaiPointFormula for FormulaV2 {
-    fn name(&self) -> &'static str {
+    fn name(&self) -> &str {
         "v2"
     }
 
@@ -28,6 +28,28 @@ impl GenkaiPointFormula for FormulaV2 {
 
         GenkaiPointFormulaOutput { point, efficiency }
     }
+
+    fn weight(&self, hour: f64) -> f64 {
+        const STEPS: u32 = 60;
+
+        (0..STEPS)
+            .map(|i| rate((hour + (i as f64 + 0.5) / STEPS as f64).rem_euclid(24.0)) / STEPS as f64)
+            .sum()
+    }
+}
+
+// points per hour at `hour`, the functions integrated in `formula`
+fn rate(hour: f64) -> f64 {
+    match hour as u32 {
+        0..=2 => hour + 7.0,
+        3..=5 => -hour + 13.0,
+        6..=8 => -2.0 * hour + 19.0,
+        9 => -hour + 10.0,
+        10..=19 => 0.0,
+        20 => hour - 20.0,
+        21..=23 => 2.0 * hour - 41.0,
+        x => unreachable!("hour {x} is not possible"),
+    }
 }
 
 fn formula(session: &Session) -> f64 {
@@ -129,6 +151,8 @@ fn session_test() {
                 user_id: 0,
                 joined_at: $d1,
                 left_at: Some($d2),
+                excluded: vec![],
+                uncertain: vec![],
             };
             assert_eq!(FormulaV2.calc(&[session]).point, $point);
         }};
@@ -7,7 +7,7 @@ use {
 pub struct FormulaV3;
 
 impl GenkaiPointFormula for FormulaV3 {
-    fn name(&self) -> &'static str {
+    fn name(&self) -> &str {
         "v3"
     }
 
@@ -44,12 +44,18 @@ impl GenkaiPointFormula for FormulaV3 {
 
         GenkaiPointFormulaOutput { point, efficiency }
     }
+
+    fn weight(&self, hour: f64) -> f64 {
+        // `calc` takes the hour of day in UTC
+        let c = (hour - 9.0).rem_euclid(24.0);
+        (formula(c, 1.0) - formula(c, 0.0)) * 10.0
+    }
 }
 
 #[rustfmt::skip]
 // rendered: https://www.geogebra.org/graphing/esdsm7rz
 // used for integrate: https://www.integral-calculator.com
-fn formula(c: f64, t: f64) -> f64 {
+pub(super) fn formula(c: f64, t: f64) -> f64 {
     let pi = core::f64::consts::PI;
 
     let sin = f64::sin;
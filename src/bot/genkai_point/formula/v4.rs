@@ -0,0 +1,111 @@
+use {
+    super::{
+        GenkaiPointFormula, GenkaiPointFormulaOutput,
+        v3::{self, FormulaV3},
+    },
+    crate::bot::genkai_point::model::Session,
+};
+
+/// v3 と同じ式を、除外期間を取り除いたアクティブな期間だけに適用します
+pub struct FormulaV4;
+
+impl GenkaiPointFormula for FormulaV4 {
+    fn name(&self) -> &str {
+        "v4"
+    }
+
+    fn calc(&self, sessions: &[Session]) -> GenkaiPointFormulaOutput {
+        const ONE_HOUR_MILLIS: i64 = 60 * 60 * 1000;
+
+        let hours = |millis: i64| millis as f64 / ONE_HOUR_MILLIS as f64;
+
+        let (now_points, max_points) = sessions
+            .iter()
+            .flat_map(|s| {
+                let c = hours(s.joined_at.timestamp_millis() % (24 * ONE_HOUR_MILLIS));
+
+                // keep the session's own timeline so that leaving for a while doesn't restart the
+                // curve, which decays with the time since joining
+                s.active_periods().into_iter().map(move |(from, until)| {
+                    let a = hours((from - s.joined_at).num_milliseconds());
+                    let b = hours((until - s.joined_at).num_milliseconds());
+
+                    #[rustfmt::skip]
+                    let now_point = v3::formula(   c, b) - v3::formula(   c, a);
+                    let max_point = v3::formula(23.0, b) - v3::formula(23.0, a);
+
+                    (now_point, max_point)
+                })
+            })
+            .unzip::<_, _, Vec<_>, Vec<_>>();
+
+        let now_point = now_points.iter().sum::<f64>() * 10.0;
+        let max_point = max_points.iter().sum::<f64>() * 10.0;
+
+        let point = now_point.round() as u64;
+        let efficiency = now_point / max_point;
+
+        GenkaiPointFormulaOutput { point, efficiency }
+    }
+
+    fn weight(&self, hour: f64) -> f64 {
+        FormulaV3.weight(hour)
+    }
+}
+
+#[test]
+fn session_test() {
+    use crate::bot::genkai_point::{
+        datetime,
+        model::{ExcludedPeriod, ExclusionReason},
+    };
+
+    let session = |excluded| Session {
+        user_id: 0,
+        joined_at: datetime!(2021/3/1 23:00:00),
+        left_at: Some(datetime!(2021/3/2 3:00:00)),
+        excluded,
+        uncertain: vec![],
+    };
+
+    // same as v3 when nothing is excluded
+    let whole = session(vec![]);
+    assert_eq!(
+        FormulaV4.calc(std::slice::from_ref(&whole)).point,
+        FormulaV3.calc(&[whole]).point
+    );
+
+    let afk = session(vec![ExcludedPeriod {
+        reason: ExclusionReason::Afk,
+        from: datetime!(2021/3/2 1:00:00),
+        until: None,
+    }]);
+    let active = Session {
+        user_id: 0,
+        joined_at: datetime!(2021/3/1 23:00:00),
+        left_at: Some(datetime!(2021/3/2 1:00:00)),
+        excluded: vec![],
+        uncertain: vec![],
+    };
+    assert_eq!(
+        FormulaV4.calc(&[afk]).point,
+        FormulaV3.calc(&[active]).point
+    );
+
+    // a short hop to AFK in the middle doesn't restart the curve (splitting the session gives 6)
+    let long = |excluded| Session {
+        user_id: 0,
+        joined_at: datetime!(2021/3/1 23:00:00),
+        left_at: Some(datetime!(2021/3/2 5:00:00)),
+        excluded,
+        uncertain: vec![],
+    };
+    let stayed = FormulaV4.calc(&[long(vec![])]);
+    let hopped = FormulaV4.calc(&[long(vec![ExcludedPeriod {
+        reason: ExclusionReason::Afk,
+        from: datetime!(2021/3/2 2:00:00),
+        until: Some(datetime!(2021/3/2 2:01:00)),
+    }])]);
+    assert_eq!(stayed.point, 4);
+    assert_eq!(hopped.point, 4);
+}
@@ -1,24 +1,50 @@
+pub mod admin;
+pub mod badge;
+pub mod compare;
+pub mod digest;
 pub mod formula;
 pub mod model;
 pub mod plot;
 
 use {
+    crate::KAWAEMON_DISCORD_USER_ID,
     crate::bot::{
-        BotService, Context, Message, Runtime, SendAttachment, SendMessage, User,
+        BotService, Context, IsUpdated, Message, Runtime, SendAttachment, SendMessage, User,
+        VcStateChange,
         genkai_point::{
+            admin::{AuditLogEntry, Correction},
+            badge::{Records, badges_unlocked_by_last_session, unlocked_badges},
+            digest::DigestPeriod,
             formula::{
-                DynGenkaiPointFormula, GenkaiPointFormula, default_formula, v1::FormulaV1,
+                DynGenkaiPointFormula, GenkaiPointFormula,
+                custom::{self, CustomFormula},
+                default_formula,
+                v1::FormulaV1,
                 v2::FormulaV2,
+                v3::FormulaV3,
+                v4::FormulaV4,
+            },
+            model::{
+                ExcludedPeriod, ExclusionReason, Session, SessionId, SubSession, UncertainPeriod,
+                UserStat,
             },
-            model::{Session, UserStat},
         },
-        parse_command, ui,
+        pagination, parse_command, ui,
     },
     anyhow::{Context as _, Result},
-    chrono::{DateTime, Duration, Utc},
+    chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc},
+    chrono_tz::Asia::Tokyo,
     clap::ValueEnum,
     once_cell::sync::Lazy,
-    std::{cmp::Ordering, collections::HashMap, fmt::Write, future::Future},
+    std::{
+        cmp::Ordering,
+        collections::HashMap,
+        future::Future,
+        sync::{
+            Arc,
+            atomic::{self, AtomicBool},
+        },
+    },
     tokio::sync::Mutex,
 };
 
@@ -32,8 +58,9 @@ ui! {
         prefix: PREFIX,
         command: Command,
 
-        #[clap(short, long, value_enum, default_value_t=Formula::V3)]
-        formula: Formula,
+        /// v1〜v4 または custom:<名前>
+        #[clap(short, long, global = true, value_parser = parse_formula, default_value = "v3")]
+        formula: FormulaSpec,
     }
 }
 
@@ -45,11 +72,66 @@ enum Command {
         user_id: Option<u64>,
     },
 
+    /// ユーザーの獲得したバッジを表示します
+    Badges {
+        /// 表示するユーザーのID
+        user_id: Option<u64>,
+    },
+
     /// トップN人のVC時間の伸びをグラフにプロットします
     Graph {
         /// トップ何人分表示するか
         #[clap(default_value_t = 5)]
         n: u8,
+
+        /// トップN人の代わりに表示するユーザーのID
+        #[clap(long, num_args = 1..)]
+        users: Vec<u64>,
+
+        /// この日付から表示します (例: 2024-01-01)
+        #[clap(long)]
+        since: Option<NaiveDate>,
+
+        /// この日付まで表示します (例: 2024-12-31)
+        #[clap(long)]
+        until: Option<NaiveDate>,
+
+        /// 累計ではなく、指定した日数の移動平均で1日あたりのVC時間を表示します
+        #[clap(long, value_parser = clap::value_parser!(u16).range(1..))]
+        rolling: Option<u16>,
+
+        /// 出力するファイルの形式 (html はブラウザで操作できるグラフになります)
+        #[clap(long, value_enum, default_value_t)]
+        format: plot::chart::OutputFormat,
+    },
+
+    /// カスタムフォーミュラを管理します
+    Formula {
+        #[clap(subcommand)]
+        command: FormulaCommand,
+    },
+
+    /// 曜日と時間帯ごとのVC時間をヒートマップにします
+    Heatmap {
+        /// 表示するユーザーのID
+        user_id: Option<u64>,
+    },
+
+    /// 2つのフォーミュラでランキングを比較します (例: compare --formula v2 --against v3)
+    Compare {
+        /// 比べる先のフォーミュラ (v1〜v4 または custom:<名前>)
+        #[clap(long, value_parser = parse_formula)]
+        against: FormulaSpec,
+
+        /// Botを含めます
+        #[clap(long)]
+        include_bot: bool,
+    },
+
+    /// セッションを確認・修正します (かわえもんのみ)
+    Admin {
+        #[clap(subcommand)]
+        command: AdminCommand,
     },
 
     /// ランキングを出します
@@ -71,11 +153,91 @@ enum Command {
         #[clap(long, value_parser = parse_duration, default_value = "1month")]
         inactive_threshold: Duration,
 
+        /// 指定した期間内だけで集計します (例: 1week)
+        #[clap(long, value_parser = parse_duration, conflicts_with = "between")]
+        since: Option<Duration>,
+
+        /// 指定した日付の範囲内だけで集計します (例: 2024-01-01..2024-01-31)
+        #[clap(long, value_parser = parse_date_range)]
+        between: Option<(DateTime<Utc>, DateTime<Utc>)>,
+
+        /// ボットが止まっていて、VCにいたか分からない時間を除いて集計します
+        #[clap(long)]
+        exclude_uncertain: bool,
+
         #[clap(value_enum, default_value_t=RankingBy::Point)]
         by: RankingBy,
     },
 }
 
+#[derive(Debug, clap::Subcommand)]
+enum FormulaCommand {
+    /// 時刻ごとの重みの表からフォーミュラを作成します (例: set night 0:10 3:7 6:0 22:5)
+    /// 表の間は線形補間され、重みが1時間あたりのポイントになります
+    Set {
+        /// `--formula custom:<名前>` で使う名前
+        name: String,
+
+        /// `時刻:重み` の組
+        #[clap(required = true, num_args = 1..)]
+        table: Vec<String>,
+    },
+
+    /// フォーミュラの定義を表示します
+    Show { name: String },
+
+    /// フォーミュラの一覧を表示します
+    List,
+
+    /// 自分が作成したフォーミュラを削除します
+    Delete { name: String },
+}
+
+// times are in JST, like `"2024-01-01 03:00"`
+#[derive(Debug, clap::Subcommand)]
+enum AdminCommand {
+    /// ユーザーのセッションを ID 付きで表示します
+    Sessions {
+        user_id: u64,
+
+        /// 指定した日付の範囲に重なるものだけ表示します (例: 2024-01-01..2024-01-31)
+        #[clap(long, value_parser = parse_date_range)]
+        range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    },
+
+    /// セッションを指定した時刻で2つに分けます (例: split <ID> "2024-01-01 03:00")
+    Split {
+        session: String,
+        #[clap(value_parser = admin::parse_datetime)]
+        at: DateTime<Utc>,
+    },
+
+    /// 閉じられていないセッションを指定した時刻で閉じます
+    Close {
+        session: String,
+        #[clap(value_parser = admin::parse_datetime)]
+        at: DateTime<Utc>,
+    },
+
+    /// セッションを削除します
+    Delete { session: String },
+
+    /// セッションの入室・退出時刻を変更します
+    Adjust {
+        session: String,
+        #[clap(long, value_parser = admin::parse_datetime)]
+        joined_at: Option<DateTime<Utc>>,
+        #[clap(long, value_parser = admin::parse_datetime)]
+        left_at: Option<DateTime<Utc>>,
+    },
+
+    /// 修正の記録を新しい順に表示します
+    Log {
+        #[clap(default_value_t = 10)]
+        n: usize,
+    },
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
 enum RankingBy {
     Point,
@@ -88,6 +250,7 @@ enum Formula {
     V1,
     V2,
     V3,
+    V4,
 }
 impl Formula {
     fn instance(self) -> DynGenkaiPointFormula {
@@ -95,12 +258,26 @@ impl Formula {
             Formula::V1 => DynGenkaiPointFormula(Box::new(FormulaV1)),
             Formula::V2 => DynGenkaiPointFormula(Box::new(FormulaV2)),
             Formula::V3 => DynGenkaiPointFormula(Box::new(FormulaV3)),
+            Formula::V4 => DynGenkaiPointFormula(Box::new(FormulaV4)),
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum FormulaSpec {
+    Builtin(Formula),
+    /// DB に保存されたカスタムフォーミュラの名前
+    Custom(String),
+}
+fn parse_formula(s: &str) -> Result<FormulaSpec, String> {
+    match s.strip_prefix("custom:") {
+        Some(name) => Ok(FormulaSpec::Custom(name.to_owned())),
+        None => Formula::from_str(s, true).map(FormulaSpec::Builtin),
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
-enum DurationError {
+pub(crate) enum DurationError {
     #[error("パースに失敗しました")]
     Parse(humantime::DurationError),
     #[error("値が大きすぎます")]
@@ -108,7 +285,7 @@ enum DurationError {
     #[error("負の値は指定できません")]
     Negative,
 }
-fn parse_duration(s: &str) -> Result<Duration, DurationError> {
+pub(crate) fn parse_duration(s: &str) -> Result<Duration, DurationError> {
     let d = humantime::parse_duration(s).map_err(DurationError::Parse)?;
     let d = Duration::from_std(d).map_err(DurationError::OutOfRange)?;
 
@@ -119,6 +296,37 @@ fn parse_duration(s: &str) -> Result<Duration, DurationError> {
     Ok(d)
 }
 
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum DateRangeError {
+    #[error("`開始日..終了日` の形式で指定してください")]
+    Format,
+    #[error("日付のパースに失敗しました: {0}")]
+    Parse(chrono::ParseError),
+    #[error("終了日が開始日より前です")]
+    Reversed,
+}
+// `2024-01-01..2024-01-31` (both inclusive, in JST)
+pub(crate) fn parse_date_range(s: &str) -> Result<(DateTime<Utc>, DateTime<Utc>), DateRangeError> {
+    let (from, until) = s.split_once("..").ok_or(DateRangeError::Format)?;
+
+    let parse = |x: &str| NaiveDate::parse_from_str(x.trim(), "%Y-%m-%d");
+    let from = parse(from).map_err(DateRangeError::Parse)?;
+    let until = parse(until).map_err(DateRangeError::Parse)?;
+
+    if until < from {
+        return Err(DateRangeError::Reversed);
+    }
+
+    let midnight = |d: NaiveDate| {
+        Tokyo
+            .from_local_datetime(&d.and_hms_opt(0, 0, 0).unwrap())
+            .unwrap()
+            .with_timezone(&Utc)
+    };
+
+    Ok((midnight(from), midnight(until) + Duration::days(1)))
+}
+
 pub trait GenkaiPointDatabase: Send + Sync {
     /// Creates a new unclosed session if not exists.
     /// If the user's last session was closed before within 5minutes from now, clear its "left_at" field.
@@ -143,62 +351,340 @@ pub trait GenkaiPointDatabase: Send + Sync {
     ) -> impl Future<Output = Result<Vec<u64>>> + Send;
     fn get_all_sessions(&self) -> impl Future<Output = Result<Vec<Session>>> + Send;
 
+    /// Appends an excluded period to the user's unclosed session.
+    fn add_excluded_period(
+        &self,
+        user_id: u64,
+        period: ExcludedPeriod,
+    ) -> impl Future<Output = Result<()>> + Send;
+    /// Sets "until" of the still-open excluded periods in the user's unclosed session.
+    fn end_excluded_periods(
+        &self,
+        user_id: u64,
+        until: DateTime<Utc>,
+    ) -> impl Future<Output = Result<()>> + Send;
+    /// Appends an uncertain period to the user's unclosed session.
+    fn add_uncertain_period(
+        &self,
+        user_id: u64,
+        period: UncertainPeriod,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Overwrites the time when the bot was last known to be running.
+    fn record_heartbeat(&self, at: DateTime<Utc>) -> impl Future<Output = Result<()>> + Send;
+    fn get_last_heartbeat(&self) -> impl Future<Output = Result<Option<DateTime<Utc>>>> + Send;
+
+    /// Records when the user started to deafen, unless it's already recorded.
+    fn start_deafen(
+        &self,
+        user_id: u64,
+        at: DateTime<Utc>,
+    ) -> impl Future<Output = Result<()>> + Send;
+    /// Removes and returns when the user started to deafen.
+    fn take_deafened_since(
+        &self,
+        user_id: u64,
+    ) -> impl Future<Output = Result<Option<DateTime<Utc>>>> + Send;
+    /// Removes and returns all the recorded deafen starts.
+    fn take_all_deafened_since(
+        &self,
+    ) -> impl Future<Output = Result<Vec<(u64, DateTime<Utc>)>>> + Send;
+
+    /// Closes the user's unclosed sub-session if exists, then opens a new one in the channel.
+    fn start_sub_session(
+        &self,
+        user_id: u64,
+        channel_id: u64,
+        joined_at: DateTime<Utc>,
+    ) -> impl Future<Output = Result<()>> + Send;
+    /// Does nothing if the user has no unclosed sub-session.
+    fn close_sub_session(
+        &self,
+        user_id: u64,
+        left_at: DateTime<Utc>,
+    ) -> impl Future<Output = Result<()>> + Send;
+    fn get_users_all_sub_sessions(
+        &self,
+        user_id: u64,
+    ) -> impl Future<Output = Result<Vec<SubSession>>> + Send;
+
+    /// Replaces the custom formula with the same name if exists.
+    fn save_custom_formula(
+        &self,
+        formula: CustomFormula,
+    ) -> impl Future<Output = Result<()>> + Send;
+    fn get_custom_formula(
+        &self,
+        name: &str,
+    ) -> impl Future<Output = Result<Option<CustomFormula>>> + Send;
+    fn get_all_custom_formulas(&self) -> impl Future<Output = Result<Vec<CustomFormula>>> + Send;
+    fn delete_custom_formula(&self, name: &str) -> impl Future<Output = Result<IsUpdated>> + Send;
+
+    /// Same as `get_users_all_sessions`, but with the identifiers of sessions.
+    fn get_users_all_identified_sessions(
+        &self,
+        user_id: u64,
+    ) -> impl Future<Output = Result<Vec<(SessionId, Session)>>> + Send;
+    fn get_session(&self, id: &SessionId) -> impl Future<Output = Result<Option<Session>>> + Send;
+    /// Overwrites the whole session including its excluded periods.
+    fn update_session(
+        &self,
+        id: &SessionId,
+        session: Session,
+    ) -> impl Future<Output = Result<IsUpdated>> + Send;
+    /// Inserts the session as is. Unlike `create_new_session`, this never resumes the last session.
+    fn insert_session(&self, session: Session) -> impl Future<Output = Result<SessionId>> + Send;
+    fn delete_session(&self, id: &SessionId) -> impl Future<Output = Result<IsUpdated>> + Send;
+
+    fn add_audit_log(&self, entry: AuditLogEntry) -> impl Future<Output = Result<()>> + Send;
+    fn get_all_audit_logs(&self) -> impl Future<Output = Result<Vec<AuditLogEntry>>> + Send;
+
     fn get_all_users_stats(
         &self,
         formula: &impl GenkaiPointFormula,
     ) -> impl Future<Output = Result<Vec<UserStat>>> + Send {
-        async move {
-            let sessions = self.get_all_sessions().await?;
-
-            let user_sessions = {
-                let mut map = HashMap::new();
-                for session in sessions {
-                    map.entry(session.user_id)
-                        .or_insert_with(Vec::new)
-                        .push(session);
-                }
-                map
-            };
+        async move { users_stats(self.get_all_sessions().await?, formula) }
+    }
 
-            user_sessions
+    /// Same as `get_all_users_stats`, but only counts the part of sessions within `[from, until)`.
+    fn get_all_users_stats_between(
+        &self,
+        formula: &impl GenkaiPointFormula,
+        from: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> impl Future<Output = Result<Vec<UserStat>>> + Send {
+        async move {
+            let sessions = self
+                .get_all_sessions()
+                .await?
                 .into_iter()
-                .flat_map(|(_, x)| UserStat::from_sessions(&x, formula).transpose())
-                .collect::<Result<_>>()
-                .context("failed to calc userstat")
+                .filter_map(|s| s.clip(from, until))
+                .collect();
+
+            users_stats(sessions, formula)
+        }
+    }
+}
+
+fn users_stats(sessions: Vec<Session>, formula: &impl GenkaiPointFormula) -> Result<Vec<UserStat>> {
+    let user_sessions = {
+        let mut map = HashMap::new();
+        for session in sessions {
+            map.entry(session.user_id)
+                .or_insert_with(Vec::new)
+                .push(session);
         }
+        map
+    };
+
+    user_sessions
+        .into_values()
+        .flat_map(|x| UserStat::from_sessions(&x, formula).transpose())
+        .collect::<Result<_>>()
+        .context("failed to calc userstat")
+}
+
+// busy or timed out renderings are told to the user instead of being logged as failures
+async fn report_render_error(ctx: &impl Context, e: anyhow::Error) -> Result<()> {
+    match e.downcast_ref::<plot::pool::RenderError>() {
+        Some(e) => ctx
+            .send_text_message(&e.to_string())
+            .await
+            .context("failed to send message"),
+        None => Err(e),
     }
 }
 
 pub trait Plotter: Send + Sync + 'static {
-    fn plot(&self, data: Vec<(String, Vec<f64>)>) -> impl Future<Output = Result<Vec<u8>>> + Send;
+    fn plot(&self, chart: plot::chart::Chart) -> impl Future<Output = Result<Vec<u8>>> + Send;
+
+    /// この形式で出力できるか
+    fn supports(&self, format: plot::chart::OutputFormat) -> bool {
+        format == plot::chart::OutputFormat::Png
+    }
 }
 
 #[derive(Debug)]
 pub enum CreateNewSessionResult {
     NewSessionCreated,
     UnclosedSessionExists,
-    SessionResumed,
+    /// `left_at` に閉じたセッションを再開しました
+    SessionResumed {
+        left_at: DateTime<Utc>,
+    },
 }
 
 pub struct GenkaiPointBot<D, P> {
-    db: D,
+    // shared with the digest tasks
+    db: Arc<D>,
     resume_msg_timeout: Mutex<DateTime<Utc>>,
-    plotter: P,
+    // user id -> when the resumed session was closed before, so that its badges aren't announced twice
+    resumed_from: Mutex<HashMap<u64, DateTime<Utc>>>,
+    plotter: Arc<P>,
+    // チャンネルごとのサブセッションを記録するか
+    record_sub_sessions: bool,
+    exclusion_rules: ExclusionRules,
+    digests: Vec<DigestPeriod>,
+    // the digest and heartbeat tasks
+    tasks_started: AtomicBool,
+}
+
+/// v4 以降のフォーミュラでポイントの計算から除外する条件
+#[derive(Debug, Clone, Default)]
+pub struct ExclusionRules {
+    /// この VC にいる間は除外します
+    pub afk_channel_id: Option<u64>,
+    /// これより長く続いたスピーカーミュートの期間は除外します
+    pub deafen_threshold: Option<Duration>,
 }
 
 // chrono::Duration::seconds is not const fn yet.
 static RESUME_MSG_TIMEOUT: Lazy<Duration> = Lazy::new(|| Duration::seconds(10));
+static HEARTBEAT_INTERVAL: Lazy<Duration> = Lazy::new(|| Duration::minutes(1));
+
+// records that the bot is running, so that its downtime can be told after restart
+async fn heartbeat<D: GenkaiPointDatabase>(db: Arc<D>) {
+    let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL.to_std().unwrap());
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = db.record_heartbeat(Utc::now()).await {
+            tracing::error!("failed to record heartbeat: {e:?}");
+        }
+    }
+}
 
 impl<D: GenkaiPointDatabase, P: Plotter> GenkaiPointBot<D, P> {
     pub fn new(db: D, plotter: P) -> Self {
         Self {
-            db,
+            db: Arc::new(db),
             resume_msg_timeout: Mutex::new(Utc::now()),
-            plotter,
+            resumed_from: Mutex::new(HashMap::new()),
+            plotter: Arc::new(plotter),
+            record_sub_sessions: false,
+            exclusion_rules: ExclusionRules::default(),
+            digests: vec![],
+            tasks_started: AtomicBool::new(false),
+        }
+    }
+
+    /// 定期的にダイジェストをデフォルトのチャンネルに投稿するようにします
+    pub fn with_digests(self, digests: Vec<DigestPeriod>) -> Self {
+        Self { digests, ..self }
+    }
+
+    pub fn with_exclusion_rules(self, exclusion_rules: ExclusionRules) -> Self {
+        Self {
+            exclusion_rules,
+            ..self
+        }
+    }
+
+    fn is_afk_channel(&self, channel_id: u64) -> bool {
+        self.exclusion_rules.afk_channel_id == Some(channel_id)
+    }
+
+    async fn record_resumed_from(&self, user_id: u64, op: &CreateNewSessionResult) {
+        let mut resumed_from = self.resumed_from.lock().await;
+
+        match op {
+            CreateNewSessionResult::SessionResumed { left_at } => {
+                resumed_from.insert(user_id, *left_at);
+            }
+            CreateNewSessionResult::NewSessionCreated => {
+                resumed_from.remove(&user_id);
+            }
+            CreateNewSessionResult::UnclosedSessionExists => {}
+        }
+    }
+
+    async fn start_afk(&self, user_id: u64, now: DateTime<Utc>) -> Result<()> {
+        self.db
+            .add_excluded_period(
+                user_id,
+                ExcludedPeriod {
+                    reason: ExclusionReason::Afk,
+                    from: now,
+                    until: None,
+                },
+            )
+            .await
+            .context("failed to add excluded period")
+    }
+
+    async fn end_deafen(&self, user_id: u64, now: DateTime<Utc>) -> Result<()> {
+        let since = self
+            .db
+            .take_deafened_since(user_id)
+            .await
+            .context("failed to take deafened time")?;
+
+        match since {
+            Some(since) => self.exclude_deafened(user_id, since, now).await,
+            None => Ok(()),
+        }
+    }
+
+    // 閾値より長く続いていたら、スピーカーミュートしていた期間を除外する
+    async fn exclude_deafened(
+        &self,
+        user_id: u64,
+        since: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<()> {
+        let Some(threshold) = self.exclusion_rules.deafen_threshold else {
+            return Ok(());
+        };
+
+        if now - since <= threshold {
+            return Ok(());
+        }
+
+        self.db
+            .add_excluded_period(
+                user_id,
+                ExcludedPeriod {
+                    reason: ExclusionReason::Deafened,
+                    from: since,
+                    until: Some(now),
+                },
+            )
+            .await
+            .context("failed to add excluded period")
+    }
+
+    /// どのチャンネルにいたかをサブセッションとして記録するようにします
+    pub fn with_sub_sessions(self) -> Self {
+        Self {
+            record_sub_sessions: true,
+            ..self
         }
     }
 
+    // drops the time in bot's downtime before calculating
+    async fn stats_without_uncertain(
+        &self,
+        formula: &impl GenkaiPointFormula,
+        period: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    ) -> Result<Vec<UserStat>> {
+        let sessions = self
+            .db
+            .get_all_sessions()
+            .await?
+            .iter()
+            .flat_map(Session::without_uncertain)
+            .filter_map(|s| match period {
+                Some((from, until)) => s.clip(from, until),
+                None => Some(s),
+            })
+            .collect();
+
+        users_stats(sessions, formula)
+    }
+
     // TODO: refactor needed
+    #[allow(clippy::too_many_arguments)]
     async fn ranking<C>(
         &self,
         ctx: &impl Context,
@@ -207,16 +693,26 @@ impl<D: GenkaiPointDatabase, P: Plotter> GenkaiPointBot<D, P> {
         sort_comparator: C,
         include_bot: bool,
         inactive_threshold: Duration,
+        period: Option<(DateTime<Utc>, DateTime<Utc>)>,
+        exclude_uncertain: bool,
     ) -> Result<()>
     where
         C: Fn(&UserStat, &UserStat) -> Ordering,
     {
         let mut ranking = {
-            let stats = self
-                .db
-                .get_all_users_stats(formula)
-                .await
-                .context("failed to fetch ranking")?;
+            let stats = if exclude_uncertain {
+                self.stats_without_uncertain(formula, period).await
+            } else {
+                match period {
+                    Some((from, until)) => {
+                        self.db
+                            .get_all_users_stats_between(formula, from, until)
+                            .await
+                    }
+                    None => self.db.get_all_users_stats(formula).await,
+                }
+            }
+            .context("failed to fetch ranking")?;
 
             let mut res = vec![];
 
@@ -236,54 +732,65 @@ impl<D: GenkaiPointDatabase, P: Plotter> GenkaiPointBot<D, P> {
         ranking.sort_unstable_by_key(|x| x.user_id);
         ranking.sort_by(sort_comparator);
 
-        let d = drop;
-
-        let mut result = String::with_capacity(256);
-        d(writeln!(result, "```"));
-        d(writeln!(
-            result,
-            "sorted by {by}, using formula {}",
-            formula.name()
-        ));
+        let mut header = format!("```\nsorted by {by}, using formula {}", formula.name());
+        if let Some((from, until)) = period {
+            let format = |d: DateTime<Utc>| d.with_timezone(&Tokyo).format("%Y/%m/%d %H:%M");
+            header += &format!(", from {} to {}", format(from), format(until));
+        }
+        if exclude_uncertain {
+            header += ", excluding uncertain time";
+        }
+        header += "\n";
 
-        let iter = ranking.iter().rev().take(20).enumerate();
+        let mut lines = Vec::with_capacity(ranking.len());
 
-        for (index, stat) in iter {
+        for (index, stat) in ranking.iter().rev().enumerate() {
             let username = ctx
                 .get_user_name(stat.user_id)
                 .await
                 .context("failed to get username")?;
 
-            d(writeln!(
-                result,
+            lines.push(format!(
                 "#{:02} {:5}pt. {:>7.2}h {:>5.2}%限界 {}",
                 index + 1,
                 stat.genkai_point,
                 (stat.total_vc_duration.num_seconds() as f64) / 3600.,
                 stat.efficiency * 100.0,
                 username
-            ))
+            ));
         }
 
-        d(writeln!(result, "```"));
+        let pages = pagination::chunk_lines(&header, &lines, "```", pagination::MAX_MESSAGE_LENGTH);
 
-        ctx.send_text_message(&result)
+        ctx.send_pages(&pages)
             .await
             .context("failed to send message")?;
         Ok(())
     }
 
-    async fn graph(&self, ctx: &impl Context, n: u8) -> Result<()> {
-        let n = n.clamp(1, 11);
+    async fn graph(&self, ctx: &impl Context, options: &plot::GraphOptions) -> Result<()> {
+        if !self.plotter.supports(options.format) {
+            ctx.send_text_message(&format!(
+                "このBotでは {} 形式のグラフを出力できません",
+                options.format.extension()
+            ))
+            .await
+            .context("failed to send message")?;
+            return Ok(());
+        }
 
-        let image = plot::plot(&self.db, ctx, &self.plotter, n as _).await?;
+        let image = match plot::plot(&*self.db, ctx, &*self.plotter, options).await {
+            Ok(image) => image,
+            Err(e) => return report_render_error(ctx, e).await,
+        };
+        let name = format!("graph.{}", options.format.extension());
 
         match image {
             Some(image) => {
                 ctx.send_message(SendMessage {
                     content: "",
                     attachments: &[SendAttachment {
-                        name: "graph.png",
+                        name: &name,
                         data: &image,
                     }],
                 })
@@ -298,6 +805,49 @@ impl<D: GenkaiPointDatabase, P: Plotter> GenkaiPointBot<D, P> {
         .context("failed to send message")
     }
 
+    async fn heatmap(&self, ctx: &impl Context, user_id: u64) -> Result<()> {
+        let username = match ctx.get_user_name(user_id).await {
+            Ok(n) => n,
+            Err(_) => {
+                ctx.send_text_message("ユーザーが見つかりませんでした")
+                    .await
+                    .context("failed to send message")?;
+                return Ok(());
+            }
+        };
+
+        let sessions = self
+            .db
+            .get_users_all_sessions(user_id)
+            .await
+            .context("failed to get sessions")?;
+
+        let title = format!("{username} のVC時間(時)");
+        let image = match plot::heatmap(&sessions, title, &*self.plotter).await {
+            Ok(image) => image,
+            Err(e) => return report_render_error(ctx, e).await,
+        };
+
+        match image {
+            Some(image) => {
+                ctx.send_message(SendMessage {
+                    content: "",
+                    attachments: &[SendAttachment {
+                        name: "heatmap.png",
+                        data: &image,
+                    }],
+                })
+                .await
+            }
+
+            None => {
+                ctx.send_text_message(&format!("{username}さんのVCの記録は見つかりませんでした"))
+                    .await
+            }
+        }
+        .context("failed to send message")
+    }
+
     async fn show(
         &self,
         ctx: &impl Context,
@@ -324,14 +874,33 @@ impl<D: GenkaiPointDatabase, P: Plotter> GenkaiPointBot<D, P> {
 
         let msg = match stat {
             Some(stat) => {
-                format!(
-                    "```\n{name} (using formula {formula_version})\n  - 限界ポイント: {points}pt.\n  - 合計VC時間: {vc_hour:.2}h\n  - 限界効率: {efficiency:.2}%\n```",
+                let mut msg = format!(
+                    "```\n{name} (using formula {formula_version})\n  - 限界ポイント: {points}pt.\n  - 合計VC時間: {vc_hour:.2}h\n  - 限界効率: {efficiency:.2}%\n",
                     name = username,
                     formula_version = formula.name(),
                     points = stat.genkai_point,
                     vc_hour = stat.total_vc_duration.num_minutes() as f64 / 60.0,
                     efficiency = stat.efficiency * 100.0,
-                )
+                );
+
+                let uncertain = sessions
+                    .iter()
+                    .fold(Duration::zero(), |acc, s| acc + s.uncertain_duration());
+                let uncertain_ends = sessions.iter().filter(|s| s.left_at_is_uncertain()).count();
+
+                if uncertain > Duration::zero() {
+                    msg += &format!(
+                        "  - うちボット停止中: {:.2}h (その間もVCにいたとみなしています)\n",
+                        uncertain.num_minutes() as f64 / 60.0
+                    );
+                }
+                if uncertain_ends > 0 {
+                    msg += &format!(
+                        "  - 退出時刻が不確かなセッション: {uncertain_ends}件 (ボットが最後に動いていた時刻で閉じています)\n"
+                    );
+                }
+
+                msg + "```"
             }
 
             None => format!("{username}さんの限界ポイントに関する情報は見つかりませんでした",),
@@ -343,23 +912,512 @@ impl<D: GenkaiPointDatabase, P: Plotter> GenkaiPointBot<D, P> {
 
         Ok(())
     }
-}
 
-#[allow(clippy::type_complexity)]
-fn comparator<'a, C>(
-    mapper: impl 'a + Send + Fn(&UserStat) -> C,
-    invert: bool,
-) -> Box<dyn 'a + Send + Fn(&UserStat, &UserStat) -> Ordering>
-where
-    C: std::cmp::PartialOrd,
-{
-    Box::new(move |a, b| {
-        let res = mapper(a).partial_cmp(&mapper(b)).unwrap();
-        if invert { res.reverse() } else { res }
-    })
-}
+    // tells the user and returns None if the custom formula doesn't exist
+    async fn resolve_formula(
+        &self,
+        ctx: &impl Context,
+        spec: FormulaSpec,
+    ) -> Result<Option<DynGenkaiPointFormula>> {
+        let name = match spec {
+            FormulaSpec::Builtin(formula) => return Ok(Some(formula.instance())),
+            FormulaSpec::Custom(name) => name,
+        };
+
+        let formula = self
+            .db
+            .get_custom_formula(&name)
+            .await
+            .context("failed to get custom formula")?;
+
+        if formula.is_none() {
+            ctx.send_text_message(&format!(
+                "フォーミュラ「custom:{name}」は見つかりませんでした"
+            ))
+            .await
+            .context("failed to send message")?;
+        }
+
+        Ok(formula.map(|x| DynGenkaiPointFormula(Box::new(x))))
+    }
+
+    async fn compare(
+        &self,
+        ctx: &impl Context,
+        before: &DynGenkaiPointFormula,
+        after: &DynGenkaiPointFormula,
+        include_bot: bool,
+    ) -> Result<()> {
+        let mut stats = vec![];
+
+        for formula in [before, after] {
+            let mut res = vec![];
+
+            for stat in self
+                .db
+                .get_all_users_stats(formula)
+                .await
+                .context("failed to fetch stats")?
+            {
+                if !include_bot && ctx.is_bot(stat.user_id).await? {
+                    continue;
+                }
+                res.push(stat);
+            }
+
+            stats.push(res);
+        }
+
+        let comparisons = compare::compare(&stats[0], &stats[1]);
+
+        let header = format!(
+            "```\nformula {} → {} (順位 / ポイント / 限界効率)\n",
+            before.name(),
+            after.name()
+        );
+
+        let mut lines = Vec::with_capacity(comparisons.len());
+
+        for c in &comparisons {
+            let username = ctx
+                .get_user_name(c.user_id)
+                .await
+                .context("failed to get username")?;
+
+            lines.push(format!(
+                "#{:02} ({:+3}) {:5}pt. → {:5}pt. ({:+5}) {:>6.2}% → {:>6.2}% ({:+6.2}) {}",
+                c.rank_after,
+                c.rank_delta(),
+                c.point_before,
+                c.point_after,
+                c.point_delta(),
+                c.efficiency_before * 100.0,
+                c.efficiency_after * 100.0,
+                c.efficiency_delta() * 100.0,
+                username
+            ));
+        }
+
+        let pages = pagination::chunk_lines(&header, &lines, "```", pagination::MAX_MESSAGE_LENGTH);
+
+        ctx.send_pages(&pages)
+            .await
+            .context("failed to send message")?;
+
+        let image = match plot::weight_curves(&[before, after], &*self.plotter).await {
+            Ok(image) => image,
+            Err(e) => return report_render_error(ctx, e).await,
+        };
+
+        ctx.send_message(SendMessage {
+            content: "",
+            attachments: &[SendAttachment {
+                name: "compare.png",
+                data: &image,
+            }],
+        })
+        .await
+        .context("failed to send message")
+    }
+
+    async fn formula(
+        &self,
+        ctx: &impl Context,
+        author_id: u64,
+        command: FormulaCommand,
+    ) -> Result<()> {
+        let message = match command {
+            FormulaCommand::Set { name, table } => {
+                let points = match custom::validate_name(&name)
+                    .and_then(|_| custom::parse_table(&table.join(" ")))
+                {
+                    Ok(points) => points,
+                    Err(e) => {
+                        ctx.send_text_message(&e.to_string())
+                            .await
+                            .context("failed to send message")?;
+                        return Ok(());
+                    }
+                };
+
+                let existing = self
+                    .db
+                    .get_custom_formula(&name)
+                    .await
+                    .context("failed to get custom formula")?;
+
+                if existing.is_some_and(|x| x.created_by != author_id) {
+                    format!("フォーミュラ「{name}」は他の人が作成したものなので上書きできません")
+                } else {
+                    self.db
+                        .save_custom_formula(CustomFormula {
+                            name: name.clone(),
+                            created_by: author_id,
+                            points,
+                        })
+                        .await
+                        .context("failed to save custom formula")?;
+
+                    format!(
+                        "フォーミュラ「{name}」を保存しました。`{PREFIX} --formula custom:{name} ranking` のように使えます"
+                    )
+                }
+            }
+
+            FormulaCommand::Show { name } => {
+                match self
+                    .db
+                    .get_custom_formula(&name)
+                    .await
+                    .context("failed to get custom formula")?
+                {
+                    Some(formula) => {
+                        let author = ctx
+                            .get_user_name(formula.created_by)
+                            .await
+                            .unwrap_or_else(|_| formula.created_by.to_string());
+
+                        let weights = (0..24)
+                            .map(|h| format!("{h:>2}時 {:>5.1}", formula.weight_at(h as f64)))
+                            .collect::<Vec<_>>()
+                            .chunks(6)
+                            .map(|x| format!("  {}", x.join(" / ")))
+                            .collect::<Vec<_>>()
+                            .join("\n");
+
+                        format!(
+                            "```\ncustom:{name} (作成者: {author})\n  表: {}\n1時間あたりのポイント:\n{weights}\n```",
+                            custom::format_table(&formula.points)
+                        )
+                    }
+                    None => format!("フォーミュラ「{name}」は見つかりませんでした"),
+                }
+            }
+
+            FormulaCommand::List => {
+                let mut formulas = self
+                    .db
+                    .get_all_custom_formulas()
+                    .await
+                    .context("failed to get custom formulas")?;
+                formulas.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+                if formulas.is_empty() {
+                    "カスタムフォーミュラはまだありません".to_owned()
+                } else {
+                    let lines = formulas
+                        .iter()
+                        .map(|f| format!("custom:{}: {}", f.name, custom::format_table(&f.points)))
+                        .collect::<Vec<_>>();
+
+                    format!("```\n{}\n```", lines.join("\n"))
+                }
+            }
+
+            FormulaCommand::Delete { name } => {
+                match self
+                    .db
+                    .get_custom_formula(&name)
+                    .await
+                    .context("failed to get custom formula")?
+                {
+                    Some(formula) if formula.created_by != author_id => {
+                        format!("フォーミュラ「{name}」は他の人が作成したものなので削除できません")
+                    }
+                    Some(_) => {
+                        self.db
+                            .delete_custom_formula(&name)
+                            .await
+                            .context("failed to delete custom formula")?;
+
+                        format!("フォーミュラ「{name}」を削除しました")
+                    }
+                    None => format!("フォーミュラ「{name}」は見つかりませんでした"),
+                }
+            }
+        };
+
+        ctx.send_text_message(&message)
+            .await
+            .context("failed to send message")
+    }
+
+    async fn admin(
+        &self,
+        ctx: &impl Context,
+        operator_id: u64,
+        command: AdminCommand,
+    ) -> Result<()> {
+        if operator_id != KAWAEMON_DISCORD_USER_ID {
+            ctx.send_text_message("セッションの修正はかわえもんにしか出来ません")
+                .await
+                .context("failed to send message")?;
+            return Ok(());
+        }
+
+        let (id, correction) = match command {
+            AdminCommand::Sessions { user_id, range } => {
+                return self.admin_sessions(ctx, user_id, range).await;
+            }
+            AdminCommand::Log { n } => return self.audit_logs(ctx, n).await,
+
+            AdminCommand::Split { session, at } => (session, Correction::Split { at }),
+            AdminCommand::Close { session, at } => (session, Correction::Close { at }),
+            AdminCommand::Delete { session } => (session, Correction::Delete),
+            AdminCommand::Adjust {
+                session,
+                joined_at,
+                left_at,
+            } => (session, Correction::Adjust { joined_at, left_at }),
+        };
+
+        let id = SessionId(id);
+        let message = self.correct_session(operator_id, &id, correction).await?;
+
+        ctx.send_text_message(&message)
+            .await
+            .context("failed to send message")
+    }
+
+    // returns the message to tell the result
+    async fn correct_session(
+        &self,
+        operator_id: u64,
+        id: &SessionId,
+        correction: Correction,
+    ) -> Result<String> {
+        let Some(before) = self
+            .db
+            .get_session(id)
+            .await
+            .context("failed to get session")?
+        else {
+            return Ok(format!("セッション {id} は見つかりませんでした"));
+        };
+
+        let after = match correction.apply(&before) {
+            Ok(after) => after,
+            Err(e) => return Ok(e.to_string()),
+        };
+
+        let sessions = self
+            .db
+            .get_users_all_identified_sessions(before.user_id)
+            .await
+            .context("failed to get sessions")?;
+
+        if let Some(other) = admin::find_overlap(&sessions, id, &after) {
+            return Ok(format!(
+                "修正後のセッションがセッション {other} と重なります"
+            ));
+        }
+
+        let mut ids = vec![];
+
+        match after.split_first() {
+            None => {
+                self.db
+                    .delete_session(id)
+                    .await
+                    .context("failed to delete session")?;
+            }
+
+            Some((first, rest)) => {
+                self.db
+                    .update_session(id, first.clone())
+                    .await
+                    .context("failed to update session")?;
+                ids.push(id.clone());
+
+                for session in rest {
+                    let id = self
+                        .db
+                        .insert_session(session.clone())
+                        .await
+                        .context("failed to insert session")?;
+                    ids.push(id);
+                }
+            }
+        }
 
-impl<R: Runtime, D: GenkaiPointDatabase, P: Plotter> BotService<R> for GenkaiPointBot<D, P> {
+        self.db
+            .add_audit_log(AuditLogEntry {
+                operator_id,
+                at: Utc::now(),
+                session_id: id.clone(),
+                correction,
+                before,
+                after: after.clone(),
+            })
+            .await
+            .context("failed to add audit log")?;
+
+        let lines = ids
+            .iter()
+            .zip(&after)
+            .map(|(id, session)| admin::format_session(id, session))
+            .collect::<Vec<_>>();
+
+        Ok(if lines.is_empty() {
+            format!("セッション {id} を削除しました")
+        } else {
+            format!(
+                "セッション {id} を修正しました\n```\n{}\n```",
+                lines.join("\n")
+            )
+        })
+    }
+
+    async fn admin_sessions(
+        &self,
+        ctx: &impl Context,
+        user_id: u64,
+        range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    ) -> Result<()> {
+        let mut sessions = self
+            .db
+            .get_users_all_identified_sessions(user_id)
+            .await
+            .context("failed to get sessions")?;
+
+        if let Some((from, until)) = range {
+            sessions.retain(|(_, s)| s.clip(from, until).is_some());
+        }
+
+        if sessions.is_empty() {
+            ctx.send_text_message("セッションは見つかりませんでした")
+                .await
+                .context("failed to send message")?;
+            return Ok(());
+        }
+
+        sessions.sort_unstable_by_key(|(_, s)| s.joined_at);
+
+        let username = ctx
+            .get_user_name(user_id)
+            .await
+            .unwrap_or_else(|_| user_id.to_string());
+
+        let header = format!("```\n{username} のセッション ({}件)\n", sessions.len());
+        let lines = sessions
+            .iter()
+            .map(|(id, s)| admin::format_session(id, s))
+            .collect::<Vec<_>>();
+
+        let pages = pagination::chunk_lines(&header, &lines, "```", pagination::MAX_MESSAGE_LENGTH);
+
+        ctx.send_pages(&pages)
+            .await
+            .context("failed to send message")
+    }
+
+    async fn audit_logs(&self, ctx: &impl Context, n: usize) -> Result<()> {
+        let mut logs = self
+            .db
+            .get_all_audit_logs()
+            .await
+            .context("failed to get audit logs")?;
+
+        if logs.is_empty() {
+            ctx.send_text_message("修正の記録はまだありません")
+                .await
+                .context("failed to send message")?;
+            return Ok(());
+        }
+
+        logs.sort_unstable_by_key(|x| std::cmp::Reverse(x.at));
+        logs.truncate(n);
+
+        let mut lines = vec![];
+
+        for log in &logs {
+            let operator = ctx
+                .get_user_name(log.operator_id)
+                .await
+                .unwrap_or_else(|_| log.operator_id.to_string());
+            let username = ctx
+                .get_user_name(log.before.user_id)
+                .await
+                .unwrap_or_else(|_| log.before.user_id.to_string());
+
+            lines.push(format!(
+                "{} {operator}: {username} のセッション {} を {}",
+                admin::format_time(log.at),
+                log.session_id,
+                log.correction
+            ));
+        }
+
+        let pages = pagination::chunk_lines("```\n", &lines, "```", pagination::MAX_MESSAGE_LENGTH);
+
+        ctx.send_pages(&pages)
+            .await
+            .context("failed to send message")
+    }
+
+    async fn badges(&self, ctx: &impl Context, user_id: u64) -> Result<()> {
+        let username = match ctx.get_user_name(user_id).await {
+            Ok(n) => n,
+            Err(_) => {
+                ctx.send_text_message("ユーザーが見つかりませんでした")
+                    .await
+                    .context("failed to send message")?;
+                return Ok(());
+            }
+        };
+
+        let sessions = self
+            .db
+            .get_users_all_sessions(user_id)
+            .await
+            .context("failed to get sessions")?;
+
+        let badges = unlocked_badges(&sessions);
+        let records = Records::from_sessions(&sessions);
+
+        let mut lines = vec![format!(
+            "{username} のバッジ ({}/{})",
+            badges.len(),
+            badge::BADGES.len()
+        )];
+
+        for badge in badges {
+            lines.push(format!("  - {}: {}", badge.name, badge.description));
+        }
+
+        lines.push(format!(
+            "最長連続: {}夜 / 最長セッション: {:.2}h",
+            records.longest_night_streak,
+            records.longest_session.num_minutes() as f64 / 60.0,
+        ));
+
+        ctx.send_text_message(&format!("```\n{}\n```", lines.join("\n")))
+            .await
+            .context("failed to send message")?;
+
+        Ok(())
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn comparator<'a, C>(
+    mapper: impl 'a + Send + Fn(&UserStat) -> C,
+    invert: bool,
+) -> Box<dyn 'a + Send + Fn(&UserStat, &UserStat) -> Ordering>
+where
+    C: std::cmp::PartialOrd,
+{
+    Box::new(move |a, b| {
+        let res = mapper(a).partial_cmp(&mapper(b)).unwrap();
+        if invert { res.reverse() } else { res }
+    })
+}
+
+impl<R: Runtime, D: GenkaiPointDatabase + 'static, P: Plotter> BotService<R>
+    for GenkaiPointBot<D, P>
+where
+    R::Context: Clone + 'static,
+{
     fn name(&self) -> &'static str {
         NAME
     }
@@ -373,15 +1431,62 @@ impl<R: Runtime, D: GenkaiPointDatabase, P: Plotter> BotService<R> for GenkaiPoi
             return Ok(());
         };
 
-        let formula = parsed.formula.instance();
+        let Some(formula) = self.resolve_formula(ctx, parsed.formula).await? else {
+            return Ok(());
+        };
         match parsed.command {
             Command::Show { user_id } => {
                 self.show(ctx, &formula, user_id.unwrap_or_else(|| msg.author().id()))
                     .await?;
             }
 
-            Command::Graph { n } => {
-                self.graph(ctx, n).await?;
+            Command::Badges { user_id } => {
+                self.badges(ctx, user_id.unwrap_or_else(|| msg.author().id()))
+                    .await?;
+            }
+
+            Command::Graph {
+                n,
+                users,
+                since,
+                until,
+                rolling,
+                format,
+            } => {
+                let options = plot::GraphOptions {
+                    users,
+                    top: n.clamp(1, 11) as _,
+                    since,
+                    until,
+                    rolling: rolling.map(|x| x as _),
+                    format,
+                };
+
+                self.graph(ctx, &options).await?;
+            }
+
+            Command::Compare {
+                against,
+                include_bot,
+            } => {
+                let Some(against) = self.resolve_formula(ctx, against).await? else {
+                    return Ok(());
+                };
+
+                self.compare(ctx, &formula, &against, include_bot).await?;
+            }
+
+            Command::Formula { command } => {
+                self.formula(ctx, msg.author().id(), command).await?;
+            }
+
+            Command::Admin { command } => {
+                self.admin(ctx, msg.author().id(), command).await?;
+            }
+
+            Command::Heatmap { user_id } => {
+                self.heatmap(ctx, user_id.unwrap_or_else(|| msg.author().id()))
+                    .await?;
             }
 
             Command::Ranking {
@@ -390,6 +1495,9 @@ impl<R: Runtime, D: GenkaiPointDatabase, P: Plotter> BotService<R> for GenkaiPoi
                 include_bot,
                 include_inactive,
                 inactive_threshold,
+                since,
+                between,
+                exclude_uncertain,
             } => {
                 let (by, comparator) = match by {
                     RankingBy::Point => ("genkai point", comparator(|x| x.genkai_point, invert)),
@@ -402,7 +1510,10 @@ impl<R: Runtime, D: GenkaiPointDatabase, P: Plotter> BotService<R> for GenkaiPoi
                     }
                 };
 
-                let inactive_threshold = if include_inactive {
+                let period = between.or_else(|| since.map(|d| (Utc::now() - d, Utc::now())));
+
+                // everyone in a past period would be inactive, so the filter makes no sense
+                let inactive_threshold = if include_inactive || period.is_some() {
                     Duration::MAX
                 } else {
                     inactive_threshold
@@ -415,6 +1526,8 @@ impl<R: Runtime, D: GenkaiPointDatabase, P: Plotter> BotService<R> for GenkaiPoi
                     comparator,
                     include_bot,
                     inactive_threshold,
+                    period,
+                    exclude_uncertain,
                 )
                 .await?
             }
@@ -423,18 +1536,33 @@ impl<R: Runtime, D: GenkaiPointDatabase, P: Plotter> BotService<R> for GenkaiPoi
         Ok(())
     }
 
-    async fn on_vc_join(&self, ctx: &R::Context, user_id: u64) -> Result<()> {
+    async fn on_vc_join(&self, ctx: &R::Context, user_id: u64, channel_id: u64) -> Result<()> {
+        let now = Utc::now();
+
         let op = self
             .db
-            .create_new_session(user_id, Utc::now())
+            .create_new_session(user_id, now)
             .await
             .context("failed to create new session")?;
 
+        self.record_resumed_from(user_id, &op).await;
+
+        if self.record_sub_sessions {
+            self.db
+                .start_sub_session(user_id, channel_id, now)
+                .await
+                .context("failed to start sub-session")?;
+        }
+
+        if self.is_afk_channel(channel_id) {
+            self.start_afk(user_id, now).await?;
+        }
+
         if ctx.is_bot(user_id).await? {
             return Ok(());
         }
 
-        if let CreateNewSessionResult::SessionResumed = op {
+        if let CreateNewSessionResult::SessionResumed { .. } = op {
             let mut timeout = self.resume_msg_timeout.lock().await;
             let now = Utc::now();
 
@@ -452,12 +1580,29 @@ impl<R: Runtime, D: GenkaiPointDatabase, P: Plotter> BotService<R> for GenkaiPoi
         Ok(())
     }
 
-    async fn on_vc_leave(&self, ctx: &R::Context, user_id: u64) -> Result<()> {
+    async fn on_vc_leave(&self, ctx: &R::Context, user_id: u64, _channel_id: u64) -> Result<()> {
+        let now = Utc::now();
+
+        self.end_deafen(user_id, now).await?;
         self.db
-            .close_session(user_id, Utc::now())
+            .end_excluded_periods(user_id, now)
+            .await
+            .context("failed to end excluded periods")?;
+
+        self.db
+            .close_session(user_id, now)
             .await
             .context("failed to close session")?;
 
+        if self.record_sub_sessions {
+            self.db
+                .close_sub_session(user_id, now)
+                .await
+                .context("failed to close sub-session")?;
+        }
+
+        let resumed_from = self.resumed_from.lock().await.remove(&user_id);
+
         if ctx.is_bot(user_id).await? {
             return Ok(());
         }
@@ -475,6 +1620,8 @@ impl<R: Runtime, D: GenkaiPointDatabase, P: Plotter> BotService<R> for GenkaiPoi
             .calc(core::slice::from_ref(last_session))
             .point;
 
+        let mut lines = vec![];
+
         if this_time_point > 0 {
             let stat = UserStat::from_sessions(&sessions, &default_formula())
                 .expect("sessions contains multiple user's session")
@@ -482,16 +1629,25 @@ impl<R: Runtime, D: GenkaiPointDatabase, P: Plotter> BotService<R> for GenkaiPoi
 
             let to_hours = |d: Duration| d.num_minutes() as f64 / 60.0;
 
-            let msg = format!(
-                "<@!{uid}>\n限界ポイント: {pt}pt (+{pt_delta}pt)\n総VC時間: {vc_hour:.2}h (+{vc_hour_delta:.2}h)",
-                uid = user_id,
+            lines.push(format!(
+                "限界ポイント: {pt}pt (+{pt_delta}pt)\n総VC時間: {vc_hour:.2}h (+{vc_hour_delta:.2}h)",
                 pt = stat.genkai_point,
                 pt_delta = this_time_point,
                 vc_hour = to_hours(stat.total_vc_duration),
                 vc_hour_delta = to_hours(last_session.duration()),
-            );
+            ));
+        }
 
-            ctx.send_text_message(&msg)
+        let new_badges = badges_unlocked_by_last_session(&sessions, resumed_from);
+        for badge in new_badges {
+            lines.push(format!(
+                "バッジ「{}」を獲得しました! ({})",
+                badge.name, badge.description
+            ));
+        }
+
+        if !lines.is_empty() {
+            ctx.send_text_message(&format!("<@!{user_id}>\n{}", lines.join("\n")))
                 .await
                 .context("failed to send message")?;
         }
@@ -499,18 +1655,156 @@ impl<R: Runtime, D: GenkaiPointDatabase, P: Plotter> BotService<R> for GenkaiPoi
         Ok(())
     }
 
-    async fn on_vc_data_available(&self, _ctx: &R::Context, joined_user_ids: &[u64]) -> Result<()> {
-        for uid in joined_user_ids {
+    async fn on_vc_move(&self, _ctx: &R::Context, user_id: u64, from: u64, to: u64) -> Result<()> {
+        let now = Utc::now();
+
+        if self.record_sub_sessions {
+            self.db
+                .start_sub_session(user_id, to, now)
+                .await
+                .context("failed to start sub-session")?;
+        }
+
+        match (self.is_afk_channel(from), self.is_afk_channel(to)) {
+            (false, true) => self.start_afk(user_id, now).await?,
+            (true, false) => self
+                .db
+                .end_excluded_periods(user_id, now)
+                .await
+                .context("failed to end excluded periods")?,
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    async fn on_vc_state_change(
+        &self,
+        _ctx: &R::Context,
+        user_id: u64,
+        _channel_id: u64,
+        change: VcStateChange,
+        _initial: bool,
+    ) -> Result<()> {
+        if self.exclusion_rules.deafen_threshold.is_none() {
+            return Ok(());
+        }
+
+        match change {
+            VcStateChange::Deafen(true) => {
+                self.db
+                    .start_deafen(user_id, Utc::now())
+                    .await
+                    .context("failed to start deafen")?;
+            }
+
+            VcStateChange::Deafen(false) => self.end_deafen(user_id, Utc::now()).await?,
+
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    async fn on_vc_data_available(
+        &self,
+        ctx: &R::Context,
+        joined_users: &[(u64, u64)],
+    ) -> Result<()> {
+        let now = Utc::now();
+
+        // this must be read before the heartbeat task overwrites it
+        let last_heartbeat = self
+            .db
+            .get_last_heartbeat()
+            .await
+            .context("failed to get last heartbeat")?;
+
+        // nobody was watching VC in this period, so events in it are lost
+        let downtime = last_heartbeat
+            .filter(|&x| now - x > *HEARTBEAT_INTERVAL * 2)
+            .map(|from| UncertainPeriod { from, until: now });
+
+        if let Some(downtime) = downtime {
+            tracing::warn!(
+                "bot seems to have been down from {} to {}",
+                downtime.from,
+                downtime.until
+            );
+        }
+
+        // this can be called again on reconnection
+        if !self.tasks_started.swap(true, atomic::Ordering::SeqCst) {
+            for &period in &self.digests {
+                tokio::spawn(digest::run(
+                    Arc::clone(&self.db),
+                    Arc::clone(&self.plotter),
+                    ctx.clone(),
+                    period,
+                ));
+            }
+
+            tokio::spawn(heartbeat(Arc::clone(&self.db)));
+        }
+
+        // we can't know whether they were deafened all the time, so close them here.
+        // those who still are will be notified again after this.
+        let deafened = self
+            .db
+            .take_all_deafened_since()
+            .await
+            .context("failed to take deafened times")?;
+
+        for (uid, since) in deafened {
+            let until = downtime.map_or(now, |x| x.from).max(since);
+
+            if let Err(e) = self.exclude_deafened(uid, since, until).await {
+                tracing::warn!("failed to close deafen of user({uid}) on startup: {e:?}");
+            }
+        }
+
+        for &(uid, channel_id) in joined_users {
             let op = self
                 .db
-                .create_new_session(*uid, Utc::now())
+                .create_new_session(uid, Utc::now())
                 .await
                 .context("failed to create new session")?;
 
+            self.record_resumed_from(uid, &op).await;
+
+            // we can't know where the user was in downtime, so split it here
+            if self.record_sub_sessions {
+                self.db
+                    .start_sub_session(uid, channel_id, Utc::now())
+                    .await
+                    .context("failed to start sub-session")?;
+            }
+
+            // same for AFK
+            self.db
+                .end_excluded_periods(uid, Utc::now())
+                .await
+                .context("failed to end excluded periods")?;
+
+            if self.is_afk_channel(channel_id) {
+                self.start_afk(uid, Utc::now()).await?;
+            }
+
             use CreateNewSessionResult::*;
 
+            // the user may have left and come back in downtime.
+            // a new session starts now, so it doesn't cover the downtime
+            if let Some(downtime) = downtime
+                && matches!(op, UnclosedSessionExists | SessionResumed { .. })
+            {
+                self.db
+                    .add_uncertain_period(uid, downtime)
+                    .await
+                    .context("failed to add uncertain period")?;
+            }
+
             match op {
-                NewSessionCreated | SessionResumed => {
+                NewSessionCreated | SessionResumed { .. } => {
                     tracing::info!("User({}) has joined to vc in bot downtime", uid);
                 }
 
@@ -527,15 +1821,51 @@ impl<R: Runtime, D: GenkaiPointDatabase, P: Plotter> BotService<R> for GenkaiPoi
             .context("failed to get users who has unclosed session")?;
 
         for uid in db_state {
-            if joined_user_ids.contains(&uid) {
+            if joined_users.iter().any(|&(x, _)| x == uid) {
                 continue;
             }
 
+            // the user has left at some time in downtime, so don't count it
+            let left_at = match downtime {
+                Some(downtime) => {
+                    let joined_at = self
+                        .db
+                        .get_users_all_sessions(uid)
+                        .await
+                        .context("failed to get sessions")?
+                        .iter()
+                        .filter(|x| x.left_at.is_none())
+                        .map(|x| x.joined_at)
+                        .max()
+                        .unwrap_or(downtime.from);
+
+                    self.db
+                        .add_uncertain_period(uid, downtime)
+                        .await
+                        .context("failed to add uncertain period")?;
+
+                    downtime.from.max(joined_at)
+                }
+                None => Utc::now(),
+            };
+
+            self.db
+                .end_excluded_periods(uid, left_at)
+                .await
+                .context("failed to end excluded periods")?;
+
             self.db
-                .close_session(uid, Utc::now())
+                .close_session(uid, left_at)
                 .await
                 .context("failed to close session")?;
 
+            if self.record_sub_sessions {
+                self.db
+                    .close_sub_session(uid, left_at)
+                    .await
+                    .context("failed to close sub-session")?;
+            }
+
             tracing::info!("User({}) has left from vc in bot downtime", uid);
         }
 
@@ -554,5 +1884,3 @@ macro_rules! datetime {
 
 #[cfg(test)]
 use datetime;
-
-use self::formula::v3::FormulaV3;
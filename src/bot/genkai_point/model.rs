@@ -49,6 +49,21 @@ impl UserStat {
             efficiency: NotNan::new(efficiency).unwrap(),
         }))
     }
+
+    /// `[from, until)` に収まるようにセッションを切り取ってから計算します
+    pub fn from_sessions_between(
+        sessions: &[Session],
+        formula: &impl GenkaiPointFormula,
+        from: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Result<Option<UserStat>> {
+        let clipped = sessions
+            .iter()
+            .filter_map(|s| s.clip(from, until))
+            .collect::<Vec<_>>();
+
+        Self::from_sessions(&clipped, formula)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -56,6 +71,36 @@ pub struct Session {
     pub user_id: u64,
     pub joined_at: DateTime<Utc>,
     pub left_at: Option<DateTime<Utc>>,
+    /// v4 以降のフォーミュラで計算から除外する期間
+    #[serde(default)]
+    pub excluded: Vec<ExcludedPeriod>,
+    /// ボットが止まっていて、このユーザーが VC にいたか分からない期間。
+    /// 退出を見逃したまま閉じたセッションでは、退出時刻より後の期間になります
+    #[serde(default)]
+    pub uncertain: Vec<UncertainPeriod>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExcludedPeriod {
+    pub reason: ExclusionReason,
+    pub from: DateTime<Utc>,
+    /// None ならまだ続いている
+    pub until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UncertainPeriod {
+    pub from: DateTime<Utc>,
+    pub until: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExclusionReason {
+    /// AFK チャンネルにいた
+    Afk,
+    /// 長時間スピーカーミュートしていた
+    Deafened,
 }
 
 impl Session {
@@ -66,6 +111,174 @@ impl Session {
     pub fn left_at(&self) -> DateTime<Utc> {
         self.left_at.unwrap_or_else(Utc::now)
     }
+
+    /// `[from, until)` に含まれる部分を切り出します。重ならなければ None を返します
+    pub fn clip(&self, from: DateTime<Utc>, until: DateTime<Utc>) -> Option<Session> {
+        let joined_at = self.joined_at.max(from);
+        let left_at = self.left_at().min(until);
+
+        if joined_at >= left_at {
+            return None;
+        }
+
+        Some(Session {
+            user_id: self.user_id,
+            joined_at,
+            // keep it unclosed if it's still ongoing before `until`
+            left_at: match self.left_at {
+                None if left_at < until => None,
+                _ => Some(left_at),
+            },
+            excluded: self.excluded.clone(),
+            uncertain: self.uncertain.clone(),
+        })
+    }
+
+    /// 除外期間を取り除いた、アクティブだった期間を古い順に返します
+    pub fn active_periods(&self) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+        let left_at = self.left_at();
+
+        let mut excluded = self
+            .excluded
+            .iter()
+            .map(|x| {
+                (
+                    x.from.max(self.joined_at),
+                    x.until.unwrap_or(left_at).min(left_at),
+                )
+            })
+            .filter(|(from, until)| from < until)
+            .collect::<Vec<_>>();
+
+        excluded.sort_unstable();
+
+        let mut periods = vec![];
+        let mut cursor = self.joined_at;
+
+        for (from, until) in excluded {
+            if cursor < from {
+                periods.push((cursor, from));
+            }
+            cursor = cursor.max(until);
+        }
+
+        if cursor < left_at {
+            periods.push((cursor, left_at));
+        }
+
+        periods
+    }
+
+    /// 入室・退出時刻を変えたセッションを返します。除外期間はその中に収まるように切り詰めます
+    pub fn resize(&self, joined_at: DateTime<Utc>, left_at: Option<DateTime<Utc>>) -> Session {
+        let excluded = self
+            .excluded
+            .iter()
+            .filter(|x| left_at.is_none_or(|l| x.from < l) && x.until.is_none_or(|u| joined_at < u))
+            .map(|x| ExcludedPeriod {
+                reason: x.reason,
+                from: x.from.max(joined_at),
+                until: match (x.until, left_at) {
+                    (Some(u), Some(l)) => Some(u.min(l)),
+                    (until, None) => until,
+                    (None, left_at) => left_at,
+                },
+            })
+            .collect();
+
+        Session {
+            user_id: self.user_id,
+            joined_at,
+            left_at,
+            excluded,
+            uncertain: self.uncertain.clone(),
+        }
+    }
+
+    /// セッションのうち、ボットが止まっていた間の時間
+    pub fn uncertain_duration(&self) -> Duration {
+        let left_at = self.left_at();
+
+        let mut periods = self
+            .uncertain
+            .iter()
+            .map(|x| (x.from.max(self.joined_at), x.until.min(left_at)))
+            .filter(|(from, until)| from < until)
+            .collect::<Vec<_>>();
+
+        periods.sort_unstable();
+
+        let mut total = Duration::zero();
+        let mut cursor = self.joined_at;
+
+        for (from, until) in periods {
+            let from = from.max(cursor);
+            if from < until {
+                total += until - from;
+            }
+            cursor = cursor.max(until);
+        }
+
+        total
+    }
+
+    /// ボットが止まっている間に退出したため、退出時刻が分からないか
+    pub fn left_at_is_uncertain(&self) -> bool {
+        self.left_at
+            .is_some_and(|l| self.uncertain.iter().any(|x| x.from <= l && l < x.until))
+    }
+
+    /// ボットが止まっていた間を取り除き、残りの部分を古い順に返します
+    pub fn without_uncertain(&self) -> Vec<Session> {
+        let mut uncertain = self.uncertain.clone();
+        uncertain.sort_unstable_by_key(|x| x.from);
+
+        let left_at = self.left_at();
+        let mut sessions = vec![];
+        let mut cursor = self.joined_at;
+
+        for period in uncertain {
+            if left_at <= period.from {
+                break;
+            }
+            if cursor < period.from {
+                sessions.push(self.resize(cursor, Some(period.from)));
+            }
+            cursor = cursor.max(period.until);
+        }
+
+        if cursor < left_at {
+            // keep it unclosed if it's still ongoing
+            sessions.push(self.resize(cursor, self.left_at));
+        }
+
+        sessions
+    }
+}
+
+/// DB 上でセッションを識別する ID
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SessionId(pub String);
+
+impl std::fmt::Display for SessionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// セッションのうち、1つのチャンネルにいた期間
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubSession {
+    pub user_id: u64,
+    pub channel_id: u64,
+    pub joined_at: DateTime<Utc>,
+    pub left_at: Option<DateTime<Utc>>,
+}
+
+impl SubSession {
+    pub fn duration(&self) -> Duration {
+        self.left_at.unwrap_or_else(Utc::now) - self.joined_at
+    }
 }
 
 #[test]
@@ -78,11 +291,15 @@ fn stat_test() {
                 user_id: 0,
                 joined_at: datetime!(2021/3/1 00:00:00),
                 left_at: Some(datetime!(2021/3/1 1:30:00)),
+                excluded: vec![],
+                uncertain: vec![],
             },
             Session {
                 user_id: 0,
                 joined_at: datetime!(2021/3/2 00:00:00),
                 left_at: Some(datetime!(2021/3/2 1:30:00)),
+                excluded: vec![],
+                uncertain: vec![],
             },
         ],
         &FormulaV1,
@@ -106,11 +323,15 @@ fn stat_test() {
                 user_id: 1,
                 joined_at: Utc::now(),
                 left_at: Some(Utc::now()),
+                excluded: vec![],
+                uncertain: vec![],
             },
             Session {
                 user_id: 2,
                 joined_at: Utc::now(),
                 left_at: Some(Utc::now()),
+                excluded: vec![],
+                uncertain: vec![],
             },
         ],
         &FormulaV1,
@@ -118,3 +339,158 @@ fn stat_test() {
 
     assert!(test_conflicting_user_id.is_err());
 }
+
+#[test]
+fn active_periods_test() {
+    use crate::bot::genkai_point::datetime;
+
+    let period = |reason, from, until| ExcludedPeriod {
+        reason,
+        from,
+        until,
+    };
+
+    let session = Session {
+        user_id: 0,
+        joined_at: datetime!(2021/3/1 00:00:00),
+        left_at: Some(datetime!(2021/3/1 5:00:00)),
+        excluded: vec![
+            // overlapping periods are merged
+            period(
+                ExclusionReason::Deafened,
+                datetime!(2021/3/1 1:30:00),
+                Some(datetime!(2021/3/1 2:30:00)),
+            ),
+            period(
+                ExclusionReason::Afk,
+                datetime!(2021/3/1 1:00:00),
+                Some(datetime!(2021/3/1 2:00:00)),
+            ),
+            // still open, so it lasts until the session ends
+            period(ExclusionReason::Afk, datetime!(2021/3/1 4:00:00), None),
+        ],
+        uncertain: vec![],
+    };
+
+    assert_eq!(
+        session.active_periods(),
+        vec![
+            (datetime!(2021/3/1 00:00:00), datetime!(2021/3/1 1:00:00)),
+            (datetime!(2021/3/1 2:30:00), datetime!(2021/3/1 4:00:00)),
+        ]
+    );
+}
+
+#[test]
+fn clip_test() {
+    use crate::bot::genkai_point::{datetime, formula::v1::FormulaV1};
+
+    let sessions = [
+        Session {
+            user_id: 0,
+            joined_at: datetime!(2021/3/1 23:00:00),
+            left_at: Some(datetime!(2021/3/2 2:00:00)),
+            excluded: vec![],
+            uncertain: vec![],
+        },
+        Session {
+            user_id: 0,
+            joined_at: datetime!(2021/3/3 1:00:00),
+            left_at: Some(datetime!(2021/3/3 3:00:00)),
+            excluded: vec![],
+            uncertain: vec![],
+        },
+    ];
+
+    let from = datetime!(2021/3/2 00:00:00);
+    let until = datetime!(2021/3/3 2:00:00);
+
+    let clipped = sessions[0].clip(from, until).unwrap();
+    assert_eq!(clipped.joined_at, from);
+    assert_eq!(clipped.left_at, Some(datetime!(2021/3/2 2:00:00)));
+
+    let clipped = sessions[1].clip(from, until).unwrap();
+    assert_eq!(clipped.joined_at, datetime!(2021/3/3 1:00:00));
+    assert_eq!(clipped.left_at, Some(until));
+
+    assert!(
+        sessions[1]
+            .clip(from, datetime!(2021/3/3 1:00:00))
+            .is_none()
+    );
+
+    // unclosed session stays unclosed if the window is still open
+    let ongoing = Session {
+        user_id: 0,
+        joined_at: Utc::now() - Duration::hours(1),
+        left_at: None,
+        excluded: vec![],
+        uncertain: vec![],
+    };
+    let clipped = ongoing
+        .clip(
+            Utc::now() - Duration::minutes(30),
+            Utc::now() + Duration::days(1),
+        )
+        .unwrap();
+    assert_eq!(clipped.left_at, None);
+
+    let stat = UserStat::from_sessions_between(&sessions, &FormulaV1, from, until)
+        .unwrap()
+        .unwrap();
+    assert_eq!(stat.total_vc_duration, Duration::hours(3));
+    assert_eq!(stat.last_activity_at, until);
+
+    assert!(
+        UserStat::from_sessions_between(&sessions, &FormulaV1, until, until)
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[test]
+fn uncertain_test() {
+    use crate::bot::genkai_point::datetime;
+
+    let downtime = |from, until| UncertainPeriod { from, until };
+
+    // stayed through the downtime from 1:00 to 2:00
+    let stayed = Session {
+        user_id: 0,
+        joined_at: datetime!(2021/3/1 00:00:00),
+        left_at: None,
+        excluded: vec![ExcludedPeriod {
+            reason: ExclusionReason::Afk,
+            from: datetime!(2021/3/1 1:30:00),
+            until: None,
+        }],
+        uncertain: vec![downtime(
+            datetime!(2021/3/1 1:00:00),
+            datetime!(2021/3/1 2:00:00),
+        )],
+    };
+
+    assert_eq!(stayed.uncertain_duration(), Duration::hours(1));
+    assert!(!stayed.left_at_is_uncertain());
+
+    let pieces = stayed.without_uncertain();
+    assert_eq!(pieces.len(), 2);
+    assert_eq!(pieces[0].left_at, Some(datetime!(2021/3/1 1:00:00)));
+    assert_eq!(pieces[1].joined_at, datetime!(2021/3/1 2:00:00));
+    // the latter one is still ongoing, and so is its afk period
+    assert_eq!(pieces[1].left_at, None);
+    assert_eq!(pieces[1].excluded[0].from, datetime!(2021/3/1 2:00:00));
+    assert_eq!(pieces[1].excluded[0].until, None);
+    assert!(pieces[0].excluded.is_empty());
+
+    // closed at the last heartbeat since the leave was missed
+    let missed = Session {
+        left_at: Some(datetime!(2021/3/1 1:00:00)),
+        excluded: vec![],
+        ..stayed
+    };
+
+    assert_eq!(missed.uncertain_duration(), Duration::zero());
+    assert!(missed.left_at_is_uncertain());
+    assert_eq!(missed.without_uncertain().len(), 1);
+}
@@ -1,108 +1,157 @@
 use {
-    crate::bot::genkai_point::plot::Plotter,
+    crate::bot::genkai_point::plot::{
+        Plotter,
+        chart::{self, AxisKind, OutputFormat, Series, value_to_date},
+    },
     anyhow::{Result, anyhow},
     charming::{
-        Chart, ImageRenderer,
-        component::{Axis, Legend},
+        Chart, HtmlRenderer, ImageRenderer,
+        component::{Axis, Legend, Title, VisualMap},
         datatype::{CompositeValue, DataPoint},
-        element::{AxisType, name_location::NameLocation},
-        series::Line,
+        element::{AxisType, Orient, name_location::NameLocation},
+        series::{Bar, Heatmap, Line},
         theme::Theme,
     },
-    crossbeam::channel::{Receiver, Sender},
     image::ImageFormat,
-    std::thread,
-    tokio::sync::oneshot,
 };
 
-pub struct Charming {
-    renderer: Renderer,
-}
+/// 描画はブロッキングするので、`RenderPool` を通して使ってください
+pub struct Charming;
 
 impl Charming {
     pub fn new() -> Self {
-        let renderer = Renderer::spawn();
-
-        Self { renderer }
+        Self
     }
 }
 
 impl Plotter for Charming {
-    async fn plot(&self, data: Vec<(String, Vec<f64>)>) -> Result<Vec<u8>> {
-        let chart = data
-            .into_iter()
-            .fold(Chart::new(), |chart, (label, data)| {
-                let data = data
-                    .into_iter()
-                    .enumerate()
-                    .map(|(x, y)| {
-                        DataPoint::Value(CompositeValue::Array(vec![(x as i64).into(), y.into()]))
-                    })
-                    .collect();
-                chart.series(Line::new().name(label).data(data))
-            })
-            .legend(Legend::new().top("bottom"))
-            .x_axis(
-                Axis::new()
-                    .min_interval(1)
-                    .type_(AxisType::Value)
-                    .name_location(NameLocation::Center)
-                    .name("時間経過(日)"),
-            )
-            .y_axis(
-                Axis::new()
-                    .type_(AxisType::Value)
-                    .name_location(NameLocation::Center)
-                    .name("累計VC時間(時)"),
-            );
-
-        self.renderer.render(chart).await
+    async fn plot(&self, chart: chart::Chart) -> Result<Vec<u8>> {
+        let (width, height) = chart.size;
+        let format = chart.format;
+        let title = chart.title.clone().unwrap_or_default();
+        let chart = to_charming(chart);
+
+        match format {
+            // html is rendered by the browser, so we don't need the ssr runtime here
+            OutputFormat::Html => HtmlRenderer::new(title, width as u64, height as u64)
+                .theme(Theme::Dark)
+                .render(&chart)
+                .map(String::into_bytes)
+                .map_err(|e| anyhow!("charming error: {e:#?}")),
+
+            OutputFormat::Png | OutputFormat::Svg => {
+                let mut renderer = ImageRenderer::new(width, height).theme(Theme::Dark);
+
+                match format {
+                    OutputFormat::Svg => renderer.render(&chart).map(String::into_bytes),
+                    _ => renderer.render_format(ImageFormat::Png, &chart),
+                }
+                .map_err(|e| anyhow!("charming error: {e:#?}"))
+            }
+        }
     }
-}
 
-struct Request {
-    data: Chart,
-    bell: oneshot::Sender<Response>,
-}
-struct Response {
-    image: Result<Vec<u8>>,
+    fn supports(&self, _format: OutputFormat) -> bool {
+        true
+    }
 }
 
-struct Renderer {
-    tx: Sender<Request>,
-    _thread_handle: thread::JoinHandle<()>,
-}
+fn to_charming(chart: chart::Chart) -> Chart {
+    let chart::Chart {
+        title,
+        x_axis,
+        y_axis,
+        series,
+        ..
+    } = chart;
+
+    let mut result = Chart::new()
+        .x_axis(to_axis(&x_axis, false))
+        .y_axis(to_axis(&y_axis, true));
+
+    if let Some(title) = title {
+        result = result.title(Title::new().text(title).left("center"));
+    }
 
-impl Renderer {
-    fn render_thread(rx: Receiver<Request>) {
-        let mut renderer = ImageRenderer::new(1280, 720).theme(Theme::Dark);
+    if series.iter().any(|s| s.name().is_some()) {
+        result = result.legend(Legend::new().top("bottom"));
+    }
 
-        for req in rx {
-            let image = renderer
-                .render_format(ImageFormat::WebP, &req.data)
-                .map_err(|e| anyhow!("charming error: {e:#?}"));
+    let to_data = |points: Vec<(f64, f64)>| {
+        points
+            .into_iter()
+            .map(|(x, y)| {
+                let x = match x_axis.kind {
+                    AxisKind::Date => value_to_date(x).format("%Y-%m-%d").to_string().into(),
+                    _ => x.into(),
+                };
 
-            req.bell.send(Response { image }).ok();
-        }
-    }
+                DataPoint::Value(CompositeValue::Array(vec![x, y.into()]))
+            })
+            .collect()
+    };
 
-    fn spawn() -> Self {
-        let (tx, rx) = crossbeam::channel::unbounded::<Request>();
+    for series in series {
+        result = match series {
+            Series::Line { name, points } => {
+                result.series(Line::new().name(name).data(to_data(points)))
+            }
 
-        let handle = std::thread::spawn(|| Self::render_thread(rx));
+            Series::Bar { name, points } => {
+                result.series(Bar::new().name(name).data(to_data(points)))
+            }
 
-        Self {
-            tx,
-            _thread_handle: handle,
-        }
+            Series::Heatmap { values } => {
+                let max = values.iter().flatten().copied().fold(0.0, f64::max);
+
+                // echarts heatmap takes [x, y, value]
+                let data = values
+                    .into_iter()
+                    .enumerate()
+                    .flat_map(|(y, xs)| {
+                        xs.into_iter().enumerate().map(move |(x, value)| {
+                            DataPoint::Value(CompositeValue::Array(vec![
+                                (x as i64).into(),
+                                (y as i64).into(),
+                                value.into(),
+                            ]))
+                        })
+                    })
+                    .collect();
+
+                result
+                    .visual_map(
+                        VisualMap::new()
+                            .min(0)
+                            .max(max)
+                            .calculable(true)
+                            .orient(Orient::Horizontal)
+                            .left("center")
+                            .bottom("0%"),
+                    )
+                    .series(Heatmap::new().data(data))
+            }
+        };
     }
 
-    async fn render(&self, data: Chart) -> Result<Vec<u8>> {
-        let (tx, rx) = oneshot::channel();
+    result
+}
 
-        self.tx.send(Request { data, bell: tx }).unwrap();
+fn to_axis(axis: &chart::Axis, vertical: bool) -> Axis {
+    let mut result = Axis::new().name_location(NameLocation::Center);
 
-        rx.await.unwrap().image
+    if let Some(label) = &axis.label {
+        result = result.name(label.clone());
+    }
+
+    match &axis.kind {
+        AxisKind::Value => result.type_(AxisType::Value),
+        AxisKind::Date => result.type_(AxisType::Time),
+        // the first category comes at the top
+        AxisKind::Category(labels) => result
+            .type_(AxisType::Category)
+            .inverse(vertical)
+            .data(labels.clone()),
     }
 }
 
@@ -118,10 +167,20 @@ async fn test() {
 
         handles.push(tokio::spawn(async move {
             let result = charming
-                .plot(vec![
-                    ("kawaemon".into(), vec![1.0, 4.0, 6.0, 7.0]),
-                    ("kawak".into(), vec![2.0, 5.0, 11.0, 14.0]),
-                ])
+                .plot(
+                    chart::Chart::new(
+                        chart::Axis::new(AxisKind::Value),
+                        chart::Axis::new(AxisKind::Value),
+                    )
+                    .series(Series::Line {
+                        name: "kawaemon".into(),
+                        points: vec![(0.0, 1.0), (1.0, 4.0), (2.0, 6.0), (3.0, 7.0)],
+                    })
+                    .series(Series::Bar {
+                        name: "kawak".into(),
+                        points: vec![(0.0, 2.0), (1.0, 5.0), (2.0, 11.0), (3.0, 14.0)],
+                    }),
+                )
                 .await
                 .unwrap();
 
@@ -0,0 +1,181 @@
+use {
+    chrono::{Duration, NaiveDate},
+    clap::ValueEnum,
+    serde::Deserialize,
+};
+
+/// 特に指定がないときの出力サイズ
+pub const DEFAULT_SIZE: (u32, u32) = (1280, 720);
+
+/// バックエンドに依存しないグラフの定義です。
+/// 新しいグラフはこれを組み立てるだけで、どの `Plotter` でも描画できます
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chart {
+    pub title: Option<String>,
+    /// (幅, 高さ) ピクセル
+    pub size: (u32, u32),
+    pub format: OutputFormat,
+    pub x_axis: Axis,
+    pub y_axis: Axis,
+    pub series: Vec<Series>,
+}
+
+/// 出力するファイルの形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Png,
+    Svg,
+    /// グラフを操作できる ECharts の HTML
+    Html,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Svg => "svg",
+            OutputFormat::Html => "html",
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Svg => "image/svg+xml",
+            OutputFormat::Html => "text/html; charset=utf-8",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Axis {
+    pub label: Option<String>,
+    pub kind: AxisKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AxisKind {
+    Value,
+    /// 値は `date_to_value` で変換した日付
+    Date,
+    /// 値はラベルのインデックス
+    Category(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Series {
+    Line {
+        name: String,
+        points: Vec<(f64, f64)>,
+    },
+    Bar {
+        name: String,
+        points: Vec<(f64, f64)>,
+    },
+    /// 両方の軸が `AxisKind::Category` である必要があります
+    Heatmap {
+        /// `values[y][x]`
+        values: Vec<Vec<f64>>,
+    },
+}
+
+impl Chart {
+    pub fn new(x_axis: Axis, y_axis: Axis) -> Self {
+        Self {
+            title: None,
+            size: DEFAULT_SIZE,
+            format: OutputFormat::default(),
+            x_axis,
+            y_axis,
+            series: vec![],
+        }
+    }
+
+    pub fn title(self, title: impl Into<String>) -> Self {
+        Self {
+            title: Some(title.into()),
+            ..self
+        }
+    }
+
+    pub fn size(self, width: u32, height: u32) -> Self {
+        Self {
+            size: (width, height),
+            ..self
+        }
+    }
+
+    pub fn format(self, format: OutputFormat) -> Self {
+        Self { format, ..self }
+    }
+
+    pub fn series(mut self, series: Series) -> Self {
+        self.series.push(series);
+        self
+    }
+}
+
+impl Axis {
+    pub fn new(kind: AxisKind) -> Self {
+        Self { label: None, kind }
+    }
+
+    pub fn label(self, label: impl Into<String>) -> Self {
+        Self {
+            label: Some(label.into()),
+            ..self
+        }
+    }
+
+    /// 目盛りに表示する文字列
+    pub fn format_value(&self, value: f64) -> String {
+        match &self.kind {
+            AxisKind::Value => format!("{value}"),
+            AxisKind::Date => value_to_date(value).format("%Y/%m/%d").to_string(),
+            AxisKind::Category(labels) => {
+                if value.fract() != 0.0 || value < 0.0 {
+                    return String::new();
+                }
+
+                labels.get(value as usize).cloned().unwrap_or_default()
+            }
+        }
+    }
+}
+
+impl Series {
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Series::Line { name, .. } | Series::Bar { name, .. } => Some(name),
+            Series::Heatmap { .. } => None,
+        }
+    }
+}
+
+fn epoch() -> NaiveDate {
+    NaiveDate::from_ymd_opt(1970, 1, 1).unwrap()
+}
+
+pub fn date_to_value(date: NaiveDate) -> f64 {
+    (date - epoch()).num_days() as f64
+}
+
+pub fn value_to_date(value: f64) -> NaiveDate {
+    epoch() + Duration::days(value.round() as i64)
+}
+
+#[test]
+fn test_axis_format() {
+    let date = NaiveDate::from_ymd_opt(2024, 2, 29).unwrap();
+    assert_eq!(value_to_date(date_to_value(date)), date);
+
+    let axis = Axis::new(AxisKind::Date);
+    assert_eq!(axis.format_value(date_to_value(date)), "2024/02/29");
+
+    let axis = Axis::new(AxisKind::Category(vec!["月".into(), "火".into()]));
+    assert_eq!(axis.format_value(1.0), "火");
+    assert_eq!(axis.format_value(0.5), "");
+    assert_eq!(axis.format_value(2.0), "");
+}
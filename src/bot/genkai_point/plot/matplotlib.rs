@@ -1,6 +1,9 @@
 use {
-    crate::bot::genkai_point::plot::Plotter,
-    anyhow::{Result, anyhow},
+    crate::bot::genkai_point::plot::{
+        Plotter,
+        chart::{Axis, AxisKind, Chart, OutputFormat, Series},
+    },
+    anyhow::{Result, anyhow, ensure},
     inline_python::{Context as PythonContext, python},
 };
 
@@ -9,7 +12,6 @@ use {
 // so Ctrl-C doesn't shutdown this process after calling plot function.
 
 // FIXME: Japanese fonts rendering are broken.
-// FIXME: No axis description.
 
 pub struct Matplotlib {}
 
@@ -20,21 +22,88 @@ impl Matplotlib {
 }
 
 impl Plotter for Matplotlib {
-    async fn plot(&self, data: Vec<(String, Vec<f64>)>) -> Result<Vec<u8>> {
+    async fn plot(&self, chart: Chart) -> Result<Vec<u8>> {
+        let Chart {
+            title,
+            size: (width, height),
+            format,
+            x_axis,
+            y_axis,
+            series,
+        } = chart;
+
+        ensure!(
+            format != OutputFormat::Html,
+            "matplotlib backend can't output html"
+        );
+        let format = format.extension();
+
+        // python can't take rust enums, so flatten them
+        let kind_of = |axis: &Axis| match axis.kind {
+            AxisKind::Value => "value",
+            AxisKind::Date => "date",
+            AxisKind::Category(_) => "category",
+        };
+        let labels_of = |axis: &Axis| match &axis.kind {
+            AxisKind::Category(labels) => labels.clone(),
+            _ => vec![],
+        };
+
+        let (x_kind, x_labels, x_desc) = (kind_of(&x_axis), labels_of(&x_axis), x_axis.label);
+        let (y_labels, y_desc) = (labels_of(&y_axis), y_axis.label);
+
+        let mut lines = vec![];
+        let mut heatmaps = vec![];
+
+        for series in series {
+            match series {
+                Series::Line { name, points } => lines.push(("line", name, points)),
+                Series::Bar { name, points } => lines.push(("bar", name, points)),
+                Series::Heatmap { values } => heatmaps.push(values),
+            }
+        }
+
         let result: Result<PythonContext, _> = std::panic::catch_unwind(|| {
             python! {
                 import io
+                import datetime
                 from matplotlib import pyplot
 
-                figure = pyplot.figure()
+                figure, axes = pyplot.subplots(figsize=('width / 100, 'height / 100), dpi=100)
 
-                for k, v in 'data:
-                    pyplot.plot(list(range(1, len(v) + 1)), v, label=k)
+                for kind, name, points in 'lines:
+                    xs = [x for x, _ in points]
+                    ys = [y for _, y in points]
 
-                figure.legend(loc="lower right")
+                    if 'x_kind == "date":
+                        xs = [datetime.date(1970, 1, 1) + datetime.timedelta(days=x) for x in xs]
+
+                    if kind == "bar":
+                        axes.bar(xs, ys, label=name)
+                    else:
+                        axes.plot(xs, ys, label=name)
+
+                for values in 'heatmaps:
+                    image = axes.imshow(values, cmap="Reds", aspect="auto")
+                    figure.colorbar(image)
+
+                if len('x_labels) > 0:
+                    axes.set_xticks(list(range(len('x_labels))), labels='x_labels)
+                if len('y_labels) > 0:
+                    axes.set_yticks(list(range(len('y_labels))), labels='y_labels)
+
+                if 'title is not None:
+                    axes.set_title('title)
+                if 'x_desc is not None:
+                    axes.set_xlabel('x_desc)
+                if 'y_desc is not None:
+                    axes.set_ylabel('y_desc)
+
+                if len('lines) > 0:
+                    figure.legend(loc="lower right")
 
                 buffer = io.BytesIO()
-                figure.savefig(buffer)
+                figure.savefig(buffer, format='format)
 
                 result = buffer.getvalue()
             }
@@ -45,15 +114,26 @@ impl Plotter for Matplotlib {
             Err(_) => Err(anyhow!("failed to plot graph")),
         }
     }
+
+    fn supports(&self, format: OutputFormat) -> bool {
+        format != OutputFormat::Html
+    }
 }
 
 #[tokio::test]
 async fn test_plot_to_image() {
     let result = Matplotlib {}
-        .plot(vec![
-            ("kawaemon".into(), vec![1.0, 4.0, 6.0, 7.0]),
-            ("kawak".into(), vec![2.0, 5.0, 11.0, 14.0]),
-        ])
+        .plot(
+            Chart::new(Axis::new(AxisKind::Value), Axis::new(AxisKind::Value))
+                .series(Series::Line {
+                    name: "kawaemon".into(),
+                    points: vec![(0.0, 1.0), (1.0, 4.0), (2.0, 6.0), (3.0, 7.0)],
+                })
+                .series(Series::Line {
+                    name: "kawak".into(),
+                    points: vec![(0.0, 2.0), (1.0, 5.0), (2.0, 11.0), (3.0, 14.0)],
+                }),
+        )
         .await;
 
     // should we assert_eq with actual png?
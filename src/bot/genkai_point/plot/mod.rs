@@ -1,14 +1,22 @@
 use {
     crate::bot::{
         Context,
-        genkai_point::{GenkaiPointDatabase, Plotter, model::Session},
+        genkai_point::{
+            GenkaiPointDatabase, Plotter,
+            formula::GenkaiPointFormula,
+            model::Session,
+            plot::chart::{Axis, AxisKind, Chart, OutputFormat, Series, date_to_value},
+        },
     },
     anyhow::{Context as _, Result},
-    chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Utc},
+    chrono::{DateTime, Datelike, Duration, DurationRound, NaiveDate, TimeZone, Timelike, Utc},
     chrono_tz::{Asia::Tokyo, Tz},
-    std::{cmp::Reverse, collections::HashMap},
+    std::collections::HashMap,
 };
 
+pub mod chart;
+pub mod pool;
+
 #[cfg(feature = "plot_matplotlib")]
 pub mod matplotlib;
 
@@ -18,20 +26,45 @@ pub mod plotters;
 #[cfg(feature = "plot_charming")]
 pub mod charming;
 
-pub(super) async fn plot<P: Plotter + Send>(
+const WEEKDAYS: [&str; 7] = ["月", "火", "水", "木", "金", "土", "日"];
+
+/// `g!point graph` で何を表示するか
+#[derive(Debug, Clone, Default)]
+pub struct GraphOptions {
+    /// 空ならトップ `top` 人を表示します
+    pub users: Vec<u64>,
+    pub top: usize,
+    /// JST の日付で、両端を含みます
+    pub since: Option<NaiveDate>,
+    pub until: Option<NaiveDate>,
+    /// Some(n) なら累計ではなく、n 日間の移動平均で1日あたりのVC時間を表示します
+    pub rolling: Option<usize>,
+    pub format: OutputFormat,
+}
+
+pub async fn plot<P: Plotter + Send>(
     db: &impl GenkaiPointDatabase,
     ctx: &impl Context,
     plotter: &P,
-    top: usize,
+    options: &GraphOptions,
 ) -> Result<Option<Vec<u8>>> {
-    let all_sessions = {
-        let sess = db.get_all_sessions().await?;
+    let sessions = db.get_all_sessions().await?;
+    plot_sessions(sessions, ctx, plotter, options).await
+}
 
-        if sess.is_empty() {
+/// 与えられたセッションだけを使って、VC時間の伸びをプロットします
+pub async fn plot_sessions<P: Plotter + Send>(
+    sessions: Vec<Session>,
+    ctx: &impl Context,
+    plotter: &P,
+    options: &GraphOptions,
+) -> Result<Option<Vec<u8>>> {
+    let all_sessions = {
+        if sessions.is_empty() {
             return Ok(None);
         }
 
-        let mut sess = sess
+        let mut sess = sessions
             .into_iter()
             .map(TzAwareSession::from)
             .collect::<Vec<_>>();
@@ -40,8 +73,14 @@ pub(super) async fn plot<P: Plotter + Send>(
         sess
     };
 
-    let duration_progess_per_user = {
-        let all_sessions_range = sessions_range(&all_sessions);
+    let in_range = |d: &NaiveDate| {
+        options.since.is_none_or(|since| since <= *d)
+            && options.until.is_none_or(|until| *d <= until)
+    };
+
+    let mut per_user = {
+        // everyone's line continues until the last day so that they can be compared
+        let end = sessions_range(&all_sessions).1;
 
         let mut sess = HashMap::new();
         for session in all_sessions {
@@ -50,45 +89,270 @@ pub(super) async fn plot<P: Plotter + Send>(
                 .push(session);
         }
 
-        let mut dur = Vec::with_capacity(sess.capacity());
+        let mut res = vec![];
         for (user_id, user_sessions) in sess {
-            if let Some(mut progress) = sessions_to_duration_progress(&user_sessions) {
-                align_duration_progresses(
-                    &mut progress,
-                    sessions_range(&user_sessions),
-                    all_sessions_range,
-                );
-                dur.push((user_id, progress));
+            if !options.users.is_empty() && !options.users.contains(&user_id) {
+                continue;
             }
-        }
 
-        dur.sort_unstable_by_key(|(_, x)| Reverse(*x.last().unwrap()));
-        dur
-    };
+            let cumulative = cumulative_hours(&user_sessions, end);
 
-    let prottable_data = {
-        let mut data = vec![];
-        for (user_id, progress) in duration_progess_per_user.into_iter().take(top) {
-            let user_name = ctx.get_user_name(user_id).await?;
-            let progress = progress
+            let Some(&(_, total)) = cumulative.iter().rev().find(|(d, _)| in_range(d)) else {
+                continue;
+            };
+            let before = cumulative
+                .iter()
+                .take_while(|(d, _)| options.since.is_some_and(|since| *d < since))
+                .last()
+                .map_or(0.0, |x| x.1);
+
+            let values = match options.rolling {
+                Some(window) => rolling_average(&cumulative, window),
+                None => cumulative,
+            };
+
+            let values = values
                 .into_iter()
-                .map(|x| x.num_seconds() as f64 / (60 * 60) as f64)
-                .collect();
-            data.push((user_name, progress));
+                .filter(|(d, _)| in_range(d))
+                .collect::<Vec<_>>();
+
+            // hours in the range
+            res.push((user_id, total - before, values));
         }
-        data
+
+        res
     };
 
-    // FIXME: plotter.plot can take unacceptable time for tokio runtime maybe?
-    //        use tokio::task::spawn_blocking to solve this problem.
+    if options.users.is_empty() {
+        per_user.sort_unstable_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        per_user.truncate(options.top);
+    } else {
+        per_user
+            .sort_unstable_by_key(|(user_id, ..)| options.users.iter().position(|x| x == user_id));
+    }
+
+    if per_user.is_empty() {
+        return Ok(None);
+    }
+
+    let y_label = match options.rolling {
+        Some(1) => "VC時間(時/日)".to_owned(),
+        Some(n) => format!("VC時間(時/日, {n}日移動平均)"),
+        None => "累計VC時間(時)".to_owned(),
+    };
+
+    let mut chart = Chart::new(
+        Axis::new(AxisKind::Date).label("日付"),
+        Axis::new(AxisKind::Value).label(y_label),
+    )
+    .format(options.format);
+
+    for (user_id, _, values) in per_user {
+        let user_name = ctx.get_user_name(user_id).await?;
+        let points = values
+            .into_iter()
+            .map(|(date, hours)| (date_to_value(date), hours))
+            .collect();
+
+        chart = chart.series(Series::Line {
+            name: user_name,
+            points,
+        });
+    }
+
+    let image = plotter.plot(chart).await.context("failed to plot graph")?;
+
+    Ok(Some(image))
+}
+
+// cumulative vc hours of each day, from the user's first day to `end`
+fn cumulative_hours(user_sessions: &[TzAwareSession], end: NaiveDate) -> Vec<(NaiveDate, f64)> {
+    let Some(progress) = sessions_to_duration_progress(user_sessions) else {
+        return vec![];
+    };
+
+    let begin = user_sessions[0].joined_at.date_naive();
+    let last = *progress.last().unwrap();
+    let len = ((end - begin).num_days() as usize + 1).max(progress.len());
+
+    progress
+        .into_iter()
+        .chain(std::iter::repeat(last))
+        .take(len)
+        .zip(begin.iter_days())
+        .map(|(d, date)| (date, d.num_seconds() as f64 / (60 * 60) as f64))
+        .collect()
+}
+
+// average hours per day in the last `window` days
+fn rolling_average(cumulative: &[(NaiveDate, f64)], window: usize) -> Vec<(NaiveDate, f64)> {
+    let window = window.max(1);
+
+    cumulative
+        .iter()
+        .enumerate()
+        .map(|(i, &(date, hours))| {
+            let before = if i >= window {
+                cumulative[i - window].1
+            } else {
+                0.0
+            };
+
+            (date, (hours - before) / window as f64)
+        })
+        .collect()
+}
+
+#[test]
+fn test_graph_values() {
+    let date = |d| NaiveDate::from_ymd_opt(2023, 1, d).unwrap();
+    let sessions = [
+        TzAwareSession {
+            user_id: 0,
+            joined_at: Tokyo.with_ymd_and_hms(2023, 1, 11, 0, 0, 0).unwrap(),
+            left_at: Tokyo.with_ymd_and_hms(2023, 1, 11, 2, 0, 0).unwrap(),
+        },
+        TzAwareSession {
+            user_id: 0,
+            joined_at: Tokyo.with_ymd_and_hms(2023, 1, 13, 0, 0, 0).unwrap(),
+            left_at: Tokyo.with_ymd_and_hms(2023, 1, 13, 4, 0, 0).unwrap(),
+        },
+    ];
+
+    let cumulative = cumulative_hours(&sessions, date(15));
+    assert_eq!(
+        cumulative,
+        vec![
+            (date(11), 2.0),
+            (date(12), 2.0),
+            (date(13), 6.0),
+            (date(14), 6.0),
+            (date(15), 6.0),
+        ]
+    );
+
+    assert_eq!(
+        rolling_average(&cumulative, 1)
+            .into_iter()
+            .map(|x| x.1)
+            .collect::<Vec<_>>(),
+        vec![2.0, 0.0, 4.0, 0.0, 0.0]
+    );
+    assert_eq!(
+        rolling_average(&cumulative, 2)
+            .into_iter()
+            .map(|x| x.1)
+            .collect::<Vec<_>>(),
+        vec![1.0, 1.0, 2.0, 2.0, 0.0]
+    );
+}
+
+/// 曜日と時間帯ごとの VC 時間 (JST) をヒートマップにします
+pub(super) async fn heatmap<P: Plotter + Send>(
+    sessions: &[Session],
+    title: String,
+    plotter: &P,
+) -> Result<Option<Vec<u8>>> {
+    if sessions.is_empty() {
+        return Ok(None);
+    }
+
+    let hours = (0..24).map(|h| h.to_string()).collect();
+    let weekdays = WEEKDAYS.iter().map(|x| x.to_string()).collect();
+
+    let chart = Chart::new(
+        Axis::new(AxisKind::Category(hours)).label("時刻"),
+        Axis::new(AxisKind::Category(weekdays)),
+    )
+    .title(title)
+    .series(Series::Heatmap {
+        values: weekday_hour_hours(sessions),
+    });
+
     let image = plotter
-        .plot(prottable_data)
+        .plot(chart)
         .await
-        .context("failed to plot graph")?;
+        .context("failed to plot heatmap")?;
 
     Ok(Some(image))
 }
 
+/// フォーミュラごとに、各時刻から1時間 VC にいたときのポイントをプロットします
+pub(super) async fn weight_curves<P: Plotter + Send>(
+    formulas: &[&dyn GenkaiPointFormula],
+    plotter: &P,
+) -> Result<Vec<u8>> {
+    // every 15 minutes
+    let hours = (0..24 * 4).map(|x| x as f64 / 4.0).collect::<Vec<_>>();
+
+    let mut chart = Chart::new(
+        Axis::new(AxisKind::Value).label("時刻"),
+        Axis::new(AxisKind::Value).label("1時間でのポイント"),
+    )
+    .title("フォーミュラの比較");
+
+    for formula in formulas {
+        chart = chart.series(Series::Line {
+            name: formula.name().to_owned(),
+            points: hours.iter().map(|&h| (h, formula.weight(h))).collect(),
+        });
+    }
+
+    plotter
+        .plot(chart)
+        .await
+        .context("failed to plot weight curves")
+}
+
+// hours spent in VC for each [weekday][hour] in JST
+fn weekday_hour_hours(sessions: &[Session]) -> Vec<Vec<f64>> {
+    let mut values = vec![vec![0.0; 24]; 7];
+
+    for session in sessions {
+        let mut cursor = session.joined_at.with_timezone(&Tokyo);
+        let end = session.left_at().with_timezone(&Tokyo);
+
+        while cursor < end {
+            let next_hour = cursor.duration_trunc(Duration::hours(1)).unwrap() + Duration::hours(1);
+            let until = next_hour.min(end);
+
+            let weekday = cursor.weekday().num_days_from_monday() as usize;
+            values[weekday][cursor.hour() as usize] +=
+                (until - cursor).num_seconds() as f64 / (60 * 60) as f64;
+
+            cursor = until;
+        }
+    }
+
+    values
+}
+
+#[test]
+fn test_weekday_hour_hours() {
+    use crate::bot::genkai_point::datetime;
+
+    let session = |joined_at: DateTime<Utc>, minutes| Session {
+        user_id: 0,
+        joined_at,
+        left_at: Some(joined_at + Duration::minutes(minutes)),
+        excluded: vec![],
+        uncertain: vec![],
+    };
+
+    let values = weekday_hour_hours(&[
+        // sunday 23:30 -> monday 1:15
+        session(datetime!(2023/1/15 23:30:00), 105),
+        // tuesday
+        session(datetime!(2023/1/17 0:45:00), 15),
+    ]);
+
+    assert_eq!(values[6][23], 0.5);
+    assert_eq!(values[0][0], 1.0);
+    assert_eq!(values[0][1], 0.25);
+    assert_eq!(values[1][0], 0.25);
+    assert_eq!(values.iter().flatten().sum::<f64>(), 2.0);
+}
+
 struct TzAwareSession {
     user_id: u64,
     joined_at: DateTime<Tz>,
@@ -121,52 +385,6 @@ fn sessions_range(sessions: &[TzAwareSession]) -> (NaiveDate, NaiveDate) {
     (min, max)
 }
 
-fn align_duration_progresses(
-    target: &mut Vec<Duration>,
-    target_range: (NaiveDate, NaiveDate),
-    align_to: (NaiveDate, NaiveDate),
-) {
-    debug_assert!(align_to.0 <= target_range.0);
-    debug_assert!(align_to.1 >= target_range.1);
-    debug_assert!(!target.is_empty());
-
-    // align beginning
-    let mut beginning_aligned =
-        vec![Duration::zero(); (target_range.0 - align_to.0).num_days() as usize];
-    beginning_aligned.append(target);
-
-    *target = beginning_aligned;
-
-    let last = *target.last().unwrap();
-
-    // align ending
-    for _ in 0..(align_to.1 - target_range.1).num_days() {
-        target.push(last);
-    }
-
-    debug_assert_eq!(
-        target.len(),
-        (align_to.1 - align_to.0).num_days() as usize + 1
-    );
-}
-
-#[test]
-fn test_align_duration_progress() {
-    let range = (
-        NaiveDate::from_ymd_opt(2022, 1, 10).unwrap(),
-        NaiveDate::from_ymd_opt(2022, 1, 15).unwrap(),
-    );
-    let target_range = (
-        NaiveDate::from_ymd_opt(2022, 1, 11).unwrap(),
-        NaiveDate::from_ymd_opt(2022, 1, 13).unwrap(),
-    );
-    let mut target = [3, 5, 10].into_iter().map(Duration::seconds).collect();
-
-    align_duration_progresses(&mut target, target_range, range);
-
-    assert_eq!(target, [0, 3, 5, 10, 10, 10].map(Duration::seconds));
-}
-
 fn next_day(d: DateTime<Tz>) -> DateTime<Tz> {
     let next_day = d.date_naive().succ_opt().unwrap();
     d.timezone()
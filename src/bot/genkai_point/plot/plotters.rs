@@ -1,10 +1,15 @@
 use {
-    crate::bot::genkai_point::plot::Plotter,
-    anyhow::{Context as _, Result},
-    ordered_float::OrderedFloat,
-    plotters::prelude::*,
+    crate::bot::genkai_point::plot::{
+        Plotter,
+        chart::{Axis, AxisKind, Chart, OutputFormat, Series},
+    },
+    anyhow::{Context as _, Result, ensure},
+    plotters::{coord::Shift, prelude::*},
+    std::ops::Range,
 };
 
+type Area<'a> = DrawingArea<BitMapBackend<'a>, Shift>;
+
 crate::assert_one_feature!("plot_plotters_static", "plot_plotters_dynamic");
 
 pub struct Plotters {}
@@ -32,43 +37,157 @@ impl Plotters {
 }
 
 impl Plotter for Plotters {
-    async fn plot(&self, data: Vec<(String, Vec<f64>)>) -> Result<Vec<u8>> {
-        const SIZE: (usize, usize) = (1280, 720);
+    async fn plot(&self, chart: Chart) -> Result<Vec<u8>> {
+        ensure!(
+            chart.format == OutputFormat::Png,
+            "plotters backend can't output {:?}",
+            chart.format
+        );
+
+        let (width, height) = chart.size;
 
-        let mut buffer = vec![0; SIZE.0 * SIZE.1 * 3];
+        let mut buffer = vec![0; width as usize * height as usize * 3];
 
-        let root =
-            BitMapBackend::with_buffer(&mut buffer, (SIZE.0 as _, SIZE.1 as _)).into_drawing_area();
+        let root = BitMapBackend::with_buffer(&mut buffer, (width, height)).into_drawing_area();
         root.fill(&WHITE).context("failed to fill buffer")?;
 
-        let x_range = 0.0..data.first().context("no data in `data`")?.1.len() as f64;
-        let y_range = 0.0..(*data
-            .iter()
-            .flat_map(|x| x.1.last())
-            .max_by_key(|&&x| OrderedFloat(x))
-            .context("no data in `data")?);
-
-        let mut chart = ChartBuilder::on(&root)
-            .margin(20)
-            .x_label_area_size(40)
-            .y_label_area_size(60)
-            .build_cartesian_2d(x_range, y_range)
-            .context("failed to build chart")?;
-
-        chart
-            .configure_mesh()
-            .x_desc("時間経過(日)")
-            .y_desc("累計VC時間(時)")
-            .axis_desc_style(("sans-serif", 15))
-            .draw()?;
-
-        for (i, (label, data)) in data.into_iter().enumerate() {
-            let color = Palette99::COLORS[i % Palette99::COLORS.len()];
-            let color = RGBColor(color.0, color.1, color.2);
-
-            chart
-                .draw_series(LineSeries::new(
-                    data.into_iter().enumerate().map(|(a, b)| (a as f64, b)),
+        draw(&root, &chart)?;
+
+        // to borrow buffer later
+        drop(root);
+
+        let mut output = vec![];
+
+        let mut encoder = png::Encoder::new(&mut output, width, height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_compression(png::Compression::Best);
+
+        encoder
+            .write_header()
+            .context("failed to write png headers")?
+            .write_image_data(&buffer)
+            .context("failed to write png image data")?;
+
+        Ok(output)
+    }
+}
+
+// categories are placed at integer positions, and the first one comes at the top on y axis
+fn axis_range(axis: &Axis, values: impl Iterator<Item = f64>, padding: f64) -> Range<f64> {
+    if let AxisKind::Category(labels) = &axis.kind {
+        return -0.5..labels.len() as f64 - 0.5;
+    }
+
+    let (min, max) = values.fold((f64::MAX, f64::MIN), |(min, max), x| {
+        (min.min(x), max.max(x))
+    });
+
+    if min > max {
+        return 0.0..1.0;
+    }
+
+    let (min, max) = (min - padding, max + padding);
+    if min == max { min..max + 1.0 } else { min..max }
+}
+
+fn draw(root: &Area, chart: &Chart) -> Result<()> {
+    let has_bar = chart.series.iter().any(|s| matches!(s, Series::Bar { .. }));
+
+    let points = || {
+        chart.series.iter().flat_map(|s| match s {
+            Series::Line { points, .. } | Series::Bar { points, .. } => points.as_slice(),
+            Series::Heatmap { .. } => &[],
+        })
+    };
+
+    let x_range = axis_range(
+        &chart.x_axis,
+        points().map(|p| p.0),
+        if has_bar { 0.5 } else { 0.0 },
+    );
+    // bars and areas start from zero
+    let y_range = axis_range(&chart.y_axis, points().map(|p| p.1).chain([0.0]), 0.0);
+
+    let y_categories = match &chart.y_axis.kind {
+        AxisKind::Category(labels) => labels.len(),
+        _ => 0,
+    };
+    let y_position = |index: usize| (y_categories - 1 - index) as f64;
+
+    let mut builder = ChartBuilder::on(root);
+    builder
+        .margin(20)
+        .x_label_area_size(40)
+        .y_label_area_size(60);
+
+    if let Some(title) = &chart.title {
+        builder.caption(title, ("sans-serif", 20));
+    }
+
+    let mut ctx = builder
+        .build_cartesian_2d(x_range, y_range)
+        .context("failed to build chart")?;
+
+    let x_formatter = |x: &f64| chart.x_axis.format_value(*x);
+    let y_formatter = |y: &f64| match chart.y_axis.kind {
+        AxisKind::Category(_) => chart.y_axis.format_value(y_position(*y as usize)),
+        _ => chart.y_axis.format_value(*y),
+    };
+
+    let mut mesh = ctx.configure_mesh();
+    mesh.axis_desc_style(("sans-serif", 15))
+        .label_style(("sans-serif", 15));
+
+    if let Some(label) = &chart.x_axis.label {
+        mesh.x_desc(label);
+    }
+    if let Some(label) = &chart.y_axis.label {
+        mesh.y_desc(label);
+    }
+
+    match &chart.x_axis.kind {
+        AxisKind::Value => {}
+        AxisKind::Date => {
+            mesh.x_label_formatter(&x_formatter);
+        }
+        AxisKind::Category(labels) => {
+            mesh.disable_x_mesh()
+                .x_labels(labels.len())
+                .x_label_formatter(&x_formatter);
+        }
+    }
+
+    match &chart.y_axis.kind {
+        AxisKind::Value => {}
+        AxisKind::Date => {
+            mesh.y_label_formatter(&y_formatter);
+        }
+        AxisKind::Category(labels) => {
+            mesh.disable_y_mesh()
+                .y_labels(labels.len())
+                .y_label_formatter(&y_formatter);
+        }
+    }
+
+    mesh.draw().context("failed to draw mesh")?;
+
+    let bars = chart
+        .series
+        .iter()
+        .filter(|s| matches!(s, Series::Bar { .. }))
+        .count();
+    let bar_width = 0.8 / bars.max(1) as f64;
+    let mut bar_index = 0;
+
+    for (i, series) in chart.series.iter().enumerate() {
+        let color = Palette99::COLORS[i % Palette99::COLORS.len()];
+        let color = RGBColor(color.0, color.1, color.2);
+
+        match series {
+            Series::Line { name, points } => {
+                ctx.draw_series(LineSeries::new(
+                    points.iter().copied(),
                     ShapeStyle {
                         color: color.to_rgba(),
                         filled: true,
@@ -76,48 +195,126 @@ impl Plotter for Plotters {
                     },
                 ))
                 .context("failed to draw series")?
-                .label(label)
+                .label(name)
+                .legend(move |(x, y)| Rectangle::new([(x - 5, y - 5), (x + 5, y + 5)], color));
+            }
+
+            Series::Bar { name, points } => {
+                let left = -0.4 + bar_width * bar_index as f64;
+                bar_index += 1;
+
+                ctx.draw_series(points.iter().map(|&(x, y)| {
+                    Rectangle::new([(x + left, 0.0), (x + left + bar_width, y)], color.filled())
+                }))
+                .context("failed to draw series")?
+                .label(name)
                 .legend(move |(x, y)| Rectangle::new([(x - 5, y - 5), (x + 5, y + 5)], color));
+            }
+
+            Series::Heatmap { values } => {
+                let max = values.iter().flatten().copied().fold(0.0, f64::max);
+
+                ctx.draw_series(values.iter().enumerate().flat_map(|(row, xs)| {
+                    let y = y_position(row);
+
+                    xs.iter().enumerate().map(move |(x, &value)| {
+                        let ratio = if max > 0.0 { value / max } else { 0.0 };
+                        let x = x as f64;
+
+                        Rectangle::new(
+                            [(x - 0.5, y - 0.5), (x + 0.5, y + 0.5)],
+                            heat_color(ratio).filled(),
+                        )
+                    })
+                }))
+                .context("failed to draw series")?;
+            }
         }
+    }
 
-        chart
-            .configure_series_labels()
+    if chart.series.iter().any(|s| s.name().is_some()) {
+        ctx.configure_series_labels()
             .background_style(WHITE.mix(0.8))
             .border_style(BLACK)
             .draw()
             .context("failed to draw series labels")?;
+    }
 
-        // to borrow buffer later
-        drop(chart);
-        drop(root);
+    Ok(())
+}
 
-        let mut output = vec![];
+// white -> red
+fn heat_color(ratio: f64) -> RGBColor {
+    let other = (255.0 * (1.0 - ratio.clamp(0.0, 1.0))) as u8;
+    RGBColor(255, other, other)
+}
 
-        let mut encoder = png::Encoder::new(&mut output, SIZE.0 as _, SIZE.1 as _);
-        encoder.set_color(png::ColorType::Rgb);
-        encoder.set_depth(png::BitDepth::Eight);
-        encoder.set_compression(png::Compression::Best);
+#[cfg(test)]
+mod test {
+    use {super::*, crate::bot::genkai_point::plot::chart::date_to_value, chrono::NaiveDate};
 
-        encoder
-            .write_header()
-            .context("failed to write png headers")?
-            .write_image_data(&buffer)
-            .context("failed to write png image data")?;
+    #[tokio::test]
+    async fn line() {
+        let chart = Chart::new(
+            Axis::new(AxisKind::Value).label("x"),
+            Axis::new(AxisKind::Value).label("y"),
+        )
+        .series(Series::Line {
+            name: "kawaemon".into(),
+            points: vec![(0.0, 1.0), (1.0, 4.0), (2.0, 6.0), (3.0, 7.0)],
+        })
+        .series(Series::Line {
+            name: "kawak".into(),
+            points: vec![(0.0, 2.0), (1.0, 5.0), (2.0, 11.0), (3.0, 14.0)],
+        });
 
-        Ok(output)
+        let result = Plotters::new().plot(chart).await.unwrap();
+
+        // should we assert_eq with actual png?
+        assert_ne!(result.len(), 0);
     }
-}
 
-#[tokio::test]
-async fn test() {
-    let result = Plotters::new()
-        .plot(vec![
-            ("kawaemon".into(), vec![1.0, 4.0, 6.0, 7.0]),
-            ("kawak".into(), vec![2.0, 5.0, 11.0, 14.0]),
-        ])
-        .await
-        .unwrap();
-
-    // should we assert_eq with actual png?
-    assert_ne!(result.len(), 0);
+    #[tokio::test]
+    async fn bar_with_date() {
+        let day = |d| date_to_value(NaiveDate::from_ymd_opt(2024, 1, d).unwrap());
+
+        let chart = Chart::new(Axis::new(AxisKind::Date), Axis::new(AxisKind::Value))
+            .title("kawaemon")
+            .size(640, 480)
+            .series(Series::Bar {
+                name: "kawaemon".into(),
+                points: vec![(day(1), 1.0), (day(2), 3.5), (day(4), 2.0)],
+            });
+
+        let result = Plotters::new().plot(chart).await.unwrap();
+
+        assert_ne!(result.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn heatmap() {
+        let chart = Chart::new(
+            Axis::new(AxisKind::Category(vec!["0".into(), "1".into(), "2".into()])),
+            Axis::new(AxisKind::Category(vec!["月".into(), "火".into()])),
+        )
+        .title("kawaemon")
+        .series(Series::Heatmap {
+            values: vec![vec![0.0, 1.0, 2.0], vec![3.0, 4.0, 0.0]],
+        });
+
+        let result = Plotters::new().plot(chart).await.unwrap();
+
+        assert_ne!(result.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn unsupported_format() {
+        let plotters = Plotters::new();
+        assert!(!plotters.supports(OutputFormat::Svg));
+
+        let chart = Chart::new(Axis::new(AxisKind::Value), Axis::new(AxisKind::Value))
+            .format(OutputFormat::Svg);
+
+        assert!(plotters.plot(chart).await.is_err());
+    }
 }
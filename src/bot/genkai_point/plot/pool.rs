@@ -0,0 +1,279 @@
+use {
+    crate::bot::genkai_point::{
+        Plotter,
+        plot::chart::{Chart, OutputFormat},
+    },
+    anyhow::{Context as _, Result},
+    parking_lot::Mutex,
+    sha2::{Digest, Sha256},
+    std::{
+        collections::{HashMap, VecDeque},
+        sync::Arc,
+        time::Duration,
+    },
+    tokio::sync::Semaphore,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum RenderError {
+    #[error("グラフの描画が混み合っています。しばらくしてからもう一度お試しください")]
+    Busy,
+    #[error("グラフの描画に時間がかかりすぎたため中断しました")]
+    Timeout,
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderPoolOptions {
+    /// 同時に描画する数
+    pub workers: usize,
+    /// 描画を待てる数。これを超えたリクエストは `RenderError::Busy` になります
+    pub queue: usize,
+    /// 1 回の描画 (待ち時間を含みます) にかけられる時間
+    pub timeout: Duration,
+    /// キャッシュしておく画像の数
+    pub cache_size: usize,
+}
+
+impl Default for RenderPoolOptions {
+    fn default() -> Self {
+        Self {
+            workers: 2,
+            queue: 8,
+            timeout: Duration::from_secs(30),
+            cache_size: 32,
+        }
+    }
+}
+
+/// 描画をブロッキングスレッドで行う `Plotter` です。
+/// 同じデータのグラフはキャッシュから返します
+pub struct RenderPool<P> {
+    plotter: Arc<P>,
+    options: RenderPoolOptions,
+    // workers + queue permits; requests that can't take one are rejected immediately
+    accepting: Arc<Semaphore>,
+    workers: Arc<Semaphore>,
+    cache: Mutex<Cache>,
+}
+
+type Key = [u8; 32];
+
+struct Cache {
+    images: HashMap<Key, Vec<u8>>,
+    // oldest first
+    order: VecDeque<Key>,
+}
+
+impl Cache {
+    fn get(&mut self, key: &Key) -> Option<Vec<u8>> {
+        let image = self.images.get(key)?.clone();
+
+        self.order.retain(|x| x != key);
+        self.order.push_back(*key);
+
+        Some(image)
+    }
+
+    fn insert(&mut self, key: Key, image: Vec<u8>, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+
+        if self.images.insert(key, image).is_none() {
+            self.order.push_back(key);
+        }
+
+        while self.order.len() > capacity {
+            let oldest = self.order.pop_front().unwrap();
+            self.images.remove(&oldest);
+        }
+    }
+}
+
+impl<P: Plotter> RenderPool<P> {
+    pub fn new(plotter: P, options: RenderPoolOptions) -> Self {
+        let workers = options.workers.max(1);
+
+        Self {
+            plotter: Arc::new(plotter),
+            accepting: Arc::new(Semaphore::new(workers + options.queue)),
+            workers: Arc::new(Semaphore::new(workers)),
+            options,
+            cache: Mutex::new(Cache {
+                images: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    async fn render(&self, chart: Chart) -> Result<Vec<u8>> {
+        let _accepted = self
+            .accepting
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| RenderError::Busy)?;
+
+        // dropping this future while waiting here cancels the request before rendering
+        let worker = self
+            .workers
+            .clone()
+            .acquire_owned()
+            .await
+            .context("render pool is closed")?;
+
+        let plotter = self.plotter.clone();
+        let handle = tokio::runtime::Handle::current();
+
+        // a running render can't be interrupted, so the worker is kept busy until it finishes
+        // even if the request has been timed out or cancelled
+        let task = tokio::task::spawn_blocking(move || {
+            let result = handle.block_on(plotter.plot(chart));
+            drop(worker);
+            result
+        });
+
+        task.await.context("render task panicked")?
+    }
+}
+
+impl<P: Plotter> Plotter for RenderPool<P> {
+    async fn plot(&self, chart: Chart) -> Result<Vec<u8>> {
+        let key = Sha256::digest(format!("{chart:?}")).into();
+
+        if let Some(image) = self.cache.lock().get(&key) {
+            return Ok(image);
+        }
+
+        let image = tokio::time::timeout(self.options.timeout, self.render(chart))
+            .await
+            .map_err(|_| RenderError::Timeout)??;
+
+        self.cache
+            .lock()
+            .insert(key, image.clone(), self.options.cache_size);
+
+        Ok(image)
+    }
+
+    fn supports(&self, format: OutputFormat) -> bool {
+        self.plotter.supports(format)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::bot::genkai_point::plot::chart::{Axis, AxisKind, Series},
+        std::sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    struct SlowPlotter {
+        wait: Duration,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Plotter for SlowPlotter {
+        async fn plot(&self, chart: Chart) -> Result<Vec<u8>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(self.wait);
+            Ok(chart.title.unwrap_or_default().into_bytes())
+        }
+    }
+
+    fn pool(
+        wait_ms: u64,
+        options: RenderPoolOptions,
+    ) -> (Arc<RenderPool<SlowPlotter>>, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let plotter = SlowPlotter {
+            wait: Duration::from_millis(wait_ms),
+            calls: calls.clone(),
+        };
+
+        (Arc::new(RenderPool::new(plotter, options)), calls)
+    }
+
+    fn chart(title: &str) -> Chart {
+        Chart::new(Axis::new(AxisKind::Value), Axis::new(AxisKind::Value))
+            .title(title)
+            .series(Series::Line {
+                name: "kawaemon".into(),
+                points: vec![(0.0, 1.0), (1.0, 4.0)],
+            })
+    }
+
+    fn render_error(result: Result<Vec<u8>>) -> Option<RenderError> {
+        result.unwrap_err().downcast::<RenderError>().ok()
+    }
+
+    #[tokio::test]
+    async fn cache() {
+        let (pool, calls) = pool(0, RenderPoolOptions::default());
+
+        assert_eq!(pool.plot(chart("a")).await.unwrap(), b"a");
+        assert_eq!(pool.plot(chart("a")).await.unwrap(), b"a");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        assert_eq!(pool.plot(chart("b")).await.unwrap(), b"b");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn cache_eviction() {
+        let mut cache = Cache {
+            images: HashMap::new(),
+            order: VecDeque::new(),
+        };
+
+        cache.insert([0; 32], vec![0], 2);
+        cache.insert([1; 32], vec![1], 2);
+        // touch the oldest one so that [1; 32] gets evicted instead
+        assert_eq!(cache.get(&[0; 32]), Some(vec![0]));
+        cache.insert([2; 32], vec![2], 2);
+
+        assert_eq!(cache.get(&[1; 32]), None);
+        assert_eq!(cache.get(&[0; 32]), Some(vec![0]));
+        assert_eq!(cache.get(&[2; 32]), Some(vec![2]));
+    }
+
+    #[tokio::test]
+    async fn busy() {
+        let options = RenderPoolOptions {
+            workers: 1,
+            queue: 0,
+            ..Default::default()
+        };
+        let (pool, _) = pool(300, options);
+
+        let first = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.plot(chart("a")).await }
+        });
+        // let the first request take the worker
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(matches!(
+            render_error(pool.plot(chart("b")).await),
+            Some(RenderError::Busy)
+        ));
+        assert_eq!(first.await.unwrap().unwrap(), b"a");
+
+        // accepts again after the first one finished
+        assert_eq!(pool.plot(chart("b")).await.unwrap(), b"b");
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let options = RenderPoolOptions {
+            timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let (pool, _) = pool(300, options);
+
+        assert!(matches!(
+            render_error(pool.plot(chart("a")).await),
+            Some(RenderError::Timeout)
+        ));
+    }
+}
@@ -0,0 +1,413 @@
+use {
+    super::Escaper,
+    anyhow::{Context as _, Result},
+    reqwest::{Client, RequestBuilder},
+    serde::Deserialize,
+    std::fmt::Write,
+    url::Url,
+};
+
+const MAX_SHOWN_FILES: usize = 10;
+const MAX_HUNK_LINES: usize = 20;
+
+/// github.com 上の issue, pull request, commit へのリンク
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) enum GitHubLink {
+    Issue {
+        owner: String,
+        repo: String,
+        number: u64,
+    },
+    Pull {
+        owner: String,
+        repo: String,
+        number: u64,
+    },
+    Commit {
+        owner: String,
+        repo: String,
+        sha: String,
+    },
+}
+
+impl GitHubLink {
+    // e.g.: https://github.com/approvers/rusty-ponyo/issues/12
+    //       https://github.com/approvers/rusty-ponyo/pull/34/files
+    //       https://github.com/approvers/rusty-ponyo/commit/02bb011de7d06e242a275dd9a9126a21effc6854
+    pub(super) fn parse(url: &Url) -> Option<Self> {
+        if url.host_str()? != "github.com" {
+            return None;
+        }
+
+        let mut segments = url.path_segments()?;
+        let owner = segments.next()?.to_owned();
+        let repo = segments.next()?.to_owned();
+        let kind = segments.next()?;
+        let id = segments.next()?;
+
+        match kind {
+            "issues" => Some(Self::Issue {
+                owner,
+                repo,
+                number: id.parse().ok()?,
+            }),
+            "pull" => Some(Self::Pull {
+                owner,
+                repo,
+                number: id.parse().ok()?,
+            }),
+            "commit" if id.len() >= 7 && id.chars().all(|c| c.is_ascii_hexdigit()) => {
+                Some(Self::Commit {
+                    owner,
+                    repo,
+                    sha: id.to_owned(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub(super) fn api_path(&self) -> String {
+        match self {
+            Self::Issue {
+                owner,
+                repo,
+                number,
+            } => format!("/repos/{owner}/{repo}/issues/{number}"),
+            Self::Pull {
+                owner,
+                repo,
+                number,
+            } => format!("/repos/{owner}/{repo}/pulls/{number}"),
+            Self::Commit { owner, repo, sha } => format!("/repos/{owner}/{repo}/commits/{sha}"),
+        }
+    }
+}
+
+/// GitHub REST API の接続先
+pub(super) struct GitHubApi {
+    base: String,
+    token: Option<String>,
+}
+
+impl GitHubApi {
+    pub(super) fn new(token: Option<String>) -> Self {
+        Self::with_base("https://api.github.com", token)
+    }
+
+    pub(super) fn with_base(base: &str, token: Option<String>) -> Self {
+        Self {
+            base: base.trim_end_matches('/').to_owned(),
+            token,
+        }
+    }
+
+    pub(super) fn request(&self, client: &Client, api_path: &str) -> RequestBuilder {
+        let req = client
+            .get(format!("{}{}", self.base, api_path))
+            .header("Accept", "application/vnd.github+json")
+            .header("X-GitHub-Api-Version", "2022-11-28");
+
+        match &self.token {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        }
+    }
+}
+
+/// `prefix` から始まるブランチとタグを取得するための API のパス
+pub(super) fn matching_refs_paths(owner: &str, repo: &str, prefix: &str) -> [String; 2] {
+    ["heads", "tags"].map(|kind| format!("/repos/{owner}/{repo}/git/matching-refs/{kind}/{prefix}"))
+}
+
+/// matching-refs API のレスポンスから ref の名前を取り出します
+pub(super) fn parse_refs(body: &str) -> Result<Vec<String>> {
+    #[derive(Deserialize)]
+    struct ApiRef {
+        #[serde(rename = "ref")]
+        name: String,
+    }
+
+    let refs = serde_json::from_str::<Vec<ApiRef>>(body)
+        .context("failed to parse matching-refs api response")?;
+
+    Ok(refs
+        .into_iter()
+        .filter_map(|x| {
+            let name = x.name.strip_prefix("refs/")?;
+            let (_kind, name) = name.split_once('/')?;
+            Some(name.to_owned())
+        })
+        .collect())
+}
+
+/// `ref_and_path` (e.g. `feature/gh/src/lib.rs`) を、存在する ref のうち最も長く一致するものとパスに分割します
+pub(super) fn split_ref<'a>(refs: &[String], ref_and_path: &'a str) -> Option<(&'a str, &'a str)> {
+    refs.iter()
+        .filter(|r| {
+            ref_and_path
+                .strip_prefix(r.as_str())
+                .is_some_and(|rest| rest.len() > 1 && rest.starts_with('/'))
+        })
+        .max_by_key(|r| r.len())
+        .map(|r| (&ref_and_path[..r.len()], &ref_and_path[r.len() + 1..]))
+}
+
+#[derive(Deserialize)]
+struct ApiUser {
+    login: String,
+}
+
+#[derive(Deserialize)]
+struct ApiLabel {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct ApiIssue {
+    title: String,
+    state: String,
+    user: ApiUser,
+    labels: Vec<ApiLabel>,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    merged: bool,
+}
+
+#[derive(Deserialize)]
+struct ApiCommit {
+    sha: String,
+    commit: ApiCommitDetail,
+    author: Option<ApiUser>,
+    stats: ApiStats,
+    files: Vec<ApiFile>,
+}
+
+#[derive(Deserialize)]
+struct ApiCommitDetail {
+    message: String,
+    author: ApiCommitAuthor,
+}
+
+#[derive(Deserialize)]
+struct ApiCommitAuthor {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct ApiStats {
+    additions: u64,
+    deletions: u64,
+}
+
+#[derive(Deserialize)]
+struct ApiFile {
+    filename: String,
+    additions: u64,
+    deletions: u64,
+    patch: Option<String>,
+}
+
+/// API のレスポンスからプレビューを生成します
+pub(super) fn render(link: &GitHubLink, body: &str, escaper: &mut Escaper) -> Result<String> {
+    match link {
+        GitHubLink::Issue {
+            owner,
+            repo,
+            number,
+        }
+        | GitHubLink::Pull {
+            owner,
+            repo,
+            number,
+        } => {
+            let issue = serde_json::from_str::<ApiIssue>(body)
+                .context("failed to parse issue api response")?;
+
+            let state = if issue.merged {
+                "merged"
+            } else if issue.draft {
+                "draft"
+            } else {
+                &issue.state
+            };
+
+            let mut buf = String::new();
+
+            macro_rules! w { ($($arg:tt)*) => { let _ = writeln!(buf, $($arg)*); } }
+
+            w!(
+                "{owner}/{repo}#{number} [{state}] {}",
+                escaper.escape(&issue.title)
+            );
+
+            let mut info = format!("author: {}", issue.user.login);
+            if !issue.labels.is_empty() {
+                let labels = issue
+                    .labels
+                    .iter()
+                    .map(|x| x.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let _ = write!(info, " / labels: {}", escaper.escape(&labels));
+            }
+            w!("{info}");
+
+            Ok(buf)
+        }
+
+        GitHubLink::Commit { owner, repo, .. } => {
+            let commit = serde_json::from_str::<ApiCommit>(body)
+                .context("failed to parse commit api response")?;
+
+            let title = commit.commit.message.lines().next().unwrap_or("");
+            let author = match &commit.author {
+                Some(user) => &user.login,
+                None => &commit.commit.author.name,
+            };
+
+            let mut buf = String::new();
+
+            macro_rules! w { ($($arg:tt)*) => { let _ = writeln!(buf, $($arg)*); } }
+
+            w!(
+                "{owner}/{repo}@{} {}",
+                &commit.sha[..7.min(commit.sha.len())],
+                escaper.escape(title)
+            );
+            w!(
+                "author: {author} / {} files changed, +{} -{}",
+                commit.files.len(),
+                commit.stats.additions,
+                commit.stats.deletions
+            );
+
+            w!("```");
+            for file in commit.files.iter().take(MAX_SHOWN_FILES) {
+                w!(
+                    "{} | +{} -{}",
+                    escaper.escape(&file.filename),
+                    file.additions,
+                    file.deletions
+                );
+            }
+            if commit.files.len() > MAX_SHOWN_FILES {
+                w!(
+                    "... and {} more files",
+                    commit.files.len() - MAX_SHOWN_FILES
+                );
+            }
+            w!("```");
+
+            let first_hunk = commit
+                .files
+                .iter()
+                .find_map(|f| Some((f, first_hunk(f.patch.as_deref()?)?)));
+
+            if let Some((file, hunk)) = first_hunk {
+                w!("{}", escaper.escape(&file.filename));
+                w!("```diff");
+                w!("{}", escaper.escape(&hunk));
+                w!("```");
+            }
+
+            Ok(buf)
+        }
+    }
+}
+
+fn first_hunk(patch: &str) -> Option<String> {
+    let mut lines = patch.lines();
+    let header = lines.next().filter(|x| x.starts_with("@@"))?;
+
+    let hunk = std::iter::once(header)
+        .chain(lines.take_while(|x| !x.starts_with("@@")))
+        .take(MAX_HUNK_LINES)
+        .collect::<Vec<_>>();
+
+    Some(hunk.join("\n"))
+}
+
+#[cfg(test)]
+mod test {
+    use {super::*, pretty_assertions::assert_eq};
+
+    fn parse(url: &str) -> Option<GitHubLink> {
+        GitHubLink::parse(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn parse_links() {
+        assert_eq!(
+            parse("https://github.com/approvers/rusty-ponyo/issues/12"),
+            Some(GitHubLink::Issue {
+                owner: "approvers".into(),
+                repo: "rusty-ponyo".into(),
+                number: 12,
+            })
+        );
+        assert_eq!(
+            parse("https://github.com/approvers/rusty-ponyo/pull/34/files"),
+            Some(GitHubLink::Pull {
+                owner: "approvers".into(),
+                repo: "rusty-ponyo".into(),
+                number: 34,
+            })
+        );
+        assert_eq!(
+            parse("https://github.com/approvers/rusty-ponyo/commit/02bb011"),
+            Some(GitHubLink::Commit {
+                owner: "approvers".into(),
+                repo: "rusty-ponyo".into(),
+                sha: "02bb011".into(),
+            })
+        );
+
+        assert_eq!(
+            parse("https://github.com/approvers/rusty-ponyo/issues"),
+            None
+        );
+        assert_eq!(
+            parse("https://github.com/approvers/rusty-ponyo/commit/main"),
+            None
+        );
+        assert_eq!(
+            parse("https://gitlab.com/approvers/rusty-ponyo/issues/1"),
+            None
+        );
+    }
+
+    #[test]
+    fn split_ref_by_longest_match() {
+        let refs = ["feature", "feature/gh", "feature/ghost"].map(String::from);
+
+        assert_eq!(
+            split_ref(&refs, "feature/gh/src/lib.rs"),
+            Some(("feature/gh", "src/lib.rs"))
+        );
+        assert_eq!(
+            split_ref(&refs, "feature/src/lib.rs"),
+            Some(("feature", "src/lib.rs"))
+        );
+        assert_eq!(split_ref(&refs, "feature/gh"), Some(("feature", "gh")));
+        assert_eq!(split_ref(&refs, "master/src/lib.rs"), None);
+    }
+
+    #[test]
+    fn parse_matching_refs() {
+        assert_eq!(
+            parse_refs(r#"[{"ref":"refs/heads/feature/gh"},{"ref":"refs/tags/v1.0"}]"#).unwrap(),
+            vec!["feature/gh", "v1.0"]
+        );
+    }
+
+    #[test]
+    fn first_hunk_only() {
+        assert_eq!(
+            first_hunk("@@ -1,2 +1,2 @@\n-a\n+b\n@@ -10,1 +10,1 @@\n-c\n+d").as_deref(),
+            Some("@@ -1,2 +1,2 @@\n-a\n+b")
+        );
+        assert_eq!(first_hunk("Binary files differ"), None);
+    }
+}
@@ -0,0 +1,327 @@
+use {
+    super::PreviewError,
+    anyhow::Context as _,
+    reqwest::{
+        Client, RequestBuilder, StatusCode,
+        header::{ETAG, IF_NONE_MATCH},
+    },
+    std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    },
+    tokio::sync::Mutex,
+};
+
+#[allow(clippy::identity_op)]
+pub(super) const DL_SIZE_LIMIT: u64 = 1 * 1024 * 1024;
+
+// キャッシュに保持するレスポンスの合計サイズの上限
+const MAX_CACHE_BYTES: usize = 32 * 1024 * 1024;
+
+// ブランチなど、内容が変わりうるものをそのまま使う期間
+const DEFAULT_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// キャッシュしたレスポンスをどれだけ信用するか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Freshness {
+    /// コミットハッシュで指定されたものなど、内容が変わらないもの
+    Immutable,
+    /// TTL が過ぎたら ETag を使って再検証するもの
+    Revalidate,
+}
+
+struct Entry {
+    body: String,
+    etag: Option<String>,
+    fetched_at: Instant,
+    freshness: Freshness,
+    last_used: u64,
+}
+
+struct Inner {
+    entries: HashMap<String, Entry>,
+    bytes: usize,
+    clock: u64,
+}
+
+/// メッセージをまたいで共有される、サイズ上限付きの LRU キャッシュ
+pub(super) struct HttpCache {
+    inner: Mutex<Inner>,
+    max_bytes: usize,
+    ttl: Duration,
+}
+
+enum Lookup {
+    Fresh(String),
+    Stale(Option<String>),
+    Miss,
+}
+
+impl HttpCache {
+    pub(super) fn new() -> Self {
+        Self::with_limits(MAX_CACHE_BYTES, DEFAULT_TTL)
+    }
+
+    pub(super) fn with_limits(max_bytes: usize, ttl: Duration) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                bytes: 0,
+                clock: 0,
+            }),
+            max_bytes,
+            ttl,
+        }
+    }
+
+    /// キャッシュを使ってリクエストを送り、レスポンスの本文を返します。
+    pub(super) async fn fetch(
+        &self,
+        client: &Client,
+        req: RequestBuilder,
+        freshness: Freshness,
+    ) -> Result<String, PreviewError> {
+        let req = req.build().context("failed to build request")?;
+        let key = req.url().to_string();
+
+        let mut req = req;
+        match self.lookup(&key).await {
+            Lookup::Fresh(body) => return Ok(body),
+            Lookup::Stale(Some(etag)) => {
+                if let Ok(etag) = etag.parse() {
+                    req.headers_mut().insert(IF_NONE_MATCH, etag);
+                }
+            }
+            Lookup::Stale(None) | Lookup::Miss => {}
+        }
+
+        let res = client
+            .execute(req)
+            .await
+            .context("failed to make request")?;
+
+        if res.status() == StatusCode::NOT_MODIFIED {
+            // the entry may have been evicted while waiting for the response
+            return self.touch(&key).await.ok_or(PreviewError::Fetch {
+                status_code: res.status(),
+            });
+        }
+
+        // API responses may come without Content-Length, so the body is checked again below.
+        if let Some(actual) = res.content_length().filter(|&c| c > DL_SIZE_LIMIT) {
+            return Err(PreviewError::Size {
+                expected: DL_SIZE_LIMIT,
+                actual,
+            });
+        }
+
+        let res = match res.error_for_status() {
+            Ok(res) => res,
+            Err(code) => {
+                if let Some(code) = code.status() {
+                    return Err(PreviewError::Fetch { status_code: code });
+                }
+                Err(code).context("failed to fetch code")?
+            }
+        };
+
+        let etag = res
+            .headers()
+            .get(ETAG)
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.to_owned());
+
+        let body = res.text().await.context("failed to download rawcode")?;
+
+        if body.len() as u64 > DL_SIZE_LIMIT {
+            return Err(PreviewError::Size {
+                expected: DL_SIZE_LIMIT,
+                actual: body.len() as u64,
+            });
+        }
+
+        self.insert(key, body.clone(), etag, freshness).await;
+
+        Ok(body)
+    }
+
+    async fn lookup(&self, key: &str) -> Lookup {
+        let mut inner = self.inner.lock().await;
+        inner.clock += 1;
+        let clock = inner.clock;
+
+        let Some(entry) = inner.entries.get_mut(key) else {
+            return Lookup::Miss;
+        };
+        entry.last_used = clock;
+
+        let is_fresh = match entry.freshness {
+            Freshness::Immutable => true,
+            Freshness::Revalidate => entry.fetched_at.elapsed() < self.ttl,
+        };
+
+        if is_fresh {
+            Lookup::Fresh(entry.body.clone())
+        } else {
+            Lookup::Stale(entry.etag.clone())
+        }
+    }
+
+    // 304 Not Modified を受け取ったエントリを新しいものとして扱う
+    async fn touch(&self, key: &str) -> Option<String> {
+        let mut inner = self.inner.lock().await;
+        let entry = inner.entries.get_mut(key)?;
+        entry.fetched_at = Instant::now();
+        Some(entry.body.clone())
+    }
+
+    async fn insert(&self, key: String, body: String, etag: Option<String>, freshness: Freshness) {
+        if body.len() > self.max_bytes {
+            return;
+        }
+
+        let mut inner = self.inner.lock().await;
+        inner.clock += 1;
+        let clock = inner.clock;
+
+        if let Some(old) = inner.entries.remove(&key) {
+            inner.bytes -= old.body.len();
+        }
+
+        while inner.bytes + body.len() > self.max_bytes {
+            let Some(lru) = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| k.clone())
+            else {
+                break;
+            };
+
+            let removed = inner.entries.remove(&lru).unwrap();
+            inner.bytes -= removed.body.len();
+        }
+
+        inner.bytes += body.len();
+        inner.entries.insert(
+            key,
+            Entry {
+                body,
+                etag,
+                fetched_at: Instant::now(),
+                freshness,
+                last_used: clock,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        axum::{
+            Router,
+            http::{HeaderMap, StatusCode},
+            response::IntoResponse,
+            routing::get,
+        },
+        pretty_assertions::assert_eq,
+        std::sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    // 本文のサイズが 4 バイトのファイルを返し、リクエストされた回数を数えるサーバー
+    async fn stand_in() -> (String, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let not_modified = Arc::new(AtomicUsize::new(0));
+
+        let app = Router::new().route(
+            "/{name}",
+            get({
+                let hits = hits.clone();
+                let not_modified = not_modified.clone();
+                move |headers: HeaderMap| async move {
+                    hits.fetch_add(1, Ordering::SeqCst);
+
+                    if headers.get("if-none-match").is_some_and(|x| x == "\"v1\"") {
+                        not_modified.fetch_add(1, Ordering::SeqCst);
+                        return StatusCode::NOT_MODIFIED.into_response();
+                    }
+
+                    ([("etag", "\"v1\"")], "body").into_response()
+                }
+            }),
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        (format!("http://{addr}"), hits, not_modified)
+    }
+
+    async fn fetch(cache: &HttpCache, url: &str, freshness: Freshness) -> String {
+        let client = Client::new();
+        cache
+            .fetch(&client, client.get(url), freshness)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn immutable_is_cached_forever() {
+        let (base, hits, _) = stand_in().await;
+        let cache = HttpCache::with_limits(1024, Duration::ZERO);
+
+        let url = format!("{base}/a");
+        assert_eq!(fetch(&cache, &url, Freshness::Immutable).await, "body");
+        assert_eq!(fetch(&cache, &url, Freshness::Immutable).await, "body");
+
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn revalidates_with_etag_after_ttl() {
+        let (base, hits, not_modified) = stand_in().await;
+        let url = format!("{base}/a");
+
+        let cache = HttpCache::with_limits(1024, Duration::from_secs(60));
+        fetch(&cache, &url, Freshness::Revalidate).await;
+        fetch(&cache, &url, Freshness::Revalidate).await;
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        let cache = HttpCache::with_limits(1024, Duration::ZERO);
+        fetch(&cache, &url, Freshness::Revalidate).await;
+        assert_eq!(fetch(&cache, &url, Freshness::Revalidate).await, "body");
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        assert_eq!(not_modified.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used() {
+        let (base, hits, _) = stand_in().await;
+
+        // room for two 4-byte bodies
+        let cache = HttpCache::with_limits(8, Duration::ZERO);
+
+        let (a, b, c) = (
+            format!("{base}/a"),
+            format!("{base}/b"),
+            format!("{base}/c"),
+        );
+        fetch(&cache, &a, Freshness::Immutable).await;
+        fetch(&cache, &b, Freshness::Immutable).await;
+        fetch(&cache, &a, Freshness::Immutable).await; // a is now newer than b
+        fetch(&cache, &c, Freshness::Immutable).await; // evicts b
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+        fetch(&cache, &a, Freshness::Immutable).await;
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+        fetch(&cache, &b, Freshness::Immutable).await;
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+    }
+}
@@ -1,11 +1,24 @@
+mod api;
+mod cache;
+mod provider;
+mod ratelimit;
+mod render;
+
 use {
-    crate::bot::{BotService, Context, Message, Runtime, parse_command, ui},
+    crate::bot::{
+        BotService, Context, Message, Runtime, SendAttachment, SendMessage, pagination,
+        parse_command, ui,
+    },
     anyhow::{Context as _, Result},
+    api::{GitHubApi, GitHubLink},
+    cache::{Freshness, HttpCache},
     derivative::Derivative,
     once_cell::sync::Lazy,
+    provider::{CodePermalink, CodeProvider, Gist, GitHub, GitLab, Gitea},
+    ratelimit::RateLimiter,
     regex::Regex,
     reqwest::StatusCode,
-    std::{collections::HashMap, fmt::Write, path::Path},
+    std::{fmt::Write, time::Instant},
     url::Url,
 };
 
@@ -14,11 +27,15 @@ const PREFIX: &str = "g!gh";
 
 const DEFAULT_SHOWN_LINES: usize = 12;
 
-#[allow(clippy::identity_op)]
-const DL_SIZE_LIMIT: u64 = 1 * 1024 * 1024;
+// 行の指定が無いリンクでファイルの先頭から表示する行数
+const WHOLE_FILE_SHOWN_LINES: usize = 20;
+
+// 大量の行を指定されたときにチャンネルを埋め尽くさないための上限
+const MAX_PREVIEW_PAGES: usize = 10;
 
 ui! {
-    /// GitHub のコードリンクからプレビューを生成します
+    /// GitHub, GitLab, Gitea/Forgejo, gist のコードリンクや
+    /// GitHub の issue, pull request, commit のリンクからプレビューを生成します
     struct Ui {
         name: NAME,
         prefix: PREFIX,
@@ -30,6 +47,10 @@ ui! {
 enum Command {
     /// 指定されたリンクのプレビューを生成します。
     Preview {
+        /// コードをシンタックスハイライトした画像として送信します
+        #[clap(long)]
+        image: bool,
+
         #[allow(rustdoc::bare_urls)]
         /// コードのリンク
         /// 例: https://github.com/approvers/rusty-ponyo/blob/02bb011de7d06e242a275dd9a9126a21effc6854/Cargo.toml#L48-L52
@@ -43,7 +64,8 @@ enum PreviewError {
     NoUrlDetected,
     Fetch { status_code: StatusCode },
     Size { expected: u64, actual: u64 },
-    CodeTooLong,
+    CodeTooLong { lines: usize },
+    RateLimited,
     Internal(#[derivative(Debug = "ignore")] anyhow::Error),
 }
 
@@ -53,12 +75,71 @@ impl From<anyhow::Error> for PreviewError {
     }
 }
 
-pub struct GitHubCodePreviewBot;
+/// プレビューに含める文字列中の ``` を ''' に置き換え、置き換えが起きたかを記録します
+struct Escaper {
+    replaced: bool,
+}
+
+impl Escaper {
+    fn new() -> Self {
+        Self { replaced: false }
+    }
+
+    fn escape(&mut self, text: &str) -> String {
+        self.replaced |= text.contains("```");
+        text.replace("```", "'''")
+    }
+}
 
-const MAX_PREVIEW_MSG_LENGTH: usize = 2000;
+pub struct GitHubCodePreviewBot {
+    client: reqwest::Client,
+    providers: Vec<Box<dyn CodeProvider>>,
+    github: GitHubApi,
+    cache: HttpCache,
+    limiter: RateLimiter,
+}
 
 impl GitHubCodePreviewBot {
-    async fn on_command(&self, message: &str, ctx: &impl Context) -> Result<()> {
+    /// github.com, gist.github.com, gitlab.com, codeberg.org に加えて、
+    /// `gitlab_hosts` と `gitea_hosts` に指定されたセルフホストのインスタンスのリンクを扱います。
+    /// `github_token` は GitHub REST API の呼び出しに使われます。
+    pub fn new(
+        gitlab_hosts: &[String],
+        gitea_hosts: &[String],
+        github_token: Option<String>,
+    ) -> Self {
+        let mut providers: Vec<Box<dyn CodeProvider>> = vec![
+            Box::new(GitHub::new()),
+            Box::new(Gist::new()),
+            Box::new(GitLab::new("gitlab.com")),
+            Box::new(Gitea::new("codeberg.org")),
+        ];
+
+        for host in gitlab_hosts {
+            providers.push(Box::new(GitLab::new(host)));
+        }
+        for host in gitea_hosts {
+            providers.push(Box::new(Gitea::new(host)));
+        }
+
+        Self::with_providers(providers, GitHubApi::new(github_token))
+    }
+
+    fn with_providers(providers: Vec<Box<dyn CodeProvider>>, github: GitHubApi) -> Self {
+        Self {
+            // api.github.com requires User-Agent
+            client: reqwest::Client::builder()
+                .user_agent("rusty-ponyo")
+                .build()
+                .unwrap(),
+            providers,
+            github,
+            cache: HttpCache::new(),
+            limiter: RateLimiter::new(),
+        }
+    }
+
+    async fn on_command(&self, message: &str, channel_id: u64, ctx: &impl Context) -> Result<()> {
         use Command::*;
 
         let Some(parsed) = parse_command::<Ui>(message, ctx).await? else {
@@ -66,11 +147,11 @@ impl GitHubCodePreviewBot {
         };
 
         match parsed.command {
-            Preview { url } => {
-                let preview_result = self.gen_preview(&url).await;
+            Preview { image, url } => {
+                let preview_result = self.gen_preview(&url, channel_id, image).await;
 
                 match preview_result {
-                    Ok(ref p) => ctx.send_text_message(p).await,
+                    Ok(ref p) => p.send(ctx).await,
                     Err(ref e) => {
                         ctx.send_text_message(&format!("couldn't generate preview: ```{e:#?}```"))
                             .await
@@ -87,64 +168,216 @@ impl GitHubCodePreviewBot {
         }
     }
 
-    async fn gen_preview(&self, message: &str) -> Result<String, PreviewError> {
-        let links = CodePermalink::find_from_str(message);
-        if links.is_empty() {
+    async fn gen_preview(
+        &self,
+        message: &str,
+        channel_id: u64,
+        force_image: bool,
+    ) -> Result<Preview, PreviewError> {
+        let links = find_permalinks(message, &self.providers);
+        let github_links = find_github_links(message);
+        if links.is_empty() && github_links.is_empty() {
             return Err(PreviewError::NoUrlDetected);
         }
 
-        let cache = CodeCache::new();
-        let mut msg = String::new();
-        let mut buf = String::new();
-
-        let mut is_skipped = false;
-        let mut is_backquote_replaced = false;
+        let count = links.len() + github_links.len();
+        let granted = self
+            .limiter
+            .try_acquire(channel_id, count, Instant::now())
+            .await;
+        if granted == 0 {
+            return Err(PreviewError::RateLimited);
+        }
 
-        for link in links {
-            let code = link.get_code(&cache).await?;
+        let mut preview = self
+            .build_preview(links, github_links, granted, force_image)
+            .await?;
 
-            is_backquote_replaced |= code.contains("```");
+        let skipped = count - granted;
+        if skipped > 0 {
+            preview
+                .pages
+                .push(format!("{skipped} link(s) were skipped due to rate limit"));
+        }
 
-            let code = code.replace("```", "'''");
+        Ok(preview)
+    }
 
-            macro_rules! w { ($($arg:tt)*) => { let _ = writeln!(buf, $($arg)*); } }
+    // previews the first `granted` links, the rest are dropped
+    async fn build_preview(
+        &self,
+        mut links: Vec<CodePermalink>,
+        mut github_links: Vec<GitHubLink>,
+        granted: usize,
+        force_image: bool,
+    ) -> Result<Preview, PreviewError> {
+        links.truncate(granted);
+        github_links.truncate(granted - links.len());
+
+        let mut snippets = vec![];
+        for mut link in links {
+            self.resolve_ref(&mut link).await;
+            snippets.push(self.get_code(link).await?);
+        }
 
-            w!(
-                "{}/{} [{}] : {}",
-                link.user,
-                link.repo,
-                link.branch,
-                link.path
-            );
-            w!("```{}", link.ext);
-            w!("{}", code);
-            w!("```");
+        let mut escaper = Escaper::new();
+        let mut api_blocks = vec![];
+
+        for link in github_links {
+            let freshness = match link {
+                GitHubLink::Commit { .. } => Freshness::Immutable,
+                GitHubLink::Issue { .. } | GitHubLink::Pull { .. } => Freshness::Revalidate,
+            };
+            let body = self
+                .cache
+                .fetch(
+                    &self.client,
+                    self.github.request(&self.client, &link.api_path()),
+                    freshness,
+                )
+                .await?;
+
+            api_blocks.push(api::render(&link, &body, &mut escaper)?);
+        }
 
-            if msg.chars().count() + buf.chars().count() > MAX_PREVIEW_MSG_LENGTH {
-                is_skipped = true;
-                continue;
+        let is_api_backquote_replaced = escaper.replaced;
+
+        if !force_image {
+            let mut blocks = snippets
+                .iter()
+                .map(|x| x.to_text(&mut escaper))
+                .collect::<Vec<_>>();
+            blocks.extend(api_blocks.iter().cloned());
+
+            let pages = to_pages(blocks, escaper.replaced);
+            if pages.len() <= MAX_PREVIEW_PAGES {
+                return Ok(Preview {
+                    pages,
+                    images: vec![],
+                });
             }
-
-            msg.push_str(&buf);
-            buf.clear();
         }
 
-        if msg.is_empty() {
-            return Err(PreviewError::CodeTooLong);
+        // too long to send as text. render code as images instead.
+        let longest = snippets
+            .iter()
+            .map(|x| x.code.lines().count())
+            .max()
+            .unwrap_or(0);
+        if longest > render::MAX_IMAGE_LINES {
+            return Err(PreviewError::CodeTooLong { lines: longest });
         }
 
-        if is_backquote_replaced {
-            msg.insert_str(0, "\\`\\`\\` is replaced to '''\n");
+        let pages = to_pages(api_blocks, is_api_backquote_replaced);
+        if pages.len() > MAX_PREVIEW_PAGES {
+            let lines = pages.iter().map(|x| x.lines().count()).sum();
+            return Err(PreviewError::CodeTooLong { lines });
         }
 
-        if is_skipped {
-            msg.insert_str(
-                0,
-                "some perma links are skipped due to message length limit.\n",
+        let mut images = vec![];
+        for snippet in snippets {
+            images.push(
+                tokio::task::spawn_blocking(move || snippet.to_image())
+                    .await
+                    .context("failed to join render task")??,
             );
         }
 
-        Ok(msg)
+        Ok(Preview { pages, images })
+    }
+}
+
+fn too_long_notice(lines: usize) -> String {
+    format!(
+        "preview too long ({lines} lines). narrow the range to at most {} lines",
+        render::MAX_IMAGE_LINES
+    )
+}
+
+fn to_pages(mut blocks: Vec<String>, is_backquote_replaced: bool) -> Vec<String> {
+    if is_backquote_replaced {
+        blocks.insert(0, "\\`\\`\\` is replaced to '''".to_owned());
+    }
+
+    pagination::pack(
+        blocks.iter().map(String::as_str),
+        pagination::MAX_MESSAGE_LENGTH,
+    )
+}
+
+struct Preview {
+    pages: Vec<String>,
+    images: Vec<RenderedImage>,
+}
+
+struct RenderedImage {
+    header: String,
+    name: String,
+    data: Vec<u8>,
+}
+
+impl Preview {
+    async fn send(&self, ctx: &impl Context) -> Result<()> {
+        ctx.send_pages(&self.pages).await?;
+
+        for image in &self.images {
+            ctx.send_message(SendMessage {
+                content: &image.header,
+                attachments: &[SendAttachment {
+                    name: &image.name,
+                    data: &image.data,
+                }],
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// プレビューするために取得したコード
+struct Snippet {
+    link: CodePermalink,
+    code: String,
+    first_line: usize,
+    /// ファイル全体を表示する際に省略した行があるか
+    is_truncated: bool,
+}
+
+impl Snippet {
+    fn header(&self) -> String {
+        format!(
+            "{}/{} [{}] : {}",
+            self.link.user, self.link.repo, self.link.branch, self.link.path
+        )
+    }
+
+    fn to_text(&self, escaper: &mut Escaper) -> String {
+        let mut buf = String::new();
+
+        macro_rules! w { ($($arg:tt)*) => { let _ = writeln!(buf, $($arg)*); } }
+
+        w!("{}", self.header());
+        w!("```{}", self.link.ext);
+        w!("{}", escaper.escape(&self.code));
+        w!("```");
+        if self.is_truncated {
+            w!("(showing first {WHOLE_FILE_SHOWN_LINES} lines)");
+        }
+
+        buf
+    }
+
+    fn to_image(&self) -> Result<RenderedImage> {
+        let header = self.header();
+        let data = render::render_png(&header, &self.code, &self.link.ext, self.first_line)?;
+        let filename = self.link.path.rsplit('/').next().unwrap_or("code");
+
+        Ok(RenderedImage {
+            header,
+            name: format!("{filename}.png"),
+            data,
+        })
     }
 }
 
@@ -155,148 +388,144 @@ impl<R: Runtime> BotService<R> for GitHubCodePreviewBot {
 
     async fn on_message(&self, msg: &R::Message, ctx: &R::Context) -> anyhow::Result<()> {
         if msg.content().starts_with(PREFIX) {
-            return self.on_command(msg.content(), ctx).await;
+            return self.on_command(msg.content(), msg.channel_id(), ctx).await;
         }
 
-        if let Ok(msg) = self.gen_preview(msg.content()).await {
-            ctx.send_text_message(&msg)
+        match self
+            .gen_preview(msg.content(), msg.channel_id(), false)
+            .await
+        {
+            Ok(preview) => preview.send(ctx).await.context("failed to send message")?,
+
+            // don't drop the link silently, tell how to get the preview
+            Err(PreviewError::CodeTooLong { lines }) => ctx
+                .send_text_message(&too_long_notice(lines))
                 .await
-                .context("failed to send message")?;
+                .context("failed to send message")?,
+
+            Err(_) => {}
         }
 
         Ok(())
     }
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
-struct CodePermalink {
-    user: String,
-    repo: String,
-    branch: String,
-    path: String,
-    ext: String,
-    l1: usize,
-    l2: Option<usize>,
+fn find_urls(msg: &str) -> impl Iterator<Item = Url> + '_ {
+    static URL_REGEX: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r"https?://(?:www\.)?[-a-zA-Z0-9@:%._\+~#=]{1,256}\.[a-zA-Z0-9()]{1,6}\b(?:[-a-zA-Z0-9()@:%_\+.~#?&/=]*)").unwrap()
+    });
+
+    URL_REGEX
+        .find_iter(msg)
+        .flat_map(|m| Url::parse(m.as_str()).ok())
 }
 
-// HashMap<Url, code>
-type CodeCache = HashMap<String, String>;
-
-impl CodePermalink {
-    fn find_from_str(msg: &str) -> Vec<Self> {
-        static URL_REGEX: Lazy<Regex> = Lazy::new(|| {
-            Regex::new(r"https?://(?:www\.)?[-a-zA-Z0-9@:%._\+~#=]{1,256}\.[a-zA-Z0-9()]{1,6}\b(?:[-a-zA-Z0-9()@:%_\+.~#?&/=]*)").unwrap()
-        });
-        static LINE_REGEX: Lazy<Regex> =
-            Lazy::new(|| Regex::new(r"L(?P<l1>\d+)(?:-L(?P<l2>\d+))?").unwrap());
-
-        let mut res = URL_REGEX
-            .find_iter(msg)
-            .flat_map(|m| {
-                // e.g.: https://github.com/approvers/rusty-ponyo/blob/02bb011de7d06e242a275dd9a9126a21effc6854/Cargo.toml#L48-L52
-                let url = Url::parse(m.as_str()).ok()?;
-
-                macro_rules! bail {
-                    ($e:expr) => {
-                        if !$e {
-                            return None;
-                        }
-                    };
-                }
+fn find_github_links(msg: &str) -> Vec<GitHubLink> {
+    let mut res = find_urls(msg)
+        .flat_map(|url| GitHubLink::parse(&url))
+        .collect::<Vec<_>>();
 
-                bail!(url.host_str()? == "github.com");
-
-                let mut segments = url.path_segments()?;
-                let user = segments.next()?;
-                let repo = segments.next()?;
-                bail!(segments.next()? == "blob");
-                let branch = segments.next()?;
-                let path = segments.collect::<Vec<&str>>().join("/");
-
-                let filename = url.path_segments()?.next_back()?;
-                let ext = Path::new(filename)
-                    .extension()
-                    .map(|x| x.to_str().unwrap())
-                    .unwrap_or("");
-
-                let fragment = url.fragment()?; // a part after #
-                let captures = LINE_REGEX.captures(fragment)?;
-
-                let l1 = captures.name("l1").unwrap().as_str().parse().ok()?;
-
-                let l2 = match captures.name("l2") {
-                    Some(l2) => Some(l2.as_str().parse().ok()?),
-                    None => None,
-                };
-
-                Some(Self {
-                    user: user.to_owned(),
-                    repo: repo.to_owned(),
-                    branch: branch.to_owned(),
-                    path,
-                    ext: ext.to_owned(),
-                    l1,
-                    l2,
-                })
-            })
-            .collect::<Vec<_>>();
+    res.sort_unstable();
+    res.dedup();
+    res
+}
 
-        res.sort_unstable();
-        res.dedup();
-        res
-    }
+fn find_permalinks(msg: &str, providers: &[Box<dyn CodeProvider>]) -> Vec<CodePermalink> {
+    let mut res = find_urls(msg)
+        .flat_map(|url| {
+            let host = url.host_str()?;
 
-    async fn get_code(&self, cache: &CodeCache) -> Result<String, PreviewError> {
-        let rawcode_url = format!(
-            "https://raw.githubusercontent.com/{}/{}/{}/{}",
-            self.user, self.repo, self.branch, self.path,
-        );
+            providers.iter().find(|p| p.host() == host)?.parse(&url)
+        })
+        .collect::<Vec<_>>();
+
+    res.sort_unstable();
+    res.dedup();
+    res
+}
+
+impl GitHubCodePreviewBot {
+    fn provider_for(&self, link: &CodePermalink) -> &dyn CodeProvider {
+        self.providers
+            .iter()
+            .find(|p| p.host() == link.host)
+            .expect("permalink must be parsed by one of providers")
+            .as_ref()
+    }
 
-        if let Some(code) = cache.get(&rawcode_url) {
-            return Ok(code.clone());
+    /// github.com のリンクでは URL 上の ref とパスの境界が分からないため、
+    /// API から ref の一覧を取得して最も長く一致する ref を採用します。
+    /// API が使えない場合は URL の最初のセグメントを ref として扱います。
+    async fn resolve_ref(&self, link: &mut CodePermalink) {
+        if link.host != "github.com" || link.is_immutable() || !link.path.contains('/') {
+            return;
         }
 
-        let res = reqwest::get(rawcode_url)
-            .await
-            .context("failed to make request")?;
+        let mut refs = vec![];
+        for path in api::matching_refs_paths(&link.user, &link.repo, &link.branch) {
+            let req = self.github.request(&self.client, &path);
+            let Ok(body) = self
+                .cache
+                .fetch(&self.client, req, Freshness::Revalidate)
+                .await
+            else {
+                continue;
+            };
+            refs.extend(api::parse_refs(&body).unwrap_or_default());
+        }
 
-        if !matches!(res.content_length(), Some(c) if c <= DL_SIZE_LIMIT) {
-            return Err(PreviewError::Size {
-                expected: DL_SIZE_LIMIT,
-                actual: res.content_length().unwrap(),
-            });
+        let ref_and_path = format!("{}/{}", link.branch, link.path);
+        if let Some((branch, path)) = api::split_ref(&refs, &ref_and_path) {
+            link.branch = branch.to_owned();
+            link.path = path.to_owned();
         }
+    }
 
-        let res = match res.error_for_status() {
-            Ok(res) => res,
-            Err(code) => {
-                if let Some(code) = code.status() {
-                    return Err(PreviewError::Fetch { status_code: code });
-                }
-                Err(code).context("failed to fetch code")?
-            }
+    /// 表示するコードと、ファイル全体を表示する際に省略した行があるかを返します
+    async fn get_code(&self, link: CodePermalink) -> Result<Snippet, PreviewError> {
+        let provider = self.provider_for(&link);
+
+        let freshness = match link.is_immutable() {
+            true => Freshness::Immutable,
+            false => Freshness::Revalidate,
         };
 
-        let code = res.text().await.context("failed to download rawcode")?;
+        let body = self
+            .cache
+            .fetch(
+                &self.client,
+                self.client.get(provider.raw_url(&link)),
+                freshness,
+            )
+            .await?;
+
+        let code = provider.extract_code(&link, body)?;
 
         const OFFSET: usize = DEFAULT_SHOWN_LINES / 2;
 
-        let (l1, l2) = match self.l2 {
-            Some(l2) => (self.l1, l2),
-            None => (
-                self.l1.saturating_sub(OFFSET),
-                self.l1.saturating_add(OFFSET),
-            ),
+        let (l1, l2) = match link.lines {
+            Some((l1, Some(l2))) => (l1, l2),
+            Some((l1, None)) => (l1.saturating_sub(OFFSET), l1.saturating_add(OFFSET)),
+            None => (1, WHOLE_FILE_SHOWN_LINES),
         };
 
         let skip = l1.saturating_sub(1);
 
-        Ok(code
+        let shown = code
             .lines()
             .skip(skip)
             .take(l2.saturating_sub(skip))
             .collect::<Vec<&str>>()
-            .join("\n"))
+            .join("\n");
+
+        let is_truncated = link.lines.is_none() && code.lines().count() > WHOLE_FILE_SHOWN_LINES;
+
+        Ok(Snippet {
+            link,
+            code: shown,
+            first_line: skip + 1,
+            is_truncated,
+        })
     }
 }
 
@@ -306,15 +535,120 @@ mod test {
         super::*,
         crate::bot::{Attachment, SendMessage, User},
         pretty_assertions::assert_eq,
-        std::{
-            future::Future,
-            sync::atomic::{AtomicBool, Ordering},
-        },
+        std::sync::Mutex,
     };
 
-    async fn test(input: &'static str, output: impl Into<Option<&'static str>>) {
-        let output = output.into();
+    fn numbered(lines: usize) -> String {
+        (1..=lines).map(|i| format!("line {i}\n")).collect()
+    }
+
+    // コードをホストしているサービスの代わりにローカルで応答する HTTP サーバーを立て、そのベース URL を返す
+    async fn stand_in() -> String {
+        let long = (1..=2000)
+            .map(|i| format!("{i:04}: {}\n", "x".repeat(20)))
+            .collect::<String>();
+
+        let wide = (1..=300)
+            .map(|i| format!("{i:03}: {}\n", "x".repeat(100)))
+            .collect::<String>();
+
+        let gist = serde_json::json!({
+            "files": {
+                "Cargo.toml": { "content": "[package]\nname = \"gist\"\n" },
+                "main.rs": { "content": "fn main() {\n    println!(\"hello\");\n}\n" },
+            }
+        })
+        .to_string();
+
+        let files = [
+            ("/approvers/rusty-ponyo/master/lines.txt", numbered(30)),
+            (
+                "/approvers/rusty-ponyo/master/src/main.rs",
+                "fn main() {\n    println!(\"```\");\n}\n".to_owned(),
+            ),
+            ("/approvers/rusty-ponyo/master/long.txt", long),
+            ("/approvers/rusty-ponyo/master/wide.txt", wide),
+            ("/group/sub/project/-/raw/main/src/lib.rs", numbered(10)),
+            ("/forgejo/forgejo/raw/branch/main/Makefile", numbered(10)),
+            ("/gists/abcdef", gist),
+            ("/approvers/rusty-ponyo/feature/gh/src/lib.rs", numbered(3)),
+            (
+                "/repos/approvers/rusty-ponyo/git/matching-refs/heads/feature",
+                serde_json::json!([
+                    { "ref": "refs/heads/feature" },
+                    { "ref": "refs/heads/feature/gh" },
+                ])
+                .to_string(),
+            ),
+            (
+                "/repos/approvers/rusty-ponyo/git/matching-refs/tags/feature",
+                "[]".to_owned(),
+            ),
+            (
+                "/repos/approvers/rusty-ponyo/issues/12",
+                serde_json::json!({
+                    "title": "```preview``` が壊れる",
+                    "state": "open",
+                    "user": { "login": "kawaemon" },
+                    "labels": [{ "name": "bug" }, { "name": "gh" }],
+                })
+                .to_string(),
+            ),
+            (
+                "/repos/approvers/rusty-ponyo/pulls/34",
+                serde_json::json!({
+                    "title": "Add gh preview",
+                    "state": "closed",
+                    "merged": true,
+                    "draft": false,
+                    "user": { "login": "kawaemon" },
+                    "labels": [],
+                })
+                .to_string(),
+            ),
+            (
+                "/repos/approvers/rusty-ponyo/commits/02bb011",
+                serde_json::json!({
+                    "sha": "02bb011de7d06e242a275dd9a9126a21effc6854",
+                    "commit": {
+                        "message": "Fix preview\n\nlong description",
+                        "author": { "name": "kawaemon" },
+                    },
+                    "author": null,
+                    "stats": { "additions": 3, "deletions": 1 },
+                    "files": [
+                        {
+                            "filename": "assets/logo.png",
+                            "additions": 0,
+                            "deletions": 0,
+                        },
+                        {
+                            "filename": "src/lib.rs",
+                            "additions": 3,
+                            "deletions": 1,
+                            "patch": "@@ -1,2 +1,4 @@\n-a\n+b\n+c\n+d\n@@ -10,1 +12,1 @@\n-e\n+f",
+                        },
+                    ],
+                })
+                .to_string(),
+            ),
+        ];
+
+        let app = files
+            .into_iter()
+            .fold(axum::Router::new(), |app, (path, body)| {
+                app.route(path, axum::routing::get(move || async move { body }))
+            });
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        format!("http://{addr}")
+    }
 
+    async fn send(input: &'static str) -> Vec<String> {
         struct Attach;
         impl Attachment for Attach {
             fn name(&self) -> &str {
@@ -359,14 +693,26 @@ mod test {
             fn content(&self) -> &str {
                 self.0
             }
+            fn channel_id(&self) -> u64 {
+                0
+            }
         }
 
         struct Ctx {
-            called: AtomicBool,
-            expected: Option<&'static str>,
+            sent: Mutex<Vec<String>>,
         }
         impl Context for Ctx {
-            async fn send_message(&self, _: SendMessage<'_>) -> Result<()> {
+            async fn send_message(&self, msg: SendMessage<'_>) -> Result<()> {
+                let mut content = msg.content.to_owned();
+                for attachment in msg.attachments {
+                    assert!(attachment.data.starts_with(b"\x89PNG"));
+                    content += &format!(" [{}]", attachment.name);
+                }
+                self.sent.lock().unwrap().push(content);
+                Ok(())
+            }
+
+            async fn send_message_to(&self, _: u64, _: SendMessage<'_>) -> Result<()> {
                 unimplemented!()
             }
 
@@ -374,27 +720,17 @@ mod test {
                 unimplemented!()
             }
 
-            async fn is_bot(&self, _: u64) -> Result<bool> {
+            async fn get_channel_name(&self, _: u64) -> Result<String> {
                 unimplemented!()
             }
 
-            fn send_text_message(&self, text: &str) -> impl Future<Output = Result<()>> + Send {
-                match self.expected {
-                    Some(expected) => {
-                        assert_eq!(text, expected);
-
-                        self.called.store(true, Ordering::Relaxed);
-                    }
-
-                    None => panic!("should never send message, but it actually sent."),
-                }
-                Box::pin(async { Ok(()) })
+            async fn is_bot(&self, _: u64) -> Result<bool> {
+                unimplemented!()
             }
         }
 
         let ctx = Ctx {
-            called: AtomicBool::new(false),
-            expected: output,
+            sent: Mutex::new(vec![]),
         };
 
         struct Rt;
@@ -403,99 +739,232 @@ mod test {
             type Context = Ctx;
         }
 
-        <GitHubCodePreviewBot as BotService<Rt>>::on_message(
-            &GitHubCodePreviewBot,
-            &Msg(input),
-            &ctx,
-        )
-        .await
-        .unwrap();
+        let base = stand_in().await;
+        let bot = GitHubCodePreviewBot::with_providers(
+            vec![
+                Box::new(GitHub::with_raw_base(&base)),
+                Box::new(Gist::with_api_base(&base)),
+                Box::new(GitLab::with_base("gitlab.example.com", &base)),
+                Box::new(Gitea::with_base("codeberg.org", &base)),
+            ],
+            GitHubApi::with_base(&base, None),
+        );
+
+        <GitHubCodePreviewBot as BotService<Rt>>::on_message(&bot, &Msg(input), &ctx)
+            .await
+            .unwrap();
 
-        assert!(output.is_some() == ctx.called.load(Ordering::Relaxed));
+        ctx.sent.into_inner().unwrap()
+    }
+
+    async fn test(input: &'static str, output: &[&str]) {
+        assert_eq!(send(input).await, output);
     }
 
     #[tokio::test]
     async fn test_get_code() {
         test(
-            r#"これはテストhttps://github.com/approvers/rusty-ponyo/blob/02bb011de7d06e242a275dd9a9126a21effc6854/Cargo.toml#L48-L52これもテストhttps://github.com/approvers/rusty-ponyo/blob/02bb011de7d06e242a275dd9a9126a21effc6854/Cargo.toml#L54"#,
-
-r#"approvers/rusty-ponyo [02bb011de7d06e242a275dd9a9126a21effc6854] : Cargo.toml
-```toml
-[dependencies.serenity]
-version = "0.10"
-optional = true
-default-features = false
-features = ["rustls_backend", "client", "gateway", "model", "cache"]
-```
-approvers/rusty-ponyo [02bb011de7d06e242a275dd9a9126a21effc6854] : Cargo.toml
-```toml
-[dependencies.serenity]
-version = "0.10"
-optional = true
-default-features = false
-features = ["rustls_backend", "client", "gateway", "model", "cache"]
-
-[dependencies.reqwest]
-version = "0.11"
-default-features = false
-features = ["rustls-tls"]
-
-[dependencies.tokio]
-version = "1"
+            r#"これはテストhttps://github.com/approvers/rusty-ponyo/blob/master/lines.txt#L3-L5これもテストhttps://github.com/approvers/rusty-ponyo/blob/master/lines.txt#L8"#,
+            &[r#"approvers/rusty-ponyo [master] : lines.txt
+```txt
+line 3
+line 4
+line 5
 ```
-"#).await
+approvers/rusty-ponyo [master] : lines.txt
+```txt
+line 2
+line 3
+line 4
+line 5
+line 6
+line 7
+line 8
+line 9
+line 10
+line 11
+line 12
+line 13
+line 14
+```"#],
+        )
+        .await
     }
 
     #[tokio::test]
     async fn test_backquote() {
         test(
-            r#"https://github.com/approvers/rusty-ponyo/blob/5793b96bbdbb75b008a1a02a07f64081f4219242/src/bot/gh/mod.rs#L81"#,
-r#"\`\`\` is replaced to '''
-approvers/rusty-ponyo [5793b96bbdbb75b008a1a02a07f64081f4219242] : src/bot/gh/mod.rs
+            r#"https://github.com/approvers/rusty-ponyo/blob/master/src/main.rs#L1-L3"#,
+            &[r#"\`\`\` is replaced to '''
+approvers/rusty-ponyo [master] : src/main.rs
 ```rs
-        };
+fn main() {
+    println!("'''");
+}
+```"#],
+        )
+        .await
+    }
 
-        let parsed = match Ui::try_parse_from(words) {
-            Ok(p) => p,
-            Err(e) => {
-                return ctx
-                    .send_text_message(&format!("'''{e}'''"))
-                    .await
-                    .context("failed to send message")
-            }
-        };
+    #[tokio::test]
+    async fn test_long() {
+        let pages =
+            send(r#"https://github.com/approvers/rusty-ponyo/blob/master/long.txt#L1-L300"#).await;
+
+        assert!(pages.len() > 1);
+        assert!(pages[0].starts_with(
+            r#"approvers/rusty-ponyo [master] : long.txt
+```txt
+0001: xxxxxxxxxxxxxxxxxxxx"#
+        ));
+
+        for page in &pages {
+            assert!(page.chars().count() <= pagination::MAX_MESSAGE_LENGTH);
+            assert!(page.starts_with("approvers/rusty-ponyo") || page.starts_with("```txt"));
+            assert!(page.ends_with("```"));
+        }
+    }
 
-        match parsed.command {
+    #[tokio::test]
+    async fn test_long2() {
+        test(
+            r#"https://github.com/approvers/rusty-ponyo/blob/master/long.txt#L1-L2000"#,
+            &["preview too long (2000 lines). narrow the range to at most 400 lines"],
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_long_fallback_to_image() {
+        test(
+            r#"https://github.com/approvers/rusty-ponyo/blob/master/wide.txt#L1-L300"#,
+            &["approvers/rusty-ponyo [master] : wide.txt [wide.txt.png]"],
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_image_command() {
+        test(
+            r#"g!gh preview --image https://github.com/approvers/rusty-ponyo/blob/master/src/main.rs#L1-L3"#,
+            &["approvers/rusty-ponyo [master] : src/main.rs [main.rs.png]"],
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_gitlab() {
+        test(
+            r#"https://gitlab.example.com/group/sub/project/-/blob/main/src/lib.rs#L2-3"#,
+            &[r#"group/sub/project [main] : src/lib.rs
+```rs
+line 2
+line 3
+```"#],
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_gitea() {
+        test(
+            r#"https://codeberg.org/forgejo/forgejo/src/branch/main/Makefile#L4-L5"#,
+            &[r#"forgejo/forgejo [main] : Makefile
 ```
-"#,).await
+line 4
+line 5
+```"#],
+        )
+        .await
     }
 
     #[tokio::test]
-    async fn test_long() {
+    async fn test_gist() {
+        test(
+            r#"https://gist.github.com/kawaemon/abcdef#file-main-rs-L2"#,
+            &[r#"kawaemon/abcdef [latest] : main-rs
+```rs
+fn main() {
+    println!("hello");
+}
+```"#],
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_unknown_host() {
+        test(
+            r#"https://gitlab.com/group/sub/project/-/blob/main/src/lib.rs#L2-3"#,
+            &[],
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_issue_and_pull() {
+        test(
+            r#"https://github.com/approvers/rusty-ponyo/pull/34 https://github.com/approvers/rusty-ponyo/issues/12"#,
+            &[r#"\`\`\` is replaced to '''
+approvers/rusty-ponyo#12 [open] '''preview''' が壊れる
+author: kawaemon / labels: bug, gh
+approvers/rusty-ponyo#34 [merged] Add gh preview
+author: kawaemon"#],
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_commit() {
         test(
-r#"https://github.com/approvers/rusty-ponyo/blob/765314882920494fb48c72a33d747d96a39bc23f/Cargo.lock#L1
-https://github.com/approvers/rusty-ponyo/blob/765314882920494fb48c72a33d747d96a39bc23f/Cargo.lock#L5-L426
-"#,
-
-r#"some perma links are skipped due to message length limit.
-approvers/rusty-ponyo [765314882920494fb48c72a33d747d96a39bc23f] : Cargo.lock
-```lock
-# This file is automatically @generated by Cargo.
-# It is not intended for manual editing.
-version = 3
-
-[[package]]
-name = "adler"
-version = "1.0.2"
+            r#"https://github.com/approvers/rusty-ponyo/commit/02bb011"#,
+            &[r#"approvers/rusty-ponyo@02bb011 Fix preview
+author: kawaemon / 2 files changed, +3 -1
+```
+assets/logo.png | +0 -0
+src/lib.rs | +3 -1
 ```
-"#).await
+src/lib.rs
+```diff
+@@ -1,2 +1,4 @@
+-a
++b
++c
++d
+```"#],
+        )
+        .await
     }
 
     #[tokio::test]
-    async fn test_long2() {
+    async fn test_whole_file() {
         test(
-            r#"https://github.com/approvers/rusty-ponyo/blob/master/Cargo.lock#L1-L2712"#,
-            None,
+            r#"https://github.com/approvers/rusty-ponyo/blob/feature/gh/src/lib.rs"#,
+            &[r#"approvers/rusty-ponyo [feature/gh] : src/lib.rs
+```rs
+line 1
+line 2
+line 3
+```"#],
+        )
+        .await;
+
+        let pages = send(r#"https://github.com/approvers/rusty-ponyo/blob/master/lines.txt"#).await;
+        assert_eq!(pages.len(), 1);
+        assert!(pages[0].contains("\nline 20\n```\n(showing first 20 lines)"));
+        assert!(!pages[0].contains("line 21"));
+    }
+
+    #[tokio::test]
+    async fn test_branch_with_slash() {
+        test(
+            r#"https://github.com/approvers/rusty-ponyo/blob/feature/gh/src/lib.rs#L2"#,
+            &[r#"approvers/rusty-ponyo [feature/gh] : src/lib.rs
+```rs
+line 1
+line 2
+line 3
+```"#],
         )
         .await
     }
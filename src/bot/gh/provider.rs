@@ -0,0 +1,541 @@
+use {
+    anyhow::{Context as _, Result},
+    once_cell::sync::Lazy,
+    regex::Regex,
+    serde::Deserialize,
+    std::{collections::BTreeMap, path::Path},
+    url::Url,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) enum RefKind {
+    /// URL からは種類が分からないもの
+    Unknown,
+    Branch,
+    Tag,
+    Commit,
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) struct CodePermalink {
+    pub(super) host: String,
+    pub(super) user: String,
+    pub(super) repo: String,
+    pub(super) branch: String,
+    pub(super) ref_kind: RefKind,
+    pub(super) path: String,
+    pub(super) ext: String,
+    /// 行の指定 (開始行, 終了行)。None ならファイル全体
+    pub(super) lines: Option<(usize, Option<usize>)>,
+}
+
+impl CodePermalink {
+    /// コミットハッシュで指定されていて、内容が変わらないか
+    pub(super) fn is_immutable(&self) -> bool {
+        let is_full_sha =
+            self.branch.len() == 40 && self.branch.chars().all(|c| c.is_ascii_hexdigit());
+
+        self.ref_kind == RefKind::Commit || is_full_sha
+    }
+}
+
+/// コードをホスティングしているサービスごとの URL の扱い方
+pub(super) trait CodeProvider: Send + Sync {
+    /// このプロバイダが担当するリンクのホスト名
+    fn host(&self) -> &str;
+
+    /// リンクをパースします。このプロバイダの形式でなければ None を返します。
+    fn parse(&self, url: &Url) -> Option<CodePermalink>;
+
+    /// コードを取得するための URL を組み立てます
+    fn raw_url(&self, link: &CodePermalink) -> String;
+
+    /// `raw_url` から取得した内容からコードを取り出します
+    fn extract_code(&self, _link: &CodePermalink, body: String) -> Result<String> {
+        Ok(body)
+    }
+}
+
+macro_rules! bail {
+    ($e:expr) => {
+        if !$e {
+            return None;
+        }
+    };
+}
+
+// L10, L10-L20 (GitHub, Gitea), L10-20 (GitLab)
+fn parse_lines(fragment: &str) -> Option<(usize, Option<usize>)> {
+    static LINE_REGEX: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"L(?P<l1>\d+)(?:-L?(?P<l2>\d+))?").unwrap());
+
+    let captures = LINE_REGEX.captures(fragment)?;
+
+    let l1 = captures.name("l1").unwrap().as_str().parse().ok()?;
+
+    let l2 = match captures.name("l2") {
+        Some(l2) => Some(l2.as_str().parse().ok()?),
+        None => None,
+    };
+
+    Some((l1, l2))
+}
+
+fn ext_of(path: &str) -> String {
+    let filename = path.rsplit('/').next().unwrap_or(path);
+
+    Path::new(filename)
+        .extension()
+        .and_then(|x| x.to_str())
+        .unwrap_or("")
+        .to_owned()
+}
+
+fn trim_base(base: &str) -> String {
+    base.trim_end_matches('/').to_owned()
+}
+
+/// github.com
+pub(super) struct GitHub {
+    raw_base: String,
+}
+
+impl GitHub {
+    pub(super) fn new() -> Self {
+        Self::with_raw_base("https://raw.githubusercontent.com")
+    }
+
+    pub(super) fn with_raw_base(raw_base: &str) -> Self {
+        Self {
+            raw_base: trim_base(raw_base),
+        }
+    }
+}
+
+impl CodeProvider for GitHub {
+    fn host(&self) -> &str {
+        "github.com"
+    }
+
+    // e.g.: https://github.com/approvers/rusty-ponyo/blob/02bb011de7d06e242a275dd9a9126a21effc6854/Cargo.toml#L48-L52
+    fn parse(&self, url: &Url) -> Option<CodePermalink> {
+        let mut segments = url.path_segments()?;
+        let user = segments.next()?;
+        let repo = segments.next()?;
+        bail!(segments.next()? == "blob");
+        let branch = segments.next()?;
+        let path = segments.collect::<Vec<&str>>().join("/");
+
+        let lines = url.fragment().and_then(parse_lines);
+
+        Some(CodePermalink {
+            host: self.host().to_owned(),
+            user: user.to_owned(),
+            repo: repo.to_owned(),
+            branch: branch.to_owned(),
+            ref_kind: RefKind::Unknown,
+            ext: ext_of(&path),
+            path,
+            lines,
+        })
+    }
+
+    fn raw_url(&self, link: &CodePermalink) -> String {
+        format!(
+            "{}/{}/{}/{}/{}",
+            self.raw_base, link.user, link.repo, link.branch, link.path,
+        )
+    }
+}
+
+/// gitlab.com やセルフホストされた GitLab
+pub(super) struct GitLab {
+    host: String,
+    base: String,
+}
+
+impl GitLab {
+    pub(super) fn new(host: &str) -> Self {
+        Self::with_base(host, &format!("https://{host}"))
+    }
+
+    pub(super) fn with_base(host: &str, base: &str) -> Self {
+        Self {
+            host: host.to_owned(),
+            base: trim_base(base),
+        }
+    }
+}
+
+impl CodeProvider for GitLab {
+    fn host(&self) -> &str {
+        &self.host
+    }
+
+    // e.g.: https://gitlab.com/group/subgroup/project/-/blob/main/src/lib.rs#L10-20
+    fn parse(&self, url: &Url) -> Option<CodePermalink> {
+        let segments = url.path_segments()?.collect::<Vec<_>>();
+
+        let separator = segments.iter().position(|&x| x == "-")?;
+        bail!(separator >= 2);
+
+        let (namespace, rest) = segments.split_at(separator);
+        let (repo, user) = namespace.split_last()?;
+
+        let mut rest = rest.iter().skip(1);
+        bail!(*rest.next()? == "blob");
+        let branch = rest.next()?;
+        let path = rest.copied().collect::<Vec<&str>>().join("/");
+
+        let lines = url.fragment().and_then(parse_lines);
+
+        Some(CodePermalink {
+            host: self.host.clone(),
+            user: user.join("/"),
+            repo: repo.to_string(),
+            branch: branch.to_string(),
+            ref_kind: RefKind::Unknown,
+            ext: ext_of(&path),
+            path,
+            lines,
+        })
+    }
+
+    fn raw_url(&self, link: &CodePermalink) -> String {
+        format!(
+            "{}/{}/{}/-/raw/{}/{}",
+            self.base, link.user, link.repo, link.branch, link.path,
+        )
+    }
+}
+
+/// Gitea / Forgejo (Codeberg を含む)
+pub(super) struct Gitea {
+    host: String,
+    base: String,
+}
+
+impl Gitea {
+    pub(super) fn new(host: &str) -> Self {
+        Self::with_base(host, &format!("https://{host}"))
+    }
+
+    pub(super) fn with_base(host: &str, base: &str) -> Self {
+        Self {
+            host: host.to_owned(),
+            base: trim_base(base),
+        }
+    }
+}
+
+impl CodeProvider for Gitea {
+    fn host(&self) -> &str {
+        &self.host
+    }
+
+    // e.g.: https://codeberg.org/forgejo/forgejo/src/branch/forgejo/Makefile#L10-L20
+    fn parse(&self, url: &Url) -> Option<CodePermalink> {
+        let mut segments = url.path_segments()?;
+        let user = segments.next()?;
+        let repo = segments.next()?;
+        bail!(segments.next()? == "src");
+        let ref_kind = match segments.next()? {
+            "branch" => RefKind::Branch,
+            "tag" => RefKind::Tag,
+            "commit" => RefKind::Commit,
+            _ => return None,
+        };
+        let branch = segments.next()?;
+        let path = segments.collect::<Vec<&str>>().join("/");
+
+        let lines = url.fragment().and_then(parse_lines);
+
+        Some(CodePermalink {
+            host: self.host.clone(),
+            user: user.to_owned(),
+            repo: repo.to_owned(),
+            branch: branch.to_owned(),
+            ref_kind,
+            ext: ext_of(&path),
+            path,
+            lines,
+        })
+    }
+
+    fn raw_url(&self, link: &CodePermalink) -> String {
+        let kind = match link.ref_kind {
+            RefKind::Branch => "branch/",
+            RefKind::Tag => "tag/",
+            RefKind::Commit => "commit/",
+            RefKind::Unknown => "",
+        };
+
+        format!(
+            "{}/{}/{}/raw/{}{}/{}",
+            self.base, link.user, link.repo, kind, link.branch, link.path,
+        )
+    }
+}
+
+/// gist.github.com
+///
+/// gist のリンクにはファイル名がスラッグ化されたものしか含まれないため、
+/// API からファイル一覧を取得してスラッグが一致するファイルを探します。
+pub(super) struct Gist {
+    api_base: String,
+}
+
+impl Gist {
+    pub(super) fn new() -> Self {
+        Self::with_api_base("https://api.github.com")
+    }
+
+    pub(super) fn with_api_base(api_base: &str) -> Self {
+        Self {
+            api_base: trim_base(api_base),
+        }
+    }
+}
+
+// https://gist.github.com の HTML 上でのファイルのアンカーと同じ規則
+fn gist_slug(filename: &str) -> String {
+    filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+impl CodeProvider for Gist {
+    fn host(&self) -> &str {
+        "gist.github.com"
+    }
+
+    // e.g.: https://gist.github.com/kawaemon/0123456789abcdef/1234abcd#file-main-rs-L3-L5
+    fn parse(&self, url: &Url) -> Option<CodePermalink> {
+        static FRAGMENT_REGEX: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"^file-(?P<slug>.+?)(?:-(?P<lines>L\d+(?:-L\d+)?))?$").unwrap()
+        });
+
+        let mut segments = url.path_segments()?;
+        let user = segments.next()?;
+        let id = segments.next()?;
+        let revision = segments.next().filter(|x| !x.is_empty());
+        bail!(segments.next().is_none());
+
+        // without the fragment, the first file is shown.
+        let (slug, lines) = match url.fragment() {
+            Some(fragment) => {
+                let captures = FRAGMENT_REGEX.captures(fragment)?;
+                (
+                    captures.name("slug").unwrap().as_str(),
+                    captures.name("lines").and_then(|x| parse_lines(x.as_str())),
+                )
+            }
+            None => ("", None),
+        };
+
+        Some(CodePermalink {
+            host: self.host().to_owned(),
+            user: user.to_owned(),
+            repo: id.to_owned(),
+            branch: revision.unwrap_or("latest").to_owned(),
+            ref_kind: match revision {
+                Some(_) => RefKind::Commit,
+                None => RefKind::Unknown,
+            },
+            path: slug.to_owned(),
+            // "main-rs" -> "rs"
+            ext: slug.rsplit('-').next().unwrap_or("").to_owned(),
+            lines,
+        })
+    }
+
+    fn raw_url(&self, link: &CodePermalink) -> String {
+        match link.ref_kind {
+            RefKind::Commit => format!("{}/gists/{}/{}", self.api_base, link.repo, link.branch),
+            _ => format!("{}/gists/{}", self.api_base, link.repo),
+        }
+    }
+
+    fn extract_code(&self, link: &CodePermalink, body: String) -> Result<String> {
+        #[derive(Deserialize)]
+        struct GistResponse {
+            files: BTreeMap<String, GistFile>,
+        }
+
+        #[derive(Deserialize)]
+        struct GistFile {
+            content: String,
+        }
+
+        let res = serde_json::from_str::<GistResponse>(&body)
+            .context("failed to parse gist api response")?;
+
+        res.files
+            .into_iter()
+            .find(|(name, _)| link.path.is_empty() || gist_slug(name) == link.path)
+            .map(|(_, file)| file.content)
+            .with_context(|| format!("file {} was not found in gist", link.path))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {super::*, pretty_assertions::assert_eq};
+
+    fn parse(provider: &impl CodeProvider, url: &str) -> Option<CodePermalink> {
+        provider.parse(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn github() {
+        let link = parse(
+            &GitHub::new(),
+            "https://github.com/approvers/rusty-ponyo/blob/02bb011de7d06e242a275dd9a9126a21effc6854/src/bot/mod.rs#L48-L52",
+        )
+        .unwrap();
+
+        assert_eq!(
+            link,
+            CodePermalink {
+                host: "github.com".into(),
+                user: "approvers".into(),
+                repo: "rusty-ponyo".into(),
+                branch: "02bb011de7d06e242a275dd9a9126a21effc6854".into(),
+                ref_kind: RefKind::Unknown,
+                path: "src/bot/mod.rs".into(),
+                ext: "rs".into(),
+                lines: Some((48, Some(52))),
+            }
+        );
+
+        assert_eq!(
+            GitHub::new().raw_url(&link),
+            "https://raw.githubusercontent.com/approvers/rusty-ponyo/02bb011de7d06e242a275dd9a9126a21effc6854/src/bot/mod.rs"
+        );
+
+        assert!(
+            parse(
+                &GitHub::new(),
+                "https://github.com/approvers/rusty-ponyo/tree/master/src#L1"
+            )
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn whole_file() {
+        let link = parse(
+            &GitHub::new(),
+            "https://github.com/approvers/rusty-ponyo/blob/master/README.md",
+        )
+        .unwrap();
+        assert_eq!(link.lines, None);
+
+        let link = parse(&Gist::new(), "https://gist.github.com/kawaemon/abcdef").unwrap();
+        assert_eq!((link.path.as_str(), link.lines), ("", None));
+
+        let link = parse(
+            &Gist::new(),
+            "https://gist.github.com/kawaemon/abcdef#file-a-rs",
+        )
+        .unwrap();
+        assert_eq!((link.path.as_str(), link.lines), ("a-rs", None));
+    }
+
+    #[test]
+    fn gitlab() {
+        let provider = GitLab::new("gitlab.com");
+        let link = parse(
+            &provider,
+            "https://gitlab.com/group/subgroup/project/-/blob/main/src/lib.rs#L10-20",
+        )
+        .unwrap();
+
+        assert_eq!(
+            link,
+            CodePermalink {
+                host: "gitlab.com".into(),
+                user: "group/subgroup".into(),
+                repo: "project".into(),
+                branch: "main".into(),
+                ref_kind: RefKind::Unknown,
+                path: "src/lib.rs".into(),
+                ext: "rs".into(),
+                lines: Some((10, Some(20))),
+            }
+        );
+
+        assert_eq!(
+            provider.raw_url(&link),
+            "https://gitlab.com/group/subgroup/project/-/raw/main/src/lib.rs"
+        );
+
+        assert!(parse(&provider, "https://gitlab.com/project/-/blob/main/a.rs#L1").is_none());
+    }
+
+    #[test]
+    fn gitea() {
+        let provider = Gitea::new("codeberg.org");
+        let link = parse(
+            &provider,
+            "https://codeberg.org/forgejo/forgejo/src/tag/v1.0.0/Makefile#L3",
+        )
+        .unwrap();
+
+        assert_eq!(
+            link,
+            CodePermalink {
+                host: "codeberg.org".into(),
+                user: "forgejo".into(),
+                repo: "forgejo".into(),
+                branch: "v1.0.0".into(),
+                ref_kind: RefKind::Tag,
+                path: "Makefile".into(),
+                ext: "".into(),
+                lines: Some((3, None)),
+            }
+        );
+
+        assert_eq!(
+            provider.raw_url(&link),
+            "https://codeberg.org/forgejo/forgejo/raw/tag/v1.0.0/Makefile"
+        );
+    }
+
+    #[test]
+    fn gist() {
+        let link = parse(
+            &Gist::new(),
+            "https://gist.github.com/kawaemon/0123456789abcdef/1234abcd#file-main-rs-L3-L5",
+        )
+        .unwrap();
+
+        assert_eq!(
+            link,
+            CodePermalink {
+                host: "gist.github.com".into(),
+                user: "kawaemon".into(),
+                repo: "0123456789abcdef".into(),
+                branch: "1234abcd".into(),
+                ref_kind: RefKind::Commit,
+                path: "main-rs".into(),
+                ext: "rs".into(),
+                lines: Some((3, Some(5))),
+            }
+        );
+
+        assert_eq!(
+            Gist::new().raw_url(&link),
+            "https://api.github.com/gists/0123456789abcdef/1234abcd"
+        );
+
+        assert_eq!(gist_slug("Cargo.toml"), "cargo-toml");
+    }
+}
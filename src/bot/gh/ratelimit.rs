@@ -0,0 +1,93 @@
+use {
+    std::{
+        collections::{HashMap, VecDeque},
+        time::{Duration, Instant},
+    },
+    tokio::sync::Mutex,
+};
+
+// 1 チャンネルあたり WINDOW の間にプレビューできるリンクの数
+const MAX_LINKS_PER_WINDOW: usize = 10;
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// チャンネルごとのスライディングウィンドウ方式のレートリミッタ
+pub(super) struct RateLimiter {
+    hits: Mutex<HashMap<u64, VecDeque<Instant>>>,
+    max: usize,
+    window: Duration,
+}
+
+impl RateLimiter {
+    pub(super) fn new() -> Self {
+        Self::with_limits(MAX_LINKS_PER_WINDOW, WINDOW)
+    }
+
+    pub(super) fn with_limits(max: usize, window: Duration) -> Self {
+        Self {
+            hits: Mutex::new(HashMap::new()),
+            max,
+            window,
+        }
+    }
+
+    /// `channel_id` で `count` 件までのリンクのプレビューを試み、許可された件数を記録して返します
+    pub(super) async fn try_acquire(&self, channel_id: u64, count: usize, now: Instant) -> usize {
+        let mut hits = self.hits.lock().await;
+
+        // forget channels that have been quiet for a while
+        hits.retain(|_, x| {
+            while x
+                .front()
+                .is_some_and(|&t| now.duration_since(t) >= self.window)
+            {
+                x.pop_front();
+            }
+            !x.is_empty()
+        });
+
+        let channel = hits.entry(channel_id).or_default();
+
+        let granted = count.min(self.max.saturating_sub(channel.len()));
+
+        channel.extend(std::iter::repeat_n(now, granted));
+        granted
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn limits_per_channel() {
+        let limiter = RateLimiter::with_limits(3, Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert_eq!(limiter.try_acquire(1, 2, now).await, 2);
+        assert_eq!(limiter.try_acquire(1, 1, now).await, 1);
+        assert_eq!(limiter.try_acquire(1, 1, now).await, 0);
+
+        // other channels are not affected
+        assert_eq!(limiter.try_acquire(2, 3, now).await, 3);
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(limiter.try_acquire(1, 3, later).await, 3);
+    }
+
+    #[tokio::test]
+    async fn grants_remaining_budget() {
+        let limiter = RateLimiter::with_limits(3, Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert_eq!(limiter.try_acquire(1, 2, now).await, 2);
+
+        // only one is left in the window
+        let later = now + Duration::from_secs(30);
+        assert_eq!(limiter.try_acquire(1, 5, later).await, 1);
+        assert_eq!(limiter.try_acquire(1, 5, later).await, 0);
+
+        // the first two expire, but the one acquired later is still counted
+        let much_later = now + Duration::from_secs(60);
+        assert_eq!(limiter.try_acquire(1, 5, much_later).await, 2);
+    }
+}
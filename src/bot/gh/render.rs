@@ -0,0 +1,245 @@
+use {
+    ab_glyph::{Font, FontVec, PxScale, ScaleFont, point},
+    anyhow::{Context as _, Result, anyhow},
+    image::{ImageFormat, Rgb, RgbImage},
+    once_cell::sync::Lazy,
+    std::{io::Cursor, path::PathBuf},
+    syntect::{
+        easy::HighlightLines,
+        highlighting::{Color, Theme, ThemeSet},
+        parsing::{SyntaxReference, SyntaxSet},
+        util::LinesWithEndings,
+    },
+};
+
+/// 画像として描画できる最大の行数
+pub(super) const MAX_IMAGE_LINES: usize = 400;
+
+const FONT_SIZE: f32 = 20.0;
+const LINE_HEIGHT: u32 = 26;
+const PADDING: u32 = 16;
+const MAX_WIDTH: u32 = 3000;
+const TAB: &str = "    ";
+
+const THEME: &str = "base16-ocean.dark";
+
+// download_font.sh でダウンロードされます
+const FONT_FILE: &str = "NotoSansCJKjp-Medium.ttf";
+// フォントを別の場所に置く場合に、そのパスを指定する環境変数
+const FONT_PATH_ENV: &str = "GH_PREVIEW_FONT_PATH";
+
+// loaded on first use, so that the bot can run without the font
+static FONT: Lazy<Result<FontVec, String>> = Lazy::new(|| {
+    let path = font_path()?;
+    let data = std::fs::read(&path).map_err(|e| {
+        format!(
+            "failed to read {}, run download_font.sh or set {FONT_PATH_ENV}: {e}",
+            path.display()
+        )
+    })?;
+
+    FontVec::try_from_vec(data).map_err(|e| format!("failed to parse {}: {e}", path.display()))
+});
+static SYNTAX_SET: Lazy<SyntaxSet> = Lazy::new(SyntaxSet::load_defaults_newlines);
+static THEME_SET: Lazy<ThemeSet> = Lazy::new(ThemeSet::load_defaults);
+
+/// 拡張子からシンタックスを探します。見つからなければプレーンテキストとして扱います。
+fn find_syntax(ext: &str) -> &'static SyntaxReference {
+    SYNTAX_SET
+        .find_syntax_by_extension(ext)
+        .or_else(|| SYNTAX_SET.find_syntax_by_token(ext))
+        .unwrap_or_else(|| SYNTAX_SET.find_syntax_plain_text())
+}
+
+fn rgb(color: Color) -> Rgb<u8> {
+    Rgb([color.r, color.g, color.b])
+}
+
+// the env var if set, otherwise next to the executable (as in the docker image),
+// otherwise the repository root where download_font.sh puts it
+fn font_path() -> Result<PathBuf, String> {
+    if let Some(path) = std::env::var_os(FONT_PATH_ENV) {
+        return Ok(path.into());
+    }
+
+    let beside_exe = std::env::current_exe()
+        .map_err(|e| format!("failed to get the path of the executable: {e}"))?
+        .with_file_name(FONT_FILE);
+
+    if beside_exe.exists() {
+        return Ok(beside_exe);
+    }
+
+    Ok(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(FONT_FILE))
+}
+
+fn font() -> Result<&'static FontVec> {
+    FONT.as_ref().map_err(|e| anyhow!("{e}"))
+}
+
+fn text_width(font: &FontVec, text: &str) -> f32 {
+    let scaled = font.as_scaled(PxScale::from(FONT_SIZE));
+    text.chars()
+        .map(|c| scaled.h_advance(font.glyph_id(c)))
+        .sum()
+}
+
+struct Canvas {
+    image: RgbImage,
+    font: &'static FontVec,
+}
+
+impl Canvas {
+    // returns x after the text
+    fn draw_text(&mut self, text: &str, x: f32, line: u32, color: Rgb<u8>) -> f32 {
+        let font = self.font.as_scaled(PxScale::from(FONT_SIZE));
+        let baseline = (line * LINE_HEIGHT + PADDING) as f32 + font.ascent();
+
+        let mut x = x;
+        for c in text.chars() {
+            let mut glyph = font.scaled_glyph(c);
+            glyph.position = point(x, baseline);
+            x += font.h_advance(glyph.id);
+
+            let Some(outlined) = self.font.outline_glyph(glyph) else {
+                continue;
+            };
+            let bounds = outlined.px_bounds();
+
+            outlined.draw(|gx, gy, coverage| {
+                let px = bounds.min.x as i64 + gx as i64;
+                let py = bounds.min.y as i64 + gy as i64;
+
+                if px < 0
+                    || py < 0
+                    || px >= self.image.width() as i64
+                    || py >= self.image.height() as i64
+                {
+                    return;
+                }
+
+                let pixel = self.image.get_pixel_mut(px as u32, py as u32);
+                for i in 0..3 {
+                    pixel.0[i] =
+                        (pixel.0[i] as f32 * (1.0 - coverage) + color.0[i] as f32 * coverage) as u8;
+                }
+            });
+        }
+
+        x
+    }
+}
+
+/// コードをシンタックスハイライトし、行番号とヘッダー付きの PNG 画像にします
+pub(super) fn render_png(
+    header: &str,
+    code: &str,
+    ext: &str,
+    first_line: usize,
+) -> Result<Vec<u8>> {
+    let font = font()?;
+    let theme: &Theme = &THEME_SET.themes[THEME];
+    let background = theme.settings.background.map(rgb).unwrap_or(Rgb([0, 0, 0]));
+    let foreground = theme
+        .settings
+        .foreground
+        .map(rgb)
+        .unwrap_or(Rgb([255, 255, 255]));
+    let gutter = theme
+        .settings
+        .gutter_foreground
+        .map(rgb)
+        .unwrap_or(Rgb([128, 128, 128]));
+
+    let code = code.replace('\t', TAB);
+    let line_count = code.lines().count();
+    let last_line = first_line + line_count.saturating_sub(1);
+
+    let gutter_width = text_width(font, &format!("{last_line} ")) + PADDING as f32;
+
+    let mut highlighter = HighlightLines::new(find_syntax(ext), theme);
+    let mut lines = vec![];
+    for line in LinesWithEndings::from(&code) {
+        let ranges = highlighter
+            .highlight_line(line, &SYNTAX_SET)
+            .context("failed to highlight code")?;
+
+        lines.push(
+            ranges
+                .into_iter()
+                .map(|(style, text)| (rgb(style.foreground), text.trim_end_matches(['\r', '\n'])))
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    let content_width = lines
+        .iter()
+        .map(|x| x.iter().map(|(_, t)| text_width(font, t)).sum::<f32>())
+        .fold(text_width(font, header), f32::max);
+
+    let width = ((gutter_width + content_width) as u32 + PADDING * 2).min(MAX_WIDTH);
+    // header + separator + code
+    let height = (lines.len() as u32 + 2) * LINE_HEIGHT + PADDING * 2;
+
+    let mut canvas = Canvas {
+        image: RgbImage::from_pixel(width, height, background),
+        font,
+    };
+
+    canvas.draw_text(header, PADDING as f32, 0, foreground);
+
+    let separator_y = LINE_HEIGHT + PADDING + LINE_HEIGHT / 2;
+    for x in PADDING..width - PADDING {
+        canvas.image.put_pixel(x, separator_y, gutter);
+    }
+
+    for (i, ranges) in lines.iter().enumerate() {
+        let line = i as u32 + 2;
+
+        let number = format!("{}", first_line + i);
+        let number_x = PADDING as f32 + gutter_width - PADDING as f32 - text_width(font, &number);
+        canvas.draw_text(&number, number_x, line, gutter);
+
+        let mut x = PADDING as f32 + gutter_width;
+        for (color, text) in ranges {
+            x = canvas.draw_text(text, x, line, *color);
+        }
+    }
+
+    let mut png = vec![];
+    canvas
+        .image
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .context("failed to encode png")?;
+
+    Ok(png)
+}
+
+#[cfg(test)]
+mod test {
+    use {super::*, pretty_assertions::assert_eq};
+
+    #[test]
+    fn detects_language_from_ext() {
+        assert_eq!(find_syntax("rs").name, "Rust");
+        assert_eq!(find_syntax("py").name, "Python");
+        assert_eq!(find_syntax("").name, "Plain Text");
+        assert_eq!(find_syntax("unknown-ext").name, "Plain Text");
+    }
+
+    #[test]
+    #[ignore = "needs the font downloaded by download_font.sh"]
+    fn renders_png() {
+        let png = render_png(
+            "approvers/rusty-ponyo [master] : src/main.rs",
+            "fn main() {\n\tprintln!(\"hello\");\n}",
+            "rs",
+            10,
+        )
+        .unwrap();
+
+        let image = image::load_from_memory_with_format(&png, ImageFormat::Png).unwrap();
+        assert_eq!(image.height(), 5 * LINE_HEIGHT + PADDING * 2);
+        assert!(image.width() > PADDING * 2);
+    }
+}
@@ -1,6 +1,8 @@
 use {
     crate::KAWAEMON_DISCORD_USER_ID,
-    crate::bot::{BotService, Context, IsUpdated, Message, Runtime, User, parse_command, ui},
+    crate::bot::{
+        BotService, Context, IsUpdated, Message, Runtime, User, pagination, parse_command, ui,
+    },
     anyhow::{Context as _, Result},
     clap::{ArgGroup, ValueEnum},
     model::{Meigen, MeigenId},
@@ -65,7 +67,6 @@ pub trait MeigenDatabase: Send + Sync {
 const NAME: &str = "rusty_ponyo::bot::meigen";
 const PREFIX: &str = "g!meigen";
 const MEIGEN_LENGTH_LIMIT: usize = 300;
-const LIST_LENGTH_LIMIT: usize = 500;
 
 ui! {
     struct Ui {
@@ -200,16 +201,20 @@ impl<R: Runtime, D: MeigenDatabase> BotService<R> for MeigenBot<D> {
                 dir,
                 reverse,
             } => {
-                self.search(FindOptions {
-                    author: author.as_deref(),
-                    content: content.as_deref(),
-                    offset,
-                    limit,
-                    sort,
-                    dir: if reverse { SortDirection::Asc } else { dir },
-                    random,
-                })
-                .await?
+                let pages = self
+                    .search(FindOptions {
+                        author: author.as_deref(),
+                        content: content.as_deref(),
+                        offset,
+                        limit,
+                        sort,
+                        dir: if reverse { SortDirection::Asc } else { dir },
+                        random,
+                    })
+                    .await?;
+
+                ctx.send_pages(&pages).await?;
+                return Ok(());
             }
             Command::Delete { id } => self.delete(msg.author().id(), id).await?,
             Command::Love { id } => self.love(msg.author().id(), id).await?,
@@ -261,10 +266,10 @@ impl<D: MeigenDatabase> MeigenBot<D> {
         Ok(format!("```\n現在登録されている名言数: {count}\n```"))
     }
 
-    async fn search(&self, opt: FindOptions<'_>) -> Result<String> {
+    async fn search(&self, opt: FindOptions<'_>) -> Result<Vec<String>> {
         let res = self.db.search(opt).await?;
         if res.is_empty() {
-            return Ok("条件に合致する名言が見つかりませんでした".into());
+            return Ok(vec!["条件に合致する名言が見つかりませんでした".into()]);
         }
 
         Ok(list(&res))
@@ -325,23 +330,13 @@ impl<D: MeigenDatabase> MeigenBot<D> {
     }
 }
 
-fn list(meigens: &[Meigen]) -> String {
-    let mut res = String::new();
-    let mut chars = 0;
-
-    for meigen in meigens {
-        if chars > LIST_LENGTH_LIMIT {
-            res.insert_str(0, "結果が長すぎたため一部の名言は省略されました\n");
-            break;
-        }
-
-        let meigen = meigen.to_string();
-        chars += meigen.chars().count();
-        res += &meigen;
-        res += "\n";
-    }
+fn list(meigens: &[Meigen]) -> Vec<String> {
+    let meigens = meigens.iter().map(Meigen::to_string).collect::<Vec<_>>();
 
-    res.trim().to_string()
+    pagination::pack(
+        meigens.iter().map(String::as_str),
+        pagination::MAX_MESSAGE_LENGTH,
+    )
 }
 
 fn format_ascii_meigen(meigen: &Meigen, ascii_art: &str) -> String {
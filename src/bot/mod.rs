@@ -11,6 +11,7 @@ pub mod auth;
 pub mod genkai_point;
 pub mod gh;
 pub mod meigen;
+pub mod pagination;
 pub mod vc_diff;
 
 // Usage of GATs like this:
@@ -26,6 +27,7 @@ pub trait Message: Send + Sync {
     fn reply(&self, msg: &str) -> impl Future<Output = Result<()>> + Send;
     fn author(&self) -> &Self::User;
     fn content(&self) -> &str;
+    fn channel_id(&self) -> u64;
     fn attachments(&self) -> &[Self::Attachment];
 }
 
@@ -64,7 +66,14 @@ pub struct SendAttachment<'a> {
 
 pub trait Context: Send + Sync {
     fn send_message(&self, msg: SendMessage<'_>) -> impl Future<Output = Result<()>> + Send;
+    /// イベントが起きたチャンネルではなく、指定したチャンネルに送信します
+    fn send_message_to(
+        &self,
+        channel_id: u64,
+        msg: SendMessage<'_>,
+    ) -> impl Future<Output = Result<()>> + Send;
     fn get_user_name(&self, user_id: u64) -> impl Future<Output = Result<String>> + Send;
+    fn get_channel_name(&self, channel_id: u64) -> impl Future<Output = Result<String>> + Send;
     fn is_bot(&self, user_id: u64) -> impl Future<Output = Result<bool>> + Send;
 
     fn send_text_message(&self, text: &str) -> impl Future<Output = Result<()>> + Send {
@@ -76,6 +85,26 @@ pub trait Context: Send + Sync {
             .await
         }
     }
+
+    /// ページ送りできるメッセージとして送信します
+    /// ページ送りに対応していないクライアントでは、全てのページを順番に送信します
+    fn send_pages(&self, pages: &[String]) -> impl Future<Output = Result<()>> + Send {
+        async move {
+            for page in pages {
+                self.send_text_message(page).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// VC 内でのユーザーの状態の変化。値は変化後の状態です
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VcStateChange {
+    Mute(bool),
+    Deafen(bool),
+    Stream(bool),
+    Video(bool),
 }
 
 pub trait Runtime {
@@ -95,10 +124,11 @@ pub trait BotService<R: Runtime>: Send + Sync {
     }
 
     // called on bot started and got who is currently joined to vc
+    // each element is (user_id, channel_id)
     fn on_vc_data_available(
         &self,
         _ctx: &R::Context,
-        _joined_user_ids: &[u64],
+        _joined_users: &[(u64, u64)],
     ) -> impl Future<Output = Result<()>> + Send {
         async { Ok(()) }
     }
@@ -108,6 +138,7 @@ pub trait BotService<R: Runtime>: Send + Sync {
         &self,
         _ctx: &R::Context,
         _user_id: u64,
+        _channel_id: u64,
     ) -> impl Future<Output = Result<()>> + Send {
         async { Ok(()) }
     }
@@ -117,6 +148,32 @@ pub trait BotService<R: Runtime>: Send + Sync {
         &self,
         _ctx: &R::Context,
         _user_id: u64,
+        _channel_id: u64,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    // called on user has moved to another vc without leaving
+    fn on_vc_move(
+        &self,
+        _ctx: &R::Context,
+        _user_id: u64,
+        _from_channel_id: u64,
+        _to_channel_id: u64,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    // called on user in vc has toggled mute, deafen, stream or video
+    // also called right after on_vc_join (and on_vc_data_available) for the states which are already on.
+    // `initial` is true for the latter from on_vc_data_available, which is replayed on every reconnect
+    fn on_vc_state_change(
+        &self,
+        _ctx: &R::Context,
+        _user_id: u64,
+        _channel_id: u64,
+        _change: VcStateChange,
+        _initial: bool,
     ) -> impl Future<Output = Result<()>> + Send {
         async { Ok(()) }
     }
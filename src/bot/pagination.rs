@@ -0,0 +1,284 @@
+//! 長いメッセージを Discord の文字数制限に収まるように分割するための機能
+
+/// Discord の 1 メッセージあたりの最大文字数
+pub const MAX_MESSAGE_LENGTH: usize = 2000;
+
+const FENCE: &str = "```";
+
+/// `text` を `limit` 文字以下のページに分割します。
+///
+/// 分割は行単位で行われ、コードブロックの途中で分割する必要がある場合は
+/// ページ末尾でコードブロックを閉じ、次のページで同じ言語指定で開き直します。
+/// 1 行だけで `limit` を超える場合はその行の途中で分割します。
+pub fn split(text: &str, limit: usize) -> Vec<String> {
+    let mut pages = vec![];
+    let mut page = Page::new(limit);
+
+    // Some(opening line) while inside a code block
+    let mut fence: Option<String> = None;
+
+    for line in text.lines() {
+        let toggles_fence = line.matches(FENCE).count() % 2 == 1;
+        let in_fence_after = fence.is_some() != toggles_fence;
+
+        let mut rest = line;
+        loop {
+            if page.fits(rest, in_fence_after) {
+                page.push_line(rest);
+                break;
+            }
+
+            if !page.is_empty() {
+                pages.extend(page.finish(fence.as_deref()));
+                page = Page::reopen(limit, fence.as_deref());
+
+                if page.fits(rest, in_fence_after) {
+                    continue;
+                }
+            }
+
+            // a single line doesn't fit into a fresh page, so we have to break it.
+            let (head, tail) = page.split_to_fit(rest, fence.is_some() || toggles_fence);
+            page.push_line(head);
+            rest = tail;
+
+            if rest.is_empty() {
+                break;
+            }
+
+            pages.extend(page.finish(fence.as_deref()));
+            page = Page::reopen(limit, fence.as_deref());
+        }
+
+        if toggles_fence {
+            fence = match fence {
+                Some(_) => None,
+                None => Some(reopening_fence(line)),
+            };
+        }
+    }
+
+    pages.extend(page.finish(fence.as_deref()));
+
+    pages
+}
+
+/// 複数のテキストの塊を、塊の途中でなるべく分割しないように `limit` 文字以下のページに詰めます。
+/// 1 つの塊が 1 ページに収まらない場合は [`split`] と同様に分割します。
+pub fn pack<'a>(blocks: impl IntoIterator<Item = &'a str>, limit: usize) -> Vec<String> {
+    let mut pages: Vec<String> = vec![];
+
+    for block in blocks {
+        for chunk in split(block, limit) {
+            match pages.last_mut() {
+                Some(last) if last.chars().count() + 1 + chunk.chars().count() <= limit => {
+                    last.push('\n');
+                    last.push_str(&chunk);
+                }
+                _ => pages.push(chunk),
+            }
+        }
+    }
+
+    pages
+}
+
+/// `lines` を `limit` 文字以下のページにまとめ、各ページを `header` と `footer` で挟みます。
+/// 表形式の出力を行の途中で途切れさせずに複数ページにするために使います。
+pub fn chunk_lines(header: &str, lines: &[String], footer: &str, limit: usize) -> Vec<String> {
+    let overhead = header.chars().count() + footer.chars().count();
+
+    let mut pages = vec![];
+    let mut page = String::new();
+    let mut page_len = overhead;
+
+    for line in lines {
+        let len = line.chars().count() + 1;
+
+        if !page.is_empty() && page_len + len > limit {
+            pages.push(format!("{header}{page}{footer}"));
+            page.clear();
+            page_len = overhead;
+        }
+
+        page.push_str(line);
+        page.push('\n');
+        page_len += len;
+    }
+
+    if !page.is_empty() || pages.is_empty() {
+        pages.push(format!("{header}{page}{footer}"));
+    }
+
+    pages
+}
+
+// e.g. "```toml" -> "```toml", "```error: something```" -> "```"
+fn reopening_fence(line: &str) -> String {
+    let trimmed = line.trim();
+
+    match trimmed.strip_prefix(FENCE) {
+        Some(lang) if !lang.is_empty() && !lang.contains(char::is_whitespace) => trimmed.to_owned(),
+        _ => FENCE.to_owned(),
+    }
+}
+
+struct Page {
+    lines: Vec<String>,
+    len: usize,
+    limit: usize,
+}
+
+impl Page {
+    fn new(limit: usize) -> Self {
+        Self {
+            lines: vec![],
+            len: 0,
+            limit,
+        }
+    }
+
+    fn reopen(limit: usize, fence: Option<&str>) -> Self {
+        let mut page = Self::new(limit);
+        if let Some(fence) = fence {
+            page.push_line(fence);
+        }
+        page
+    }
+
+    fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    // room for "\n```" to close the code block at the end of page.
+    fn reserved(in_fence: bool) -> usize {
+        if in_fence { FENCE.len() + 1 } else { 0 }
+    }
+
+    fn separator_len(&self) -> usize {
+        if self.lines.is_empty() { 0 } else { 1 }
+    }
+
+    fn fits(&self, line: &str, in_fence: bool) -> bool {
+        self.len + self.separator_len() + line.chars().count() + Self::reserved(in_fence)
+            <= self.limit
+    }
+
+    fn push_line(&mut self, line: &str) {
+        self.len += self.separator_len() + line.chars().count();
+        self.lines.push(line.to_owned());
+    }
+
+    fn split_to_fit<'a>(&self, line: &'a str, in_fence: bool) -> (&'a str, &'a str) {
+        let room = self
+            .limit
+            .saturating_sub(self.len + self.separator_len() + Self::reserved(in_fence))
+            .max(1);
+
+        match line.char_indices().nth(room) {
+            Some((i, _)) => line.split_at(i),
+            None => (line, ""),
+        }
+    }
+
+    fn finish(mut self, fence: Option<&str>) -> Option<String> {
+        if let Some(fence) = fence {
+            // the code block has just been opened and has no content in this page.
+            // leave it to the next page instead of sending an empty code block.
+            if self.lines.last().map(String::as_str) == Some(fence) {
+                self.lines.pop();
+            } else {
+                self.lines.push(FENCE.to_owned());
+            }
+        }
+
+        if self.lines.is_empty() {
+            return None;
+        }
+
+        Some(self.lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {super::*, pretty_assertions::assert_eq};
+
+    #[test]
+    fn short_text_is_untouched() {
+        assert_eq!(split("hello\nworld", 20), vec!["hello\nworld"]);
+        assert_eq!(split("", 20), Vec::<String>::new());
+    }
+
+    #[test]
+    fn splits_at_line_boundary() {
+        assert_eq!(split("aaaa\nbbbb\ncccc", 10), vec!["aaaa\nbbbb", "cccc"]);
+    }
+
+    #[test]
+    fn never_splits_inside_code_fence() {
+        let text = "title\n```rs\nline1\nline2\nline3\n```\nafter";
+
+        let pages = split(text, 24);
+
+        assert_eq!(
+            pages,
+            vec![
+                "title\n```rs\nline1\n```",
+                "```rs\nline2\nline3\n```",
+                "after",
+            ]
+        );
+
+        for page in &pages {
+            assert!(page.chars().count() <= 24);
+            assert_eq!(page.matches(FENCE).count() % 2, 0, "unbalanced: {page}");
+        }
+    }
+
+    #[test]
+    fn breaks_too_long_line() {
+        let pages = split(&"a".repeat(25), 10);
+        assert_eq!(pages, vec!["a".repeat(10), "a".repeat(10), "a".repeat(5)]);
+
+        let pages = split(&format!("```\n{}\n```", "あ".repeat(12)), 10);
+        assert_eq!(
+            pages,
+            vec![
+                format!("```\n{}\n```", "あ".repeat(2)),
+                format!("```\n{}\n```", "あ".repeat(2)),
+                format!("```\n{}\n```", "あ".repeat(2)),
+                format!("```\n{}\n```", "あ".repeat(2)),
+                format!("```\n{}\n```", "あ".repeat(2)),
+                format!("```\n{}\n```", "あ".repeat(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn inline_fence_is_not_reopened_with_garbage() {
+        assert_eq!(reopening_fence("```toml"), "```toml");
+        assert_eq!(reopening_fence("```error: unexpected"), "```");
+        assert_eq!(reopening_fence("```"), "```");
+    }
+
+    #[test]
+    fn pack_keeps_blocks_together() {
+        assert_eq!(
+            pack(["aaa\nbbb", "ccc", "ddd\neee"], 12),
+            vec!["aaa\nbbb\nccc", "ddd\neee"]
+        );
+        assert_eq!(pack(["aaaa\nbbbb\ncccc"], 10), vec!["aaaa\nbbbb", "cccc"]);
+    }
+
+    #[test]
+    fn chunk_lines_keeps_header_and_footer() {
+        let lines = ["#01 a", "#02 b", "#03 c"].map(String::from);
+
+        assert_eq!(
+            chunk_lines("```\n", &lines, "```", 20),
+            vec!["```\n#01 a\n#02 b\n```", "```\n#03 c\n```"]
+        );
+        assert_eq!(chunk_lines("```\n", &[], "```", 20), vec!["```\n```"]);
+    }
+}
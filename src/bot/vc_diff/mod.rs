@@ -1,10 +1,12 @@
+pub mod model;
+
 use {
-    crate::bot::{BotService, Context, Message, Runtime, parse_command, ui},
+    crate::bot::{
+        BotService, Context, Message, Runtime, SendMessage, VcStateChange, parse_command, ui,
+        vc_diff::model::VcDiffConfig,
+    },
     anyhow::{Context as _, Result},
-    chrono::{DateTime, Duration, Utc},
-    once_cell::sync::Lazy,
-    std::sync::atomic::AtomicBool,
-    std::sync::atomic::Ordering::Relaxed,
+    std::{future::Future, sync::Arc, time::Duration},
     tokio::sync::Mutex,
 };
 
@@ -28,66 +30,200 @@ enum Command {
     /// 機能を無効化します
     Disable,
 
-    /// 現在機能が有効か無効かを表示します
+    /// 現在の設定を表示します
     Status,
+
+    /// 通知先のチャンネルを設定します
+    Channel {
+        /// チャンネル ID かメンション。省略するとこのチャンネルになります
+        #[clap(conflicts_with = "reset")]
+        channel: Option<String>,
+
+        /// デフォルトのチャンネルに戻します
+        #[clap(long)]
+        reset: bool,
+    },
+}
+
+pub trait VcDiffDatabase: Send + Sync {
+    fn get_vc_diff_config(&self) -> impl Future<Output = Result<Option<VcDiffConfig>>> + Send;
+    fn set_vc_diff_config(&self, config: VcDiffConfig) -> impl Future<Output = Result<()>> + Send;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VcEvent {
+    Join {
+        user_id: u64,
+        channel_id: u64,
+    },
+    Leave {
+        user_id: u64,
+        channel_id: u64,
+    },
+    Move {
+        user_id: u64,
+        from: u64,
+        to: u64,
+    },
+    Stream {
+        user_id: u64,
+        channel_id: u64,
+        started: bool,
+    },
 }
 
-pub struct VcDiffBot {
-    enabled: AtomicBool,
-    timeout: Mutex<DateTime<Utc>>,
+pub struct VcDiffBot<D> {
+    db: D,
+    // TIMEOUT の間に起きたイベント。空でなければ送信が予約されている
+    pending: Arc<Mutex<Vec<VcEvent>>>,
 }
 
-static TIMEOUT: Lazy<Duration> = Lazy::new(|| Duration::seconds(1));
+// この間に起きた入退出はまとめて1つのメッセージにする
+const TIMEOUT: Duration = Duration::from_secs(1);
 
-impl VcDiffBot {
-    pub fn new() -> Self {
+impl<D: VcDiffDatabase> VcDiffBot<D> {
+    pub fn new(db: D) -> Self {
         Self {
-            enabled: AtomicBool::new(false),
-            timeout: Mutex::new(Utc::now()),
+            db,
+            pending: Arc::new(Mutex::new(vec![])),
         }
     }
 
-    async fn should_notify(&self) -> bool {
-        if !self.enabled.load(Relaxed) {
-            return false;
+    async fn config(&self) -> Result<VcDiffConfig> {
+        Ok(self
+            .db
+            .get_vc_diff_config()
+            .await
+            .context("failed to get vc_diff config")?
+            .unwrap_or_default())
+    }
+
+    async fn notify<C: Context + Clone + 'static>(&self, ctx: &C, event: VcEvent) -> Result<()> {
+        let config = self.config().await?;
+        if !config.enabled {
+            return Ok(());
         }
 
-        let mut timeout = self.timeout.lock().await;
+        let mut pending = self.pending.lock().await;
+        pending.push(event);
 
-        let now = Utc::now();
-        if *timeout >= now {
-            return false;
+        if pending.len() > 1 {
+            return Ok(());
         }
 
-        *timeout = now + *TIMEOUT;
-        true
+        drop(pending);
+
+        // the hooks of other services shouldn't wait for the batch, so send it in background
+        let pending = Arc::clone(&self.pending);
+        let ctx = ctx.clone();
+
+        tokio::spawn(async move {
+            tokio::time::sleep(TIMEOUT).await;
+
+            let events = std::mem::take(&mut *pending.lock().await);
+
+            if let Err(e) = send_diff(&ctx, config.channel_id, &events).await {
+                tracing::error!("failed to send vc diff: {e:?}");
+            }
+        });
+
+        Ok(())
     }
 
-    async fn notify(&self, ctx: &impl Context, user_id: u64, joined: bool) -> Result<()> {
-        if !self.should_notify().await {
-            return Ok(());
-        }
+    async fn set_channel(&self, channel_id: Option<u64>) -> Result<()> {
+        let config = self.config().await?;
 
-        let name = ctx
-            .get_user_name(user_id)
+        self.db
+            .set_vc_diff_config(VcDiffConfig {
+                channel_id,
+                ..config
+            })
             .await
-            .context("failed to get user name")?;
+            .context("failed to set vc_diff config")
+    }
 
-        let msg = if joined {
-            format!("```diff\n+ {name}\n```")
-        } else {
-            format!("```diff\n- {name}\n```")
-        };
+    async fn set_enabled(&self, enabled: bool) -> Result<()> {
+        let config = self.config().await?;
 
-        ctx.send_text_message(&msg)
+        self.db
+            .set_vc_diff_config(VcDiffConfig { enabled, ..config })
             .await
-            .context("failed to send message")?;
+            .context("failed to set vc_diff config")
+    }
+}
 
-        Ok(())
+async fn send_diff(ctx: &impl Context, channel_id: Option<u64>, events: &[VcEvent]) -> Result<()> {
+    let mut lines = vec![];
+
+    for event in events {
+        let line = match *event {
+            VcEvent::Join {
+                user_id,
+                channel_id,
+            } => format!(
+                "+ {}: {}",
+                ctx.get_user_name(user_id).await?,
+                ctx.get_channel_name(channel_id).await?
+            ),
+
+            VcEvent::Leave {
+                user_id,
+                channel_id,
+            } => format!(
+                "- {}: {}",
+                ctx.get_user_name(user_id).await?,
+                ctx.get_channel_name(channel_id).await?
+            ),
+
+            VcEvent::Move { user_id, from, to } => format!(
+                "! {}: {} → {}",
+                ctx.get_user_name(user_id).await?,
+                ctx.get_channel_name(from).await?,
+                ctx.get_channel_name(to).await?
+            ),
+
+            VcEvent::Stream {
+                user_id,
+                channel_id,
+                started,
+            } => format!(
+                "* {}: {} で配信{}",
+                ctx.get_user_name(user_id).await?,
+                ctx.get_channel_name(channel_id).await?,
+                if started { "開始" } else { "終了" }
+            ),
+        };
+
+        lines.push(line);
     }
+
+    let content = format!("```diff\n{}\n```", lines.join("\n"));
+    let msg = SendMessage {
+        content: &content,
+        attachments: &[],
+    };
+
+    match channel_id {
+        Some(channel_id) => ctx.send_message_to(channel_id, msg).await,
+        None => ctx.send_message(msg).await,
+    }
+    .context("failed to send message")
+}
+
+// `<#123>` or `123`
+fn parse_channel(channel: &str) -> Option<u64> {
+    channel
+        .strip_prefix("<#")
+        .and_then(|x| x.strip_suffix('>'))
+        .unwrap_or(channel)
+        .parse()
+        .ok()
 }
 
-impl<R: Runtime> BotService<R> for VcDiffBot {
+impl<R: Runtime, D: VcDiffDatabase> BotService<R> for VcDiffBot<D>
+where
+    R::Context: Clone + 'static,
+{
     fn name(&self) -> &'static str {
         NAME
     }
@@ -103,38 +239,266 @@ impl<R: Runtime> BotService<R> for VcDiffBot {
 
         use Command::*;
 
-        let msg = match parsed.command {
+        let text = match parsed.command {
             Enable => {
-                self.enabled.store(true, Relaxed);
-                "vcdiff を有効化しました"
+                self.set_enabled(true).await?;
+                "vcdiff を有効化しました".to_owned()
             }
 
             Disable => {
-                self.enabled.store(false, Relaxed);
-                "vcdiff を無効化しました"
+                self.set_enabled(false).await?;
+                "vcdiff を無効化しました".to_owned()
             }
 
             Status => {
-                if self.enabled.load(Relaxed) {
-                    "vcdiff は現在有効です"
-                } else {
-                    "vcdiff は現在無効です"
+                let config = self.config().await?;
+
+                let status = if config.enabled { "有効" } else { "無効" };
+                let channel = match config.channel_id {
+                    Some(id) => format!("<#{id}>"),
+                    None => "デフォルトのチャンネル".to_owned(),
+                };
+
+                format!("vcdiff は現在{status}です (通知先: {channel})")
+            }
+
+            Channel { reset: true, .. } => {
+                self.set_channel(None).await?;
+                "通知先をデフォルトのチャンネルに戻しました".to_owned()
+            }
+
+            Channel { channel, .. } => {
+                let channel_id = match channel {
+                    Some(channel) => parse_channel(&channel),
+                    None => Some(msg.channel_id()),
+                };
+
+                match channel_id {
+                    Some(id) => {
+                        self.set_channel(Some(id)).await?;
+                        format!("通知先を <#{id}> に設定しました")
+                    }
+                    None => "チャンネルの指定が正しくありません".to_owned(),
                 }
             }
         };
 
-        ctx.send_text_message(msg)
+        ctx.send_text_message(&text)
             .await
             .context("failed to send message")?;
 
         Ok(())
     }
 
-    async fn on_vc_join(&self, ctx: &R::Context, user_id: u64) -> Result<()> {
-        self.notify(ctx, user_id, true).await
+    async fn on_vc_join(&self, ctx: &R::Context, user_id: u64, channel_id: u64) -> Result<()> {
+        self.notify(
+            ctx,
+            VcEvent::Join {
+                user_id,
+                channel_id,
+            },
+        )
+        .await
     }
 
-    async fn on_vc_leave(&self, ctx: &R::Context, user_id: u64) -> Result<()> {
-        self.notify(ctx, user_id, false).await
+    async fn on_vc_leave(&self, ctx: &R::Context, user_id: u64, channel_id: u64) -> Result<()> {
+        self.notify(
+            ctx,
+            VcEvent::Leave {
+                user_id,
+                channel_id,
+            },
+        )
+        .await
+    }
+
+    async fn on_vc_move(&self, ctx: &R::Context, user_id: u64, from: u64, to: u64) -> Result<()> {
+        self.notify(ctx, VcEvent::Move { user_id, from, to }).await
+    }
+
+    async fn on_vc_state_change(
+        &self,
+        ctx: &R::Context,
+        user_id: u64,
+        channel_id: u64,
+        change: VcStateChange,
+        initial: bool,
+    ) -> Result<()> {
+        // the stream was started before the bot (re)connected, and may already be notified
+        if initial {
+            return Ok(());
+        }
+
+        let VcStateChange::Stream(started) = change else {
+            return Ok(());
+        };
+
+        self.notify(
+            ctx,
+            VcEvent::Stream {
+                user_id,
+                channel_id,
+                started,
+            },
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::{client::test::TestMessage, db::mem::MemoryDB},
+        pretty_assertions::assert_eq,
+        std::sync::Mutex as StdMutex,
+    };
+
+    // (送信先のチャンネル, 内容)
+    type Sent = (Option<u64>, String);
+
+    struct SpyRuntime;
+    impl Runtime for SpyRuntime {
+        type Message = TestMessage;
+        type Context = SpyContext;
+    }
+
+    #[derive(Clone, Default)]
+    struct SpyContext {
+        sent: Arc<StdMutex<Vec<Sent>>>,
+    }
+
+    impl Context for SpyContext {
+        async fn send_message(&self, msg: SendMessage<'_>) -> Result<()> {
+            self.sent
+                .lock()
+                .unwrap()
+                .push((None, msg.content.to_owned()));
+            Ok(())
+        }
+
+        async fn send_message_to(&self, channel_id: u64, msg: SendMessage<'_>) -> Result<()> {
+            self.sent
+                .lock()
+                .unwrap()
+                .push((Some(channel_id), msg.content.to_owned()));
+            Ok(())
+        }
+
+        async fn get_user_name(&self, user_id: u64) -> Result<String> {
+            Ok(format!("user{user_id}"))
+        }
+
+        async fn get_channel_name(&self, channel_id: u64) -> Result<String> {
+            Ok(format!("vc{channel_id}"))
+        }
+
+        async fn is_bot(&self, _user_id: u64) -> Result<bool> {
+            Ok(false)
+        }
+    }
+
+    #[test]
+    fn channels() {
+        assert_eq!(parse_channel("<#123>"), Some(123));
+        assert_eq!(parse_channel("123"), Some(123));
+        assert_eq!(parse_channel("<@123>"), None);
+    }
+
+    #[tokio::test]
+    async fn batch_events() {
+        let bot = VcDiffBot::new(MemoryDB::new());
+        let ctx = SpyContext::default();
+
+        // disabled by default
+        bot.notify(
+            &ctx,
+            VcEvent::Join {
+                user_id: 1,
+                channel_id: 10,
+            },
+        )
+        .await
+        .unwrap();
+
+        bot.set_enabled(true).await.unwrap();
+        bot.set_channel(Some(100)).await.unwrap();
+
+        let events = [
+            VcEvent::Join {
+                user_id: 1,
+                channel_id: 10,
+            },
+            VcEvent::Move {
+                user_id: 2,
+                from: 10,
+                to: 20,
+            },
+            VcEvent::Stream {
+                user_id: 2,
+                channel_id: 20,
+                started: true,
+            },
+            VcEvent::Leave {
+                user_id: 3,
+                channel_id: 20,
+            },
+        ];
+
+        for event in events {
+            bot.notify(&ctx, event).await.unwrap();
+        }
+
+        tokio::time::sleep(TIMEOUT * 2).await;
+
+        assert_eq!(
+            *ctx.sent.lock().unwrap(),
+            vec![(
+                Some(100),
+                "```diff\n+ user1: vc10\n! user2: vc10 → vc20\n* user2: vc20 で配信開始\n- user3: vc20\n```".to_owned()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn ignore_initial_states() {
+        let bot = VcDiffBot::new(MemoryDB::new());
+        let ctx = SpyContext::default();
+
+        bot.set_enabled(true).await.unwrap();
+        bot.set_channel(Some(100)).await.unwrap();
+
+        // replayed on reconnect
+        BotService::<SpyRuntime>::on_vc_state_change(
+            &bot,
+            &ctx,
+            1,
+            10,
+            VcStateChange::Stream(true),
+            true,
+        )
+        .await
+        .unwrap();
+
+        BotService::<SpyRuntime>::on_vc_state_change(
+            &bot,
+            &ctx,
+            2,
+            10,
+            VcStateChange::Stream(true),
+            false,
+        )
+        .await
+        .unwrap();
+
+        tokio::time::sleep(TIMEOUT * 2).await;
+
+        assert_eq!(
+            *ctx.sent.lock().unwrap(),
+            vec![(
+                Some(100),
+                "```diff\n* user2: vc10 で配信開始\n```".to_owned()
+            )]
+        );
     }
 }
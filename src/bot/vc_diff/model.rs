@@ -0,0 +1,8 @@
+use serde::Serialize;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VcDiffConfig {
+    pub enabled: bool,
+    /// 通知先のチャンネル。None ならデフォルトのチャンネル
+    pub channel_id: Option<u64>,
+}
@@ -153,6 +153,10 @@ impl Message for ConsoleMessage {
         &self.content
     }
 
+    fn channel_id(&self) -> u64 {
+        0
+    }
+
     fn attachments(&self) -> &[ConsoleAttachment] {
         &self.attachments
     }
@@ -218,6 +222,7 @@ impl Attachment for ConsoleAttachment {
     }
 }
 
+#[derive(Clone)]
 pub struct ConsoleContext {
     service_name: &'static str,
     begin: Instant,
@@ -251,6 +256,11 @@ impl Context for ConsoleContext {
         Ok(())
     }
 
+    async fn send_message_to(&self, channel_id: u64, msg: SendMessage<'_>) -> Result<()> {
+        println!("(to channel {channel_id})");
+        self.send_message(msg).await
+    }
+
     async fn is_bot(&self, _user_id: u64) -> Result<bool> {
         Ok(false)
     }
@@ -258,4 +268,8 @@ impl Context for ConsoleContext {
     async fn get_user_name(&self, _user_id: u64) -> Result<String> {
         Ok("ConsoleUser".to_string())
     }
+
+    async fn get_channel_name(&self, _channel_id: u64) -> Result<String> {
+        Ok("ConsoleChannel".to_string())
+    }
 }
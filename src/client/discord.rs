@@ -2,7 +2,9 @@ use {
     super::ServiceVisitor,
     crate::{
         APPROVERS_DEFAULT_CHANNEL_ID, APPROVERS_GUILD_ID,
-        bot::{Attachment, BotService, Context, Message, Runtime, SendMessage, User},
+        bot::{
+            Attachment, BotService, Context, Message, Runtime, SendMessage, User, VcStateChange,
+        },
         client::{ListCons, ListNil, ServiceList},
     },
     anyhow::{Context as _, Result},
@@ -10,11 +12,18 @@ use {
     serenity::{
         all::{DiscordJsonError, ErrorResponse, HttpError::UnsuccessfulRequest},
         async_trait,
-        builder::{CreateAttachment, CreateMessage},
+        builder::{
+            CreateActionRow, CreateAttachment, CreateButton, CreateInteractionResponse,
+            CreateInteractionResponseMessage, CreateMessage,
+        },
         model::{
+            application::{ButtonStyle, Interaction},
             channel::{Attachment as SerenityAttachment, Message as SerenityMessage},
             gateway::Ready,
-            id::{ChannelId as SerenityChannelId, UserId as SerenityUserId},
+            id::{
+                ChannelId as SerenityChannelId, MessageId as SerenityMessageId,
+                UserId as SerenityUserId,
+            },
             voice::VoiceState,
         },
         prelude::{Client, Context as SerenityContext, EventHandler, GatewayIntents},
@@ -23,7 +32,7 @@ use {
         collections::{HashMap, HashSet},
         future::Future,
         sync::Arc,
-        time::Duration,
+        time::{Duration, Instant},
     },
     tokio::{
         sync::{Mutex, RwLock},
@@ -91,9 +100,48 @@ impl<L: ServiceList<DiscordRuntime> + 'static> DiscordClient<L> {
 
 struct EvHandlerInner<L: ServiceList<DiscordRuntime>> {
     services: L,
-    vc_joined_users: Mutex<HashSet<SerenityUserId>>,
+    vc_joined_users: Mutex<HashMap<SerenityUserId, VcState>>,
     nickname_cache: Arc<RwLock<NicknameCache>>,
     is_bot_cache: Arc<RwLock<IsBotCache>>,
+    pager_cache: Arc<RwLock<PagerCache>>,
+}
+
+// VC に入っているユーザーの状態
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct VcState {
+    channel_id: SerenityChannelId,
+    mute: bool,
+    deaf: bool,
+    stream: bool,
+    video: bool,
+}
+
+impl VcState {
+    fn from_serenity(state: &VoiceState) -> Option<Self> {
+        Some(Self {
+            channel_id: state.channel_id?,
+            mute: state.self_mute,
+            deaf: state.self_deaf,
+            stream: state.self_stream.unwrap_or(false),
+            video: state.self_video,
+        })
+    }
+
+    // old が None なら、全て false の状態から変化したものとして扱う
+    fn changes_since(&self, old: Option<&Self>) -> Vec<VcStateChange> {
+        let [mute, deaf, stream, video] =
+            old.map_or([false; 4], |x| [x.mute, x.deaf, x.stream, x.video]);
+
+        [
+            (mute != self.mute).then_some(VcStateChange::Mute(self.mute)),
+            (deaf != self.deaf).then_some(VcStateChange::Deafen(self.deaf)),
+            (stream != self.stream).then_some(VcStateChange::Stream(self.stream)),
+            (video != self.video).then_some(VcStateChange::Video(self.video)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
 }
 
 struct EvHandler<L: ServiceList<DiscordRuntime>> {
@@ -125,7 +173,7 @@ mod visitors {
 
     pub struct VcDataAvailableVisitor<'a> {
         pub ctx: &'a DiscordContext,
-        pub users: &'a [u64],
+        pub users: &'a [(u64, u64)],
     }
     impl ForEachService for VcDataAvailableVisitor<'_> {
         const OP: &'static str = "on_vc_data_avaialble";
@@ -137,22 +185,58 @@ mod visitors {
     pub struct VcJoinVisitor<'a> {
         pub ctx: &'a DiscordContext,
         pub uid: u64,
+        pub channel_id: u64,
     }
     impl ForEachService for VcJoinVisitor<'_> {
         const OP: &'static str = "on_vc_join";
         async fn accept(&self, s: &impl BotService<DiscordRuntime>) -> Result<()> {
-            s.on_vc_join(self.ctx, self.uid).await
+            s.on_vc_join(self.ctx, self.uid, self.channel_id).await
         }
     }
 
     pub struct VcLeaveVisitor<'a> {
         pub ctx: &'a DiscordContext,
         pub uid: u64,
+        pub channel_id: u64,
     }
     impl ForEachService for VcLeaveVisitor<'_> {
         const OP: &'static str = "on_vc_leave";
         async fn accept(&self, s: &impl BotService<DiscordRuntime>) -> Result<()> {
-            s.on_vc_leave(self.ctx, self.uid).await
+            s.on_vc_leave(self.ctx, self.uid, self.channel_id).await
+        }
+    }
+
+    pub struct VcMoveVisitor<'a> {
+        pub ctx: &'a DiscordContext,
+        pub uid: u64,
+        pub from: u64,
+        pub to: u64,
+    }
+    impl ForEachService for VcMoveVisitor<'_> {
+        const OP: &'static str = "on_vc_move";
+        async fn accept(&self, s: &impl BotService<DiscordRuntime>) -> Result<()> {
+            s.on_vc_move(self.ctx, self.uid, self.from, self.to).await
+        }
+    }
+
+    pub struct VcStateChangeVisitor<'a> {
+        pub ctx: &'a DiscordContext,
+        pub uid: u64,
+        pub channel_id: u64,
+        pub change: VcStateChange,
+        pub initial: bool,
+    }
+    impl ForEachService for VcStateChangeVisitor<'_> {
+        const OP: &'static str = "on_vc_state_change";
+        async fn accept(&self, s: &impl BotService<DiscordRuntime>) -> Result<()> {
+            s.on_vc_state_change(
+                self.ctx,
+                self.uid,
+                self.channel_id,
+                self.change,
+                self.initial,
+            )
+            .await
         }
     }
 }
@@ -162,9 +246,10 @@ impl<L: ServiceList<DiscordRuntime> + 'static> EvHandler<L> {
         Self {
             inner: Arc::new(EvHandlerInner {
                 services,
-                vc_joined_users: Mutex::new(HashSet::new()),
+                vc_joined_users: Mutex::new(HashMap::new()),
                 nickname_cache: Arc::new(RwLock::new(NicknameCache(HashMap::new()))),
                 is_bot_cache: Arc::new(RwLock::new(IsBotCache(HashMap::new()))),
+                pager_cache: Arc::new(RwLock::new(PagerCache(HashMap::new()))),
             }),
         }
     }
@@ -223,20 +308,21 @@ impl<L: ServiceList<DiscordRuntime> + 'static> EvHandler<L> {
                 None => continue,
             };
 
-            let joined_users = guild
+            let joined = guild
                 .voice_states
-                .keys()
-                .map(|user_id| user_id.get())
+                .values()
+                .filter_map(|x| Some((x.user_id, VcState::from_serenity(x)?)))
                 .collect::<Vec<_>>();
 
-            for user_id in &joined_users {
-                tracing::info!("joined users on startup: {}", user_id);
+            let joined_users = joined
+                .iter()
+                .map(|(uid, state)| (uid.get(), state.channel_id.get()))
+                .collect::<Vec<_>>();
 
-                inner
-                    .vc_joined_users
-                    .lock()
-                    .await
-                    .insert(SerenityUserId::new(*user_id));
+            for (user_id, state) in &joined {
+                tracing::info!("joined users on startup: {}", user_id.get());
+
+                inner.vc_joined_users.lock().await.insert(*user_id, *state);
             }
 
             let converted_ctx = DiscordContext::from_serenity(
@@ -244,6 +330,7 @@ impl<L: ServiceList<DiscordRuntime> + 'static> EvHandler<L> {
                 APPROVERS_DEFAULT_CHANNEL_ID,
                 &inner.nickname_cache,
                 &inner.is_bot_cache,
+                &inner.pager_cache,
             );
 
             Self::do_for_each_service(
@@ -256,6 +343,24 @@ impl<L: ServiceList<DiscordRuntime> + 'static> EvHandler<L> {
             )
             .await;
 
+            // users who were already muted or streaming
+            for (user_id, state) in &joined {
+                for change in state.changes_since(None) {
+                    Self::do_for_each_service(
+                        &ctx,
+                        &inner,
+                        visitors::VcStateChangeVisitor {
+                            ctx: &converted_ctx,
+                            uid: user_id.get(),
+                            channel_id: state.channel_id.get(),
+                            change,
+                            initial: true,
+                        },
+                    )
+                    .await;
+                }
+            }
+
             tracing::info!("vc status checking on startup complete");
             break;
         }
@@ -284,53 +389,125 @@ impl<L: ServiceList<DiscordRuntime> + 'static> EvHandler<L> {
                 APPROVERS_DEFAULT_CHANNEL_ID,
                 &inner.nickname_cache,
                 &inner.is_bot_cache,
+                &inner.pager_cache,
             );
 
             let mut self_state = inner.vc_joined_users.lock().await;
-            let serenity_state = &guild.voice_states;
+            let serenity_state = guild
+                .voice_states
+                .values()
+                .filter_map(|x| Some((x.user_id, VcState::from_serenity(x)?)))
+                .collect::<HashMap<_, _>>();
 
-            let missing_in_self_state = serenity_state
+            let user_ids = self_state
                 .keys()
-                .filter(|x| !self_state.contains(x))
-                .cloned()
-                .collect::<Vec<_>>();
+                .chain(serenity_state.keys())
+                .copied()
+                .collect::<HashSet<_>>();
 
-            let missing_in_serenity_state = self_state
-                .iter()
-                .filter(|x| !serenity_state.contains_key(x))
-                .cloned()
-                .collect::<Vec<_>>();
+            for uid in user_ids {
+                let actual = serenity_state.get(&uid).copied();
+                if self_state.get(&uid).copied() == actual {
+                    continue;
+                }
 
-            for uid in missing_in_self_state {
-                self_state.insert(uid);
-                tracing::info!("user({}) has actually joined to vc", uid.get());
+                tracing::info!("vc state of user({}) was out of sync", uid.get());
+
+                Self::update_vc_state(&ctx, &inner, &converted_ctx, &mut self_state, uid, actual)
+                    .await;
+            }
+        }
+    }
+
+    // 保持している状態を new に更新し、変化に応じたイベントを各サービスに通知します
+    async fn update_vc_state(
+        ctx: &SerenityContext,
+        inner: &EvHandlerInner<L>,
+        converted_ctx: &DiscordContext,
+        self_state: &mut HashMap<SerenityUserId, VcState>,
+        user_id: SerenityUserId,
+        new: Option<VcState>,
+    ) {
+        let uid = user_id.get();
+        let old = match new {
+            Some(new) => self_state.insert(user_id, new),
+            None => self_state.remove(&user_id),
+        };
+
+        match (old, new) {
+            // joined
+            (None, Some(new)) => {
+                tracing::debug!("User({uid}) has joined to vc");
 
                 Self::do_for_each_service(
-                    &ctx,
-                    &inner,
+                    ctx,
+                    inner,
                     visitors::VcJoinVisitor {
-                        ctx: &converted_ctx,
-                        uid: uid.get(),
+                        ctx: converted_ctx,
+                        uid,
+                        channel_id: new.channel_id.get(),
                     },
                 )
                 .await;
             }
 
-            for uid in missing_in_serenity_state {
-                tracing::info!("user({}) has actually left from vc", uid.get());
-
-                self_state.remove(&uid);
+            // left
+            (Some(old), None) => {
+                tracing::debug!("User({uid}) has left from vc");
 
                 Self::do_for_each_service(
-                    &ctx,
-                    &inner,
+                    ctx,
+                    inner,
                     visitors::VcLeaveVisitor {
-                        ctx: &converted_ctx,
-                        uid: uid.get(),
+                        ctx: converted_ctx,
+                        uid,
+                        channel_id: old.channel_id.get(),
+                    },
+                )
+                .await;
+            }
+
+            // moved to other channel
+            (Some(old), Some(new)) if old.channel_id != new.channel_id => {
+                tracing::debug!("User({uid}) has moved to other vc");
+
+                Self::do_for_each_service(
+                    ctx,
+                    inner,
+                    visitors::VcMoveVisitor {
+                        ctx: converted_ctx,
+                        uid,
+                        from: old.channel_id.get(),
+                        to: new.channel_id.get(),
                     },
                 )
                 .await;
             }
+
+            // muted or something
+            (Some(_), Some(_)) => {}
+
+            // ???
+            (None, None) => {}
+        }
+
+        let Some(new) = new else {
+            return;
+        };
+
+        for change in new.changes_since(old.as_ref()) {
+            Self::do_for_each_service(
+                ctx,
+                inner,
+                visitors::VcStateChangeVisitor {
+                    ctx: converted_ctx,
+                    uid,
+                    channel_id: new.channel_id.get(),
+                    change,
+                    initial: false,
+                },
+            )
+            .await;
         }
     }
 }
@@ -356,61 +533,63 @@ impl<L: ServiceList<DiscordRuntime> + Send + Sync + 'static> EventHandler for Ev
             return;
         }
 
-        let user_id = state.user_id;
-        let currently_joined = state.channel_id.is_some();
-
         let mut self_state = self.inner.vc_joined_users.lock().await;
 
-        let self_state_currently_joined = self_state.iter().any(|x| *x == user_id);
-
         let converted_ctx = DiscordContext::from_serenity(
             &ctx,
             APPROVERS_DEFAULT_CHANNEL_ID,
             &self.inner.nickname_cache,
             &self.inner.is_bot_cache,
+            &self.inner.pager_cache,
         );
 
-        match (currently_joined, self_state_currently_joined) {
-            // joined
-            (true, false) => {
-                tracing::debug!("User({}) has joined to vc", user_id.get());
+        Self::update_vc_state(
+            &ctx,
+            &self.inner,
+            &converted_ctx,
+            &mut self_state,
+            state.user_id,
+            VcState::from_serenity(&state),
+        )
+        .await;
+    }
 
-                self_state.insert(user_id);
+    async fn interaction_create(&self, ctx: SerenityContext, interaction: Interaction) {
+        let Interaction::Component(component) = interaction else {
+            return;
+        };
 
-                Self::do_for_each_service(
-                    &ctx,
-                    &self.inner,
-                    visitors::VcJoinVisitor {
-                        ctx: &converted_ctx,
-                        uid: user_id.get(),
-                    },
-                )
-                .await;
-            }
+        let custom_id = component.data.custom_id.as_str();
+        if !matches!(custom_id, PAGER_PREV_BUTTON_ID | PAGER_NEXT_BUTTON_ID) {
+            return;
+        }
 
-            // left
-            (false, true) => {
-                tracing::debug!("User({}) has left from vc", user_id.get());
+        let message = {
+            let mut pager_cache = self.inner.pager_cache.write().await;
 
-                self_state.remove(&user_id);
+            match pager_cache.get_mut(component.message.id) {
+                Some(pager) => {
+                    if custom_id == PAGER_PREV_BUTTON_ID {
+                        pager.current = pager.current.saturating_sub(1);
+                    } else {
+                        pager.current = (pager.current + 1).min(pager.pages.len() - 1);
+                    }
 
-                Self::do_for_each_service(
-                    &ctx,
-                    &self.inner,
-                    visitors::VcLeaveVisitor {
-                        ctx: &converted_ctx,
-                        uid: user_id.get(),
-                    },
-                )
-                .await;
+                    CreateInteractionResponseMessage::new()
+                        .content(&pager.pages[pager.current])
+                        .components(pager.components())
+                }
+
+                // expired, or forgotten by a restart. the buttons no longer work, so remove them
+                None => CreateInteractionResponseMessage::new().components(vec![]),
             }
+        };
 
-            // moved to other channel or something
-            (true, true) => {}
+        let response = CreateInteractionResponse::UpdateMessage(message);
 
-            // ???
-            (false, false) => {}
-        };
+        if let Err(e) = component.create_response(&ctx.http, response).await {
+            tracing::warn!("failed to respond to pager interaction: {e:?}");
+        }
     }
 
     async fn message(&self, ctx: SerenityContext, message: SerenityMessage) {
@@ -437,6 +616,7 @@ impl<L: ServiceList<DiscordRuntime> + Send + Sync + 'static> EventHandler for Ev
             message.channel_id,
             &self.inner.nickname_cache,
             &self.inner.is_bot_cache,
+            &self.inner.pager_cache,
         );
 
         let converted_message = DiscordMessage {
@@ -472,6 +652,62 @@ struct NicknameCache(HashMap<SerenityUserId, String>);
 
 struct IsBotCache(HashMap<SerenityUserId, bool>);
 
+const PAGER_PREV_BUTTON_ID: &str = "rusty_ponyo::pager::prev";
+const PAGER_NEXT_BUTTON_ID: &str = "rusty_ponyo::pager::next";
+const PAGER_PAGE_BUTTON_ID: &str = "rusty_ponyo::pager::page";
+
+// ページ送りを受け付ける期間
+const PAGER_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+struct PagerCache(HashMap<SerenityMessageId, Pager>);
+
+impl PagerCache {
+    fn insert(&mut self, id: SerenityMessageId, pager: Pager) {
+        self.0
+            .retain(|_, x| x.created_at.elapsed() < PAGER_LIFETIME);
+        self.0.insert(id, pager);
+    }
+
+    fn get_mut(&mut self, id: SerenityMessageId) -> Option<&mut Pager> {
+        if self
+            .0
+            .get(&id)
+            .is_some_and(|x| x.created_at.elapsed() >= PAGER_LIFETIME)
+        {
+            self.0.remove(&id);
+        }
+
+        self.0.get_mut(&id)
+    }
+}
+
+struct Pager {
+    pages: Vec<String>,
+    current: usize,
+    created_at: Instant,
+}
+
+impl Pager {
+    fn components(&self) -> Vec<CreateActionRow> {
+        let last = self.pages.len() - 1;
+
+        vec![CreateActionRow::Buttons(vec![
+            CreateButton::new(PAGER_PREV_BUTTON_ID)
+                .label("◀")
+                .style(ButtonStyle::Secondary)
+                .disabled(self.current == 0),
+            CreateButton::new(PAGER_PAGE_BUTTON_ID)
+                .label(format!("{}/{}", self.current + 1, self.pages.len()))
+                .style(ButtonStyle::Secondary)
+                .disabled(true),
+            CreateButton::new(PAGER_NEXT_BUTTON_ID)
+                .label("▶")
+                .style(ButtonStyle::Secondary)
+                .disabled(self.current == last),
+        ])]
+    }
+}
+
 pub struct DiscordMessage {
     ctx: SerenityContext,
     message: SerenityMessage,
@@ -495,6 +731,10 @@ impl Message for DiscordMessage {
         &self.message.content
     }
 
+    fn channel_id(&self) -> u64 {
+        self.message.channel_id.get()
+    }
+
     fn attachments(&self) -> &[DiscordAttachment] {
         &self.attachments
     }
@@ -560,11 +800,13 @@ impl Attachment for DiscordAttachment {
     }
 }
 
+#[derive(Clone)]
 pub struct DiscordContext {
     origin: SerenityContext,
     channel_id: SerenityChannelId,
     nickname_cache: Arc<RwLock<NicknameCache>>,
     is_bot_cache: Arc<RwLock<IsBotCache>>,
+    pager_cache: Arc<RwLock<PagerCache>>,
 }
 
 impl DiscordContext {
@@ -573,18 +815,24 @@ impl DiscordContext {
         channel_id: impl Into<SerenityChannelId>,
         nickname_cache: &Arc<RwLock<NicknameCache>>,
         is_bot_cache: &Arc<RwLock<IsBotCache>>,
+        pager_cache: &Arc<RwLock<PagerCache>>,
     ) -> Self {
         Self {
             origin: origin.clone(),
             channel_id: channel_id.into(),
             nickname_cache: Arc::clone(nickname_cache),
             is_bot_cache: Arc::clone(is_bot_cache),
+            pager_cache: Arc::clone(pager_cache),
         }
     }
 }
 
 impl Context for DiscordContext {
     async fn send_message(&self, msg: SendMessage<'_>) -> Result<()> {
+        self.send_message_to(self.channel_id.get(), msg).await
+    }
+
+    async fn send_message_to(&self, channel_id: u64, msg: SendMessage<'_>) -> Result<()> {
         let files = msg
             .attachments
             .iter()
@@ -593,7 +841,7 @@ impl Context for DiscordContext {
 
         let msg = CreateMessage::new().content(msg.content);
 
-        self.channel_id
+        SerenityChannelId::new(channel_id)
             .send_files(&self.origin.http, files, msg)
             .await
             .context("failed to send message to discord")?;
@@ -601,6 +849,35 @@ impl Context for DiscordContext {
         Ok(())
     }
 
+    async fn send_pages(&self, pages: &[String]) -> Result<()> {
+        if pages.len() <= 1 {
+            for page in pages {
+                self.send_text_message(page).await?;
+            }
+            return Ok(());
+        }
+
+        let pager = Pager {
+            pages: pages.to_vec(),
+            current: 0,
+            created_at: Instant::now(),
+        };
+
+        let msg = CreateMessage::new()
+            .content(&pager.pages[0])
+            .components(pager.components());
+
+        let sent = self
+            .channel_id
+            .send_message(&self.origin.http, msg)
+            .await
+            .context("failed to send message to discord")?;
+
+        self.pager_cache.write().await.insert(sent.id, pager);
+
+        Ok(())
+    }
+
     async fn get_user_name(&self, user_id: u64) -> Result<String> {
         let user_id = SerenityUserId::new(user_id);
 
@@ -624,6 +901,13 @@ impl Context for DiscordContext {
         Ok(user.name)
     }
 
+    async fn get_channel_name(&self, channel_id: u64) -> Result<String> {
+        SerenityChannelId::new(channel_id)
+            .name(&self.origin)
+            .await
+            .context("failed to get channel name from discord")
+    }
+
     async fn is_bot(&self, user_id: u64) -> Result<bool> {
         let user_id = SerenityUserId::new(user_id);
 
@@ -48,9 +48,15 @@ impl Context for TestContext {
         self.msg.lock().unwrap().push(msg.content.to_owned());
         Ok(())
     }
+    async fn send_message_to(&self, _channel_id: u64, _msg: SendMessage<'_>) -> Result<()> {
+        todo!()
+    }
     async fn get_user_name(&self, _user_id: u64) -> Result<String> {
         todo!()
     }
+    async fn get_channel_name(&self, _channel_id: u64) -> Result<String> {
+        todo!()
+    }
     async fn is_bot(&self, _user_id: u64) -> Result<bool> {
         todo!()
     }
@@ -72,6 +78,9 @@ impl Message for TestMessage {
     fn content(&self) -> &str {
         &self.content
     }
+    fn channel_id(&self) -> u64 {
+        0
+    }
     fn attachments(&self) -> &[Self::Attachment] {
         todo!()
     }
@@ -2,14 +2,23 @@ use {
     crate::bot::{
         IsUpdated,
         alias::{MessageAliasDatabase, model::MessageAlias},
-        auth::GenkaiAuthDatabase,
-        genkai_point::{CreateNewSessionResult, GenkaiPointDatabase, model::Session},
+        auth::{
+            GenkaiAuthDatabase,
+            model::{DeviceAuthorization, Token},
+        },
+        genkai_point::{
+            CreateNewSessionResult, GenkaiPointDatabase,
+            admin::AuditLogEntry,
+            formula::custom::CustomFormula,
+            model::{ExcludedPeriod, Session, SessionId, SubSession, UncertainPeriod},
+        },
         meigen::{
             self, MeigenDatabase, SortDirection, SortKey,
             model::{Meigen, MeigenId},
         },
+        vc_diff::{VcDiffDatabase, model::VcDiffConfig},
     },
-    anyhow::{Context as _, Result, anyhow},
+    anyhow::{Context as _, Result, anyhow, bail},
     chrono::{DateTime, Duration, Utc},
     rand::{rng, seq::SliceRandom},
     serde::Serialize,
@@ -20,9 +29,25 @@ use {
 #[derive(Serialize)]
 struct MemoryDBInner {
     aliases: Vec<MessageAlias>,
-    sessions: Vec<Session>,
+    sessions: Vec<MemorySession>,
+    next_session_id: u64,
+    sub_sessions: Vec<SubSession>,
+    custom_formulas: Vec<CustomFormula>,
+    audit_logs: Vec<AuditLogEntry>,
+    last_heartbeat: Option<DateTime<Utc>>,
+    deafened_since: HashMap<u64, DateTime<Utc>>,
     auth_entries: HashMap<u64, AuthEntry>,
+    auth_tokens: Vec<Token>,
+    device_authorizations: Vec<DeviceAuthorization>,
     meigens: Vec<Meigen>,
+    vc_diff_config: Option<VcDiffConfig>,
+}
+
+#[derive(Clone, Serialize)]
+struct MemorySession {
+    id: u64,
+    #[serde(flatten)]
+    session: Session,
 }
 
 pub struct MemoryDB(Arc<Mutex<MemoryDBInner>>);
@@ -38,8 +63,17 @@ impl MemoryDB {
         Self(Arc::new(Mutex::new(MemoryDBInner {
             aliases: vec![],
             sessions: vec![],
+            next_session_id: 0,
+            sub_sessions: vec![],
+            custom_formulas: vec![],
+            audit_logs: vec![],
+            last_heartbeat: None,
+            deafened_since: HashMap::new(),
             auth_entries: HashMap::new(),
+            auth_tokens: vec![],
+            device_authorizations: vec![],
             meigens: vec![],
+            vc_diff_config: None,
         })))
     }
 
@@ -49,6 +83,19 @@ impl MemoryDB {
 }
 
 impl MemoryDBInner {
+    fn push_session(&mut self, session: Session) -> SessionId {
+        let id = self.next_session_id;
+        self.next_session_id += 1;
+
+        self.sessions.push(MemorySession { id, session });
+
+        SessionId(id.to_string())
+    }
+
+    fn session_index(&self, id: &SessionId) -> Option<usize> {
+        self.sessions.iter().position(|x| x.id.to_string() == id.0)
+    }
+
     pub async fn dump(&self) -> Result<()> {
         let json = serde_json::to_string_pretty(self).context("failed to serialize")?;
 
@@ -132,21 +179,28 @@ impl GenkaiPointDatabase for MemoryDB {
         }
 
         let mut me = self.inner().await;
-        me.sessions.sort_unstable_by_key(|x| x.joined_at);
+        me.sessions.sort_unstable_by_key(|x| x.session.joined_at);
 
-        if let Some(session) = me.sessions.iter_mut().rev().find(|x| x.user_id == user_id)
+        if let Some(session) = me
+            .sessions
+            .iter_mut()
+            .rev()
+            .map(|x| &mut x.session)
+            .find(|x| x.user_id == user_id)
             && let Some(left_at) = session.left_at
             && (Utc::now() - left_at) < Duration::minutes(5)
         {
             session.left_at = None;
             me.dump().await?;
-            return Ok(CreateNewSessionResult::SessionResumed);
+            return Ok(CreateNewSessionResult::SessionResumed { left_at });
         }
 
-        me.sessions.push(Session {
+        me.push_session(Session {
             user_id,
             joined_at,
             left_at: None,
+            excluded: vec![],
+            uncertain: vec![],
         });
 
         me.dump().await?;
@@ -161,8 +215,8 @@ impl GenkaiPointDatabase for MemoryDB {
             .await
             .sessions
             .iter()
-            .filter(|x| x.user_id == user_id)
-            .any(|x| x.left_at.is_none()))
+            .filter(|x| x.session.user_id == user_id)
+            .any(|x| x.session.left_at.is_none()))
     }
 
     async fn close_session(
@@ -174,6 +228,7 @@ impl GenkaiPointDatabase for MemoryDB {
 
         me.sessions
             .iter_mut()
+            .map(|x| &mut x.session)
             .filter(|x| x.user_id == user_id)
             .find(|x| x.left_at.is_none())
             .ok_or_else(|| anyhow!("there is no unclosed session"))?
@@ -191,8 +246,8 @@ impl GenkaiPointDatabase for MemoryDB {
             .await
             .sessions
             .iter()
-            .filter(|x| x.left_at.is_none())
-            .map(|x| x.user_id)
+            .filter(|x| x.session.left_at.is_none())
+            .map(|x| x.session.user_id)
             .collect::<Vec<_>>();
 
         list.dedup();
@@ -207,30 +262,287 @@ impl GenkaiPointDatabase for MemoryDB {
             .await
             .sessions
             .iter()
+            .filter(|x| x.session.user_id == user_id)
+            .map(|x| x.session.clone())
+            .collect())
+    }
+
+    async fn get_all_sessions(&self) -> Result<Vec<Session>> {
+        Ok(self
+            .inner()
+            .await
+            .sessions
+            .iter()
+            .map(|x| x.session.clone())
+            .collect())
+    }
+
+    async fn add_excluded_period(&self, user_id: u64, period: ExcludedPeriod) -> Result<()> {
+        let mut me = self.inner().await;
+
+        me.sessions
+            .iter_mut()
+            .map(|x| &mut x.session)
+            .filter(|x| x.user_id == user_id)
+            .find(|x| x.left_at.is_none())
+            .ok_or_else(|| anyhow!("there is no unclosed session"))?
+            .excluded
+            .push(period);
+
+        me.dump().await?;
+
+        Ok(())
+    }
+
+    async fn end_excluded_periods(&self, user_id: u64, until: DateTime<Utc>) -> Result<()> {
+        let mut me = self.inner().await;
+
+        let periods = me
+            .sessions
+            .iter_mut()
+            .map(|x| &mut x.session)
+            .filter(|x| x.user_id == user_id && x.left_at.is_none())
+            .flat_map(|x| x.excluded.iter_mut())
+            .filter(|x| x.until.is_none());
+
+        for period in periods {
+            period.until = Some(until);
+        }
+
+        me.dump().await?;
+
+        Ok(())
+    }
+
+    async fn add_uncertain_period(&self, user_id: u64, period: UncertainPeriod) -> Result<()> {
+        let mut me = self.inner().await;
+
+        me.sessions
+            .iter_mut()
+            .map(|x| &mut x.session)
+            .filter(|x| x.user_id == user_id)
+            .find(|x| x.left_at.is_none())
+            .ok_or_else(|| anyhow!("there is no unclosed session"))?
+            .uncertain
+            .push(period);
+
+        me.dump().await?;
+
+        Ok(())
+    }
+
+    async fn record_heartbeat(&self, at: DateTime<Utc>) -> Result<()> {
+        let mut me = self.inner().await;
+        me.last_heartbeat = Some(at);
+        me.dump().await?;
+
+        Ok(())
+    }
+
+    async fn get_last_heartbeat(&self) -> Result<Option<DateTime<Utc>>> {
+        Ok(self.inner().await.last_heartbeat)
+    }
+
+    async fn start_deafen(&self, user_id: u64, at: DateTime<Utc>) -> Result<()> {
+        let mut me = self.inner().await;
+        me.deafened_since.entry(user_id).or_insert(at);
+        me.dump().await?;
+
+        Ok(())
+    }
+
+    async fn take_deafened_since(&self, user_id: u64) -> Result<Option<DateTime<Utc>>> {
+        let mut me = self.inner().await;
+        let since = me.deafened_since.remove(&user_id);
+        me.dump().await?;
+
+        Ok(since)
+    }
+
+    async fn take_all_deafened_since(&self) -> Result<Vec<(u64, DateTime<Utc>)>> {
+        let mut me = self.inner().await;
+        let all = me.deafened_since.drain().collect();
+        me.dump().await?;
+
+        Ok(all)
+    }
+
+    async fn start_sub_session(
+        &self,
+        user_id: u64,
+        channel_id: u64,
+        joined_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let mut me = self.inner().await;
+
+        if let Some(sub) = me
+            .sub_sessions
+            .iter_mut()
+            .find(|x| x.user_id == user_id && x.left_at.is_none())
+        {
+            sub.left_at = Some(joined_at);
+        }
+
+        me.sub_sessions.push(SubSession {
+            user_id,
+            channel_id,
+            joined_at,
+            left_at: None,
+        });
+
+        me.dump().await?;
+
+        Ok(())
+    }
+
+    async fn close_sub_session(&self, user_id: u64, left_at: DateTime<Utc>) -> Result<()> {
+        let mut me = self.inner().await;
+
+        if let Some(sub) = me
+            .sub_sessions
+            .iter_mut()
+            .find(|x| x.user_id == user_id && x.left_at.is_none())
+        {
+            sub.left_at = Some(left_at);
+        }
+
+        me.dump().await?;
+
+        Ok(())
+    }
+
+    async fn get_users_all_sub_sessions(&self, user_id: u64) -> Result<Vec<SubSession>> {
+        Ok(self
+            .inner()
+            .await
+            .sub_sessions
+            .iter()
             .filter(|x| x.user_id == user_id)
             .cloned()
             .collect())
     }
 
-    async fn get_all_sessions(&self) -> Result<Vec<Session>> {
-        Ok(self.inner().await.sessions.clone())
+    async fn save_custom_formula(&self, formula: CustomFormula) -> Result<()> {
+        let mut me = self.inner().await;
+
+        me.custom_formulas.retain(|x| x.name != formula.name);
+        me.custom_formulas.push(formula);
+
+        me.dump().await?;
+
+        Ok(())
+    }
+
+    async fn get_custom_formula(&self, name: &str) -> Result<Option<CustomFormula>> {
+        Ok(self
+            .inner()
+            .await
+            .custom_formulas
+            .iter()
+            .find(|x| x.name == name)
+            .cloned())
+    }
+
+    async fn get_all_custom_formulas(&self) -> Result<Vec<CustomFormula>> {
+        Ok(self.inner().await.custom_formulas.clone())
+    }
+
+    async fn delete_custom_formula(&self, name: &str) -> Result<IsUpdated> {
+        let mut me = self.inner().await;
+        let index = me.custom_formulas.iter().position(|x| x.name == name);
+
+        if let Some(index) = index {
+            me.custom_formulas.remove(index);
+        }
+
+        me.dump().await?;
+
+        Ok(index.is_some())
+    }
+
+    async fn get_users_all_identified_sessions(
+        &self,
+        user_id: u64,
+    ) -> Result<Vec<(SessionId, Session)>> {
+        Ok(self
+            .inner()
+            .await
+            .sessions
+            .iter()
+            .filter(|x| x.session.user_id == user_id)
+            .map(|x| (SessionId(x.id.to_string()), x.session.clone()))
+            .collect())
+    }
+
+    async fn get_session(&self, id: &SessionId) -> Result<Option<Session>> {
+        let me = self.inner().await;
+
+        Ok(me
+            .session_index(id)
+            .map(|index| me.sessions[index].session.clone()))
+    }
+
+    async fn update_session(&self, id: &SessionId, session: Session) -> Result<IsUpdated> {
+        let mut me = self.inner().await;
+
+        let Some(index) = me.session_index(id) else {
+            return Ok(false);
+        };
+        me.sessions[index].session = session;
+
+        me.dump().await?;
+
+        Ok(true)
+    }
+
+    async fn insert_session(&self, session: Session) -> Result<SessionId> {
+        let mut me = self.inner().await;
+        let id = me.push_session(session);
+
+        me.dump().await?;
+
+        Ok(id)
+    }
+
+    async fn delete_session(&self, id: &SessionId) -> Result<IsUpdated> {
+        let mut me = self.inner().await;
+        let index = me.session_index(id);
+
+        if let Some(index) = index {
+            me.sessions.remove(index);
+        }
+
+        me.dump().await?;
+
+        Ok(index.is_some())
+    }
+
+    async fn add_audit_log(&self, entry: AuditLogEntry) -> Result<()> {
+        let mut me = self.inner().await;
+        me.audit_logs.push(entry);
+        me.dump().await?;
+
+        Ok(())
+    }
+
+    async fn get_all_audit_logs(&self) -> Result<Vec<AuditLogEntry>> {
+        Ok(self.inner().await.audit_logs.clone())
     }
 }
 
 #[derive(Serialize, Default)]
 struct AuthEntry {
     pgp_pub_key: Option<String>,
-    token: Option<String>,
+    pgp_key_verified_at: Option<DateTime<Utc>>,
 }
 
 impl GenkaiAuthDatabase for MemoryDB {
     async fn register_pgp_key(&self, user_id: u64, key: &str) -> Result<()> {
-        self.inner()
-            .await
-            .auth_entries
-            .entry(user_id)
-            .or_default()
-            .pgp_pub_key = Some(key.to_string());
+        let mut inner = self.inner().await;
+        let entry = inner.auth_entries.entry(user_id).or_default();
+
+        entry.pgp_pub_key = Some(key.to_string());
+        entry.pgp_key_verified_at = None;
 
         Ok(())
     }
@@ -244,35 +556,196 @@ impl GenkaiAuthDatabase for MemoryDB {
             .and_then(|x| x.pgp_pub_key.clone()))
     }
 
-    async fn register_token(&self, user_id: u64, token: &str) -> Result<()> {
-        self.inner()
+    async fn mark_pgp_key_verified(
+        &self,
+        user_id: u64,
+        key: &str,
+        verified_at: DateTime<Utc>,
+    ) -> Result<IsUpdated> {
+        let mut inner = self.inner().await;
+
+        match inner.auth_entries.get_mut(&user_id) {
+            Some(entry) if entry.pgp_pub_key.as_deref() == Some(key) => {
+                entry.pgp_key_verified_at = Some(verified_at);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn get_pgp_key_verified_at(&self, user_id: u64) -> Result<Option<DateTime<Utc>>> {
+        Ok(self
+            .inner()
             .await
             .auth_entries
-            .entry(user_id)
-            .or_default()
-            .token = Some(token.to_string());
+            .get(&user_id)
+            .and_then(|x| x.pgp_key_verified_at))
+    }
+
+    async fn register_token(&self, token: Token) -> Result<()> {
+        let tokens = &mut self.inner().await.auth_tokens;
+
+        // same as the unique index in mongodb
+        if tokens
+            .iter()
+            .any(|x| x.user_id == token.user_id && x.name == token.name)
+        {
+            bail!("token named {} already exists", token.name);
+        }
 
+        tokens.push(token);
         Ok(())
     }
 
-    async fn revoke_token(&self, user_id: u64) -> Result<()> {
-        self.inner()
+    async fn revoke_token(&self, user_id: u64, name: &str) -> Result<IsUpdated> {
+        let tokens = &mut self.inner().await.auth_tokens;
+        let before = tokens.len();
+        tokens.retain(|x| !(x.user_id == user_id && x.name == name));
+
+        Ok(tokens.len() != before)
+    }
+
+    async fn get_tokens(&self, user_id: u64) -> Result<Vec<Token>> {
+        Ok(self
+            .inner()
             .await
-            .auth_entries
-            .entry(user_id)
-            .or_default()
-            .token = None;
+            .auth_tokens
+            .iter()
+            .filter(|x| x.user_id == user_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn get_token(&self, id: &str) -> Result<Option<Token>> {
+        Ok(self
+            .inner()
+            .await
+            .auth_tokens
+            .iter()
+            .find(|x| x.id == id)
+            .cloned())
+    }
+
+    async fn touch_token(&self, id: &str, used_at: DateTime<Utc>) -> Result<()> {
+        if let Some(token) = self
+            .inner()
+            .await
+            .auth_tokens
+            .iter_mut()
+            .find(|x| x.id == id)
+        {
+            token.last_used_at = Some(used_at);
+        }
 
         Ok(())
     }
 
-    async fn get_token(&self, user_id: u64) -> Result<Option<String>> {
+    async fn create_device_authorization(&self, auth: DeviceAuthorization) -> Result<IsUpdated> {
+        let auths = &mut self.inner().await.device_authorizations;
+
+        // abandoned ones would pile up otherwise
+        auths.retain(|x| !x.is_expired(auth.created_at));
+
+        if auths.iter().any(|x| x.user_code == auth.user_code) {
+            return Ok(false);
+        }
+
+        auths.push(auth);
+
+        Ok(true)
+    }
+
+    async fn count_device_authorizations(&self, now: DateTime<Utc>) -> Result<usize> {
         Ok(self
             .inner()
             .await
-            .auth_entries
-            .get(&user_id)
-            .and_then(|x| x.token.clone()))
+            .device_authorizations
+            .iter()
+            .filter(|x| !x.is_expired(now))
+            .count())
+    }
+
+    async fn get_device_authorization(
+        &self,
+        hashed_device_code: &str,
+    ) -> Result<Option<DeviceAuthorization>> {
+        Ok(self
+            .inner()
+            .await
+            .device_authorizations
+            .iter()
+            .find(|x| x.hashed_device_code == hashed_device_code)
+            .cloned())
+    }
+
+    async fn get_device_authorization_by_user_code(
+        &self,
+        user_code: &str,
+    ) -> Result<Option<DeviceAuthorization>> {
+        Ok(self
+            .inner()
+            .await
+            .device_authorizations
+            .iter()
+            .find(|x| x.user_code == user_code)
+            .cloned())
+    }
+
+    async fn approve_device_authorization(
+        &self,
+        hashed_device_code: &str,
+        user_id: u64,
+    ) -> Result<IsUpdated> {
+        let mut inner = self.inner().await;
+        let Some(auth) = inner
+            .device_authorizations
+            .iter_mut()
+            .find(|x| x.hashed_device_code == hashed_device_code && x.approved_by.is_none())
+        else {
+            return Ok(false);
+        };
+
+        auth.approved_by = Some(user_id);
+
+        Ok(true)
+    }
+
+    async fn record_device_poll(
+        &self,
+        hashed_device_code: &str,
+        polled_at: DateTime<Utc>,
+        last_polled_before: DateTime<Utc>,
+    ) -> Result<IsUpdated> {
+        let mut inner = self.inner().await;
+        let Some(auth) = inner.device_authorizations.iter_mut().find(|x| {
+            x.hashed_device_code == hashed_device_code
+                && x.last_polled_at.is_none_or(|x| x <= last_polled_before)
+        }) else {
+            return Ok(false);
+        };
+
+        auth.last_polled_at = Some(polled_at);
+
+        Ok(true)
+    }
+
+    async fn delete_device_authorization(&self, hashed_device_code: &str) -> Result<IsUpdated> {
+        let auths = &mut self.inner().await.device_authorizations;
+        let before = auths.len();
+        auths.retain(|x| x.hashed_device_code != hashed_device_code);
+
+        Ok(auths.len() != before)
+    }
+}
+
+impl VcDiffDatabase for MemoryDB {
+    async fn get_vc_diff_config(&self) -> Result<Option<VcDiffConfig>> {
+        Ok(self.inner().await.vc_diff_config.clone())
+    }
+
+    async fn set_vc_diff_config(&self, config: VcDiffConfig) -> Result<()> {
+        self.inner().await.vc_diff_config = Some(config);
+        Ok(())
     }
 }
 
@@ -5,21 +5,36 @@ use {
         bot::{
             IsUpdated,
             alias::{MessageAliasDatabase, model::MessageAlias},
-            auth::GenkaiAuthDatabase,
-            genkai_point::{CreateNewSessionResult, GenkaiPointDatabase, model::Session},
+            auth::{
+                GenkaiAuthDatabase, legacy_token_id,
+                model::{DeviceAuthorization, Token, TokenScope},
+            },
+            genkai_point::{
+                CreateNewSessionResult, GenkaiPointDatabase,
+                admin::AuditLogEntry,
+                formula::custom::CustomFormula,
+                model::{ExcludedPeriod, Session, SessionId, SubSession, UncertainPeriod},
+            },
             meigen::{
                 self, MeigenDatabase, SortDirection, SortKey,
                 model::{Meigen, MeigenId},
             },
+            vc_diff::{VcDiffDatabase, model::VcDiffConfig},
+        },
+        db::mongodb::model::{
+            GenkaiAuthData, MongoAuditLog, MongoAuthToken, MongoCustomFormula, MongoDeafen,
+            MongoDeviceAuthorization, MongoExcludedPeriod, MongoHeartbeat, MongoMeigen,
+            MongoMessageAlias, MongoSession, MongoSubSession, MongoUncertainPeriod,
+            MongoVcDiffConfig,
         },
-        db::mongodb::model::{GenkaiAuthData, MongoMeigen, MongoMessageAlias, MongoSession},
     },
     anyhow::{Context as _, Result, bail},
     chrono::{DateTime, Duration, Utc},
+    clap::ValueEnum,
     mongodb::{
-        Client, Collection, Database,
+        Client, Collection, Database, IndexModel,
         bson::{self, Document, doc, oid::ObjectId},
-        options::ClientOptions,
+        options::{ClientOptions, IndexOptions},
     },
     serde::{Deserialize, de::DeserializeOwned},
     tokio_stream::StreamExt,
@@ -40,14 +55,127 @@ impl MongoDb {
             .context("failed to create mongodb client")?
             .database("RustyPonyo");
 
-        Ok(Self { inner: db })
+        let me = Self { inner: db };
+        me.migrate().await?;
+
+        Ok(me)
+    }
+
+    // brings documents written by older versions up to date, and ensures indexes
+    async fn migrate(&self) -> Result<()> {
+        self.migrate_legacy_tokens().await?;
+
+        self.inner
+            .collection::<MongoAuthToken>(GENKAI_AUTH_TOKEN_COLLECTION_NAME)
+            .create_indexes([
+                // tokens are looked up by the id carried in themselves
+                IndexModel::builder()
+                    .keys(doc! { "id": 1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                IndexModel::builder()
+                    .keys(doc! { "user_id": 1, "name": 1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+            ])
+            .await
+            .context("failed to create indexes of tokens")?;
+
+        self.inner
+            .collection::<MongoDeviceAuthorization>(GENKAI_AUTH_DEVICE_COLLECTION_NAME)
+            .create_indexes([
+                // mongodb removes expired device authorizations by itself
+                IndexModel::builder()
+                    .keys(doc! { "expires_at": 1 })
+                    .options(
+                        IndexOptions::builder()
+                            .expire_after(std::time::Duration::ZERO)
+                            .build(),
+                    )
+                    .build(),
+                // user codes are typed by users to approve, so each must point to one authorization
+                IndexModel::builder()
+                    .keys(doc! { "user_code": 1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+            ])
+            .await
+            .context("failed to create indexes of device authorizations")?;
+
+        Ok(())
+    }
+
+    // tokens used to be stored in GenkaiAuth as a hash, one per user
+    async fn migrate_legacy_tokens(&self) -> Result<()> {
+        let collection = self
+            .inner
+            .collection::<Document>(GENKAI_AUTH_COLLECTION_NAME);
+
+        let legacy = collection
+            .find(doc! { "token": { "$exists": true } })
+            .await
+            .context("failed to find legacy tokens")?
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .context("failed to deserialize legacy token")?;
+
+        for doc in legacy {
+            let user_id = doc
+                .get_str("user_id")
+                .context("legacy auth data must have user_id")?;
+            let hashed_token = doc
+                .get_str("token")
+                .context("legacy token must be a string")?;
+            let id = legacy_token_id(hashed_token);
+
+            // they had no restriction
+            let token = MongoAuthToken::from(Token {
+                id: id.clone(),
+                user_id: user_id.parse().context("user_id must be valid number")?,
+                name: "legacy".to_owned(),
+                hashed_token: hashed_token.to_owned(),
+                scopes: TokenScope::value_variants().to_vec(),
+                created_at: Utc::now(),
+                expires_at: None,
+                last_used_at: None,
+            });
+            let token =
+                bson::serialize_to_document(&token).context("failed to serialize legacy token")?;
+
+            self.inner
+                .collection::<MongoAuthToken>(GENKAI_AUTH_TOKEN_COLLECTION_NAME)
+                .update_one(doc! { "id": &id }, doc! { "$setOnInsert": &token })
+                .upsert(true)
+                .await
+                .context("failed to insert legacy token")?;
+
+            collection
+                .update_one(
+                    doc! { "user_id": user_id },
+                    doc! { "$unset": { "token": "" } },
+                )
+                .await
+                .context("failed to unset legacy token")?;
+
+            tracing::info!("migrated legacy token of user({user_id})");
+        }
+
+        Ok(())
     }
 }
 
 const MESSAGE_ALIAS_COLLECTION_NAME: &str = "MessageAlias";
 const GENKAI_POINT_COLLECTION_NAME: &str = "GenkaiPoint";
+const GENKAI_POINT_SUB_SESSION_COLLECTION_NAME: &str = "GenkaiPointSubSession";
+const GENKAI_POINT_CUSTOM_FORMULA_COLLECTION_NAME: &str = "GenkaiPointCustomFormula";
+const GENKAI_POINT_AUDIT_LOG_COLLECTION_NAME: &str = "GenkaiPointAuditLog";
+const GENKAI_POINT_HEARTBEAT_COLLECTION_NAME: &str = "GenkaiPointHeartbeat";
+const GENKAI_POINT_DEAFEN_COLLECTION_NAME: &str = "GenkaiPointDeafen";
 const GENKAI_AUTH_COLLECTION_NAME: &str = "GenkaiAuth";
+const GENKAI_AUTH_TOKEN_COLLECTION_NAME: &str = "GenkaiAuthToken";
+const GENKAI_AUTH_DEVICE_COLLECTION_NAME: &str = "GenkaiAuthDevice";
 const MEIGEN_COLLECTION_NAME: &str = "Meigen";
+const VC_DIFF_CONFIG_COLLECTION_NAME: &str = "VcDiffConfig";
 
 impl MessageAliasDatabase for MongoDb {
     async fn save(&self, alias: MessageAlias) -> Result<()> {
@@ -199,7 +327,7 @@ impl GenkaiPointDatabase for MongoDb {
                     .await
                     .context("failed to unset left_at")?;
 
-                return Ok(CreateNewSessionResult::SessionResumed);
+                return Ok(CreateNewSessionResult::SessionResumed { left_at });
             }
         }
 
@@ -207,6 +335,8 @@ impl GenkaiPointDatabase for MongoDb {
             user_id,
             joined_at,
             left_at: None,
+            excluded: vec![],
+            uncertain: vec![],
         }
         .into();
 
@@ -315,6 +445,335 @@ impl GenkaiPointDatabase for MongoDb {
             .await
             .context("failed to deserialize document")
     }
+
+    async fn add_excluded_period(&self, user_id: u64, period: ExcludedPeriod) -> Result<()> {
+        let period = bson::serialize_to_bson(&MongoExcludedPeriod::from(period))
+            .context("failed to serialize excluded period")?;
+
+        let result = self
+            .inner
+            .collection::<MongoSession>(GENKAI_POINT_COLLECTION_NAME)
+            .find_one_and_update(
+                doc! {
+                    "user_id": user_id.to_string(),
+                    "left_at": { "$exists": false }
+                },
+                doc! { "$push": { "excluded": period } },
+            )
+            .await
+            .context("failed to push excluded period")?;
+
+        if result.is_none() {
+            bail!("user({}) has no unclosed session", user_id);
+        }
+
+        Ok(())
+    }
+
+    async fn end_excluded_periods(&self, user_id: u64, until: DateTime<Utc>) -> Result<()> {
+        self.inner
+            .collection::<MongoSession>(GENKAI_POINT_COLLECTION_NAME)
+            .update_one(
+                // array updates fail on documents without `excluded` (it's skipped when empty),
+                // so only match the ones which actually have an open period
+                doc! {
+                    "user_id": user_id.to_string(),
+                    "left_at": { "$exists": false },
+                    "excluded": { "$elemMatch": { "until": { "$exists": false } } }
+                },
+                doc! { "$set": { "excluded.$[open].until": until } },
+            )
+            .array_filters(vec![doc! { "open.until": { "$exists": false } }])
+            .await
+            .context("failed to end excluded periods")?;
+
+        Ok(())
+    }
+
+    async fn add_uncertain_period(&self, user_id: u64, period: UncertainPeriod) -> Result<()> {
+        let period = bson::serialize_to_bson(&MongoUncertainPeriod::from(period))
+            .context("failed to serialize uncertain period")?;
+
+        let result = self
+            .inner
+            .collection::<MongoSession>(GENKAI_POINT_COLLECTION_NAME)
+            .find_one_and_update(
+                doc! {
+                    "user_id": user_id.to_string(),
+                    "left_at": { "$exists": false }
+                },
+                doc! { "$push": { "uncertain": period } },
+            )
+            .await
+            .context("failed to push uncertain period")?;
+
+        if result.is_none() {
+            bail!("user({}) has no unclosed session", user_id);
+        }
+
+        Ok(())
+    }
+
+    // the collection holds only one document
+    async fn record_heartbeat(&self, at: DateTime<Utc>) -> Result<()> {
+        self.inner
+            .collection::<MongoHeartbeat>(GENKAI_POINT_HEARTBEAT_COLLECTION_NAME)
+            .replace_one(doc! {}, MongoHeartbeat { at: at.into() })
+            .upsert(true)
+            .await
+            .context("failed to upsert heartbeat")?;
+
+        Ok(())
+    }
+
+    async fn get_last_heartbeat(&self) -> Result<Option<DateTime<Utc>>> {
+        self.inner
+            .collection::<MongoHeartbeat>(GENKAI_POINT_HEARTBEAT_COLLECTION_NAME)
+            .find_one(doc! {})
+            .await
+            .context("failed to find heartbeat")
+            .map(|x| x.map(|x| x.at.into()))
+    }
+
+    async fn start_deafen(&self, user_id: u64, at: DateTime<Utc>) -> Result<()> {
+        let since: bson::DateTime = at.into();
+
+        self.inner
+            .collection::<MongoDeafen>(GENKAI_POINT_DEAFEN_COLLECTION_NAME)
+            .update_one(
+                doc! { "user_id": user_id.to_string() },
+                doc! { "$setOnInsert": { "since": since } },
+            )
+            .upsert(true)
+            .await
+            .context("failed to upsert deafen")?;
+
+        Ok(())
+    }
+
+    async fn take_deafened_since(&self, user_id: u64) -> Result<Option<DateTime<Utc>>> {
+        self.inner
+            .collection::<MongoDeafen>(GENKAI_POINT_DEAFEN_COLLECTION_NAME)
+            .find_one_and_delete(doc! { "user_id": user_id.to_string() })
+            .await
+            .context("failed to delete deafen")
+            .map(|x| x.map(|x| x.since.into()))
+    }
+
+    async fn take_all_deafened_since(&self) -> Result<Vec<(u64, DateTime<Utc>)>> {
+        let collection = self
+            .inner
+            .collection::<MongoDeafen>(GENKAI_POINT_DEAFEN_COLLECTION_NAME);
+
+        let all = collection
+            .find(doc! {})
+            .await
+            .context("failed to find deafens")?
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .context("failed to deserialize deafen")?;
+
+        collection
+            .delete_many(doc! {})
+            .await
+            .context("failed to delete deafens")?;
+
+        all.into_iter()
+            .map(|x| {
+                let user_id = x.user_id.parse().context("user_id must be valid number")?;
+                Ok((user_id, x.since.into()))
+            })
+            .collect()
+    }
+
+    async fn start_sub_session(
+        &self,
+        user_id: u64,
+        channel_id: u64,
+        joined_at: DateTime<Utc>,
+    ) -> Result<()> {
+        self.close_sub_session(user_id, joined_at).await?;
+
+        let sub: MongoSubSession = SubSession {
+            user_id,
+            channel_id,
+            joined_at,
+            left_at: None,
+        }
+        .into();
+
+        self.inner
+            .collection::<MongoSubSession>(GENKAI_POINT_SUB_SESSION_COLLECTION_NAME)
+            .insert_one(sub)
+            .await
+            .context("failed to insert sub-session")?;
+
+        Ok(())
+    }
+
+    async fn close_sub_session(&self, user_id: u64, left_at: DateTime<Utc>) -> Result<()> {
+        self.inner
+            .collection::<MongoSubSession>(GENKAI_POINT_SUB_SESSION_COLLECTION_NAME)
+            .update_many(
+                doc! {
+                    "user_id": user_id.to_string(),
+                    "left_at": { "$exists": false }
+                },
+                doc! { "$set": { "left_at": left_at } },
+            )
+            .await
+            .context("failed to close sub-session")?;
+
+        Ok(())
+    }
+
+    async fn get_users_all_sub_sessions(&self, user_id: u64) -> Result<Vec<SubSession>> {
+        self.inner
+            .collection::<MongoSubSession>(GENKAI_POINT_SUB_SESSION_COLLECTION_NAME)
+            .find(doc! { "user_id": user_id.to_string() })
+            .await
+            .context("failed to find")?
+            .map(|x| x.map(Into::into))
+            .collect::<Result<_, _>>()
+            .await
+            .context("failed to deserialize sub-session")
+    }
+
+    async fn save_custom_formula(&self, formula: CustomFormula) -> Result<()> {
+        let name = formula.name.clone();
+
+        self.inner
+            .collection::<MongoCustomFormula>(GENKAI_POINT_CUSTOM_FORMULA_COLLECTION_NAME)
+            .replace_one(doc! { "name": name }, MongoCustomFormula::from(formula))
+            .upsert(true)
+            .await
+            .context("failed to upsert custom formula")?;
+
+        Ok(())
+    }
+
+    async fn get_custom_formula(&self, name: &str) -> Result<Option<CustomFormula>> {
+        self.inner
+            .collection::<MongoCustomFormula>(GENKAI_POINT_CUSTOM_FORMULA_COLLECTION_NAME)
+            .find_one(doc! { "name": name })
+            .await
+            .context("failed to find custom formula")
+            .map(|x| x.map(Into::into))
+    }
+
+    async fn get_all_custom_formulas(&self) -> Result<Vec<CustomFormula>> {
+        self.inner
+            .collection::<MongoCustomFormula>(GENKAI_POINT_CUSTOM_FORMULA_COLLECTION_NAME)
+            .find(doc! {})
+            .await
+            .context("failed to find custom formulas")?
+            .map(|x| x.map(Into::into))
+            .collect::<Result<_, _>>()
+            .await
+            .context("failed to deserialize custom formula")
+    }
+
+    async fn delete_custom_formula(&self, name: &str) -> Result<IsUpdated> {
+        self.inner
+            .collection::<MongoCustomFormula>(GENKAI_POINT_CUSTOM_FORMULA_COLLECTION_NAME)
+            .delete_one(doc! { "name": name })
+            .await
+            .context("failed to delete custom formula")
+            .map(|x| x.deleted_count == 1)
+    }
+
+    async fn get_users_all_identified_sessions(
+        &self,
+        user_id: u64,
+    ) -> Result<Vec<(SessionId, Session)>> {
+        self.inner
+            .collection::<SessionWithDocId>(GENKAI_POINT_COLLECTION_NAME)
+            .find(doc! { "user_id": user_id.to_string() })
+            .await
+            .context("failed to find")?
+            .map(|x| x.map(|x| (SessionId(x.doc_id.to_hex()), x.session.into())))
+            .collect::<Result<_, _>>()
+            .await
+            .context("failed to deserialize session")
+    }
+
+    async fn get_session(&self, id: &SessionId) -> Result<Option<Session>> {
+        // ids that are not even an ObjectId can't exist
+        let Ok(doc_id) = ObjectId::parse_str(&id.0) else {
+            return Ok(None);
+        };
+
+        self.inner
+            .collection::<MongoSession>(GENKAI_POINT_COLLECTION_NAME)
+            .find_one(doc! { "_id": doc_id })
+            .await
+            .context("failed to find session")
+            .map(|x| x.map(Into::into))
+    }
+
+    async fn update_session(&self, id: &SessionId, session: Session) -> Result<IsUpdated> {
+        let Ok(doc_id) = ObjectId::parse_str(&id.0) else {
+            return Ok(false);
+        };
+
+        self.inner
+            .collection::<MongoSession>(GENKAI_POINT_COLLECTION_NAME)
+            .replace_one(doc! { "_id": doc_id }, MongoSession::from(session))
+            .await
+            .context("failed to replace session")
+            .map(|x| x.matched_count == 1)
+    }
+
+    async fn insert_session(&self, session: Session) -> Result<SessionId> {
+        let result = self
+            .inner
+            .collection::<MongoSession>(GENKAI_POINT_COLLECTION_NAME)
+            .insert_one(MongoSession::from(session))
+            .await
+            .context("failed to insert session")?;
+
+        let doc_id = result
+            .inserted_id
+            .as_object_id()
+            .context("inserted id must be ObjectId")?;
+
+        Ok(SessionId(doc_id.to_hex()))
+    }
+
+    async fn delete_session(&self, id: &SessionId) -> Result<IsUpdated> {
+        let Ok(doc_id) = ObjectId::parse_str(&id.0) else {
+            return Ok(false);
+        };
+
+        self.inner
+            .collection::<MongoSession>(GENKAI_POINT_COLLECTION_NAME)
+            .delete_one(doc! { "_id": doc_id })
+            .await
+            .context("failed to delete session")
+            .map(|x| x.deleted_count == 1)
+    }
+
+    async fn add_audit_log(&self, entry: AuditLogEntry) -> Result<()> {
+        self.inner
+            .collection::<MongoAuditLog>(GENKAI_POINT_AUDIT_LOG_COLLECTION_NAME)
+            .insert_one(MongoAuditLog::from(entry))
+            .await
+            .context("failed to insert audit log")?;
+
+        Ok(())
+    }
+
+    async fn get_all_audit_logs(&self) -> Result<Vec<AuditLogEntry>> {
+        self.inner
+            .collection::<MongoAuditLog>(GENKAI_POINT_AUDIT_LOG_COLLECTION_NAME)
+            .find(doc! {})
+            .await
+            .context("failed to find audit logs")?
+            .map(|x| x.map(Into::into))
+            .collect::<Result<_, _>>()
+            .await
+            .context("failed to deserialize audit log")
+    }
 }
 
 impl GenkaiAuthDatabase for MongoDb {
@@ -325,7 +784,10 @@ impl GenkaiAuthDatabase for MongoDb {
             .collection::<GenkaiAuthData>(GENKAI_AUTH_COLLECTION_NAME)
             .find_one_and_update(
                 doc! { "user_id": &user_id },
-                doc! { "$set": { "pgp_pub_key": key } },
+                doc! {
+                    "$set": { "pgp_pub_key": key },
+                    "$unset": { "pgp_key_verified_at": "" },
+                },
             )
             .upsert(true)
             .await
@@ -345,47 +807,187 @@ impl GenkaiAuthDatabase for MongoDb {
             .map(|x| x.and_then(|x| x.pgp_pub_key))
     }
 
-    async fn register_token(&self, user_id: u64, token: &str) -> Result<()> {
+    async fn mark_pgp_key_verified(
+        &self,
+        user_id: u64,
+        key: &str,
+        verified_at: DateTime<Utc>,
+    ) -> Result<IsUpdated> {
         let user_id = user_id.to_string();
 
         self.inner
             .collection::<GenkaiAuthData>(GENKAI_AUTH_COLLECTION_NAME)
-            .find_one_and_update(
-                doc! { "user_id": &user_id },
-                doc! { "$set": { "token": token } },
+            .update_one(
+                doc! { "user_id": &user_id, "pgp_pub_key": key },
+                doc! { "$set": { "pgp_key_verified_at": bson::DateTime::from(verified_at) } },
             )
-            .upsert(true)
             .await
-            .context("failed to upsert")?;
+            .context("failed to mark pgp key as verified")
+            .map(|x| x.matched_count == 1)
+    }
+
+    async fn get_pgp_key_verified_at(&self, user_id: u64) -> Result<Option<DateTime<Utc>>> {
+        let user_id = user_id.to_string();
+
+        self.inner
+            .collection::<GenkaiAuthData>(GENKAI_AUTH_COLLECTION_NAME)
+            .find_one(doc! { "user_id": &user_id })
+            .await
+            .context("failed to find pgp key")
+            .map(|x| x.and_then(|x| x.pgp_key_verified_at).map(Into::into))
+    }
+
+    async fn register_token(&self, token: Token) -> Result<()> {
+        self.inner
+            .collection::<MongoAuthToken>(GENKAI_AUTH_TOKEN_COLLECTION_NAME)
+            .insert_one(MongoAuthToken::from(token))
+            .await
+            .context("failed to insert new token")?;
 
         Ok(())
     }
 
-    async fn revoke_token(&self, user_id: u64) -> Result<()> {
+    async fn revoke_token(&self, user_id: u64, name: &str) -> Result<IsUpdated> {
         let user_id = user_id.to_string();
 
         self.inner
-            .collection::<GenkaiAuthData>(GENKAI_AUTH_COLLECTION_NAME)
-            .find_one_and_update(
-                doc! { "user_id": &user_id },
-                doc! { "$unset": { "token": "" } },
+            .collection::<MongoAuthToken>(GENKAI_AUTH_TOKEN_COLLECTION_NAME)
+            .delete_one(doc! { "user_id": &user_id, "name": name })
+            .await
+            .context("failed to delete token")
+            .map(|x| x.deleted_count == 1)
+    }
+
+    async fn get_tokens(&self, user_id: u64) -> Result<Vec<Token>> {
+        let user_id = user_id.to_string();
+
+        self.inner
+            .collection::<MongoAuthToken>(GENKAI_AUTH_TOKEN_COLLECTION_NAME)
+            .find(doc! { "user_id": &user_id })
+            .await
+            .context("failed to find tokens")?
+            .map(|x| x.map(Into::into))
+            .collect::<Result<_, _>>()
+            .await
+            .context("failed to deserialize token")
+    }
+
+    async fn get_token(&self, id: &str) -> Result<Option<Token>> {
+        self.inner
+            .collection::<MongoAuthToken>(GENKAI_AUTH_TOKEN_COLLECTION_NAME)
+            .find_one(doc! { "id": id })
+            .await
+            .context("failed to find token")
+            .map(|x| x.map(Into::into))
+    }
+
+    async fn touch_token(&self, id: &str, used_at: DateTime<Utc>) -> Result<()> {
+        self.inner
+            .collection::<MongoAuthToken>(GENKAI_AUTH_TOKEN_COLLECTION_NAME)
+            .update_one(
+                doc! { "id": id },
+                doc! { "$set": { "last_used_at": bson::DateTime::from(used_at) } },
             )
-            .upsert(true)
             .await
-            .context("failed to upsert")?;
+            .context("failed to update token")?;
 
         Ok(())
     }
 
-    async fn get_token(&self, user_id: u64) -> Result<Option<String>> {
-        let user_id = user_id.to_string();
+    async fn create_device_authorization(&self, auth: DeviceAuthorization) -> Result<IsUpdated> {
+        let result = self
+            .inner
+            .collection::<MongoDeviceAuthorization>(GENKAI_AUTH_DEVICE_COLLECTION_NAME)
+            .insert_one(MongoDeviceAuthorization::from(auth))
+            .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(e) if is_duplicate_key_error(&e) => Ok(false),
+            Err(e) => Err(e).context("failed to insert device authorization"),
+        }
+    }
 
+    async fn count_device_authorizations(&self, now: DateTime<Utc>) -> Result<usize> {
+        // the ttl monitor runs only once a minute
         self.inner
-            .collection::<GenkaiAuthData>(GENKAI_AUTH_COLLECTION_NAME)
-            .find_one(doc! { "user_id": &user_id })
+            .collection::<MongoDeviceAuthorization>(GENKAI_AUTH_DEVICE_COLLECTION_NAME)
+            .count_documents(doc! { "expires_at": { "$gt": bson::DateTime::from(now) } })
             .await
-            .context("failed to find pgp key")
-            .map(|x| x.and_then(|x| x.token))
+            .context("failed to count device authorizations")
+            .map(|x| x as usize)
+    }
+
+    async fn get_device_authorization(
+        &self,
+        hashed_device_code: &str,
+    ) -> Result<Option<DeviceAuthorization>> {
+        self.inner
+            .collection::<MongoDeviceAuthorization>(GENKAI_AUTH_DEVICE_COLLECTION_NAME)
+            .find_one(doc! { "hashed_device_code": hashed_device_code })
+            .await
+            .context("failed to find device authorization")
+            .map(|x| x.map(Into::into))
+    }
+
+    async fn get_device_authorization_by_user_code(
+        &self,
+        user_code: &str,
+    ) -> Result<Option<DeviceAuthorization>> {
+        self.inner
+            .collection::<MongoDeviceAuthorization>(GENKAI_AUTH_DEVICE_COLLECTION_NAME)
+            .find_one(doc! { "user_code": user_code })
+            .await
+            .context("failed to find device authorization")
+            .map(|x| x.map(Into::into))
+    }
+
+    async fn approve_device_authorization(
+        &self,
+        hashed_device_code: &str,
+        user_id: u64,
+    ) -> Result<IsUpdated> {
+        self.inner
+            .collection::<MongoDeviceAuthorization>(GENKAI_AUTH_DEVICE_COLLECTION_NAME)
+            .update_one(
+                doc! { "hashed_device_code": hashed_device_code, "approved_by": null },
+                doc! { "$set": { "approved_by": user_id.to_string() } },
+            )
+            .await
+            .context("failed to approve device authorization")
+            .map(|x| x.modified_count == 1)
+    }
+
+    async fn record_device_poll(
+        &self,
+        hashed_device_code: &str,
+        polled_at: DateTime<Utc>,
+        last_polled_before: DateTime<Utc>,
+    ) -> Result<IsUpdated> {
+        self.inner
+            .collection::<MongoDeviceAuthorization>(GENKAI_AUTH_DEVICE_COLLECTION_NAME)
+            .update_one(
+                doc! {
+                    "hashed_device_code": hashed_device_code,
+                    "$or": [
+                        { "last_polled_at": null },
+                        { "last_polled_at": { "$lte": bson::DateTime::from(last_polled_before) } },
+                    ],
+                },
+                doc! { "$set": { "last_polled_at": bson::DateTime::from(polled_at) } },
+            )
+            .await
+            .context("failed to record device poll")
+            .map(|x| x.modified_count == 1)
+    }
+
+    async fn delete_device_authorization(&self, hashed_device_code: &str) -> Result<IsUpdated> {
+        self.inner
+            .collection::<MongoDeviceAuthorization>(GENKAI_AUTH_DEVICE_COLLECTION_NAME)
+            .delete_one(doc! { "hashed_device_code": hashed_device_code })
+            .await
+            .context("failed to delete device authorization")
+            .map(|x| x.deleted_count == 1)
     }
 }
 
@@ -407,6 +1009,29 @@ impl MongoDb {
     }
 }
 
+// the collection holds only one document
+impl VcDiffDatabase for MongoDb {
+    async fn get_vc_diff_config(&self) -> Result<Option<VcDiffConfig>> {
+        self.inner
+            .collection::<MongoVcDiffConfig>(VC_DIFF_CONFIG_COLLECTION_NAME)
+            .find_one(doc! {})
+            .await
+            .context("failed to find vc_diff config")
+            .map(|x| x.map(Into::into))
+    }
+
+    async fn set_vc_diff_config(&self, config: VcDiffConfig) -> Result<()> {
+        self.inner
+            .collection::<MongoVcDiffConfig>(VC_DIFF_CONFIG_COLLECTION_NAME)
+            .replace_one(doc! {}, MongoVcDiffConfig::from(config))
+            .upsert(true)
+            .await
+            .context("failed to upsert vc_diff config")?;
+
+        Ok(())
+    }
+}
+
 impl MeigenDatabase for MongoDb {
     async fn save(
         &self,
@@ -592,6 +1217,14 @@ impl MeigenDatabase for MongoDb {
     }
 }
 
+fn is_duplicate_key_error(e: &mongodb::error::Error) -> bool {
+    use mongodb::error::{ErrorKind, WriteFailure};
+
+    const DUPLICATE_KEY: i32 = 11000;
+
+    matches!(&*e.kind, ErrorKind::Write(WriteFailure::WriteError(x)) if x.code == DUPLICATE_KEY)
+}
+
 trait PipelineExt {
     fn pipe<F, R>(self, f: F) -> R
     where
@@ -620,3 +1253,173 @@ impl<T, E> PipelineExt for std::result::Result<T, E> {
         f(self)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use {super::*, crate::bot::genkai_point::model::ExclusionReason, chrono::SubsecRound};
+
+    // these tests need a running MongoDB, e.g. `MONGODB_TEST_URI=mongodb://localhost:27017`.
+    // run them with `cargo test -- --ignored`.
+    async fn db(name: &str) -> MongoDb {
+        let uri = std::env::var("MONGODB_TEST_URI").expect("MONGODB_TEST_URI must be set");
+
+        let db = Client::with_uri_str(uri)
+            .await
+            .expect("failed to connect to mongodb")
+            .database(&format!("RustyPonyoTest_{name}"));
+        db.drop().await.expect("failed to drop test database");
+
+        MongoDb { inner: db }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB"]
+    async fn migrate_legacy_tokens() {
+        let db = db("migrate_legacy_tokens").await;
+
+        db.inner
+            .collection::<Document>(GENKAI_AUTH_COLLECTION_NAME)
+            .insert_one(
+                doc! { "user_id": "1", "pgp_pub_key": "key", "token": "abcdef0123456789abcdef" },
+            )
+            .await
+            .unwrap();
+
+        // it runs on every startup
+        db.migrate().await.unwrap();
+        db.migrate().await.unwrap();
+
+        let tokens = db.get_tokens(1).await.unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].id, "legacy-abcdef0123456789");
+        assert_eq!(tokens[0].hashed_token, "abcdef0123456789abcdef");
+        assert_eq!(tokens[0].expires_at, None);
+        assert_eq!(db.get_pgp_key(1).await.unwrap().as_deref(), Some("key"));
+
+        // names are unique per user
+        let mut duplicated = tokens[0].clone();
+        duplicated.id = "0123456789abcdef".to_owned();
+        assert!(db.register_token(duplicated).await.is_err());
+
+        // and so are ids
+        let mut duplicated = tokens[0].clone();
+        duplicated.name = "another".to_owned();
+        assert!(db.register_token(duplicated).await.is_err());
+
+        db.inner.drop().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB"]
+    async fn end_excluded_periods() {
+        let db = db("end_excluded_periods").await;
+        // bson keeps only milliseconds
+        let now = Utc::now().trunc_subsecs(3);
+
+        // a session without `excluded` field in the document
+        db.create_new_session(1, now).await.unwrap();
+        db.end_excluded_periods(1, now).await.unwrap();
+        db.close_session(1, now + Duration::hours(1)).await.unwrap();
+
+        db.create_new_session(2, now).await.unwrap();
+        db.add_excluded_period(
+            2,
+            ExcludedPeriod {
+                reason: ExclusionReason::Afk,
+                from: now,
+                until: None,
+            },
+        )
+        .await
+        .unwrap();
+        db.end_excluded_periods(2, now + Duration::minutes(30))
+            .await
+            .unwrap();
+
+        let sessions = db.get_users_all_sessions(2).await.unwrap();
+        assert_eq!(
+            sessions[0].excluded[0].until,
+            Some(now + Duration::minutes(30))
+        );
+
+        db.inner.drop().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB"]
+    async fn deafen() {
+        let db = db("deafen").await;
+        let now = Utc::now().trunc_subsecs(3);
+
+        // the earlier one is kept
+        db.start_deafen(1, now).await.unwrap();
+        db.start_deafen(1, now + Duration::minutes(1))
+            .await
+            .unwrap();
+        db.start_deafen(2, now).await.unwrap();
+
+        assert_eq!(db.take_deafened_since(1).await.unwrap(), Some(now));
+        assert_eq!(db.take_deafened_since(1).await.unwrap(), None);
+
+        assert_eq!(db.take_all_deafened_since().await.unwrap(), vec![(2, now)]);
+        assert!(db.take_all_deafened_since().await.unwrap().is_empty());
+
+        db.inner.drop().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs MongoDB"]
+    async fn device_authorizations() {
+        let db = db("device_authorizations").await;
+        db.migrate().await.unwrap();
+
+        let now = Utc::now().trunc_subsecs(3);
+        let auth = |hashed_device_code: &str| DeviceAuthorization {
+            hashed_device_code: hashed_device_code.to_owned(),
+            user_code: "BCDFGHJK".to_owned(),
+            name: "tool".to_owned(),
+            scopes: vec![TokenScope::MeigenRead],
+            created_at: now,
+            expires_at: now + Duration::minutes(10),
+            approved_by: None,
+            last_polled_at: None,
+        };
+
+        assert!(db.create_device_authorization(auth("first")).await.unwrap());
+        // user codes are unique
+        assert!(
+            !db.create_device_authorization(auth("second"))
+                .await
+                .unwrap()
+        );
+        assert_eq!(db.count_device_authorizations(now).await.unwrap(), 1);
+
+        assert!(db.approve_device_authorization("first", 1).await.unwrap());
+        assert!(!db.approve_device_authorization("first", 2).await.unwrap());
+
+        let interval = Duration::seconds(5);
+        assert!(
+            db.record_device_poll("first", now, now - interval)
+                .await
+                .unwrap()
+        );
+        let soon = now + Duration::seconds(1);
+        assert!(
+            !db.record_device_poll("first", soon, soon - interval)
+                .await
+                .unwrap()
+        );
+        let later = now + interval;
+        assert!(
+            db.record_device_poll("first", later, later - interval)
+                .await
+                .unwrap()
+        );
+
+        let found = db.get_device_authorization("first").await.unwrap().unwrap();
+        assert_eq!(found.approved_by, Some(1));
+        assert_eq!(found.last_polled_at, Some(later));
+
+        db.inner.drop().await.unwrap();
+    }
+}
@@ -3,8 +3,16 @@
 use {
     crate::bot::{
         alias::model::{MessageAlias, MessageAliasAttachment},
-        genkai_point::model::Session,
+        auth::model::{DeviceAuthorization, Token, TokenScope},
+        genkai_point::{
+            admin::{AuditLogEntry, Correction},
+            formula::custom::{CustomFormula, WeightPoint},
+            model::{
+                ExcludedPeriod, ExclusionReason, Session, SessionId, SubSession, UncertainPeriod,
+            },
+        },
         meigen::model::{Meigen, MeigenId},
+        vc_diff::model::VcDiffConfig,
     },
     anyhow::{Context as _, Result},
     mongodb::bson::{Binary, DateTime, spec::BinarySubtype},
@@ -78,6 +86,14 @@ pub(super) struct MongoSession {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(super) left_at: Option<DateTime>,
+
+    // Added for formula v4. Old documents don't have this field.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(super) excluded: Vec<MongoExcludedPeriod>,
+
+    // Added for downtime detection. Old documents don't have this field.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(super) uncertain: Vec<MongoUncertainPeriod>,
 }
 
 impl From<Session> for MongoSession {
@@ -86,6 +102,8 @@ impl From<Session> for MongoSession {
             user_id: s.user_id.to_string(),
             joined_at: DateTime::from(s.joined_at),
             left_at: s.left_at.map(DateTime::from),
+            excluded: s.excluded.into_iter().map(Into::into).collect(),
+            uncertain: s.uncertain.into_iter().map(Into::into).collect(),
         }
     }
 }
@@ -96,6 +114,176 @@ impl Into<Session> for MongoSession {
             user_id: self.user_id.parse().expect("invalid session user_id"),
             joined_at: self.joined_at.into(),
             left_at: self.left_at.map(|x| x.into()),
+            excluded: self.excluded.into_iter().map(Into::into).collect(),
+            uncertain: self.uncertain.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct MongoUncertainPeriod {
+    pub(super) from: DateTime,
+    pub(super) until: DateTime,
+}
+
+impl From<UncertainPeriod> for MongoUncertainPeriod {
+    fn from(p: UncertainPeriod) -> Self {
+        Self {
+            from: DateTime::from(p.from),
+            until: DateTime::from(p.until),
+        }
+    }
+}
+
+impl Into<UncertainPeriod> for MongoUncertainPeriod {
+    fn into(self) -> UncertainPeriod {
+        UncertainPeriod {
+            from: self.from.into(),
+            until: self.until.into(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct MongoHeartbeat {
+    pub(super) at: DateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct MongoDeafen {
+    pub(super) user_id: String,
+    pub(super) since: DateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct MongoExcludedPeriod {
+    pub(super) reason: ExclusionReason,
+    pub(super) from: DateTime,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) until: Option<DateTime>,
+}
+
+impl From<ExcludedPeriod> for MongoExcludedPeriod {
+    fn from(p: ExcludedPeriod) -> Self {
+        Self {
+            reason: p.reason,
+            from: DateTime::from(p.from),
+            until: p.until.map(DateTime::from),
+        }
+    }
+}
+
+impl Into<ExcludedPeriod> for MongoExcludedPeriod {
+    fn into(self) -> ExcludedPeriod {
+        ExcludedPeriod {
+            reason: self.reason,
+            from: self.from.into(),
+            until: self.until.map(|x| x.into()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct MongoSubSession {
+    pub(super) user_id: String,
+    pub(super) channel_id: String,
+    pub(super) joined_at: DateTime,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) left_at: Option<DateTime>,
+}
+
+impl From<SubSession> for MongoSubSession {
+    fn from(s: SubSession) -> Self {
+        Self {
+            user_id: s.user_id.to_string(),
+            channel_id: s.channel_id.to_string(),
+            joined_at: DateTime::from(s.joined_at),
+            left_at: s.left_at.map(DateTime::from),
+        }
+    }
+}
+
+impl Into<SubSession> for MongoSubSession {
+    fn into(self) -> SubSession {
+        SubSession {
+            user_id: self.user_id.parse().expect("invalid sub-session user_id"),
+            channel_id: self
+                .channel_id
+                .parse()
+                .expect("invalid sub-session channel_id"),
+            joined_at: self.joined_at.into(),
+            left_at: self.left_at.map(|x| x.into()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct MongoCustomFormula {
+    pub(super) name: String,
+    pub(super) created_by: String,
+    pub(super) points: Vec<WeightPoint>,
+}
+
+impl From<CustomFormula> for MongoCustomFormula {
+    fn from(f: CustomFormula) -> Self {
+        Self {
+            name: f.name,
+            created_by: f.created_by.to_string(),
+            points: f.points,
+        }
+    }
+}
+
+impl Into<CustomFormula> for MongoCustomFormula {
+    fn into(self) -> CustomFormula {
+        CustomFormula {
+            name: self.name,
+            created_by: self
+                .created_by
+                .parse()
+                .expect("invalid custom formula created_by"),
+            points: self.points,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct MongoAuditLog {
+    pub(super) operator_id: String,
+    pub(super) at: DateTime,
+    pub(super) session_id: String,
+    pub(super) correction: Correction,
+    pub(super) before: MongoSession,
+    pub(super) after: Vec<MongoSession>,
+}
+
+impl From<AuditLogEntry> for MongoAuditLog {
+    fn from(e: AuditLogEntry) -> Self {
+        Self {
+            operator_id: e.operator_id.to_string(),
+            at: e.at.into(),
+            session_id: e.session_id.0,
+            correction: e.correction,
+            before: e.before.into(),
+            after: e.after.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl Into<AuditLogEntry> for MongoAuditLog {
+    fn into(self) -> AuditLogEntry {
+        AuditLogEntry {
+            operator_id: self
+                .operator_id
+                .parse()
+                .expect("invalid audit log operator_id"),
+            at: self.at.into(),
+            session_id: SessionId(self.session_id),
+            correction: self.correction,
+            before: self.before.into(),
+            after: self.after.into_iter().map(Into::into).collect(),
         }
     }
 }
@@ -104,7 +292,121 @@ impl Into<Session> for MongoSession {
 pub(super) struct GenkaiAuthData {
     pub(super) user_id: String,
     pub(super) pgp_pub_key: Option<String>,
-    pub(super) token: Option<String>,
+    pub(super) pgp_key_verified_at: Option<DateTime>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct MongoAuthToken {
+    pub(super) id: String,
+    pub(super) user_id: String,
+    pub(super) name: String,
+    pub(super) hashed_token: String,
+    pub(super) scopes: Vec<TokenScope>,
+    pub(super) created_at: DateTime,
+    pub(super) expires_at: Option<DateTime>,
+    pub(super) last_used_at: Option<DateTime>,
+}
+
+impl From<Token> for MongoAuthToken {
+    fn from(t: Token) -> Self {
+        Self {
+            id: t.id,
+            user_id: t.user_id.to_string(),
+            name: t.name,
+            hashed_token: t.hashed_token,
+            scopes: t.scopes,
+            created_at: t.created_at.into(),
+            expires_at: t.expires_at.map(DateTime::from),
+            last_used_at: t.last_used_at.map(DateTime::from),
+        }
+    }
+}
+
+impl Into<Token> for MongoAuthToken {
+    fn into(self) -> Token {
+        Token {
+            id: self.id,
+            user_id: self.user_id.parse().expect("invalid token user_id"),
+            name: self.name,
+            hashed_token: self.hashed_token,
+            scopes: self.scopes,
+            created_at: self.created_at.into(),
+            expires_at: self.expires_at.map(|x| x.into()),
+            last_used_at: self.last_used_at.map(|x| x.into()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct MongoDeviceAuthorization {
+    pub(super) hashed_device_code: String,
+    pub(super) user_code: String,
+    pub(super) name: String,
+    pub(super) scopes: Vec<TokenScope>,
+    pub(super) created_at: DateTime,
+    pub(super) expires_at: DateTime,
+    pub(super) approved_by: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) last_polled_at: Option<DateTime>,
+}
+
+impl From<DeviceAuthorization> for MongoDeviceAuthorization {
+    fn from(a: DeviceAuthorization) -> Self {
+        Self {
+            hashed_device_code: a.hashed_device_code,
+            user_code: a.user_code,
+            name: a.name,
+            scopes: a.scopes,
+            created_at: a.created_at.into(),
+            expires_at: a.expires_at.into(),
+            approved_by: a.approved_by.map(|x| x.to_string()),
+            last_polled_at: a.last_polled_at.map(|x| x.into()),
+        }
+    }
+}
+
+impl Into<DeviceAuthorization> for MongoDeviceAuthorization {
+    fn into(self) -> DeviceAuthorization {
+        DeviceAuthorization {
+            hashed_device_code: self.hashed_device_code,
+            user_code: self.user_code,
+            name: self.name,
+            scopes: self.scopes,
+            created_at: self.created_at.into(),
+            expires_at: self.expires_at.into(),
+            approved_by: self
+                .approved_by
+                .map(|x| x.parse().expect("invalid approved_by user_id")),
+            last_polled_at: self.last_polled_at.map(|x| x.into()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct MongoVcDiffConfig {
+    pub(super) enabled: bool,
+    pub(super) channel_id: Option<String>,
+}
+
+impl From<VcDiffConfig> for MongoVcDiffConfig {
+    fn from(c: VcDiffConfig) -> Self {
+        Self {
+            enabled: c.enabled,
+            channel_id: c.channel_id.map(|x| x.to_string()),
+        }
+    }
+}
+
+impl Into<VcDiffConfig> for MongoVcDiffConfig {
+    fn into(self) -> VcDiffConfig {
+        VcDiffConfig {
+            enabled: self.enabled,
+            channel_id: self
+                .channel_id
+                .map(|x| x.parse().expect("invalid vc_diff channel_id")),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]